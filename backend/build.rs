@@ -2,7 +2,17 @@ fn main() {
     #[cfg(windows)]
     {
         let mut res = winres::WindowsResource::new();
-        res.set_manifest_file("app.manifest");
+        // Only the capture hook needs elevation; a controlled-only build runs as the user
+        if std::env::var_os("CARGO_FEATURE_CAPTURE").is_some() {
+            res.set_manifest_file("app.manifest");
+        } else {
+            let manifest = std::fs::read_to_string("app.manifest")
+                .unwrap()
+                .replace("requireAdministrator", "asInvoker");
+            let path = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("app.manifest");
+            std::fs::write(&path, manifest).unwrap();
+            res.set_manifest_file(path.to_str().unwrap());
+        }
         res.set_icon("icon.ico");
         res.compile().unwrap();
     }
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_service::protocol;
+
+// A single frame payload, as received over TCP or a UDP discovery packet
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = protocol::decode(data) {
+        // Whatever decodes must survive a round trip
+        let encoded = protocol::encode(&message).unwrap();
+        protocol::decode(&encoded).unwrap();
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_service::transport::Transport;
+
+// A raw byte stream from a peer: length prefixes and payloads in any state
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let mut reader = data;
+        while Transport::recv_tcp_split(&mut reader).await.is_ok() {}
+    });
+});
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+/// Where macOS lets the user grant the permission
+const SETTINGS_URL: &str = "x-apple.systempreferences:com.apple.preference.security?Privacy_Accessibility";
+
+/// Whether macOS lets us grab and post input events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessibilityState {
+    /// Not macOS: nothing to grant
+    Unsupported,
+    Granted,
+    /// Capture and injection silently do nothing until the user allows ShareFlow
+    Denied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessibilityStatus {
+    pub state: AccessibilityState,
+    /// Opens System Settings → Privacy & Security → Accessibility
+    pub settings_url: Option<String>,
+}
+
+impl AccessibilityStatus {
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    fn new(granted: bool) -> Self {
+        if granted {
+            AccessibilityStatus { state: AccessibilityState::Granted, settings_url: None }
+        } else {
+            AccessibilityStatus { state: AccessibilityState::Denied, settings_url: Some(SETTINGS_URL.to_string()) }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[allow(non_upper_case_globals)]
+mod ffi {
+    use std::os::raw::c_void;
+
+    #[link(name = "ApplicationServices", kind = "framework")]
+    extern "C" {
+        pub fn AXIsProcessTrusted() -> bool;
+        pub fn AXIsProcessTrustedWithOptions(options: *const c_void) -> bool;
+        pub static kAXTrustedCheckOptionPrompt: *const c_void;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        pub static kCFBooleanTrue: *const c_void;
+        pub static kCFTypeDictionaryKeyCallBacks: c_void;
+        pub static kCFTypeDictionaryValueCallBacks: c_void;
+        pub fn CFDictionaryCreate(
+            allocator: *const c_void,
+            keys: *const *const c_void,
+            values: *const *const c_void,
+            count: isize,
+            key_callbacks: *const c_void,
+            value_callbacks: *const c_void,
+        ) -> *const c_void;
+        pub fn CFRelease(object: *const c_void);
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn status() -> AccessibilityStatus {
+    AccessibilityStatus::new(unsafe { ffi::AXIsProcessTrusted() })
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn status() -> AccessibilityStatus {
+    AccessibilityStatus { state: AccessibilityState::Unsupported, settings_url: None }
+}
+
+/// Asks macOS to show its own "would like to control this computer" dialog,
+/// which offers to open the Accessibility settings. Doesn't wait for the
+/// answer: the user has to tick ShareFlow there, so check again afterwards.
+#[cfg(target_os = "macos")]
+pub fn prompt() -> AccessibilityStatus {
+    use std::ptr;
+
+    let granted = unsafe {
+        let keys = [ffi::kAXTrustedCheckOptionPrompt];
+        let values = [ffi::kCFBooleanTrue];
+        let options = ffi::CFDictionaryCreate(
+            ptr::null(),
+            keys.as_ptr(),
+            values.as_ptr(),
+            1,
+            ptr::addr_of!(ffi::kCFTypeDictionaryKeyCallBacks).cast(),
+            ptr::addr_of!(ffi::kCFTypeDictionaryValueCallBacks).cast(),
+        );
+        let granted = ffi::AXIsProcessTrustedWithOptions(options);
+        if !options.is_null() {
+            ffi::CFRelease(options);
+        }
+        granted
+    };
+    AccessibilityStatus::new(granted)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn prompt() -> AccessibilityStatus {
+    status()
+}
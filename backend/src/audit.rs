@@ -0,0 +1,151 @@
+use crate::protocol::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much injected input the controlled machine writes down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditMode {
+    #[default]
+    Off,
+    /// Event counts per type and peer, one line per minute; never key contents
+    Summary,
+    /// Summary plus every key and button event with its code (explicit opt-in only)
+    Full,
+}
+
+#[derive(Default)]
+struct Counts {
+    mouse_moves: u64,
+    clicks: u64,
+    wheel: u64,
+    keys: u64,
+}
+
+struct AuditState {
+    mode: AuditMode,
+    file: Option<File>,
+    // Start of the minute being counted (Unix seconds)
+    minute: u64,
+    // Peer device ID -> counts for `minute`
+    counts: HashMap<String, Counts>,
+}
+
+/// Append-only JSON lines log of remote-control activity on this machine
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<AuditState>,
+}
+
+fn now() -> std::time::Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog {
+            path,
+            state: Mutex::new(AuditState { mode: AuditMode::Off, file: None, minute: 0, counts: HashMap::new() }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> AuditMode {
+        self.state.lock().unwrap().mode
+    }
+
+    pub fn set_mode(&self, mode: AuditMode) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::flush_counts(&mut state);
+        if mode != AuditMode::Off && state.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            state.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        // Mode changes are part of the trail too
+        Self::write_line(&mut state, serde_json::json!({ "time": now().as_millis() as u64, "auditMode": mode }));
+        if mode == AuditMode::Off {
+            state.file = None;
+        }
+        state.mode = mode;
+        Ok(())
+    }
+
+    /// Note one message injected on behalf of `device_id`
+    pub fn record(&self, device_id: &str, msg: &Message) {
+        // Only injected input is audited, not reports like CursorPos
+        let injected = matches!(
+            msg,
+            Message::MouseMove { .. } | Message::MouseMoveBatch(_) | Message::MouseClick { .. }
+                | Message::MouseWheel { .. } | Message::KeyPress { .. }
+        );
+        let mut state = self.state.lock().unwrap();
+        if !injected || state.mode == AuditMode::Off {
+            return;
+        }
+        let now = now();
+        let minute = now.as_secs() / 60 * 60;
+        if minute != state.minute {
+            Self::flush_counts(&mut state);
+            state.minute = minute;
+        }
+
+        let counts = state.counts.entry(device_id.to_string()).or_default();
+        match msg {
+            Message::MouseMove { .. } | Message::MouseMoveBatch(_) => counts.mouse_moves += 1,
+            Message::MouseClick { .. } => counts.clicks += 1,
+            Message::MouseWheel { .. } => counts.wheel += 1,
+            Message::KeyPress { .. } => counts.keys += 1,
+            _ => {}
+        }
+
+        if state.mode == AuditMode::Full {
+            let event = match *msg {
+                Message::KeyPress { key, state } => Some(serde_json::json!({ "key": key, "down": state })),
+                Message::MouseClick { button, state, .. } => Some(serde_json::json!({ "button": button, "down": state })),
+                _ => None,
+            };
+            if let Some(mut event) = event {
+                event["time"] = (now.as_millis() as u64).into();
+                event["deviceId"] = device_id.into();
+                Self::write_line(&mut state, event);
+            }
+        }
+    }
+
+    /// Write out the current minute's counts, e.g. when a session ends
+    pub fn flush(&self) {
+        Self::flush_counts(&mut self.state.lock().unwrap());
+    }
+
+    fn flush_counts(state: &mut AuditState) {
+        let minute = state.minute;
+        for (device_id, counts) in std::mem::take(&mut state.counts) {
+            Self::write_line(state, serde_json::json!({
+                "minute": minute,
+                "deviceId": device_id,
+                "mouseMoves": counts.mouse_moves,
+                "clicks": counts.clicks,
+                "wheel": counts.wheel,
+                "keys": counts.keys,
+            }));
+        }
+    }
+
+    fn write_line(state: &mut AuditState, line: serde_json::Value) {
+        if let Some(file) = state.file.as_mut() {
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("写入审计日志失败: {}", e);
+            }
+        }
+    }
+}
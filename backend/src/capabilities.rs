@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+/// Whether one input feature works on this machine, and why not
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capability {
+    pub available: bool,
+    pub reason: Option<String>,
+}
+
+impl Capability {
+    fn available() -> Self {
+        Capability { available: true, reason: None }
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn limited(reason: &str) -> Self {
+        Capability { available: true, reason: Some(reason.to_string()) }
+    }
+
+    fn unavailable(reason: &str) -> Self {
+        Capability { available: false, reason: Some(reason.to_string()) }
+    }
+}
+
+/// Which capture/injection paths this machine supports, probed once at startup
+/// so the frontend can explain missing features instead of rdev failing mid-capture
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub platform: String,
+    /// "x11", "wayland", "tty"... (Linux only)
+    pub session_type: Option<String>,
+    /// Grabbing local input while controlling a peer
+    pub capture: Capability,
+    /// Injecting a peer's input here
+    pub injection: Capability,
+    /// Global hotkeys outside of capture
+    pub hotkeys: Capability,
+    /// xdg-desktop-portal RemoteDesktop, the sanctioned route on Wayland (Linux only)
+    pub portal: Option<Capability>,
+    /// Leaving chosen physical devices (a foot pedal, a macro pad) out of capture
+    pub device_filter: Capability,
+}
+
+/// What this machine supports, and this build: one without the capture
+/// feature can only be controlled, one without inject can only control
+pub fn probe() -> Capabilities {
+    let mut capabilities = probe_platform();
+    if !cfg!(feature = "capture") {
+        capabilities.capture = Capability::unavailable("not in this build (controlled-only)");
+        capabilities.hotkeys = Capability::unavailable("not in this build (controlled-only)");
+        capabilities.device_filter = Capability::unavailable("not in this build (controlled-only)");
+    }
+    if !cfg!(feature = "inject") {
+        capabilities.injection = Capability::unavailable("not in this build (controller-only)");
+    }
+    capabilities
+}
+
+// Only our evdev grab (Linux, Wayland sessions) knows each event's device: rdev
+// merges all keyboards and mice, and its events don't say which device they came from
+#[cfg(not(target_os = "linux"))]
+const DEVICE_FILTER_MISSING: &str = "capture can't tell input devices apart here";
+
+#[cfg(target_os = "linux")]
+fn probe_platform() -> Capabilities {
+    use std::path::Path;
+
+    let has_display = std::env::var_os("DISPLAY").is_some();
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let session_type = std::env::var("XDG_SESSION_TYPE")
+        .ok()
+        .filter(|t| !t.is_empty())
+        .or_else(|| wayland.then(|| "wayland".to_string()))
+        .or_else(|| has_display.then(|| "x11".to_string()));
+
+    // rdev's grab, and ours on Wayland, read /dev/input/event* and re-emit through uinput
+    let evdev_readable = std::fs::read_dir("/dev/input")
+        .map(|entries| {
+            entries.flatten().any(|entry| {
+                entry.file_name().to_string_lossy().starts_with("event")
+                    && std::fs::File::open(entry.path()).is_ok()
+            })
+        })
+        .unwrap_or(false);
+    let uinput_writable = std::fs::OpenOptions::new().write(true).open("/dev/uinput").is_ok();
+    let capture = match (evdev_readable, uinput_writable) {
+        (true, true) => Capability::available(),
+        (false, _) => Capability::unavailable("no read access to /dev/input (add the user to the input group)"),
+        (true, false) => Capability::unavailable("no write access to /dev/uinput"),
+    };
+
+    // rdev simulates and listens through X11 (XTest/XRecord); on Wayland
+    // injection goes through a uinput device instead when it can be created
+    let (injection, hotkeys) = match (has_display, wayland) {
+        (_, true) if uinput_writable => (
+            Capability::available(),
+            if has_display {
+                Capability::limited("XWayland only: keys typed into native Wayland windows are missed")
+            } else {
+                Capability::unavailable("no X11 display")
+            },
+        ),
+        (false, _) => (
+            Capability::unavailable("no X11 display"),
+            Capability::unavailable("no X11 display"),
+        ),
+        (true, true) => (
+            Capability::limited("XWayland only: reaches X11 windows, not native Wayland ones (no write access to /dev/uinput)"),
+            Capability::limited("XWayland only: keys typed into native Wayland windows are missed"),
+        ),
+        (true, false) => (Capability::available(), Capability::available()),
+    };
+
+    let portal_installed = ["/usr/libexec/xdg-desktop-portal", "/usr/lib/xdg-desktop-portal"]
+        .iter()
+        .any(|path| Path::new(path).exists());
+    let portal = if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() {
+        Capability::unavailable("no D-Bus session bus")
+    } else if !portal_installed {
+        Capability::unavailable("xdg-desktop-portal is not installed")
+    } else {
+        Capability::limited("detected but not used yet")
+    };
+
+    let device_filter = if !crate::wayland::session() {
+        Capability::unavailable("Wayland sessions only: rdev's X11 grab can't tell input devices apart")
+    } else if !capture.available {
+        capture.clone()
+    } else {
+        Capability::available()
+    };
+
+    Capabilities {
+        platform: "linux".to_string(),
+        session_type,
+        capture,
+        injection,
+        hotkeys,
+        portal: Some(portal),
+        device_filter,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn probe_platform() -> Capabilities {
+    use crate::accessibility::{self, AccessibilityState};
+
+    // rdev's grab is a CGEventTap and simulation posts CGEvents: both need
+    // the Accessibility permission, and both fail silently without it
+    let input = || {
+        if accessibility::status().state == AccessibilityState::Granted {
+            Capability::available()
+        } else {
+            Capability::unavailable(
+                "Accessibility permission missing (System Settings → Privacy & Security → Accessibility)",
+            )
+        }
+    };
+
+    Capabilities {
+        platform: "macos".to_string(),
+        session_type: None,
+        capture: input(),
+        injection: input(),
+        hotkeys: input(),
+        portal: None,
+        device_filter: Capability::unavailable(DEVICE_FILTER_MISSING),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn probe_platform() -> Capabilities {
+    Capabilities {
+        platform: std::env::consts::OS.to_string(),
+        session_type: None,
+        capture: Capability::available(),
+        injection: Capability::available(),
+        hotkeys: Capability::available(),
+        portal: None,
+        device_filter: Capability::unavailable(DEVICE_FILTER_MISSING),
+    }
+}
+
+impl Capabilities {
+    /// One line per capability for the startup log
+    pub fn log(&self) {
+        println!("平台: {} ({})", self.platform, self.session_type.as_deref().unwrap_or("-"));
+        let rows = [
+            ("capture", Some(&self.capture)),
+            ("injection", Some(&self.injection)),
+            ("hotkeys", Some(&self.hotkeys)),
+            ("portal", self.portal.as_ref()),
+            ("deviceFilter", Some(&self.device_filter)),
+        ];
+        for (name, capability) in rows {
+            let Some(capability) = capability else {
+                continue;
+            };
+            let mark = if capability.available { "✓" } else { "✗" };
+            match &capability.reason {
+                Some(reason) => println!("  {} {}: {}", mark, name, reason),
+                None => println!("  {} {}", mark, name),
+            }
+        }
+    }
+}
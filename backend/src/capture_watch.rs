@@ -0,0 +1,53 @@
+use crate::websocket::CaptureState;
+
+/// Follows capture and connection state, which change in different tasks,
+/// and notices when they stop matching
+pub struct CaptureWatch {
+    state: CaptureState,
+    auto_stop: bool,
+    lost_last_peer: bool,
+}
+
+impl Default for CaptureWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaptureWatch {
+    pub fn new() -> Self {
+        Self {
+            state: CaptureState::Idle,
+            // Otherwise a vanished peer leaves the controller's input grabbed
+            auto_stop: true,
+            lost_last_peer: false,
+        }
+    }
+
+    pub fn set_auto_stop(&mut self, enabled: bool) {
+        self.auto_stop = enabled;
+    }
+
+    /// Feed the current state; returns the new one if it changed
+    pub fn update(&mut self, capturing: bool, connections: usize) -> Option<CaptureState> {
+        let state = match (capturing, connections > 0) {
+            (false, false) => CaptureState::Idle,
+            (true, true) => CaptureState::Active,
+            (true, false) => CaptureState::CapturingWithoutPeer,
+            (false, true) => CaptureState::ConnectedWithoutCapture,
+        };
+        if state == self.state {
+            return None;
+        }
+        // Starting capture before connecting is a mismatch too, but only
+        // a drop out of an active session warrants stopping on our own
+        self.lost_last_peer = self.state == CaptureState::Active && state == CaptureState::CapturingWithoutPeer;
+        self.state = state;
+        Some(state)
+    }
+
+    /// Whether capture should be stopped after the last update
+    pub fn should_stop(&self) -> bool {
+        self.auto_stop && self.lost_last_peer
+    }
+}
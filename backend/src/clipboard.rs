@@ -0,0 +1,540 @@
+use crate::forwarder::MessageSender;
+use crate::protocol::{self, ClipboardUpdate, Message, Permission, PeerFeature};
+use crate::session::SessionContext;
+use crate::websocket::{Event, WebSocketServer};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Larger text is not synced
+pub const MAX_TEXT_LEN: usize = 16 * 1024 * 1024;
+/// Text up to this size goes in one Message::Clipboard, anything longer is streamed
+pub const INLINE_LEN: usize = protocol::MAX_FRAME_LEN - 1024;
+const CHUNK_LEN: usize = 16 * 1024;
+// ClipboardTransfer is reported about every this many bytes
+const PROGRESS_STEP: u64 = 256 * 1024;
+/// A local copy is only sent once the clipboard kept it this long
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Decides which clipboard changes go out and which incoming ones are applied.
+///
+/// Text we applied from a peer is remembered as the current content, so seeing
+/// it in our own clipboard afterwards is not a change and nothing bounces back.
+/// When both sides copy at about the same time the later copy wins on both.
+pub struct ClipboardSync {
+    device_id: String,
+    seq: u64,
+    /// Last text sent or applied; None until the first read after a session started
+    current: Option<String>,
+    /// (timestamp, origin) of the update `current` came from
+    latest: (u64, String),
+    /// Highest seq applied per origin
+    seen: HashMap<String, u64>,
+    /// Local change waiting out the debounce
+    pending: Option<(String, Instant)>,
+}
+
+impl ClipboardSync {
+    pub fn new(device_id: String) -> Self {
+        ClipboardSync { device_id, seq: 0, current: None, latest: (0, String::new()), seen: HashMap::new(), pending: None }
+    }
+
+    /// Feed what the local clipboard holds now; returns the update to send, if any
+    pub fn poll(&mut self, text: Option<String>, now: Instant) -> Option<ClipboardUpdate> {
+        let text = text?;
+        // What was there when syncing started is not a copy the user just made
+        if self.current.is_none() {
+            self.current = Some(text);
+            return None;
+        }
+        if self.current.as_ref() == Some(&text) {
+            self.pending = None;
+            return None;
+        }
+        match &self.pending {
+            Some((pending, since)) if *pending == text => {
+                if now.duration_since(*since) < DEBOUNCE {
+                    return None;
+                }
+            }
+            _ => {
+                self.pending = Some((text, now));
+                return None;
+            }
+        }
+        self.pending = None;
+        self.current = Some(text.clone());
+        if text.len() > MAX_TEXT_LEN {
+            println!("📋 剪贴板内容过大 ({} 字节)，不同步", text.len());
+            return None;
+        }
+        self.seq += 1;
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        self.latest = (timestamp_ms, self.device_id.clone());
+        Some(ClipboardUpdate { origin: self.device_id.clone(), seq: self.seq, timestamp_ms, text })
+    }
+
+    /// Returns the text to put into the local clipboard, None if the update lost or was seen before
+    pub fn receive(&mut self, update: ClipboardUpdate) -> Option<String> {
+        if update.origin == self.device_id {
+            return None;
+        }
+        if self.seen.get(&update.origin).is_some_and(|seq| *seq >= update.seq) {
+            return None;
+        }
+        self.seen.insert(update.origin.clone(), update.seq);
+        // Last writer wins; the device ID only breaks exact ties so both sides agree
+        if (update.timestamp_ms, &update.origin) < (self.latest.0, &self.latest.1) {
+            println!("📋 忽略较旧的剪贴板更新 (来自 {})", update.origin);
+            return None;
+        }
+        self.latest = (update.timestamp_ms, update.origin);
+        self.current = Some(update.text.clone());
+        self.pending = None;
+        Some(update.text)
+    }
+
+    /// Text we put into the clipboard ourselves, not a copy to pass on
+    pub fn adopt(&mut self, text: String) {
+        self.current = Some(text);
+        self.pending = None;
+    }
+
+    /// Between sessions changes aren't tracked, so the next session starts from a fresh read
+    pub fn reset(&mut self) {
+        self.current = None;
+        self.pending = None;
+    }
+}
+
+/// What the clipboard task is told, besides its own polling
+#[derive(Debug)]
+pub enum ClipboardEvent {
+    /// A clipboard message from the peer on `conn_key`
+    Peer { conn_key: String, device_id: String, msg: Message },
+    /// The user gave up on a streamed transfer
+    Cancel { origin: String, seq: u64 },
+    /// Message::HandOff from a peer
+    HandOff { device_id: String, text: String },
+}
+
+/// What a received hand-off was used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HandOffAction {
+    /// A web link, left for the user to open from the frontend
+    Link,
+    Copied,
+}
+
+/// Only web links are offered as links, anything else could start a program
+fn is_web_url(text: &str) -> bool {
+    let text = text.trim();
+    (text.starts_with("https://") || text.starts_with("http://")) && !text.contains(char::is_whitespace)
+}
+
+/// Text being streamed in from one connection
+struct Incoming {
+    device_id: String,
+    origin: String,
+    seq: u64,
+    timestamp_ms: u64,
+    len: u64,
+    data: Vec<u8>,
+}
+
+/// Our latest streamed copy: seq and, per connection, (peer device ID, task)
+type Outgoing = (u64, HashMap<String, (String, tokio::task::AbortHandle)>);
+
+/// Owns the OS clipboard: polls it for local copies and applies incoming
+/// updates, one at a time so a write can't race a read
+pub async fn run(ctx: SessionContext, device_id: String, mut events: mpsc::UnboundedReceiver<ClipboardEvent>) {
+    let mut sync = ClipboardSync::new(device_id.clone());
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut syncing = false;
+    let mut incoming: HashMap<String, Incoming> = HashMap::new();
+    let mut outgoing: Option<Outgoing> = None;
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                match event {
+                    ClipboardEvent::Peer { conn_key, device_id: peer_id, msg } => match msg {
+                        Message::Clipboard(update) => apply(&mut sync, update).await,
+                        Message::ClipboardBegin { origin, seq, timestamp_ms, len } => {
+                            if len > MAX_TEXT_LEN as u64 {
+                                println!("📋 来自 {} 的剪贴板内容过大 ({} 字节)，拒绝接收", peer_id, len);
+                                send_to(&ctx, &conn_key, Message::ClipboardCancel { origin, seq }).await;
+                                continue;
+                            }
+                            ctx.ws_server.broadcast(Event::ClipboardTransfer {
+                                device_id: peer_id.clone(),
+                                origin: origin.clone(),
+                                seq,
+                                done: 0,
+                                total: len,
+                            });
+                            // A new transfer from the same peer replaces an unfinished one
+                            let data = Vec::with_capacity(len as usize);
+                            let transfer = Incoming { device_id: peer_id, origin, seq, timestamp_ms, len, data };
+                            if let Some(replaced) = incoming.insert(conn_key, transfer) {
+                                transfer_ended(&ctx, &replaced.device_id, &replaced.origin, replaced.seq, true);
+                            }
+                        }
+                        Message::ClipboardChunk { seq, data } => {
+                            let Some(transfer) = incoming.get_mut(&conn_key).filter(|transfer| transfer.seq == seq) else {
+                                continue;
+                            };
+                            if (transfer.data.len() + data.len()) as u64 > transfer.len {
+                                eprintln!("📋 来自 {} 的剪贴板数据超出声明的长度，已丢弃", transfer.device_id);
+                                let transfer = incoming.remove(&conn_key).unwrap();
+                                send_to(&ctx, &conn_key, Message::ClipboardCancel { origin: transfer.origin.clone(), seq }).await;
+                                transfer_ended(&ctx, &transfer.device_id, &transfer.origin, seq, true);
+                                continue;
+                            }
+                            let before = transfer.data.len() as u64;
+                            transfer.data.extend_from_slice(&data);
+                            let done = transfer.data.len() as u64;
+                            if done < transfer.len {
+                                if before / PROGRESS_STEP != done / PROGRESS_STEP {
+                                    ctx.ws_server.broadcast(Event::ClipboardTransfer {
+                                        device_id: transfer.device_id.clone(),
+                                        origin: transfer.origin.clone(),
+                                        seq,
+                                        done,
+                                        total: transfer.len,
+                                    });
+                                }
+                                continue;
+                            }
+                            let transfer = incoming.remove(&conn_key).unwrap();
+                            transfer_ended(&ctx, &transfer.device_id, &transfer.origin, seq, false);
+                            match String::from_utf8(transfer.data) {
+                                Ok(text) => {
+                                    let update = ClipboardUpdate { origin: transfer.origin, seq, timestamp_ms: transfer.timestamp_ms, text };
+                                    apply(&mut sync, update).await;
+                                }
+                                Err(_) => eprintln!("📋 来自 {} 的剪贴板内容不是有效的 UTF-8", transfer.device_id),
+                            }
+                        }
+                        Message::ClipboardCancel { origin, seq } => {
+                            if origin == device_id {
+                                // The peer doesn't want our text (any more)
+                                let task = outgoing
+                                    .as_mut()
+                                    .filter(|(current, _)| *current == seq)
+                                    .and_then(|(_, tasks)| tasks.remove(&conn_key));
+                                if let Some((_, task)) = task {
+                                    task.abort();
+                                    println!("📋 {} 取消了剪贴板传输", peer_id);
+                                    transfer_ended(&ctx, &peer_id, &origin, seq, true);
+                                }
+                            } else if incoming.get(&conn_key).is_some_and(|transfer| transfer.seq == seq) {
+                                incoming.remove(&conn_key);
+                                println!("📋 {} 取消了剪贴板传输", peer_id);
+                                transfer_ended(&ctx, &peer_id, &origin, seq, true);
+                            }
+                        }
+                        _ => {}
+                    },
+                    ClipboardEvent::HandOff { device_id: peer_id, text } => {
+                        // A peer doesn't get to open pages here by itself
+                        let (action, result) = if is_web_url(&text) {
+                            (HandOffAction::Link, Ok(Ok(())))
+                        } else {
+                            let copied = text.clone();
+                            (HandOffAction::Copied, tokio::task::spawn_blocking(move || write_text(&copied)).await)
+                        };
+                        let error = match result {
+                            Ok(Ok(())) => None,
+                            Ok(Err(e)) => Some(e.to_string()),
+                            Err(e) => Some(e.to_string()),
+                        };
+                        match &error {
+                            None => println!("📤 收到 {} 的接力内容: {:?}", peer_id, action),
+                            Some(e) => eprintln!("📤 处理 {} 的接力内容失败: {}", peer_id, e),
+                        }
+                        if action == HandOffAction::Copied && error.is_none() {
+                            sync.adopt(text.clone());
+                        }
+                        ctx.ws_server.broadcast(Event::HandOffReceived { device_id: peer_id, text, action, error });
+                    }
+                    ClipboardEvent::Cancel { origin, seq } => {
+                        if origin == device_id {
+                            if outgoing.as_ref().is_some_and(|(current, _)| *current == seq) {
+                                cancel_outgoing(&ctx, &device_id, outgoing.take()).await;
+                            }
+                            continue;
+                        }
+                        let cancelled: Vec<String> = incoming
+                            .iter()
+                            .filter(|(_, transfer)| transfer.origin == origin && transfer.seq == seq)
+                            .map(|(conn_key, _)| conn_key.clone())
+                            .collect();
+                        for conn_key in cancelled {
+                            let transfer = incoming.remove(&conn_key).unwrap();
+                            send_to(&ctx, &conn_key, Message::ClipboardCancel { origin: origin.clone(), seq }).await;
+                            transfer_ended(&ctx, &transfer.device_id, &origin, seq, true);
+                        }
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let targets = sync_targets(&ctx).await;
+                // Transfers from peers that went away will never complete
+                incoming.retain(|conn_key, _| targets.iter().any(|(key, _, _)| key == conn_key));
+                if targets.is_empty() {
+                    if syncing {
+                        sync.reset();
+                        syncing = false;
+                    }
+                    continue;
+                }
+                syncing = true;
+                let text = tokio::task::spawn_blocking(read_text).await.ok().flatten();
+                let Some(update) = sync.poll(text, Instant::now()) else {
+                    continue;
+                };
+                println!("📋 同步剪贴板到 {} 个设备 ({} 字节)", targets.len(), update.text.len());
+                if update.text.len() <= INLINE_LEN {
+                    for (_, _, tx) in targets {
+                        let _ = tx.send(Message::Clipboard(update.clone()));
+                    }
+                    continue;
+                }
+                // A newer copy makes the one still streaming pointless
+                cancel_outgoing(&ctx, &device_id, outgoing.take()).await;
+                let update = Arc::new(update);
+                let tasks = targets
+                    .into_iter()
+                    .map(|(conn_key, peer_id, tx)| {
+                        let cap = ctx.transport.read().unwrap().bulk_cap_kib_per_sec.map(|kib| kib * 1024);
+                        let task = tokio::spawn(stream_text(tx, Arc::clone(&ctx.ws_server), peer_id.clone(), Arc::clone(&update), cap));
+                        (conn_key, (peer_id, task.abort_handle()))
+                    })
+                    .collect();
+                outgoing = Some((update.seq, tasks));
+            }
+        }
+    }
+}
+
+/// Apply text from a peer unless a newer copy already won
+async fn apply(sync: &mut ClipboardSync, update: ClipboardUpdate) {
+    let origin = update.origin.clone();
+    let Some(text) = sync.receive(update) else {
+        return;
+    };
+    match tokio::task::spawn_blocking(move || write_text(&text)).await {
+        Ok(Ok(())) => println!("📋 已应用来自 {} 的剪贴板内容", origin),
+        Ok(Err(e)) => eprintln!("📋 写入剪贴板失败: {}", e),
+        Err(e) => eprintln!("📋 写入剪贴板失败: {}", e),
+    }
+}
+
+/// Send `update` in chunks, each only once the connection's queue is empty so
+/// input queued meanwhile goes out first, and no faster than `cap` bytes per second
+async fn stream_text(tx: MessageSender, ws_server: Arc<WebSocketServer>, peer_id: String, update: Arc<ClipboardUpdate>, cap: Option<u64>) {
+    let total = update.text.len() as u64;
+    let (origin, seq) = (update.origin.clone(), update.seq);
+    let begin = Message::ClipboardBegin { origin: origin.clone(), seq, timestamp_ms: update.timestamp_ms, len: total };
+    if tx.send(begin).is_err() {
+        return;
+    }
+    let mut done = 0;
+    let started = Instant::now();
+    for chunk in update.text.as_bytes().chunks(CHUNK_LEN) {
+        if let Some(cap) = cap.filter(|cap| *cap > 0) {
+            let due = Duration::from_secs_f64(done as f64 / cap as f64);
+            tokio::time::sleep(due.saturating_sub(started.elapsed())).await;
+        }
+        while tx.backlog() > 0 {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        if tx.send(Message::ClipboardChunk { seq, data: chunk.to_vec() }).is_err() {
+            return;
+        }
+        let before = done;
+        done += chunk.len() as u64;
+        if before / PROGRESS_STEP != done / PROGRESS_STEP || done == total {
+            ws_server.broadcast(Event::ClipboardTransfer { device_id: peer_id.clone(), origin: origin.clone(), seq, done, total });
+        }
+    }
+    transfer_ended_on(&ws_server, &peer_id, &origin, seq, false);
+}
+
+async fn cancel_outgoing(ctx: &SessionContext, device_id: &str, outgoing: Option<Outgoing>) {
+    let Some((seq, tasks)) = outgoing else {
+        return;
+    };
+    for (conn_key, (peer_id, task)) in tasks {
+        if task.is_finished() {
+            continue;
+        }
+        task.abort();
+        send_to(ctx, &conn_key, Message::ClipboardCancel { origin: device_id.to_string(), seq }).await;
+        transfer_ended(ctx, &peer_id, device_id, seq, true);
+    }
+}
+
+async fn send_to(ctx: &SessionContext, conn_key: &str, msg: Message) {
+    if let Some((tx, _, _)) = ctx.active_connections.lock().await.get(conn_key) {
+        let _ = tx.send(msg);
+    }
+}
+
+fn transfer_ended(ctx: &SessionContext, peer_id: &str, origin: &str, seq: u64, cancelled: bool) {
+    transfer_ended_on(&ctx.ws_server, peer_id, origin, seq, cancelled);
+}
+
+fn transfer_ended_on(ws_server: &WebSocketServer, peer_id: &str, origin: &str, seq: u64, cancelled: bool) {
+    ws_server.broadcast(Event::ClipboardTransferEnded {
+        device_id: peer_id.to_string(),
+        origin: origin.to_string(),
+        seq,
+        cancelled,
+    });
+}
+
+/// Connections that were granted the clipboard and whose peer understands
+/// clipboard messages: (connection, peer device ID, sender)
+async fn sync_targets(ctx: &SessionContext) -> Vec<(String, String, MessageSender)> {
+    // Connections first: teardown holds them while clearing the other two
+    let connections = ctx.active_connections.lock().await;
+    let permissions = ctx.permissions.lock().await;
+    let features = ctx.peer_features.lock().await;
+    connections
+        .iter()
+        .filter(|(key, _)| permissions.get(*key).is_some_and(|granted| granted.contains(&Permission::Clipboard)))
+        .filter(|(key, _)| features.get(*key).is_some_and(|(_, features)| features.contains(&PeerFeature::Clipboard)))
+        .map(|(key, (tx, _, peer_id))| (key.clone(), peer_id.clone(), tx.clone()))
+        .collect()
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn OpenClipboard(owner: isize) -> i32;
+    fn CloseClipboard() -> i32;
+    fn EmptyClipboard() -> i32;
+    fn GetClipboardData(format: u32) -> isize;
+    fn SetClipboardData(format: u32, mem: isize) -> isize;
+    fn GlobalAlloc(flags: u32, bytes: usize) -> isize;
+    fn GlobalFree(mem: isize) -> isize;
+    fn GlobalLock(mem: isize) -> *mut u16;
+    fn GlobalUnlock(mem: isize) -> i32;
+}
+
+#[cfg(windows)]
+const CF_UNICODETEXT: u32 = 13;
+
+/// Clipboard text, None if it holds something else or can't be read. Blocks.
+#[cfg(windows)]
+pub fn read_text() -> Option<String> {
+    unsafe {
+        if OpenClipboard(0) == 0 {
+            return None;
+        }
+        let data = GetClipboardData(CF_UNICODETEXT);
+        let ptr = if data == 0 { std::ptr::null_mut() } else { GlobalLock(data) };
+        let text = if ptr.is_null() {
+            None
+        } else {
+            let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+            let text = String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len));
+            GlobalUnlock(data);
+            Some(text)
+        };
+        CloseClipboard();
+        text
+    }
+}
+
+#[cfg(windows)]
+pub fn write_text(text: &str) -> Result<()> {
+    const GMEM_MOVEABLE: u32 = 0x0002;
+    let wide: Vec<u16> = text.encode_utf16().chain(std::iter::once(0)).collect();
+    unsafe {
+        let mem = GlobalAlloc(GMEM_MOVEABLE, wide.len() * 2);
+        if mem == 0 {
+            anyhow::bail!("GlobalAlloc failed");
+        }
+        let ptr = GlobalLock(mem);
+        if ptr.is_null() {
+            GlobalFree(mem);
+            anyhow::bail!("GlobalLock failed");
+        }
+        std::ptr::copy_nonoverlapping(wide.as_ptr(), ptr, wide.len());
+        GlobalUnlock(mem);
+        if OpenClipboard(0) == 0 {
+            GlobalFree(mem);
+            anyhow::bail!("the clipboard is in use by another program");
+        }
+        EmptyClipboard();
+        // On success the clipboard owns the memory
+        let ok = SetClipboardData(CF_UNICODETEXT, mem) != 0;
+        if !ok {
+            GlobalFree(mem);
+        }
+        CloseClipboard();
+        if !ok {
+            anyhow::bail!("SetClipboardData failed");
+        }
+    }
+    Ok(())
+}
+
+/// Clipboard text, None if it holds something else or can't be read. Blocks.
+#[cfg(not(windows))]
+pub fn read_text() -> Option<String> {
+    let (program, args) = clipboard_tool(false);
+    let output = std::process::Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(not(windows))]
+pub fn write_text(text: &str) -> Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let (program, args) = clipboard_tool(true);
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("cannot start {}", program))?;
+    child.stdin.take().context("no stdin")?.write_all(text.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", program, status);
+    }
+    Ok(())
+}
+
+/// Command line of the platform's clipboard tool, for writing or reading
+#[cfg(not(windows))]
+fn clipboard_tool(write: bool) -> (&'static str, &'static [&'static str]) {
+    if cfg!(target_os = "macos") {
+        return if write { ("pbcopy", &[]) } else { ("pbpaste", &[]) };
+    }
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return if write { ("wl-copy", &[]) } else { ("wl-paste", &["--no-newline", "--type", "text"]) };
+    }
+    if write {
+        ("xclip", &["-selection", "clipboard", "-in"])
+    } else {
+        ("xclip", &["-selection", "clipboard", "-out"])
+    }
+}
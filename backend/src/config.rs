@@ -0,0 +1,131 @@
+//! The config file: startup options (identity, ports, discovery, the capture
+//! shortcut, where received files go) that can be edited by hand, in TOML next
+//! to the settings.
+//!
+//! Every entry is optional and falls back to the built-in default. SHAREFLOW_*
+//! variables and command line flags win over the file.
+
+use crate::input_capture::Shortcut;
+use crate::persist;
+use crate::service::BackendConfig;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Longest pause between discovery broadcasts; peers drop devices they haven't heard from for a while
+pub const MAX_BROADCAST_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    /// Shown to other devices instead of the hostname; the device ID stays the same
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    /// UDP discovery and TCP peer connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_port: Option<u16>,
+    /// The frontend's WebSocket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ws_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broadcast_interval_secs: Option<u64>,
+    /// Device IDs whose connection requests are accepted without asking, on top
+    /// of the auto-accept groups; like those, only once the device is paired.
+    /// The only entry that applies without a restart.
+    pub auto_accept: Vec<String>,
+    /// Starts capture toward the connected peer, e.g. "Ctrl+Alt+S"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_hotkey: Option<String>,
+    /// Where files received from peers are saved, instead of Downloads/ShareFlow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<PathBuf>,
+}
+
+/// `config.toml` in the same directory as the settings
+pub fn default_path() -> PathBuf {
+    dirs::config_dir().unwrap_or_else(std::env::temp_dir).join("ShareFlow").join("config.toml")
+}
+
+impl AppConfig {
+    /// A missing file is an empty config; an unusable one is set aside like the settings
+    pub fn load(path: &Path) -> Self {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("读取配置文件失败 {}: {}", path.display(), e);
+                }
+                return AppConfig::default();
+            }
+        };
+        let parsed = toml::from_str::<AppConfig>(&text).map_err(anyhow::Error::from);
+        match parsed.and_then(|config| config.validate().map(|()| config)) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("配置文件无法使用 {}: {}", path.display(), e);
+                if let Some(kept) = persist::set_aside(path) {
+                    eprintln!("  原文件已保留为 {}", kept.display());
+                }
+                AppConfig::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string_pretty(self).context("无法序列化配置")?;
+        persist::write_atomic(path, text.as_bytes())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.device_name.as_deref().is_some_and(|name| name.trim().is_empty()) {
+            bail!("device_name 不能为空");
+        }
+        if self.peer_port == Some(0) || self.ws_port == Some(0) {
+            bail!("端口不能为 0");
+        }
+        if self.peer_port.is_some() && self.peer_port == self.ws_port {
+            bail!("peer_port 和 ws_port 不能相同");
+        }
+        if let Some(secs) = self.broadcast_interval_secs {
+            if !(1..=MAX_BROADCAST_INTERVAL_SECS).contains(&secs) {
+                bail!("broadcast_interval_secs 须在 1-{} 之间", MAX_BROADCAST_INTERVAL_SECS);
+            }
+        }
+        if let Some(hotkey) = &self.capture_hotkey {
+            hotkey.parse::<Shortcut>()?;
+        }
+        if self.download_dir.as_ref().is_some_and(|dir| !dir.is_absolute()) {
+            bail!("download_dir 须为绝对路径");
+        }
+        Ok(())
+    }
+
+    /// Put the file's entries over the defaults in `config`
+    pub fn apply(&self, config: &mut BackendConfig) {
+        if let Some(name) = &self.device_name {
+            config.device_name = name.clone();
+        }
+        if let Some(port) = self.peer_port {
+            config.peer_port = port;
+        }
+        if let Some(port) = self.ws_port {
+            config.ws_port = port;
+        }
+        if let Some(secs) = self.broadcast_interval_secs {
+            config.broadcast_interval = Duration::from_secs(secs);
+        }
+        if let Some(shortcut) = self.capture_hotkey.as_deref().and_then(|hotkey| hotkey.parse().ok()) {
+            config.capture_hotkey = shortcut;
+        }
+        if let Some(dir) = &self.download_dir {
+            config.downloads = dir.clone();
+        }
+    }
+
+    /// Whether going from `other` to this takes a restart to be in effect
+    pub fn restart_required(&self, other: &AppConfig) -> bool {
+        let startup = |config: &AppConfig| AppConfig { auto_accept: Vec::new(), ..config.clone() };
+        startup(self) != startup(other)
+    }
+}
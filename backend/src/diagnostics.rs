@@ -0,0 +1,199 @@
+use crate::websocket::{Command, Event};
+use anyhow::{bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+/// Where an input event spends its time on the way from one machine to the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Stage {
+    /// OS hook until the forwarder handed it to the connection (controller)
+    Capture,
+    /// Queued for a peer until written to its socket (controller)
+    Send,
+    /// Read from the socket until the session picked it up (controlled)
+    Receive,
+    /// Injecting into the OS (controlled)
+    Inject,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Capture, Stage::Send, Stage::Receive, Stage::Inject];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Capture => "capture",
+            Stage::Send => "send",
+            Stage::Receive => "receive",
+            Stage::Inject => "inject",
+        }
+    }
+}
+
+// Upper bounds of the histogram buckets in microseconds; one more bucket catches the rest
+const BUCKET_BOUNDS_US: [u64; 10] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000];
+const BUCKETS: usize = BUCKET_BOUNDS_US.len() + 1;
+
+struct StageHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl StageHistogram {
+    const fn new() -> Self {
+        StageHistogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, us: u64) {
+        let bucket = BUCKET_BOUNDS_US.iter().position(|bound| us <= *bound).unwrap_or(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.total_us.store(0, Ordering::Relaxed);
+        self.max_us.store(0, Ordering::Relaxed);
+    }
+}
+
+// Off by default: the pipeline only pays for an atomic load
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HISTOGRAMS: [StageHistogram; 4] = [const { StageHistogram::new() }; 4];
+
+/// Start (from zero) or stop collecting stage timings
+pub fn set_enabled(enabled: bool) {
+    if enabled {
+        for histogram in &HISTOGRAMS {
+            histogram.reset();
+        }
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn record(stage: Stage, elapsed: Duration) {
+    if enabled() {
+        HISTOGRAMS[stage as usize].record(elapsed.as_micros() as u64);
+    }
+}
+
+/// Collected timings of one stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StageTiming {
+    pub stage: Stage,
+    pub count: u64,
+    pub mean_us: u64,
+    /// Bucket upper bounds, so an estimate; u64::MAX means above the last bound
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    /// Counts per bucket, see `bucket_bounds_us`
+    pub buckets: Vec<u64>,
+    pub bucket_bounds_us: Vec<u64>,
+}
+
+pub fn snapshot() -> Vec<StageTiming> {
+    Stage::ALL
+        .iter()
+        .map(|stage| {
+            let histogram = &HISTOGRAMS[*stage as usize];
+            let buckets: Vec<u64> = histogram.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+            let count = buckets.iter().sum::<u64>();
+            let percentile = |p: u64| {
+                let rank = (count * p).div_ceil(100).max(1);
+                let mut seen = 0;
+                for (i, n) in buckets.iter().enumerate() {
+                    seen += n;
+                    if seen >= rank {
+                        return BUCKET_BOUNDS_US.get(i).copied().unwrap_or(u64::MAX);
+                    }
+                }
+                0
+            };
+            StageTiming {
+                stage: *stage,
+                count,
+                mean_us: histogram.total_us.load(Ordering::Relaxed).checked_div(count).unwrap_or(0),
+                p50_us: if count == 0 { 0 } else { percentile(50) },
+                p99_us: if count == 0 { 0 } else { percentile(99) },
+                max_us: histogram.max_us.load(Ordering::Relaxed),
+                buckets,
+                bucket_bounds_us: BUCKET_BOUNDS_US.to_vec(),
+            }
+        })
+        .collect()
+}
+
+fn format_us(us: u64) -> String {
+    if us == u64::MAX {
+        format!(">{}ms", BUCKET_BOUNDS_US[BUCKETS - 2] / 1000)
+    } else if us >= 1000 {
+        format!("{:.1}ms", us as f64 / 1000.0)
+    } else {
+        format!("{}µs", us)
+    }
+}
+
+/// Text table for the `diagnose` command
+pub fn render(timings: &[StageTiming]) -> String {
+    let mut out = format!("{:<8} {:>8} {:>9} {:>9} {:>9} {:>9}\n", "stage", "count", "mean", "p50", "p99", "max");
+    for timing in timings {
+        out.push_str(&format!(
+            "{:<8} {:>8} {:>9} {:>9} {:>9} {:>9}\n",
+            timing.stage.name(),
+            timing.count,
+            format_us(timing.mean_us),
+            format_us(timing.p50_us),
+            format_us(timing.p99_us),
+            format_us(timing.max_us),
+        ));
+    }
+    out
+}
+
+/// Attach to a running backend, collect timings for `duration` and print them.
+/// Capture/send are measured on the controller, receive/inject on the controlled side,
+/// so run it on both machines for the whole path.
+pub async fn diagnose(ws_port: u16, duration: Duration) -> Result<()> {
+    let url = format!("ws://127.0.0.1:{}", ws_port);
+    let Ok((mut ws, _)) = connect_async(&url).await else {
+        bail!("ShareFlow is not running (nothing on {})", url);
+    };
+
+    let send = |command: Command| Message::Text(serde_json::to_string(&command).unwrap());
+    ws.send(send(Command::SetDiagnostics { enabled: true })).await?;
+    println!("Collecting pipeline timings for {}s, use ShareFlow as usual...", duration.as_secs());
+    tokio::time::sleep(duration).await;
+    ws.send(send(Command::GetDiagnostics)).await?;
+
+    let stages = loop {
+        let Some(msg) = ws.next().await else {
+            bail!("ShareFlow closed the connection");
+        };
+        if let Message::Text(text) = msg? {
+            if let Ok(Event::Diagnostics { stages }) = serde_json::from_str(&text) {
+                break stages;
+            }
+        }
+    };
+    ws.send(send(Command::SetDiagnostics { enabled: false })).await?;
+
+    print!("{}", render(&stages));
+    Ok(())
+}
@@ -1,10 +1,136 @@
-use crate::protocol::Message;
-use anyhow::Result;
+use crate::privacy::LoggedMessage;
+use crate::protocol::{self, Message};
+use crate::websocket::DeviceInfo;
+use crate::error::DiscoveryError;
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 use tokio::time::{self, Duration};
+use utoipa::ToSchema;
+
+/// Which backend found a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum DiscoverySource {
+    /// UDP broadcast on the LAN
+    Broadcast,
+    /// Added from the frontend by address
+    Manual,
+    /// `BackendConfig::static_peers`
+    Static,
+}
+
+impl DiscoverySource {
+    /// Broadcast sightings repeat every second and go stale; the others are announced once
+    pub fn expires(&self) -> bool {
+        matches!(self, DiscoverySource::Broadcast)
+    }
+}
+
+/// A device seen by one of the discovery backends
+#[derive(Debug, Clone)]
+pub struct Sighting {
+    pub device: DeviceInfo,
+    pub source: DiscoverySource,
+}
+
+/// One way of finding peers; all configured backends run at once and feed the same channel
+pub trait DiscoveryBackend: Send {
+    fn source(&self) -> DiscoverySource;
+
+    /// Set up and spawn whatever keeps feeding `tx`; an error means this backend is unavailable
+    fn start(self: Box<Self>, tx: mpsc::Sender<Sighting>) -> BoxFuture<'static, Result<(), DiscoveryError>>;
+}
+
+/// Announce ourselves by UDP broadcast every `interval` and listen for the others' announcements
+pub struct BroadcastDiscovery {
+    pub device_id: String,
+    pub device_name: String,
+    /// Discovery port, also the peer connection port announced to others
+    pub port: u16,
+    pub interval: Duration,
+}
+
+impl DiscoveryBackend for BroadcastDiscovery {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Broadcast
+    }
+
+    fn start(self: Box<Self>, tx: mpsc::Sender<Sighting>) -> BoxFuture<'static, Result<(), DiscoveryError>> {
+        Box::pin(async move {
+            // Start Discovery Listener
+            println!("\n>>> 启动 Discovery 监听器...");
+            Discovery::listen(self.port, self.device_id.clone(), tx).await?;
+
+            // Start Discovery Broadcaster
+            println!("\n>>> 创建 Discovery 广播器...");
+            let discovery = Discovery::new(self.port).await?;
+            let broadcast_msg = Message::Discovery { id: self.device_id, name: self.device_name, port: self.port };
+            println!("\n>>> 启动广播，消息内容: {:?}", broadcast_msg);
+            discovery.start_broadcast(broadcast_msg, self.interval);
+            Ok(())
+        })
+    }
+}
+
+/// Peers from the config, reachable without any broadcast
+pub struct StaticPeers(pub Vec<DeviceInfo>);
+
+impl DiscoveryBackend for StaticPeers {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Static
+    }
+
+    fn start(self: Box<Self>, tx: mpsc::Sender<Sighting>) -> BoxFuture<'static, Result<(), DiscoveryError>> {
+        Box::pin(async move {
+            // The main loop isn't draining the channel yet
+            tokio::spawn(async move {
+                for device in self.0 {
+                    println!("  静态设备: {} ({}) at {}:{}", device.name, device.id, device.ip, device.port);
+                    if tx.send(Sighting { device, source: DiscoverySource::Static }).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(())
+        })
+    }
+}
+
+/// Devices the user typed in, for networks where broadcasts don't get through
+pub struct ManualPeers {
+    rx: mpsc::UnboundedReceiver<DeviceInfo>,
+}
+
+impl ManualPeers {
+    /// The sender adds a device
+    pub fn new() -> (Self, mpsc::UnboundedSender<DeviceInfo>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (ManualPeers { rx }, tx)
+    }
+}
+
+impl DiscoveryBackend for ManualPeers {
+    fn source(&self) -> DiscoverySource {
+        DiscoverySource::Manual
+    }
+
+    fn start(mut self: Box<Self>, tx: mpsc::Sender<Sighting>) -> BoxFuture<'static, Result<(), DiscoveryError>> {
+        Box::pin(async move {
+            tokio::spawn(async move {
+                while let Some(device) = self.rx.recv().await {
+                    if tx.send(Sighting { device, source: DiscoverySource::Manual }).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(())
+        })
+    }
+}
 
 pub struct Discovery {
     socket: Arc<UdpSocket>,
@@ -12,11 +138,11 @@ pub struct Discovery {
 }
 
 impl Discovery {
-    pub async fn new(port: u16) -> Result<Self> {
+    pub async fn new(port: u16) -> Result<Self, DiscoveryError> {
         println!("\n=== Discovery 初始化 ===");
         
         // Bind to any available port for sending
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(|source| DiscoveryError::Bind { port: 0, source })?;
         let local_addr = socket.local_addr()?;
         println!("UDP 发送 socket 绑定到: {}", local_addr);
         
@@ -89,8 +215,13 @@ impl Discovery {
         })
     }
 
-    pub fn start_broadcast(&self, message: Message) {
-        let data = match bincode::serialize(&message) {
+    /// Where announcements go: the subnet broadcast address of each usable interface
+    pub fn broadcast_addrs(&self) -> &[SocketAddr] {
+        &self.broadcast_addrs
+    }
+
+    pub fn start_broadcast(&self, message: Message, every: Duration) {
+        let data = match protocol::encode(&message) {
             Ok(d) => {
                 println!("广播消息序列化成功，大小: {} 字节", d.len());
                 d
@@ -103,10 +234,10 @@ impl Discovery {
         let socket = self.socket.clone();
         let addrs = self.broadcast_addrs.clone();
 
-        println!("启动广播任务，每秒发送一次");
+        println!("启动广播任务，每 {} 秒发送一次", every.as_secs_f32());
         
         tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(1));
+            let mut interval = time::interval(every);
             
             loop {
                 interval.tick().await;
@@ -121,12 +252,13 @@ impl Discovery {
         });
     }
 
-    pub async fn listen(port: u16, tx: mpsc::Sender<(Message, SocketAddr)>) -> Result<()> {
+    /// Turn announcements on `port` into sightings, skipping our own (`own_id`)
+    pub async fn listen(port: u16, own_id: String, tx: mpsc::Sender<Sighting>) -> Result<(), DiscoveryError> {
         println!("\n=== Discovery 监听器 ===");
         let bind_addr = format!("0.0.0.0:{}", port);
         println!("尝试绑定 UDP 监听: {}", bind_addr);
         
-        let socket = UdpSocket::bind(&bind_addr).await?;
+        let socket = UdpSocket::bind(&bind_addr).await.map_err(|source| DiscoveryError::Bind { port, source })?;
         let local_addr = socket.local_addr()?;
         println!("✓ UDP 监听器成功绑定到: {}", local_addr);
         println!("等待接收广播消息...");
@@ -138,13 +270,25 @@ impl Discovery {
             loop {
                 match socket.recv_from(&mut buf).await {
                     Ok((len, addr)) => {
-                        match bincode::deserialize::<Message>(&buf[..len]) {
-                            Ok(msg) => {
-                                if let Err(e) = tx.send((msg, addr)).await {
+                        match protocol::decode(&buf[..len]) {
+                            Ok(Message::Discovery { id, name, port: peer_port }) => {
+                                // Skip our own broadcasts
+                                if id == own_id {
+                                    continue;
+                                }
+                                let device = DeviceInfo {
+                                    id,
+                                    name,
+                                    ip: addr.ip().to_string(),
+                                    port: peer_port,
+                                    device_type: "DESKTOP".to_string(),
+                                };
+                                if let Err(e) = tx.send(Sighting { device, source: DiscoverySource::Broadcast }).await {
                                     eprintln!("❌ 发送到主循环失败: {}", e);
                                     break;
                                 }
                             }
+                            Ok(msg) => println!("收到其他消息: {:?}", LoggedMessage(&msg)),
                             Err(e) => {
                                 eprintln!("❌ 消息反序列化失败: {} (来自 {})", e, addr);
                             }
@@ -0,0 +1,94 @@
+//! Lock-step verification: the controlled side echoes a digest of every input
+//! message once it was injected, the controller matches the echoes against
+//! what it wrote to the socket. Proves the whole path works end to end, and
+//! counts how much of the input actually arrives.
+
+use crate::protocol::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Unanswered for this long, an input message counts as lost
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Bounds memory when a peer stops echoing altogether
+const MAX_PENDING: usize = 4096;
+
+/// FNV-1a of the message's wire encoding, the same on both ends
+pub fn digest(msg: &Message) -> u32 {
+    bincode::serialize(msg)
+        .unwrap_or_default()
+        .iter()
+        .fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EchoStats {
+    /// Input messages sent since verification started
+    pub sent: u64,
+    /// Echoed back with a matching digest
+    pub confirmed: u64,
+    /// Never echoed: dropped on the way, refused (no input permission) or too late
+    pub lost: u64,
+    /// Echoes of nothing we sent, e.g. a message altered on the way
+    pub unexpected: u64,
+    /// Sent and still waiting for their echo
+    pub pending: u64,
+    /// Confirmed out of those that were settled, None before the first
+    pub success_rate: Option<f64>,
+    /// Mean time from the socket to the echo, over the confirmed ones
+    pub average_round_trip_ms: Option<f64>,
+}
+
+/// Controller side, per peer: what was sent and not yet echoed, in order
+#[derive(Debug, Default)]
+pub struct EchoTracker {
+    pending: VecDeque<(u32, Instant)>,
+    stats: EchoStats,
+    round_trips: Duration,
+}
+
+impl EchoTracker {
+    pub fn sent(&mut self, digest: u32, now: Instant) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+            self.stats.lost += 1;
+        }
+        self.pending.push_back((digest, now));
+        self.stats.sent += 1;
+    }
+
+    /// Echoes come in sending order, so anything still pending before the
+    /// matching message will never be echoed. False if nothing matched.
+    pub fn echoed(&mut self, digest: u32, now: Instant) -> bool {
+        let Some(index) = self.pending.iter().position(|(sent, _)| *sent == digest) else {
+            self.stats.unexpected += 1;
+            return false;
+        };
+        self.stats.lost += index as u64;
+        let sent_at = self.pending.drain(..=index).next_back().map_or(now, |(_, sent_at)| sent_at);
+        self.stats.confirmed += 1;
+        self.round_trips += now.saturating_duration_since(sent_at);
+        true
+    }
+
+    /// Counts what waited longer than ECHO_TIMEOUT as lost
+    pub fn expire(&mut self, now: Instant) {
+        while self.pending.front().is_some_and(|(_, sent_at)| now.saturating_duration_since(*sent_at) >= ECHO_TIMEOUT) {
+            self.pending.pop_front();
+            self.stats.lost += 1;
+        }
+    }
+
+    pub fn stats(&self) -> EchoStats {
+        let settled = self.stats.confirmed + self.stats.lost;
+        EchoStats {
+            pending: self.pending.len() as u64,
+            success_rate: (settled > 0).then(|| self.stats.confirmed as f64 / settled as f64),
+            average_round_trip_ms: (self.stats.confirmed > 0)
+                .then(|| self.round_trips.as_secs_f64() * 1000.0 / self.stats.confirmed as f64),
+            ..self.stats
+        }
+    }
+}
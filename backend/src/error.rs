@@ -0,0 +1,184 @@
+//! Typed errors for discovery, the peer transport, connection setup and pairing.
+//!
+//! Display texts are for logs; the frontend gets an ErrorCode, which it can
+//! translate, plus the text for details.
+
+use crate::protocol::RejectReason;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Stable identifiers for the frontend, one per failure it may want to word differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The discovery port is taken or not allowed
+    DiscoveryBindFailed,
+    DiscoveryNetwork,
+    /// The peer closed the connection
+    ConnectionClosed,
+    FrameTooLarge,
+    MalformedFrame,
+    EncryptionFailed,
+    NetworkError,
+    DeviceNotFound,
+    /// Nothing accepted a connection at the peer's address
+    Unreachable,
+    ConnectTimeout,
+    HandshakeFailed,
+    /// Connected, but nobody answered the request in time
+    HandshakeTimeout,
+    /// Connected, but the peer's service never said it got the request:
+    /// hung, or its answers are lost on the way
+    NotAcknowledged,
+    /// The peer said no; see the reject reason
+    Rejected,
+    /// The peer proved a different key than the one it was paired with
+    IdentityChanged,
+    /// The peer's protocol version is older than this build still speaks
+    IncompatibleVersion,
+    /// There is no pairing code waiting for confirmation from this device
+    PairingNotPending,
+    /// The code the user confirmed isn't the one of the last handshake
+    PairingCodeMismatch,
+    /// The paired devices file couldn't be written
+    PairingSaveFailed,
+}
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("connection closed by peer")]
+    Closed,
+    #[error("frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("malformed frame: {0}")]
+    Malformed(String),
+    /// The Noise handshake failed, or a frame didn't decrypt
+    #[error("encryption failed: {0}")]
+    Encryption(String),
+    #[error(transparent)]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe => {
+                TransportError::Closed
+            }
+            _ => TransportError::Io(e),
+        }
+    }
+}
+
+impl TransportError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TransportError::Closed => ErrorCode::ConnectionClosed,
+            TransportError::FrameTooLarge(_) => ErrorCode::FrameTooLarge,
+            TransportError::Malformed(_) => ErrorCode::MalformedFrame,
+            TransportError::Encryption(_) => ErrorCode::EncryptionFailed,
+            TransportError::Io(_) => ErrorCode::NetworkError,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("cannot bind UDP port {port}: {source}")]
+    Bind { port: u16, source: std::io::Error },
+    #[error(transparent)]
+    Network(#[from] std::io::Error),
+}
+
+impl DiscoveryError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DiscoveryError::Bind { .. } => ErrorCode::DiscoveryBindFailed,
+            DiscoveryError::Network(_) => ErrorCode::DiscoveryNetwork,
+        }
+    }
+}
+
+/// Why an outgoing connection didn't become a session
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("设备未找到")]
+    DeviceNotFound,
+    #[error("连接失败: {0}")]
+    Unreachable(std::io::Error),
+    #[error("连接超时")]
+    ConnectTimeout,
+    #[error("握手失败: {0}")]
+    Handshake(#[from] TransportError),
+    #[error("握手协议错误")]
+    UnexpectedResponse,
+    #[error("握手超时")]
+    HandshakeTimeout,
+    #[error("对方服务未确认收到请求")]
+    NotAcknowledged,
+    #[error("{}", .0.map_or("对方拒绝连接", |reason| reason.describe()))]
+    Rejected(Option<RejectReason>),
+    #[error("对方设备的密钥与配对时不同")]
+    IdentityChanged,
+    #[error("对方协议版本 {0} 过旧，无法连接")]
+    IncompatibleVersion(u16),
+}
+
+impl SessionError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SessionError::DeviceNotFound => ErrorCode::DeviceNotFound,
+            SessionError::Unreachable(_) => ErrorCode::Unreachable,
+            SessionError::ConnectTimeout => ErrorCode::ConnectTimeout,
+            SessionError::Handshake(_) | SessionError::UnexpectedResponse => ErrorCode::HandshakeFailed,
+            SessionError::HandshakeTimeout => ErrorCode::HandshakeTimeout,
+            SessionError::NotAcknowledged => ErrorCode::NotAcknowledged,
+            SessionError::Rejected(_) => ErrorCode::Rejected,
+            SessionError::IdentityChanged => ErrorCode::IdentityChanged,
+            SessionError::IncompatibleVersion(_) => ErrorCode::IncompatibleVersion,
+        }
+    }
+
+    pub fn reject_reason(&self) -> Option<RejectReason> {
+        match self {
+            SessionError::Rejected(reason) => *reason,
+            SessionError::IncompatibleVersion(_) => Some(RejectReason::VersionMismatch),
+            _ => None,
+        }
+    }
+
+    /// Error kind in the connection stats
+    pub fn stats_kind(&self) -> String {
+        match self {
+            SessionError::DeviceNotFound => "deviceNotFound".to_string(),
+            SessionError::Unreachable(_) => "connectFailed".to_string(),
+            SessionError::ConnectTimeout | SessionError::HandshakeTimeout => "timeout".to_string(),
+            SessionError::NotAcknowledged => "notAcknowledged".to_string(),
+            SessionError::Handshake(_) | SessionError::UnexpectedResponse => "handshakeFailed".to_string(),
+            SessionError::Rejected(reason) => reason.map_or("rejected".to_string(), |r| format!("rejected:{:?}", r)),
+            SessionError::IdentityChanged => "identityChanged".to_string(),
+            SessionError::IncompatibleVersion(_) => "incompatibleVersion".to_string(),
+        }
+    }
+}
+
+/// Why confirming or removing a pairing didn't go through
+#[derive(Debug, Error)]
+pub enum PairingError {
+    #[error("没有来自该设备的待确认配对")]
+    NotPending,
+    #[error("配对码与本次连接的不一致")]
+    CodeMismatch,
+    #[error("保存配对设备失败: {0}")]
+    Save(String),
+}
+
+impl PairingError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PairingError::NotPending => ErrorCode::PairingNotPending,
+            PairingError::CodeMismatch => ErrorCode::PairingCodeMismatch,
+            PairingError::Save(_) => ErrorCode::PairingSaveFailed,
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Files sent over the control connection.
+//!
+//! The sender offers a file, the receiver answers with how much of it it
+//! already has and the rest follows in chunks, streamed between input like
+//! large clipboard text. What arrived is kept in a `.part` file named after
+//! the transfer ID, which stays the same for the same file: offering it again
+//! after a dropped connection picks up where it stopped.
+
+use crate::forwarder::{ActiveConnections, MessageSender};
+use crate::protocol::{Message, PeerFeature, Permission};
+use crate::session::SessionContext;
+use crate::websocket::{Event, WebSocketServer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const CHUNK_LEN: usize = 32 * 1024;
+// FileTransfer is reported about every this many bytes
+const PROGRESS_STEP: u64 = 1024 * 1024;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the file transfer task is told
+#[derive(Debug)]
+pub enum FileEvent {
+    /// A file transfer message from the peer on `conn_key`
+    Peer { conn_key: String, device_id: String, msg: Message },
+    /// The frontend wants `path` sent to `device_id`
+    Send { device_id: String, path: PathBuf },
+    /// The user gave up on a transfer, in either direction
+    Cancel { id: u64 },
+}
+
+/// A file being received on one connection
+struct Incoming {
+    device_id: String,
+    name: String,
+    size: u64,
+    part: PathBuf,
+    file: tokio::fs::File,
+    done: u64,
+}
+
+/// A file we offered on one connection; `task` streams it once the peer answered
+struct Outgoing {
+    device_id: String,
+    name: String,
+    size: u64,
+    path: PathBuf,
+    task: Option<tokio::task::AbortHandle>,
+}
+
+/// Same file, same ID: path, size and modification time, hashed (FNV-1a) so
+/// the ID doesn't change between runs
+pub fn transfer_id(path: &Path, size: u64, modified_ms: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+    for byte in bytes.iter().chain(&size.to_le_bytes()).chain(&modified_ms.to_le_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The offered name as a plain file name in the downloads directory, None if
+/// nothing usable is left. A peer must not be able to write anywhere else.
+pub fn safe_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    let name = name.trim().trim_end_matches('.').to_string();
+    (!name.is_empty() && name != "..").then_some(name)
+}
+
+/// `name` in `dir`, or "name (2).ext" and so on if that is taken
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Owns every file transfer, in both directions. Received files go to `downloads`.
+pub async fn run(ctx: SessionContext, downloads: PathBuf, mut events: mpsc::UnboundedReceiver<FileEvent>) {
+    let mut incoming: HashMap<(String, u64), Incoming> = HashMap::new();
+    let mut outgoing: HashMap<(String, u64), Outgoing> = HashMap::new();
+    let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                match event {
+                    FileEvent::Send { device_id, path } => {
+                        if let Some((conn_key, transfer, id)) = offer(&ctx, &device_id, path).await {
+                            outgoing.insert((conn_key, id), transfer);
+                        }
+                    }
+                    FileEvent::Cancel { id } => {
+                        let sent: Vec<_> = outgoing.keys().filter(|(_, key_id)| *key_id == id).cloned().collect();
+                        for key in sent {
+                            let transfer = outgoing.remove(&key).unwrap();
+                            if let Some(task) = &transfer.task {
+                                task.abort();
+                            }
+                            send_to(&ctx, &key.0, Message::FileCancel { id }).await;
+                            ended(&ctx.ws_server, &transfer.device_id, id, false, None, Some("cancelled".to_string()));
+                        }
+                        let received: Vec<_> = incoming.keys().filter(|(_, key_id)| *key_id == id).cloned().collect();
+                        for key in received {
+                            let transfer = incoming.remove(&key).unwrap();
+                            send_to(&ctx, &key.0, Message::FileCancel { id }).await;
+                            // Not wanted, so nothing to resume either
+                            drop(transfer.file);
+                            let _ = tokio::fs::remove_file(&transfer.part).await;
+                            ended(&ctx.ws_server, &transfer.device_id, id, true, None, Some("cancelled".to_string()));
+                        }
+                    }
+                    FileEvent::Peer { conn_key, device_id: peer_id, msg } => match msg {
+                        Message::FileOffer { id, name, size } => {
+                            let key = (conn_key.clone(), id);
+                            match accept(&downloads, &peer_id, id, &name, size).await {
+                                Ok(transfer) => {
+                                    let offset = transfer.done;
+                                    println!("📁 接收 {} 的文件 {} ({} 字节，从 {} 开始)", peer_id, transfer.name, size, offset);
+                                    progress(&ctx.ws_server, &transfer.device_id, id, &transfer.name, true, offset, size);
+                                    if offset == size {
+                                        // Empty, or complete from an earlier try: nothing needs to be sent
+                                        finish(&ctx, &downloads, &conn_key, transfer, id).await;
+                                        continue;
+                                    }
+                                    send_to(&ctx, &conn_key, Message::FileAck { id, offset }).await;
+                                    incoming.insert(key, transfer);
+                                }
+                                Err(e) => {
+                                    eprintln!("📁 无法接收 {} 的文件 {:?}: {}", peer_id, name, e);
+                                    send_to(&ctx, &conn_key, Message::FileCancel { id }).await;
+                                    ended(&ctx.ws_server, &peer_id, id, true, None, Some(e.to_string()));
+                                }
+                            }
+                        }
+                        Message::FileChunk { id, offset, data } => {
+                            let key = (conn_key.clone(), id);
+                            let Some(transfer) = incoming.get_mut(&key) else {
+                                continue;
+                            };
+                            if offset != transfer.done || transfer.done + data.len() as u64 > transfer.size {
+                                eprintln!("📁 来自 {} 的文件数据与声明不符，已取消", peer_id);
+                                let transfer = incoming.remove(&key).unwrap();
+                                send_to(&ctx, &conn_key, Message::FileCancel { id }).await;
+                                ended(&ctx.ws_server, &transfer.device_id, id, true, None, Some("unexpected data from the peer".to_string()));
+                                continue;
+                            }
+                            if let Err(e) = transfer.file.write_all(&data).await {
+                                eprintln!("📁 写入文件失败 {}: {}", transfer.part.display(), e);
+                                let transfer = incoming.remove(&key).unwrap();
+                                send_to(&ctx, &conn_key, Message::FileCancel { id }).await;
+                                ended(&ctx.ws_server, &transfer.device_id, id, true, None, Some(e.to_string()));
+                                continue;
+                            }
+                            let before = transfer.done;
+                            transfer.done += data.len() as u64;
+                            if transfer.done < transfer.size {
+                                if before / PROGRESS_STEP != transfer.done / PROGRESS_STEP {
+                                    progress(&ctx.ws_server, &transfer.device_id, id, &transfer.name, true, transfer.done, transfer.size);
+                                }
+                                continue;
+                            }
+                            finish(&ctx, &downloads, &conn_key, incoming.remove(&key).unwrap(), id).await;
+                        }
+                        Message::FileAck { id, offset } => {
+                            let key = (conn_key.clone(), id);
+                            let Some(transfer) = outgoing.get_mut(&key) else {
+                                continue;
+                            };
+                            if offset >= transfer.size {
+                                let transfer = outgoing.remove(&key).unwrap();
+                                println!("📁 {} 已收到文件 {}", peer_id, transfer.name);
+                                ended(&ctx.ws_server, &peer_id, id, false, Some(transfer.path), None);
+                                continue;
+                            }
+                            // A repeated answer doesn't start a second stream
+                            if transfer.task.is_some() {
+                                continue;
+                            }
+                            let Some(tx) = ctx.active_connections.lock().await.get(&conn_key).map(|(tx, _, _)| tx.clone()) else {
+                                continue;
+                            };
+                            if offset > 0 {
+                                println!("📁 {} 已有 {} 字节，从断点继续发送 {}", peer_id, offset, transfer.name);
+                            }
+                            let cap = ctx.transport.read().unwrap().bulk_cap_kib_per_sec.map(|kib| kib * 1024);
+                            let stream = FileStream {
+                                tx,
+                                ws_server: Arc::clone(&ctx.ws_server),
+                                peer_id: peer_id.clone(),
+                                id,
+                                name: transfer.name.clone(),
+                                path: transfer.path.clone(),
+                                size: transfer.size,
+                            };
+                            transfer.task = Some(tokio::spawn(stream.run(offset, cap)).abort_handle());
+                        }
+                        Message::FileCancel { id } => {
+                            let key = (conn_key.clone(), id);
+                            if let Some(transfer) = outgoing.remove(&key) {
+                                if let Some(task) = &transfer.task {
+                                    task.abort();
+                                }
+                                println!("📁 {} 取消了文件 {}", peer_id, transfer.name);
+                                ended(&ctx.ws_server, &peer_id, id, false, None, Some("cancelled by the peer".to_string()));
+                            } else if let Some(transfer) = incoming.remove(&key) {
+                                // Kept for when it is offered again
+                                println!("📁 {} 取消了文件 {}，已收到的部分保留", peer_id, transfer.name);
+                                ended(&ctx.ws_server, &peer_id, id, true, None, Some("cancelled by the peer".to_string()));
+                            }
+                        }
+                        _ => {}
+                    },
+                }
+            }
+            _ = cleanup.tick() => {
+                // Transfers on connections that went away will never complete
+                let connections = ctx.active_connections.lock().await;
+                let lost = "connection closed; sending the file again resumes it";
+                for ((_, id), transfer) in take_orphans(&mut incoming, &connections) {
+                    ended(&ctx.ws_server, &transfer.device_id, id, true, None, Some(lost.to_string()));
+                }
+                for ((_, id), transfer) in take_orphans(&mut outgoing, &connections) {
+                    if let Some(task) = &transfer.task {
+                        task.abort();
+                    }
+                    ended(&ctx.ws_server, &transfer.device_id, id, false, None, Some(lost.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Remove and return the transfers whose connection is gone
+fn take_orphans<T>(transfers: &mut HashMap<(String, u64), T>, connections: &ActiveConnections) -> Vec<((String, u64), T)> {
+    let gone: Vec<_> = transfers.keys().filter(|(conn_key, _)| !connections.contains_key(conn_key)).cloned().collect();
+    gone.into_iter().filter_map(|key| transfers.remove(&key).map(|transfer| (key, transfer))).collect()
+}
+
+/// Offer `path` to `device_id`: (connection, transfer, ID) if it went out
+async fn offer(ctx: &SessionContext, device_id: &str, path: PathBuf) -> Option<(String, Outgoing, u64)> {
+    let metadata = tokio::fs::metadata(&path).await;
+    let modified_ms = metadata
+        .as_ref()
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_millis() as u64);
+    let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+    let id = transfer_id(&path, size, modified_ms);
+    let fail = |error: String| {
+        eprintln!("📁 无法发送 {} 到 {}: {}", path.display(), device_id, error);
+        ended(&ctx.ws_server, device_id, id, false, None, Some(error));
+        None
+    };
+    match &metadata {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return fail("not a file".to_string()),
+        Err(e) => return fail(e.to_string()),
+    }
+    let Some(name) = path.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+        return fail("no file name".to_string());
+    };
+    let Some((conn_key, tx)) = transfer_target(ctx, device_id).await else {
+        return fail("not connected, or the session has no file transfer permission".to_string());
+    };
+    println!("📁 向 {} 提供文件 {} ({} 字节)", device_id, name, size);
+    if tx.send(Message::FileOffer { id, name: name.clone(), size }).is_err() {
+        return fail("connection closed".to_string());
+    }
+    progress(&ctx.ws_server, device_id, id, &name, false, 0, size);
+    Some((conn_key, Outgoing { device_id: device_id.to_string(), name, size, path, task: None }, id))
+}
+
+/// The connection to `device_id` if it was granted file transfer and its peer
+/// understands the messages
+async fn transfer_target(ctx: &SessionContext, device_id: &str) -> Option<(String, MessageSender)> {
+    // Connections first: teardown holds them while clearing the other two
+    let connections = ctx.active_connections.lock().await;
+    let permissions = ctx.permissions.lock().await;
+    let features = ctx.peer_features.lock().await;
+    connections
+        .iter()
+        .filter(|(_, (_, _, peer_id))| peer_id == device_id)
+        .filter(|(key, _)| permissions.get(*key).is_some_and(|granted| granted.contains(&Permission::FileTransfer)))
+        .find(|(key, _)| features.get(*key).is_some_and(|(_, features)| features.contains(&PeerFeature::FileTransfer)))
+        .map(|(key, (tx, _, _))| (key.clone(), tx.clone()))
+}
+
+/// Open (or reopen, to resume) the part file for an offered file
+async fn accept(downloads: &Path, peer_id: &str, id: u64, name: &str, size: u64) -> anyhow::Result<Incoming> {
+    let Some(name) = safe_file_name(name) else {
+        anyhow::bail!("unusable file name");
+    };
+    tokio::fs::create_dir_all(downloads).await?;
+    let part = downloads.join(format!("{}.{:016x}.part", name, id));
+    let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&part).await?;
+    let mut done = file.metadata().await?.len();
+    // More than offered: not the same file after all
+    if done > size {
+        file.set_len(0).await?;
+        done = 0;
+    }
+    Ok(Incoming { device_id: peer_id.to_string(), name, size, part, file, done })
+}
+
+/// All of it arrived: move it into place and tell both ends
+async fn finish(ctx: &SessionContext, downloads: &Path, conn_key: &str, mut transfer: Incoming, id: u64) {
+    let result = async {
+        transfer.file.flush().await?;
+        drop(transfer.file);
+        let path = unused_path(downloads, &transfer.name);
+        tokio::fs::rename(&transfer.part, &path).await?;
+        Ok::<_, std::io::Error>(path)
+    }
+    .await;
+    match result {
+        Ok(path) => {
+            println!("📁 已收到 {} 的文件: {}", transfer.device_id, path.display());
+            send_to(ctx, conn_key, Message::FileAck { id, offset: transfer.size }).await;
+            ended(&ctx.ws_server, &transfer.device_id, id, true, Some(path), None);
+        }
+        Err(e) => {
+            eprintln!("📁 保存文件失败 {}: {}", transfer.part.display(), e);
+            send_to(ctx, conn_key, Message::FileCancel { id }).await;
+            ended(&ctx.ws_server, &transfer.device_id, id, true, None, Some(e.to_string()));
+        }
+    }
+}
+
+/// Sends one file from an offset on
+struct FileStream {
+    tx: MessageSender,
+    ws_server: Arc<WebSocketServer>,
+    peer_id: String,
+    id: u64,
+    name: String,
+    path: PathBuf,
+    size: u64,
+}
+
+impl FileStream {
+    /// Each chunk only goes once the connection's queue is empty so input queued
+    /// meanwhile goes out first, and no faster than `cap` bytes per second.
+    /// Completion is reported when the peer acknowledges the last byte.
+    async fn run(self, offset: u64, cap: Option<u64>) {
+        if let Err(e) = self.send_from(offset, cap).await {
+            eprintln!("📁 读取文件失败 {}: {}", self.path.display(), e);
+            let _ = self.tx.send(Message::FileCancel { id: self.id });
+            ended(&self.ws_server, &self.peer_id, self.id, false, None, Some(e.to_string()));
+        }
+    }
+
+    async fn send_from(&self, offset: u64, cap: Option<u64>) -> std::io::Result<()> {
+        let mut file = tokio::fs::File::open(&self.path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut buf = vec![0; CHUNK_LEN];
+        let mut done = offset;
+        let started = Instant::now();
+        while done < self.size {
+            let want = CHUNK_LEN.min((self.size - done) as usize);
+            let read = file.read(&mut buf[..want]).await?;
+            if read == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the file got shorter"));
+            }
+            if let Some(cap) = cap.filter(|cap| *cap > 0) {
+                let due = Duration::from_secs_f64((done - offset) as f64 / cap as f64);
+                tokio::time::sleep(due.saturating_sub(started.elapsed())).await;
+            }
+            while self.tx.backlog() > 0 {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            if self.tx.send(Message::FileChunk { id: self.id, offset: done, data: buf[..read].to_vec() }).is_err() {
+                // The connection is gone; the cleanup reports it
+                return Ok(());
+            }
+            let before = done;
+            done += read as u64;
+            if before / PROGRESS_STEP != done / PROGRESS_STEP || done == self.size {
+                progress(&self.ws_server, &self.peer_id, self.id, &self.name, false, done, self.size);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn send_to(ctx: &SessionContext, conn_key: &str, msg: Message) {
+    if let Some((tx, _, _)) = ctx.active_connections.lock().await.get(conn_key) {
+        let _ = tx.send(msg);
+    }
+}
+
+fn progress(ws_server: &WebSocketServer, peer_id: &str, id: u64, name: &str, incoming: bool, done: u64, total: u64) {
+    ws_server.broadcast(Event::FileTransfer {
+        device_id: peer_id.to_string(),
+        id,
+        name: name.to_string(),
+        incoming,
+        done,
+        total,
+    });
+}
+
+fn ended(ws_server: &WebSocketServer, peer_id: &str, id: u64, incoming: bool, path: Option<PathBuf>, error: Option<String>) {
+    ws_server.broadcast(Event::FileTransferEnded { device_id: peer_id.to_string(), id, incoming, path, error });
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+/// Whether Windows Defender Firewall lets peers reach us
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FirewallState {
+    /// Not Windows: the frontend shows the generic self-check hint instead
+    Unsupported,
+    /// netsh failed or printed something we could not parse (e.g. localized output)
+    Unknown,
+    Off,
+    Allowed,
+    /// A block rule for this program, or no allow rule at all (inbound is dropped by default)
+    Blocked,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirewallStatus {
+    pub state: FirewallState,
+    pub detail: Option<String>,
+}
+
+impl FirewallStatus {
+    fn new(state: FirewallState, detail: &str) -> Self {
+        FirewallStatus { state, detail: Some(detail.to_string()) }
+    }
+}
+
+/// Runs netsh, so call it off the async runtime
+#[cfg(windows)]
+pub fn status(port: u16) -> FirewallStatus {
+    use std::process::Command;
+
+    let netsh = |args: &[&str]| -> Option<String> {
+        let output = Command::new("netsh").args(args).output().ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let Some(profile) = netsh(&["advfirewall", "show", "currentprofile", "state"]) else {
+        return FirewallStatus::new(FirewallState::Unknown, "netsh is not available");
+    };
+    let Some(state_line) = profile.lines().find(|line| line.starts_with("State")) else {
+        return FirewallStatus::new(FirewallState::Unknown, "could not read the firewall state");
+    };
+    if state_line.contains("OFF") {
+        return FirewallStatus { state: FirewallState::Off, detail: None };
+    }
+
+    let Some(rules) = netsh(&["advfirewall", "firewall", "show", "rule", "name=all", "dir=in", "verbose"]) else {
+        return FirewallStatus::new(FirewallState::Unknown, "could not list firewall rules");
+    };
+    let exe = std::env::current_exe().ok().map(|p| p.to_string_lossy().to_lowercase());
+    let port = port.to_string();
+
+    // Rules are blocks of "Key: value" lines separated by blank lines
+    let (mut udp, mut tcp) = (false, false);
+    for block in rules.split("\r\n\r\n").flat_map(|b| b.split("\n\n")) {
+        let field = |key: &str| {
+            block
+                .lines()
+                .find_map(|line| line.strip_prefix(key).map(|rest| rest.trim_start_matches(':').trim().to_string()))
+        };
+        if field("Enabled").as_deref() != Some("Yes") {
+            continue;
+        }
+        let program = field("Program").map(|p| p.to_lowercase());
+        let ours = match (&program, &exe) {
+            (Some(program), Some(exe)) => program == exe,
+            _ => false,
+        };
+        let any_program = program.as_deref().is_none_or(|p| p == "any");
+        let local_port = field("LocalPort").unwrap_or_default();
+        let covers_port = local_port == "Any" || local_port.split(',').any(|p| p.trim() == port);
+        let protocol = field("Protocol").unwrap_or_default();
+
+        match field("Action").as_deref() {
+            Some("Block") if ours => {
+                let name = field("Rule Name").unwrap_or_default();
+                return FirewallStatus::new(FirewallState::Blocked, &format!("rule \"{}\" blocks ShareFlow", name));
+            }
+            Some("Allow") if (ours || any_program) && covers_port => {
+                udp |= protocol == "UDP" || protocol == "Any";
+                tcp |= protocol == "TCP" || protocol == "Any";
+            }
+            _ => {}
+        }
+    }
+
+    match (udp, tcp) {
+        (true, true) => FirewallStatus { state: FirewallState::Allowed, detail: None },
+        (false, false) => FirewallStatus::new(FirewallState::Blocked, &format!("no inbound rule allows port {}", port)),
+        (false, true) => {
+            FirewallStatus::new(FirewallState::Blocked, &format!("UDP {} is not allowed, discovery will not work", port))
+        }
+        (true, false) => {
+            FirewallStatus::new(FirewallState::Blocked, &format!("TCP {} is not allowed, peers cannot connect", port))
+        }
+    }
+}
+
+#[cfg(not(windows))]
+pub fn status(_port: u16) -> FirewallStatus {
+    FirewallStatus::new(FirewallState::Unsupported, "rule assistance is only available on Windows")
+}
+
+/// Replace this program's inbound rules with allow rules for UDP and TCP `port`.
+/// Shows a UAC prompt; blocks until it was answered and netsh finished.
+#[cfg(windows)]
+pub fn add_rules(port: u16) -> anyhow::Result<()> {
+    use anyhow::{bail, Context};
+    use std::process::Command;
+    // Prefix of our rules, so they can be told apart from the ones Windows' prompt creates
+    const RULE_NAME: &str = "ShareFlow";
+
+    let exe = std::env::current_exe().context("cannot find the ShareFlow executable")?;
+    let exe = exe.to_string_lossy();
+    // Windows' own "blocked by the user" rules win over allow rules, so they go first
+    let netsh = [
+        format!("netsh advfirewall firewall delete rule name=all dir=in program=\"{}\"", exe),
+        format!(
+            "netsh advfirewall firewall add rule name=\"{} (UDP {})\" dir=in action=allow protocol=UDP localport={} program=\"{}\"",
+            RULE_NAME, port, port, exe
+        ),
+        format!(
+            "netsh advfirewall firewall add rule name=\"{} (TCP {})\" dir=in action=allow protocol=TCP localport={} program=\"{}\"",
+            RULE_NAME, port, port, exe
+        ),
+    ]
+    .join(" & ");
+    // One elevated cmd for all three, so there is only one UAC prompt
+    let script = format!(
+        "$p = Start-Process -FilePath cmd -ArgumentList '/c {}' -Verb RunAs -Wait -PassThru -WindowStyle Hidden; exit $p.ExitCode",
+        netsh.replace('\'', "''")
+    );
+    let status = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .status()
+        .context("cannot start PowerShell")?;
+    if !status.success() {
+        // Declining the UAC prompt ends up here as well
+        bail!("rule creation was cancelled or failed (exit code {:?})", status.code());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn add_rules(_port: u16) -> anyhow::Result<()> {
+    anyhow::bail!("firewall rules can only be created on Windows")
+}
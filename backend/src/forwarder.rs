@@ -0,0 +1,411 @@
+use crate::protocol::{self, Message};
+use crate::websocket::SessionMode;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// Messages queued for one peer beyond which further mouse moves are held back
+const MAX_MOVE_BACKLOG: usize = 16;
+
+// A link counts as congested from this backlog or smoothed queue-to-socket delay on
+const CONGESTED_BACKLOG: usize = MAX_MOVE_BACKLOG / 2;
+const CONGESTED_SEND_DELAY: Duration = Duration::from_millis(40);
+// Coalescing intervals stepped through while congested
+const ADAPTIVE_INTERVALS: [Duration; 3] = [
+    Duration::from_millis(16),
+    Duration::from_millis(33),
+    Duration::from_millis(66),
+];
+// Back off quickly, recover slowly so a flaky link doesn't oscillate
+const ESCALATE_AFTER: Duration = Duration::from_millis(100);
+const RECOVER_AFTER: Duration = Duration::from_secs(1);
+
+// Shared between both ends of a connection's send queue
+#[derive(Default)]
+struct QueueStats {
+    backlog: AtomicUsize,
+    // Exponentially smoothed time from queueing to written on the socket
+    send_delay_us: AtomicU64,
+}
+
+/// Lock-free send side of a peer connection that knows how far the socket writer is behind
+#[derive(Clone)]
+pub struct MessageSender {
+    tx: mpsc::UnboundedSender<(Message, Instant)>,
+    stats: Arc<QueueStats>,
+}
+
+/// Does not keep the connection open on its own
+#[derive(Clone)]
+pub struct WeakMessageSender {
+    tx: mpsc::WeakUnboundedSender<(Message, Instant)>,
+    stats: Arc<QueueStats>,
+}
+
+/// Receive side handed to the connection's socket writer
+pub struct MessageReceiver {
+    rx: mpsc::UnboundedReceiver<(Message, Instant)>,
+    stats: Arc<QueueStats>,
+}
+
+impl MessageSender {
+    pub fn channel() -> (MessageSender, MessageReceiver) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = Arc::new(QueueStats::default());
+        (
+            MessageSender { tx, stats: Arc::clone(&stats) },
+            MessageReceiver { rx, stats },
+        )
+    }
+
+    pub fn send(&self, msg: Message) -> Result<(), mpsc::error::SendError<Message>> {
+        self.stats.backlog.fetch_add(1, Ordering::Relaxed);
+        self.tx.send((msg, Instant::now())).map_err(|mpsc::error::SendError((msg, _))| {
+            self.stats.backlog.fetch_sub(1, Ordering::Relaxed);
+            mpsc::error::SendError(msg)
+        })
+    }
+
+    /// Messages queued but not yet picked up by the socket writer
+    pub fn backlog(&self) -> usize {
+        self.stats.backlog.load(Ordering::Relaxed)
+    }
+
+    /// Smoothed time messages take from queueing to being written to the socket
+    pub fn send_delay(&self) -> Duration {
+        Duration::from_micros(self.stats.send_delay_us.load(Ordering::Relaxed))
+    }
+
+    pub fn downgrade(&self) -> WeakMessageSender {
+        WeakMessageSender { tx: self.tx.downgrade(), stats: Arc::clone(&self.stats) }
+    }
+}
+
+impl WeakMessageSender {
+    pub fn upgrade(&self) -> Option<MessageSender> {
+        let tx = self.tx.upgrade()?;
+        Some(MessageSender { tx, stats: Arc::clone(&self.stats) })
+    }
+}
+
+impl MessageReceiver {
+    /// Next message and when it was queued
+    pub async fn recv(&mut self) -> Option<(Message, Instant)> {
+        let queued = self.rx.recv().await?;
+        self.stats.backlog.fetch_sub(1, Ordering::Relaxed);
+        Some(queued)
+    }
+
+    /// Report that a message queued at `queued_at` has been written to the socket
+    pub fn written(&self, queued_at: Instant) {
+        let sample = queued_at.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        let smoothed = self.stats.send_delay_us.load(Ordering::Relaxed);
+        // Only the socket writer updates it, so load/store is enough
+        self.stats.send_delay_us.store(smoothed - smoothed / 8 + sample / 8, Ordering::Relaxed);
+    }
+}
+
+// addr -> (sender, receive task, peer device ID)
+pub type ActiveConnections = HashMap<String, (MessageSender, tokio::task::AbortHandle, String)>;
+
+/// What one peer has been told is held down, so redundant events can be dropped
+#[derive(Default)]
+struct PeerInputState {
+    keys: HashSet<u32>,
+    buttons: HashSet<u8>,
+    // Displacement held back while the peer's queue was saturated
+    carried_move: (i32, i32),
+}
+
+impl PeerInputState {
+    /// Whether `msg` would change anything on the peer
+    fn admit(&mut self, msg: &Message) -> bool {
+        match *msg {
+            // Repeated keydowns are auto-repeat and still go through
+            Message::KeyPress { key, state: true } => {
+                self.keys.insert(key);
+                true
+            }
+            Message::KeyPress { key, state: false } => self.keys.remove(&key),
+            Message::MouseClick { button, state: true, .. } => self.buttons.insert(button),
+            Message::MouseClick { button, state: false, .. } => self.buttons.remove(&button),
+            Message::MouseMove { x: 0, y: 0 } | Message::MouseWheel { delta_x: 0, delta_y: 0 } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Forwards local input to connected peers and remembers which mouse buttons
+/// are held down on the remote side, so a drag survives switching targets.
+pub struct InputForwarder {
+    pub mode: SessionMode,
+    held_buttons: BTreeSet<u8>,
+    last_button_event: Option<Instant>,
+    coalesce_interval: Option<Duration>,
+    pending_moves: Vec<(i16, i16)>,
+    last_flush: Option<Instant>,
+    peers: HashMap<String, PeerInputState>,
+    // Index into ADAPTIVE_INTERVALS + 1 while the link is congested, 0 otherwise
+    congestion_level: usize,
+    last_level_change: Option<Instant>,
+    last_congested: Option<Instant>,
+    // Device ID captured input goes to; None sends it to every peer
+    target: Option<String>,
+}
+
+impl Default for InputForwarder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputForwarder {
+    pub fn new() -> Self {
+        Self {
+            mode: SessionMode::Normal,
+            held_buttons: BTreeSet::new(),
+            last_button_event: None,
+            coalesce_interval: None,
+            pending_moves: Vec::new(),
+            last_flush: None,
+            peers: HashMap::new(),
+            congestion_level: 0,
+            last_level_change: None,
+            last_congested: None,
+            target: None,
+        }
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// Point captured input at one peer (None: all of them). Whatever peers that
+    /// are no longer targeted still hold down is released there, and held mouse
+    /// buttons are pressed on the new target so a drag carries over.
+    pub fn set_target(&mut self, connections: &ActiveConnections, target: Option<String>) {
+        self.flush(connections);
+        for (addr, (sender, _, device_id)) in connections {
+            if target.as_ref().is_none_or(|target| target == device_id) {
+                continue;
+            }
+            let Some(peer) = self.peers.get_mut(addr) else {
+                continue;
+            };
+            for key in peer.keys.drain() {
+                let _ = sender.send(Message::KeyPress { key, state: false });
+            }
+            for button in peer.buttons.drain() {
+                let _ = sender.send(Message::MouseClick { button, state: false, elapsed_ms: 0 });
+            }
+        }
+        self.target = target;
+        // Peers that already have them pressed drop the repeat
+        let held: Vec<u8> = self.held_buttons.iter().copied().collect();
+        for button in held {
+            let target = self.target.clone();
+            self.send(connections, target.as_deref(), Message::MouseClick { button, state: true, elapsed_ms: 0 });
+        }
+    }
+
+    /// None sends every move immediately; callers should flush before changing it
+    pub fn set_coalesce_interval(&mut self, interval: Option<Duration>) {
+        self.coalesce_interval = interval;
+    }
+
+    /// Forward an input message to the target peer (every peer without one),
+    /// honouring the session mode filter
+    pub fn forward(&mut self, connections: &ActiveConnections, msg: Message) {
+        let target = self.target.clone();
+        self.forward_to(connections, target.as_deref(), msg);
+    }
+
+    /// Like `forward`, but only to the peer with device ID `target` when one is given
+    pub fn forward_to(&mut self, connections: &ActiveConnections, target: Option<&str>, msg: Message) {
+        if matches!(msg, Message::MouseMove { .. }) {
+            self.adapt(connections);
+        }
+        let coalesce = self.coalesce_interval.is_some() || self.congestion_level > 0;
+        self.dispatch(connections, target, msg, coalesce);
+    }
+
+    /// Input from WS clients: browsers emit mousemove at uncapped rates, so
+    /// moves always wait for the next flush whatever the coalescing setting
+    pub fn forward_client(&mut self, connections: &ActiveConnections, target: Option<&str>, msg: Message) {
+        self.dispatch(connections, target, msg, true);
+    }
+
+    /// Whether moves are waiting for the next flush
+    pub fn has_pending_moves(&self) -> bool {
+        !self.pending_moves.is_empty() || self.peers.values().any(|peer| peer.carried_move != (0, 0))
+    }
+
+    /// Periodic flush: re-checks the link and sends pending moves once the
+    /// current (possibly widened) coalescing interval has passed
+    pub fn tick(&mut self, connections: &ActiveConnections) {
+        self.adapt(connections);
+        let due = match self.congestion_level {
+            0 => true,
+            level => self.last_flush.is_none_or(|last| last.elapsed() >= ADAPTIVE_INTERVALS[level - 1]),
+        };
+        if due {
+            self.flush(connections);
+        }
+    }
+
+    // Widen coalescing while any peer's queue backs up or its writes stall,
+    // and step back down once the link has been healthy for a while
+    fn adapt(&mut self, connections: &ActiveConnections) {
+        let now = Instant::now();
+        let congested = connections.values().any(|(sender, _, _)| {
+            sender.backlog() >= CONGESTED_BACKLOG || sender.send_delay() >= CONGESTED_SEND_DELAY
+        });
+        let since_change = self.last_level_change.map_or(Duration::MAX, |last| now.duration_since(last));
+        if congested {
+            self.last_congested = Some(now);
+            if self.congestion_level < ADAPTIVE_INTERVALS.len() && since_change >= ESCALATE_AFTER {
+                self.congestion_level += 1;
+                self.last_level_change = Some(now);
+                println!("[主控端] 链路拥塞，鼠标合并间隔提高到 {:?}", ADAPTIVE_INTERVALS[self.congestion_level - 1]);
+            }
+        } else if self.congestion_level > 0
+            && since_change >= RECOVER_AFTER
+            && self.last_congested.is_none_or(|last| now.duration_since(last) >= RECOVER_AFTER)
+        {
+            self.congestion_level -= 1;
+            self.last_level_change = Some(now);
+            match self.congestion_level {
+                0 => println!("[主控端] 链路恢复，鼠标移动恢复全速"),
+                level => println!("[主控端] 链路好转，鼠标合并间隔降到 {:?}", ADAPTIVE_INTERVALS[level - 1]),
+            }
+        }
+    }
+
+    fn dispatch(&mut self, connections: &ActiveConnections, target: Option<&str>, mut msg: Message, coalesce: bool) {
+        if !self.mode.allows(&msg) {
+            return;
+        }
+        // The move queue goes to the forwarder's target, moves for anyone else skip it
+        if let (Message::MouseMove { x, y }, true) = (&msg, coalesce && target == self.target.as_deref()) {
+            self.queue_move(*x, *y);
+            return;
+        }
+        // Keep ordering: pending moves go out before anything else
+        self.flush(connections);
+        if let Message::MouseClick { button, state, ref mut elapsed_ms } = msg {
+            if state {
+                self.held_buttons.insert(button);
+            } else {
+                self.held_buttons.remove(&button);
+            }
+            // Stamp the original spacing so the peer can replay double-clicks faithfully
+            let now = Instant::now();
+            *elapsed_ms = self.last_button_event
+                .map(|last| now.duration_since(last).as_millis().min(u32::MAX as u128) as u32)
+                .unwrap_or(0);
+            self.last_button_event = Some(now);
+        }
+        self.send(connections, target, msg);
+    }
+
+    /// Send coalesced moves as a single frame, preserving the total displacement
+    pub fn flush(&mut self, connections: &ActiveConnections) {
+        self.last_flush = Some(Instant::now());
+        let msg = match self.pending_moves.len() {
+            0 => {
+                self.release_carried(connections);
+                return;
+            }
+            // Congested: the path in between isn't worth the bandwidth, only where it ends
+            _ if self.congestion_level > 0 => {
+                let (x, y) = protocol::batch_displacement(&std::mem::take(&mut self.pending_moves));
+                Message::MouseMove { x, y }
+            }
+            1 => {
+                let (x, y) = self.pending_moves.remove(0);
+                Message::MouseMove { x: x as i32, y: y as i32 }
+            }
+            _ => Message::MouseMoveBatch(std::mem::take(&mut self.pending_moves)),
+        };
+        let target = self.target.clone();
+        self.send(connections, target.as_deref(), msg);
+    }
+
+    fn queue_move(&mut self, mut x: i32, mut y: i32) {
+        // Split deltas that don't fit the batch's i16 entries
+        loop {
+            let step_x = x.clamp(i16::MIN as i32, i16::MAX as i32);
+            let step_y = y.clamp(i16::MIN as i32, i16::MAX as i32);
+            self.pending_moves.push((step_x as i16, step_y as i16));
+            x -= step_x;
+            y -= step_y;
+            if x == 0 && y == 0 {
+                break;
+            }
+        }
+    }
+
+    fn send(&mut self, connections: &ActiveConnections, target: Option<&str>, msg: Message) {
+        self.peers.retain(|addr, _| connections.contains_key(addr));
+        let displacement = match &msg {
+            Message::MouseMove { x, y } => Some((*x, *y)),
+            Message::MouseMoveBatch(deltas) => Some(protocol::batch_displacement(deltas)),
+            _ => None,
+        };
+        for (addr, (sender, _, device_id)) in connections {
+            if target.is_some_and(|target| target != device_id) {
+                continue;
+            }
+            let peer = self.peers.entry(addr.clone()).or_default();
+            if !peer.admit(&msg) {
+                continue;
+            }
+            if let Some((x, y)) = displacement {
+                if sender.backlog() >= MAX_MOVE_BACKLOG {
+                    // Peer is behind: drop the stale frame but keep its displacement
+                    peer.carried_move.0 += x;
+                    peer.carried_move.1 += y;
+                    continue;
+                }
+            }
+            if peer.carried_move != (0, 0) {
+                let (x, y) = std::mem::take(&mut peer.carried_move);
+                let _ = sender.send(Message::MouseMove { x, y });
+            }
+            let _ = sender.send(msg.clone());
+        }
+    }
+
+    // Deliver held-back displacement to peers that have caught up
+    fn release_carried(&mut self, connections: &ActiveConnections) {
+        for (addr, peer) in self.peers.iter_mut() {
+            let Some((sender, _, _)) = connections.get(addr) else {
+                continue;
+            };
+            if peer.carried_move != (0, 0) && sender.backlog() < MAX_MOVE_BACKLOG {
+                let (x, y) = std::mem::take(&mut peer.carried_move);
+                let _ = sender.send(Message::MouseMove { x, y });
+            }
+        }
+    }
+
+    /// Press buttons on the peer that were already held locally when control moved over
+    pub fn press_held(&mut self, connections: &ActiveConnections, buttons: &[u8]) {
+        for &button in buttons {
+            self.forward(connections, Message::MouseClick { button, state: true, elapsed_ms: 0 });
+        }
+    }
+
+    /// Release every button still held on the peer and return them,
+    /// so the caller can press them again on the new target
+    pub fn release_held(&mut self, connections: &ActiveConnections) -> Vec<u8> {
+        let buttons: Vec<u8> = self.held_buttons.iter().copied().collect();
+        for &button in &buttons {
+            self.forward(connections, Message::MouseClick { button, state: false, elapsed_ms: 0 });
+        }
+        self.held_buttons.clear();
+        buttons
+    }
+}
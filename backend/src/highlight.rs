@@ -0,0 +1,198 @@
+//! "Look here": a ring flashed around the cursor of a machine being
+//! controlled, so whoever sits in front of it sees what the controller points at.
+
+use std::time::Duration;
+
+/// How long the ring stays up
+pub const DURATION: Duration = Duration::from_millis(900);
+
+/// Rings (x, y) on a thread of its own and returns at once.
+/// False if nothing can be drawn here, or a ring is still up.
+pub fn show(x: i32, y: i32) -> bool {
+    #[cfg(windows)]
+    {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Another ring while one is up would only stack on top of it
+        static SHOWING: AtomicBool = AtomicBool::new(false);
+        if SHOWING.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        std::thread::spawn(move || {
+            draw_gdi(x, y);
+            SHOWING.store(false, Ordering::Release);
+        });
+        true
+    }
+
+    #[cfg(not(windows))]
+    {
+        let _ = (x, y);
+        false
+    }
+}
+
+/// A topmost, click-through layered window that never takes focus. Black is
+/// its transparent color key, so only the shrinking ring shows.
+#[cfg(windows)]
+fn draw_gdi(x: i32, y: i32) {
+    use std::ptr;
+    use std::sync::Once;
+    use std::time::Instant;
+
+    // Diameter of the window; the ring starts at the edge
+    const SIZE: i32 = 96;
+    const FRAME: Duration = Duration::from_millis(30);
+    // 0x00BBGGRR: orange, visible on light and dark backgrounds
+    const RING_COLOR: u32 = 0x0000_8CFF;
+    const RING_WIDTH: i32 = 6;
+
+    #[repr(C)]
+    struct WndClassW {
+        style: u32,
+        wnd_proc: unsafe extern "system" fn(isize, u32, usize, isize) -> isize,
+        cls_extra: i32,
+        wnd_extra: i32,
+        instance: isize,
+        icon: isize,
+        cursor: isize,
+        background: isize,
+        menu_name: *const u16,
+        class_name: *const u16,
+    }
+
+    #[repr(C)]
+    struct Msg {
+        window: isize,
+        message: u32,
+        w_param: usize,
+        l_param: isize,
+        time: u32,
+        pt: [i32; 2],
+    }
+
+    #[repr(C)]
+    struct Rect {
+        left: i32,
+        top: i32,
+        right: i32,
+        bottom: i32,
+    }
+
+    extern "system" {
+        fn GetModuleHandleW(name: *const u16) -> isize;
+        fn RegisterClassW(class: *const WndClassW) -> u16;
+        fn DefWindowProcW(window: isize, message: u32, w_param: usize, l_param: isize) -> isize;
+        fn CreateWindowExW(
+            ex_style: u32,
+            class_name: *const u16,
+            window_name: *const u16,
+            style: u32,
+            x: i32,
+            y: i32,
+            width: i32,
+            height: i32,
+            parent: isize,
+            menu: isize,
+            instance: isize,
+            param: *const std::ffi::c_void,
+        ) -> isize;
+        fn SetLayeredWindowAttributes(window: isize, key: u32, alpha: u8, flags: u32) -> i32;
+        fn ShowWindow(window: isize, command: i32) -> i32;
+        fn DestroyWindow(window: isize) -> i32;
+        fn PeekMessageW(msg: *mut Msg, window: isize, min: u32, max: u32, remove: u32) -> i32;
+        fn TranslateMessage(msg: *const Msg) -> i32;
+        fn DispatchMessageW(msg: *const Msg) -> isize;
+        fn GetDC(window: isize) -> isize;
+        fn ReleaseDC(window: isize, dc: isize) -> i32;
+        fn FillRect(dc: isize, rect: *const Rect, brush: isize) -> i32;
+    }
+    #[link(name = "gdi32")]
+    extern "system" {
+        fn GetStockObject(index: i32) -> isize;
+        fn CreatePen(style: i32, width: i32, color: u32) -> isize;
+        fn SelectObject(dc: isize, object: isize) -> isize;
+        fn Ellipse(dc: isize, left: i32, top: i32, right: i32, bottom: i32) -> i32;
+        fn DeleteObject(object: isize) -> i32;
+    }
+
+    const WS_POPUP: u32 = 0x8000_0000;
+    const WS_EX_TOPMOST: u32 = 0x0000_0008;
+    const WS_EX_TRANSPARENT: u32 = 0x0000_0020;
+    const WS_EX_TOOLWINDOW: u32 = 0x0000_0080;
+    const WS_EX_LAYERED: u32 = 0x0008_0000;
+    const WS_EX_NOACTIVATE: u32 = 0x0800_0000;
+    const LWA_COLORKEY: u32 = 0x1;
+    const SW_SHOWNOACTIVATE: i32 = 4;
+    const PM_REMOVE: u32 = 0x1;
+    const BLACK_BRUSH: i32 = 4;
+    const NULL_BRUSH: i32 = 5;
+    const PS_SOLID: i32 = 0;
+
+    static REGISTER: Once = Once::new();
+    let class_name: Vec<u16> = "ShareFlowHighlight\0".encode_utf16().collect();
+
+    unsafe {
+        let instance = GetModuleHandleW(ptr::null());
+        REGISTER.call_once(|| {
+            let class = WndClassW {
+                style: 0,
+                wnd_proc: DefWindowProcW,
+                cls_extra: 0,
+                wnd_extra: 0,
+                instance,
+                icon: 0,
+                cursor: 0,
+                background: 0,
+                menu_name: ptr::null(),
+                class_name: class_name.as_ptr(),
+            };
+            RegisterClassW(&class);
+        });
+
+        let window = CreateWindowExW(
+            WS_EX_LAYERED | WS_EX_TRANSPARENT | WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+            class_name.as_ptr(),
+            ptr::null(),
+            WS_POPUP,
+            x - SIZE / 2,
+            y - SIZE / 2,
+            SIZE,
+            SIZE,
+            0,
+            0,
+            instance,
+            ptr::null(),
+        );
+        if window == 0 {
+            return;
+        }
+        SetLayeredWindowAttributes(window, 0, 0, LWA_COLORKEY);
+        ShowWindow(window, SW_SHOWNOACTIVATE);
+
+        let pen = CreatePen(PS_SOLID, RING_WIDTH, RING_COLOR);
+        let started = Instant::now();
+        while started.elapsed() < DURATION {
+            // Closes in from the edge towards the cursor
+            let progress = started.elapsed().as_secs_f32() / DURATION.as_secs_f32();
+            let inset = RING_WIDTH / 2 + ((SIZE / 2 - RING_WIDTH * 2) as f32 * progress) as i32;
+            let dc = GetDC(window);
+            FillRect(dc, &Rect { left: 0, top: 0, right: SIZE, bottom: SIZE }, GetStockObject(BLACK_BRUSH));
+            let previous_pen = SelectObject(dc, pen);
+            let previous_brush = SelectObject(dc, GetStockObject(NULL_BRUSH));
+            Ellipse(dc, inset, inset, SIZE - inset, SIZE - inset);
+            SelectObject(dc, previous_pen);
+            SelectObject(dc, previous_brush);
+            ReleaseDC(window, dc);
+
+            let mut msg: Msg = std::mem::zeroed();
+            while PeekMessageW(&mut msg, 0, 0, 0, PM_REMOVE) != 0 {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+            std::thread::sleep(FRAME);
+        }
+        DeleteObject(pen);
+        DestroyWindow(window);
+    }
+}
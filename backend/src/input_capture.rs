@@ -1,109 +1,702 @@
-use rdev::{grab, Event, EventType, Key};
+// Without the capture feature only the shared types and key mapping are used
+#![cfg_attr(not(feature = "capture"), allow(dead_code))]
+
+#[cfg(feature = "capture")]
+use rdev::{listen, Event, EventType};
+use rdev::Key;
+use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use crate::input_simulator::{InjectionWatcher, InputBackend};
+use crate::protocol::{LockState, Message, ScreenEdge, BUTTON_BACK, BUTTON_FORWARD};
+use crate::screens::{Monitor, Topology};
 use tokio::sync::mpsc;
 
+/// Modifier keys held down when an input event happened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Modifiers {
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub alt: bool,
+    #[serde(default)]
+    pub meta: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct InputEventData {
     pub event_type: String,
     pub key: Option<String>,
     pub key_code: Option<u32>, // Added key_code
+    pub button: Option<u8>, // 0: Left, 1: Right, 2: Middle, 3: Back, 4: Forward
+    pub modifiers: Modifiers,
     pub x: Option<f64>,
     pub y: Option<f64>,
     pub dx: Option<f64>,
     pub dy: Option<f64>,
+    /// When the hook saw it, for pipeline timing
+    pub captured_at: Instant,
+}
+
+impl InputEventData {
+    /// A mousedown or mouseup as the hook reports it; None for buttons beyond
+    /// back/forward, which have no number on the wire
+    pub fn from_button(button: rdev::Button, down: bool, modifiers: Modifiers, captured_at: Instant) -> Option<Self> {
+        let button = protocol_button(button)?;
+        Some(InputEventData {
+            event_type: if down { "mousedown" } else { "mouseup" }.to_string(),
+            key: Some(format!("button{}", button)),
+            key_code: None,
+            button: Some(button),
+            modifiers,
+            x: None,
+            y: None,
+            dx: None,
+            dy: None,
+            captured_at,
+        })
+    }
+
+    /// The MouseClick a captured mousedown or mouseup goes to peers as
+    pub fn click(&self) -> Option<Message> {
+        let state = match self.event_type.as_str() {
+            "mousedown" => true,
+            "mouseup" => false,
+            _ => return None,
+        };
+        Some(Message::MouseClick { button: self.button?, state, elapsed_ms: 0 })
+    }
+}
+
+/// Groups of keys assistive tech relies on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyClass {
+    /// Insert and Caps Lock, the NVDA/JAWS/Narrator modifier keys
+    ScreenReader,
+    /// Keypad keys, used by screen reader review cursors and dictation software
+    Numpad,
+}
+
+impl KeyClass {
+    fn contains(&self, key: Key) -> bool {
+        match self {
+            KeyClass::ScreenReader => matches!(key, Key::Insert | Key::CapsLock),
+            KeyClass::Numpad => matches!(
+                key,
+                Key::Kp0 | Key::Kp1 | Key::Kp2 | Key::Kp3 | Key::Kp4 | Key::Kp5 | Key::Kp6
+                    | Key::Kp7 | Key::Kp8 | Key::Kp9 | Key::KpReturn | Key::KpMinus | Key::KpPlus
+                    | Key::KpMultiply | Key::KpDivide | Key::KpDelete | Key::NumLock
+            ),
+        }
+    }
+}
+
+/// Keyboard input that capture never takes away from this machine
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GrabExclusions {
+    #[serde(default)]
+    pub key_classes: Vec<KeyClass>,
+    /// Extra key codes, as produced by `rdev_key_to_code`
+    #[serde(default)]
+    pub keys: Vec<u32>,
+    /// Executable names (e.g. "nvda.exe"); while one has focus no key is grabbed.
+    /// Only supported on Windows.
+    #[serde(default)]
+    pub processes: Vec<String>,
+    /// Input device names as the kernel reports them (e.g. a foot pedal's
+    /// "PCsensor FootSwitch"); keys and moves from these always act here.
+    /// Only supported by the evdev grab in Wayland sessions.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl GrabExclusions {
+    /// Whether everything from the input device called `name` should stay on this machine
+    fn passes_device(&self, name: &str) -> bool {
+        self.devices.iter().any(|device| device == name)
+    }
+
+    /// Whether `key` should reach local applications instead of the peer
+    fn passes(&self, key: Key) -> bool {
+        self.key_classes.iter().any(|class| class.contains(key))
+            || self.keys.contains(&rdev_key_to_code(key))
+            || (!self.processes.is_empty() && self.foreground_excluded())
+    }
+
+    fn foreground_excluded(&self) -> bool {
+        foreground_process_name().is_some_and(|name| {
+            self.processes.iter().any(|process| process.eq_ignore_ascii_case(&name))
+        })
+    }
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn GetForegroundWindow() -> isize;
+    fn GetWindowThreadProcessId(hwnd: isize, process_id: *mut u32) -> u32;
+    fn OpenProcess(desired_access: u32, inherit_handle: i32, process_id: u32) -> isize;
+    fn QueryFullProcessImageNameW(process: isize, flags: u32, name: *mut u16, size: *mut u32) -> i32;
+    fn CloseHandle(handle: isize) -> i32;
+}
+
+/// Executable file name of the application owning the focused window
+#[cfg(windows)]
+fn foreground_process_name() -> Option<String> {
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    unsafe {
+        let mut process_id = 0;
+        GetWindowThreadProcessId(GetForegroundWindow(), &mut process_id);
+        if process_id == 0 {
+            return None;
+        }
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process == 0 {
+            return None;
+        }
+        let mut buffer = [0u16; 260];
+        let mut size = buffer.len() as u32;
+        let ok = QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size);
+        CloseHandle(process);
+        if ok == 0 {
+            return None;
+        }
+        let path = String::from_utf16_lossy(&buffer[..size as usize]);
+        path.rsplit(['\\', '/']).next().map(str::to_string)
+    }
+}
+
+#[cfg(not(windows))]
+fn foreground_process_name() -> Option<String> {
+    None
 }
 
 #[derive(Debug, Clone)]
 pub enum CaptureControl {
     InputEvent(InputEventData),
     ExitRequested,
+    /// Ctrl+Alt+<digit>: send input to the device in that slot
+    SwitchSlot(u8),
+}
+
+/// Global hotkeys outside of capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    /// The capture shortcut, Ctrl+Alt+S unless the config file says otherwise
+    StartCapture,
+    /// Ctrl+Alt+<digit>
+    Slot(u8),
+}
+
+/// A key and the modifiers that must be held with it, written like "Ctrl+Alt+S"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shortcut {
+    pub modifiers: Modifiers,
+    pub key: Key,
+}
+
+/// Keys a shortcut can end in. Digits are left out, Ctrl+Alt+<digit> belongs to the slots.
+const SHORTCUT_KEYS: [(Key, &str); 38] = [
+    (Key::KeyA, "A"), (Key::KeyB, "B"), (Key::KeyC, "C"), (Key::KeyD, "D"), (Key::KeyE, "E"),
+    (Key::KeyF, "F"), (Key::KeyG, "G"), (Key::KeyH, "H"), (Key::KeyI, "I"), (Key::KeyJ, "J"),
+    (Key::KeyK, "K"), (Key::KeyL, "L"), (Key::KeyM, "M"), (Key::KeyN, "N"), (Key::KeyO, "O"),
+    (Key::KeyP, "P"), (Key::KeyQ, "Q"), (Key::KeyR, "R"), (Key::KeyS, "S"), (Key::KeyT, "T"),
+    (Key::KeyU, "U"), (Key::KeyV, "V"), (Key::KeyW, "W"), (Key::KeyX, "X"), (Key::KeyY, "Y"),
+    (Key::KeyZ, "Z"),
+    (Key::F1, "F1"), (Key::F2, "F2"), (Key::F3, "F3"), (Key::F4, "F4"), (Key::F5, "F5"),
+    (Key::F6, "F6"), (Key::F7, "F7"), (Key::F8, "F8"), (Key::F9, "F9"), (Key::F10, "F10"),
+    (Key::F11, "F11"), (Key::F12, "F12"),
+];
+
+impl Default for Shortcut {
+    fn default() -> Self {
+        Shortcut { modifiers: Modifiers { ctrl: true, alt: true, ..Modifiers::default() }, key: Key::KeyS }
+    }
+}
+
+impl std::str::FromStr for Shortcut {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let mut modifiers = Modifiers::default();
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let last = parts.pop().unwrap_or_default();
+        for part in parts {
+            let held = match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => &mut modifiers.ctrl,
+                "alt" => &mut modifiers.alt,
+                "shift" => &mut modifiers.shift,
+                "meta" | "win" | "cmd" | "super" => &mut modifiers.meta,
+                _ => anyhow::bail!("未知的修饰键 {:?}", part),
+            };
+            *held = true;
+        }
+        let Some(&(key, _)) = SHORTCUT_KEYS.iter().find(|(_, name)| name.eq_ignore_ascii_case(last)) else {
+            anyhow::bail!("快捷键只能以字母或 F1-F12 结尾: {:?}", last);
+        };
+        // Shift alone would fire while typing capitals
+        if !(modifiers.ctrl || modifiers.alt || modifiers.meta) {
+            anyhow::bail!("快捷键需要 Ctrl、Alt 或 Meta: {:?}", text);
+        }
+        let shortcut = Shortcut { modifiers, key };
+        if shortcut == (Shortcut { key: Key::KeyQ, ..Shortcut::default() }) {
+            anyhow::bail!("Ctrl+Alt+Q 用于退出控制");
+        }
+        Ok(shortcut)
+    }
+}
+
+impl std::fmt::Display for Shortcut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let held = [(self.modifiers.ctrl, "Ctrl"), (self.modifiers.alt, "Alt"), (self.modifiers.shift, "Shift"), (self.modifiers.meta, "Meta")];
+        for (_, name) in held.iter().filter(|(on, _)| *on) {
+            write!(f, "{}+", name)?;
+        }
+        let name = SHORTCUT_KEYS.iter().find(|(key, _)| *key == self.key).map_or("?", |(_, name)| name);
+        f.write_str(name)
+    }
+}
+
+/// Slot number of a digit key (1-9)
+#[cfg(feature = "capture")]
+fn slot_key(key: Key) -> Option<u8> {
+    const DIGITS: [Key; 9] =
+        [Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9];
+    DIGITS.iter().position(|digit| *digit == key).map(|i| i as u8 + 1)
 }
 
 
 #[cfg(windows)]
 extern "system" {
     fn SetCursorPos(x: i32, y: i32) -> i32;
+    fn GetAsyncKeyState(v_key: i32) -> i16;
+    fn MonitorFromPoint(pt: Point, flags: u32) -> isize;
+}
+
+#[cfg(windows)]
+#[link(name = "shcore")]
+extern "system" {
+    fn GetDpiForMonitor(monitor: isize, dpi_type: u32, dpi_x: *mut u32, dpi_y: *mut u32) -> i32;
+}
+
+#[cfg(target_os = "macos")]
+#[repr(C)]
+struct CGPoint {
+    x: f64,
+    y: f64,
+}
+
+#[cfg(target_os = "macos")]
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGWarpMouseCursorPosition(point: CGPoint) -> i32;
+    fn CGAssociateMouseAndMouseCursorPosition(connected: u32) -> i32;
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+/// Display scale of the monitor at a screen position, 1.0 at 96 DPI.
+/// The manifest makes us per-monitor DPI aware, so hook positions are
+/// physical pixels and differ in size between monitors.
+#[cfg(feature = "capture")]
+fn monitor_scale(x: f64, y: f64) -> f64 {
+    #[cfg(windows)]
+    {
+        const MONITOR_DEFAULTTONEAREST: u32 = 2;
+        const MDT_EFFECTIVE_DPI: u32 = 0;
+        let (mut dpi_x, mut dpi_y) = (0u32, 0u32);
+        let ok = unsafe {
+            let monitor = MonitorFromPoint(Point { x: x as i32, y: y as i32 }, MONITOR_DEFAULTTONEAREST);
+            GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) == 0
+        };
+        if ok && dpi_x > 0 {
+            return dpi_x as f64 / 96.0;
+        }
+        1.0
+    }
+
+    #[cfg(not(windows))]
+    {
+        // rdev reports logical coordinates here already
+        let _ = (x, y);
+        1.0
+    }
+}
+
+/// Turns physical mouse deltas into 96-DPI units, so the same hand movement
+/// gives the same remote distance whichever controller monitor it starts on.
+/// Whole units come out; the fractions carry over to the next move.
+#[derive(Debug, Default)]
+pub struct DeltaNormalizer {
+    remainder: (f64, f64),
+}
+
+impl DeltaNormalizer {
+    pub fn normalize(&mut self, dx: f64, dy: f64, scale: f64) -> (f64, f64) {
+        let scale = if scale > 0.0 { scale } else { 1.0 };
+        let x = dx / scale + self.remainder.0;
+        let y = dy / scale + self.remainder.1;
+        let whole = (x.trunc(), y.trunc());
+        self.remainder = (x - whole.0, y - whole.1);
+        whole
+    }
+}
+
+/// Screen-edge switching: follows the local cursor while capture is off and tells
+/// when it reaches an outer edge of the desktop with a device beyond it. Edges
+/// where two monitors meet don't count.
+#[derive(Debug, Default)]
+pub struct EdgeWatch {
+    // Set once the cursor was away from the watched edges, so one left against an
+    // edge (e.g. where control was just handed back) doesn't switch right away
+    armed: bool,
+}
+
+impl EdgeWatch {
+    /// The edge out of `edges` that the cursor at `pos` just reached
+    pub fn update(&mut self, pos: (i32, i32), screens: &Topology, edges: impl IntoIterator<Item = ScreenEdge>) -> Option<ScreenEdge> {
+        match edges.into_iter().find(|edge| screens.touches(*edge, pos)) {
+            None => {
+                self.armed = true;
+                None
+            }
+            Some(edge) if self.armed => {
+                self.armed = false;
+                Some(edge)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Wait for the cursor to leave the edges again, e.g. after it was put on one
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+/// Controller side: while capturing, our own keyboard LEDs show the controlled
+/// peer's lock keys, since that is where typing goes. Ours are put back after.
+#[derive(Debug, Default)]
+pub struct LockMirror {
+    // Our own lock keys from before mirroring started
+    saved: Option<LockState>,
+}
+
+impl LockMirror {
+    /// Set our lock keys to `peer`'s, or with None back to how they were
+    pub fn update(&mut self, peer: Option<LockState>, local: &dyn InputBackend) {
+        let Some(current) = local.lock_state() else {
+            return;
+        };
+        let target = match peer {
+            Some(peer) => {
+                self.saved.get_or_insert(current);
+                peer
+            }
+            None => match self.saved.take() {
+                Some(saved) => saved,
+                None => return,
+            },
+        };
+        // Injected, so a running capture keeps them here
+        for key in current.toggles_to(target) {
+            local.key_press(key, true);
+            local.key_press(key, false);
+        }
+    }
 }
 
+/// Protocol number of a mouse button; None for buttons beyond back/forward
+fn protocol_button(button: rdev::Button) -> Option<u8> {
+    match button {
+        rdev::Button::Left => Some(0),
+        rdev::Button::Right => Some(1),
+        rdev::Button::Middle => Some(2),
+        // XBUTTON1/2 on Windows, buttons 8/9 on X11
+        rdev::Button::Unknown(1 | 8) => Some(BUTTON_BACK),
+        rdev::Button::Unknown(2 | 9) => Some(BUTTON_FORWARD),
+        rdev::Button::Unknown(_) => None,
+    }
+}
+
+/// Mouse buttons (protocol numbering) currently held down on this machine
+pub fn pressed_mouse_buttons() -> Vec<u8> {
+    #[cfg(windows)]
+    {
+        // VK_LBUTTON, VK_RBUTTON, VK_MBUTTON, VK_XBUTTON1, VK_XBUTTON2
+        const BUTTONS: [(i32, u8); 5] = [(0x01, 0), (0x02, 1), (0x04, 2), (0x05, BUTTON_BACK), (0x06, BUTTON_FORWARD)];
+        BUTTONS
+            .iter()
+            .filter(|(vk, _)| unsafe { GetAsyncKeyState(*vk) } < 0)
+            .map(|(_, button)| *button)
+            .collect()
+    }
+
+    #[cfg(not(windows))]
+    {
+        Vec::new()
+    }
+}
+
+// Where the capture keeps the cursor if the monitors can't be listed
+const FALLBACK_TRAP: (i32, i32) = (500, 500);
+
+/// Where the capture keeps the cursor, deltas are measured against it: the
+/// middle of the main monitor, as far from its edges as it gets
+fn trap_point() -> (i32, i32) {
+    Topology::local().as_ref().and_then(Topology::primary).map(Monitor::center).unwrap_or(FALLBACK_TRAP)
+}
+
+/// Puts the cursor back on the trap point
+fn warp_cursor(x: i32, y: i32) {
+    #[cfg(windows)]
+    unsafe {
+        SetCursorPos(x, y);
+    }
+
+    // A warp normally freezes local mouse events for a quarter second;
+    // re-associating the mouse lifts that, or capture would stutter
+    #[cfg(target_os = "macos")]
+    unsafe {
+        CGWarpMouseCursorPosition(CGPoint { x: x as f64, y: y as f64 });
+        CGAssociateMouseAndMouseCursorPosition(1);
+    }
+
+    // Only our own evdev grab keeps a cursor of its own to put back
+    #[cfg(target_os = "linux")]
+    crate::wayland::warp(x, y);
+
+    #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+    let _ = (x, y);
+}
+
+/// rdev's grab, or our own evdev grab in a Wayland session, where rdev's needs an X display.
+/// Only the evdev grab knows which device an event came from; for devices `excluded`
+/// names it passes everything on without showing it to `callback`.
+#[cfg(feature = "capture")]
+fn grab<T, E>(callback: T, excluded: E) -> Result<(), String>
+where
+    T: Fn(Event) -> Option<Event> + 'static,
+    E: Fn(&str) -> bool + 'static,
+{
+    #[cfg(target_os = "linux")]
+    if crate::wayland::session() {
+        return crate::wayland::grab(callback, excluded).map_err(|e| e.to_string());
+    }
+    let _ = excluded;
+    rdev::grab(callback).map_err(|e| format!("{:?}", e))
+}
+
+/// Where the capture hook is in its lifecycle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HookState {
+    /// No hook, or the last one failed; the next start installs one
+    #[default]
+    Uninstalled,
+    /// Installed but passing everything through
+    Idle,
+    Capturing,
+}
+
+/// The global input hook behind capture mode.
+///
+/// rdev has no way to end a grab once it runs, so this installs one hook
+/// thread on the first start and keeps it for the life of the process.
+/// Stopping detaches the hook from its receiver, starting again attaches a
+/// new one; repeated start/stop cycles never add hooks.
 pub struct InputCapture {
-    tx: mpsc::UnboundedSender<CaptureControl>,
-    should_stop: Arc<AtomicBool>,
+    shared: Arc<CaptureShared>,
+    // Shared with the backend so changes apply to a running capture
+    exclusions: Arc<RwLock<GrabExclusions>>,
+}
+
+// What the hook thread and the backend both touch
+#[derive(Default)]
+struct CaptureShared {
+    // Receiver of the current capture; None while stopped
+    tx: Mutex<Option<mpsc::UnboundedSender<CaptureControl>>>,
+    state: Mutex<HookState>,
+    ctrl_pressed: AtomicBool,
+    alt_pressed: AtomicBool,
+    shift_pressed: AtomicBool,
+    meta_pressed: AtomicBool,
+    // Previous mouse position for delta calculation
+    last_mouse_pos: Mutex<Option<(f64, f64)>>,
+    // trap_point() as the current capture started
+    trap: Mutex<(i32, i32)>,
+    normalizer: Mutex<DeltaNormalizer>,
+}
+
+impl CaptureShared {
+    fn detach(&self) {
+        self.tx.lock().unwrap().take();
+        crate::input_simulator::track_injected(InjectionWatcher::Capture, false);
+        let mut state = self.state.lock().unwrap();
+        if *state == HookState::Capturing {
+            *state = HookState::Idle;
+        }
+    }
 }
 
 impl InputCapture {
-    pub fn new() -> (Self, mpsc::UnboundedReceiver<CaptureControl>) {
+    pub fn new(exclusions: Arc<RwLock<GrabExclusions>>) -> Self {
+        Self { shared: Arc::default(), exclusions }
+    }
+
+    pub fn state(&self) -> HookState {
+        *self.shared.state.lock().unwrap()
+    }
+
+    /// Start sending input to the returned receiver, installing the hook if
+    /// there is none yet. The receiver closes if the hook dies.
+    pub fn start_capture(&self) -> mpsc::UnboundedReceiver<CaptureControl> {
         let (tx, rx) = mpsc::unbounded_channel();
-        let should_stop = Arc::new(AtomicBool::new(false));
-        (Self { tx, should_stop }, rx)
+        let shared = &self.shared;
+
+        // Keys held when the last capture stopped were released unseen
+        for pressed in [&shared.ctrl_pressed, &shared.alt_pressed, &shared.shift_pressed, &shared.meta_pressed] {
+            pressed.store(false, Ordering::Relaxed);
+        }
+
+        // Initialize cursor to center
+        let (trap_x, trap_y) = trap_point();
+        *shared.trap.lock().unwrap() = (trap_x, trap_y);
+        warp_cursor(trap_x, trap_y);
+        *shared.last_mouse_pos.lock().unwrap() = Some((trap_x as f64, trap_y as f64));
+        *shared.normalizer.lock().unwrap() = DeltaNormalizer::default();
+
+        crate::input_simulator::track_injected(InjectionWatcher::Capture, true);
+        *shared.tx.lock().unwrap() = Some(tx);
+
+        let mut state = shared.state.lock().unwrap();
+        if *state == HookState::Uninstalled {
+            if !self.install_hook() {
+                // Closes the receiver right away
+                shared.tx.lock().unwrap().take();
+                crate::input_simulator::track_injected(InjectionWatcher::Capture, false);
+                return rx;
+            }
+        } else {
+            println!("Input capture resumed on the installed hook");
+        }
+        *state = HookState::Capturing;
+        rx
     }
 
-    pub fn start_capture(self: Arc<Self>) {
-        let tx = self.tx.clone();
-        let should_stop = Arc::clone(&self.should_stop);
-        
-        // Track modifier keys
-        let ctrl_pressed = Arc::new(AtomicBool::new(false));
-        let alt_pressed = Arc::new(AtomicBool::new(false));
-        
+    /// Stop forwarding; the hook stays installed and passes everything through
+    pub fn stop_capture(&self) {
+        self.shared.detach();
+        println!("Input capture stopped, hook idle");
+    }
+
+    #[cfg(not(feature = "capture"))]
+    fn install_hook(&self) -> bool {
+        eprintln!("❌ 此版本不含输入捕获 (构建时未启用 capture)");
+        false
+    }
+
+    #[cfg(feature = "capture")]
+    fn install_hook(&self) -> bool {
+        let shared = Arc::clone(&self.shared);
+        let exclusions = Arc::clone(&self.exclusions);
+        let device_exclusions = Arc::clone(&self.exclusions);
+
         // Spawn blocking thread for rdev grab
         std::thread::spawn(move || {
-            let ctrl_pressed_clone = Arc::clone(&ctrl_pressed);
-            let alt_pressed_clone = Arc::clone(&alt_pressed);
-            let tx_clone = tx.clone();
-            let should_stop_clone = Arc::clone(&should_stop);
-            
-            // Center position for virtual mouse trap
-            const CENTER_X: i32 = 500;
-            const CENTER_Y: i32 = 500;
-            
-            // Track previous mouse position for delta calculation
-            use std::sync::Mutex;
-            let last_mouse_pos = Arc::new(Mutex::new(Option::<(f64, f64)>::None));
-            let last_mouse_pos_clone = Arc::clone(&last_mouse_pos);
-            
-            // Initialize cursor to center
-            #[cfg(windows)]
-            unsafe {
-                SetCursorPos(CENTER_X, CENTER_Y);
-            }
-            *last_mouse_pos.lock().unwrap() = Some((CENTER_X as f64, CENTER_Y as f64));
-            
+            let hook_shared = Arc::clone(&shared);
+
             let callback = move |event: Event| -> Option<Event> {
-                // Check if we should stop
-                if should_stop_clone.load(Ordering::Relaxed) {
-                    return Some(event); // Pass through all events
+                let shared = &hook_shared;
+                // Not capturing: pass through all events
+                let Some(tx) = shared.tx.lock().unwrap().clone() else {
+                    return Some(event);
+                };
+
+                // Input we are injecting for a peer controlling us; sending it
+                // back would loop between the two machines
+                if crate::input_simulator::claim_injected(InjectionWatcher::Capture, &event.event_type) {
+                    return Some(event);
                 }
                 
                 // Track modifier keys
                 match &event.event_type {
                     EventType::KeyPress(Key::ControlLeft) | EventType::KeyPress(Key::ControlRight) => {
-                        ctrl_pressed_clone.store(true, Ordering::Relaxed);
+                        shared.ctrl_pressed.store(true, Ordering::Relaxed);
                     }
                     EventType::KeyRelease(Key::ControlLeft) | EventType::KeyRelease(Key::ControlRight) => {
-                        ctrl_pressed_clone.store(false, Ordering::Relaxed);
+                        shared.ctrl_pressed.store(false, Ordering::Relaxed);
                     }
                     EventType::KeyPress(Key::Alt) | EventType::KeyPress(Key::AltGr) => {
-                        alt_pressed_clone.store(true, Ordering::Relaxed);
+                        shared.alt_pressed.store(true, Ordering::Relaxed);
                     }
                     EventType::KeyRelease(Key::Alt) | EventType::KeyRelease(Key::AltGr) => {
-                        alt_pressed_clone.store(false, Ordering::Relaxed);
+                        shared.alt_pressed.store(false, Ordering::Relaxed);
+                    }
+                    EventType::KeyPress(Key::ShiftLeft) | EventType::KeyPress(Key::ShiftRight) => {
+                        shared.shift_pressed.store(true, Ordering::Relaxed);
+                    }
+                    EventType::KeyRelease(Key::ShiftLeft) | EventType::KeyRelease(Key::ShiftRight) => {
+                        shared.shift_pressed.store(false, Ordering::Relaxed);
+                    }
+                    EventType::KeyPress(Key::MetaLeft) | EventType::KeyPress(Key::MetaRight) => {
+                        shared.meta_pressed.store(true, Ordering::Relaxed);
+                    }
+                    EventType::KeyRelease(Key::MetaLeft) | EventType::KeyRelease(Key::MetaRight) => {
+                        shared.meta_pressed.store(false, Ordering::Relaxed);
                     }
                     EventType::KeyPress(Key::KeyQ) => {
-                        if ctrl_pressed_clone.load(Ordering::Relaxed) && alt_pressed_clone.load(Ordering::Relaxed) {
+                        if shared.ctrl_pressed.load(Ordering::Relaxed) && shared.alt_pressed.load(Ordering::Relaxed) {
                             println!("Exit shortcut detected (Ctrl+Alt+Q) - stopping capture");
-                            let _ = tx_clone.send(CaptureControl::ExitRequested);
-                            should_stop_clone.store(true, Ordering::Relaxed);
+                            let _ = tx.send(CaptureControl::ExitRequested);
+                            shared.detach();
                             return Some(event); // Pass through the Q key
                         }
                     }
+                    EventType::KeyPress(key) | EventType::KeyRelease(key)
+                        if shared.ctrl_pressed.load(Ordering::Relaxed) && shared.alt_pressed.load(Ordering::Relaxed) =>
+                    {
+                        // Switching keys go neither to the old nor the new target
+                        if let Some(slot) = slot_key(*key) {
+                            if matches!(event.event_type, EventType::KeyPress(_)) {
+                                println!("Slot shortcut detected (Ctrl+Alt+{})", slot);
+                                let _ = tx.send(CaptureControl::SwitchSlot(slot));
+                            }
+                            return None;
+                        }
+                    }
                     _ => {}
                 }
                 
+                // Accessibility exclusions stay on this machine and are not forwarded
+                if let EventType::KeyPress(key) | EventType::KeyRelease(key) = event.event_type {
+                    if exclusions.read().unwrap().passes(key) {
+                        return Some(event);
+                    }
+                }
+                
+                let modifiers = Modifiers {
+                    shift: shared.shift_pressed.load(Ordering::Relaxed),
+                    ctrl: shared.ctrl_pressed.load(Ordering::Relaxed),
+                    alt: shared.alt_pressed.load(Ordering::Relaxed),
+                    meta: shared.meta_pressed.load(Ordering::Relaxed),
+                };
+                
+                let captured_at = Instant::now();
+                let _span = tracing::trace_span!("capture").entered();
+
                 // Convert event to our format and decide whether to block
                 let (input_event, should_block) = match event.event_type {
                     EventType::MouseMove { x, y } => {
-                        let mut last_pos = last_mouse_pos_clone.lock().unwrap();
+                        let mut last_pos = shared.last_mouse_pos.lock().unwrap();
                         
                         if let Some((prev_x, prev_y)) = *last_pos {
                             // Calculate delta relative to PREVIOUS position
@@ -112,24 +705,31 @@ impl InputCapture {
                             
                             // Only process if there's actual movement
                             if dx != 0.0 || dy != 0.0 {
+                                // In the units of the monitor the move started on
+                                let scale = monitor_scale(prev_x, prev_y);
+                                let (dx, dy) = shared.normalizer.lock().unwrap().normalize(dx, dy, scale);
+                                
                                 // Reset cursor to center to prevent hitting screen edges
-                                #[cfg(windows)]
-                                unsafe {
-                                    SetCursorPos(CENTER_X, CENTER_Y);
-                                }
+                                let (trap_x, trap_y) = *shared.trap.lock().unwrap();
+                                warp_cursor(trap_x, trap_y);
                                 
                                 // Update last_pos to CENTER (where we just moved the cursor)
                                 // The next event will be relative to this center
-                                *last_pos = Some((CENTER_X as f64, CENTER_Y as f64));
+                                *last_pos = Some((trap_x as f64, trap_y as f64));
                                 
-                                (Some(InputEventData {
+                                // Less than a unit so far; the rest comes with the next move
+                                let moved = dx != 0.0 || dy != 0.0;
+                                (moved.then(|| InputEventData {
                                     event_type: "mousemove".to_string(),
                                     key: None,
                                     key_code: None,
+                                    button: None,
+                                    modifiers,
                                     x: None,
                                     y: None,
                                     dx: Some(dx),
                                     dy: Some(dy),
+                                    captured_at,
                                 }), true) // BLOCK mouse move (keep cursor centered)
                             } else {
                                 (None, true) // Block even if no movement (keep centered)
@@ -146,10 +746,13 @@ impl InputCapture {
                             event_type: "keydown".to_string(),
                             key: Some(key_str),
                             key_code: Some(rdev_key_to_code(key)),
+                            button: None,
+                            modifiers,
                             x: None,
                             y: None,
                             dx: None,
                             dy: None,
+                            captured_at,
                         }), true) // Block keyboard events
                     }
                     EventType::KeyRelease(key) => {
@@ -158,64 +761,42 @@ impl InputCapture {
                             event_type: "keyup".to_string(),
                             key: Some(key_str),
                             key_code: Some(rdev_key_to_code(key)),
+                            button: None,
+                            modifiers,
                             x: None,
                             y: None,
                             dx: None,
                             dy: None,
+                            captured_at,
                         }), true) // Block keyboard events
                     }
-                    EventType::ButtonPress(button) => {
-                        let button_name = match button {
-                            rdev::Button::Left => "button0",
-                            rdev::Button::Right => "button1",
-                            rdev::Button::Middle => "button2",
-                            _ => "button0",
-                        };
-                        
-                        (Some(InputEventData {
-                            event_type: "mousedown".to_string(),
-                            key: Some(button_name.to_string()),
-                            key_code: None,
-                            x: None,
-                            y: None,
-                            dx: None,
-                            dy: None,
-                        }), true) // Block mouse clicks
-                    }
-                    EventType::ButtonRelease(button) => {
-                        let button_name = match button {
-                            rdev::Button::Left => "button0",
-                            rdev::Button::Right => "button1",
-                            rdev::Button::Middle => "button2",
-                            _ => "button0",
-                        };
-                        
-                        (Some(InputEventData {
-                            event_type: "mouseup".to_string(),
-                            key: Some(button_name.to_string()),
-                            key_code: None,
-                            x: None,
-                            y: None,
-                            dx: None,
-                            dy: None,
-                        }), true) // Block mouse clicks
+                    EventType::ButtonPress(button) | EventType::ButtonRelease(button) => {
+                        // Extra buttons beyond back/forward have no number on the wire; swallow them
+                        let down = matches!(event.event_type, EventType::ButtonPress(_));
+                        let clicked = InputEventData::from_button(button, down, modifiers, captured_at)?;
+                        (Some(clicked), true) // Block mouse clicks
                     }
                     EventType::Wheel { delta_x, delta_y } => {
+                        // rdev reports whole notches here; main.rs scales to WHEEL_DELTA units
                         (Some(InputEventData {
                             event_type: "wheel".to_string(),
                             key: None,
                             key_code: None,
+                            button: None,
+                            modifiers,
                             x: None,
                             y: None,
                             dx: Some(delta_x as f64),
                             dy: Some(delta_y as f64),
+                            captured_at,
                         }), true) // Block wheel events
                     }
                 };
 
                 if let Some(evt) = input_event {
-                    if let Err(e) = tx_clone.send(CaptureControl::InputEvent(evt)) {
-                        eprintln!("[Capture] 发送事件失败: {:?}", e);
+                    if tx.send(CaptureControl::InputEvent(evt)).is_err() {
+                        // The event itself stays out of the log
+                        eprintln!("[Capture] 发送事件失败: 接收端已关闭");
                     }
                 }
                 
@@ -232,7 +813,8 @@ impl InputCapture {
             println!("Press Ctrl+Alt+Q to exit capture mode");
             println!("========================================\n");
             
-            match grab(callback) {
+            let excluded = move |name: &str| device_exclusions.read().unwrap().passes_device(name);
+            match grab(callback, excluded) {
                 Ok(_) => {
                     println!("Input capture ended normally");
                 }
@@ -241,17 +823,212 @@ impl InputCapture {
                     eprintln!("提示: 请确保程序以管理员身份运行！");
                 }
             }
+            // Closing the receiver tells the backend; the next start tries again
+            shared.detach();
+            *shared.state.lock().unwrap() = HookState::Uninstalled;
         });
+        true
     }
+}
 
-    pub fn stop_capture(&self) {
-        self.should_stop.store(true, Ordering::Relaxed);
-        println!("Input capture stop requested");
+/// Blocks this machine's own keyboard and mouse while it is being controlled.
+/// Injected events still pass, and Ctrl+Alt+Q always gives control back.
+pub struct LocalInputLock {
+    active: Arc<AtomicBool>,
+    started: AtomicBool,
+    unlock_tx: mpsc::UnboundedSender<()>,
+}
+
+impl LocalInputLock {
+    /// The receiver fires when the user breaks the lock with the emergency hotkey
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<()>) {
+        let (unlock_tx, unlock_rx) = mpsc::unbounded_channel();
+        let lock = Self {
+            active: Arc::new(AtomicBool::new(false)),
+            started: AtomicBool::new(false),
+            unlock_tx,
+        };
+        (lock, unlock_rx)
+    }
+
+    pub fn activate(&self) {
+        if !self.ensure_started() {
+            return;
+        }
+        crate::input_simulator::track_injected(InjectionWatcher::LocalLock, true);
+        self.active.store(true, Ordering::SeqCst);
+        println!("[被控端] 本地输入已暂停 (Ctrl+Alt+Q 恢复)");
+    }
+
+    pub fn deactivate(&self) {
+        if self.active.swap(false, Ordering::SeqCst) {
+            crate::input_simulator::track_injected(InjectionWatcher::LocalLock, false);
+            println!("[被控端] 本地输入已恢复");
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
     }
+
+    #[cfg(not(feature = "capture"))]
+    fn ensure_started(&self) -> bool {
+        if !self.started.swap(true, Ordering::SeqCst) {
+            eprintln!("⚠ 此版本不含输入钩子，无法暂停本地输入");
+        }
+        false
+    }
+
+    // The hook is installed on first use and then just toggled
+    #[cfg(feature = "capture")]
+    fn ensure_started(&self) -> bool {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+        let active = Arc::clone(&self.active);
+        let unlock_tx = self.unlock_tx.clone();
+
+        std::thread::spawn(move || {
+            let ctrl_pressed = AtomicBool::new(false);
+            let alt_pressed = AtomicBool::new(false);
+
+            let callback = move |event: Event| -> Option<Event> {
+                if !active.load(Ordering::SeqCst) {
+                    return Some(event);
+                }
+
+                match event.event_type {
+                    EventType::KeyPress(Key::ControlLeft) | EventType::KeyPress(Key::ControlRight) => {
+                        ctrl_pressed.store(true, Ordering::Relaxed);
+                    }
+                    EventType::KeyRelease(Key::ControlLeft) | EventType::KeyRelease(Key::ControlRight) => {
+                        ctrl_pressed.store(false, Ordering::Relaxed);
+                    }
+                    EventType::KeyPress(Key::Alt) | EventType::KeyPress(Key::AltGr) => {
+                        alt_pressed.store(true, Ordering::Relaxed);
+                    }
+                    EventType::KeyRelease(Key::Alt) | EventType::KeyRelease(Key::AltGr) => {
+                        alt_pressed.store(false, Ordering::Relaxed);
+                    }
+                    EventType::KeyPress(Key::KeyQ)
+                        if ctrl_pressed.load(Ordering::Relaxed) && alt_pressed.load(Ordering::Relaxed) =>
+                    {
+                        println!("Emergency shortcut detected (Ctrl+Alt+Q) - releasing local input");
+                        active.store(false, Ordering::SeqCst);
+                        crate::input_simulator::track_injected(InjectionWatcher::LocalLock, false);
+                        let _ = unlock_tx.send(());
+                        return Some(event);
+                    }
+                    _ => {}
+                }
+
+                if crate::input_simulator::claim_injected(InjectionWatcher::LocalLock, &event.event_type) {
+                    Some(event)
+                } else {
+                    None // Physical input while being controlled
+                }
+            };
+
+            // Every physical device is locked, excluded ones included
+            if let Err(error) = grab(callback, |_: &str| false) {
+                eprintln!("❌ Local input lock error: {:?}", error);
+            }
+        });
+        true
+    }
+}
+
+/// Global hotkeys without the frontend: `start` (normally Ctrl+Alt+S) starts capture,
+/// Ctrl+Alt+<digit> switches to the device in that slot.
+/// Only listens, so the keys still reach whatever application has focus.
+#[cfg(feature = "capture")]
+pub fn spawn_hotkeys(start: Shortcut) -> mpsc::UnboundedReceiver<Hotkey> {
+    let (hotkey_tx, hotkey_rx) = mpsc::unbounded_channel();
+
+    std::thread::spawn(move || {
+        let ctrl_pressed = AtomicBool::new(false);
+        let alt_pressed = AtomicBool::new(false);
+        let shift_pressed = AtomicBool::new(false);
+        let meta_pressed = AtomicBool::new(false);
+
+        let callback = move |event: Event| {
+            let held = || Modifiers {
+                shift: shift_pressed.load(Ordering::Relaxed),
+                ctrl: ctrl_pressed.load(Ordering::Relaxed),
+                alt: alt_pressed.load(Ordering::Relaxed),
+                meta: meta_pressed.load(Ordering::Relaxed),
+            };
+            match event.event_type {
+                EventType::KeyPress(Key::ControlLeft) | EventType::KeyPress(Key::ControlRight) => {
+                    ctrl_pressed.store(true, Ordering::Relaxed);
+                }
+                EventType::KeyRelease(Key::ControlLeft) | EventType::KeyRelease(Key::ControlRight) => {
+                    ctrl_pressed.store(false, Ordering::Relaxed);
+                }
+                EventType::KeyPress(Key::Alt) | EventType::KeyPress(Key::AltGr) => {
+                    alt_pressed.store(true, Ordering::Relaxed);
+                }
+                EventType::KeyRelease(Key::Alt) | EventType::KeyRelease(Key::AltGr) => {
+                    alt_pressed.store(false, Ordering::Relaxed);
+                }
+                EventType::KeyPress(Key::ShiftLeft) | EventType::KeyPress(Key::ShiftRight) => {
+                    shift_pressed.store(true, Ordering::Relaxed);
+                }
+                EventType::KeyRelease(Key::ShiftLeft) | EventType::KeyRelease(Key::ShiftRight) => {
+                    shift_pressed.store(false, Ordering::Relaxed);
+                }
+                EventType::KeyPress(Key::MetaLeft) | EventType::KeyPress(Key::MetaRight) => {
+                    meta_pressed.store(true, Ordering::Relaxed);
+                }
+                EventType::KeyRelease(Key::MetaLeft) | EventType::KeyRelease(Key::MetaRight) => {
+                    meta_pressed.store(false, Ordering::Relaxed);
+                }
+                EventType::KeyPress(key) if key == start.key && held() == start.modifiers => {
+                    println!("Start shortcut detected ({})", start);
+                    let _ = hotkey_tx.send(Hotkey::StartCapture);
+                }
+                EventType::KeyPress(key)
+                    if ctrl_pressed.load(Ordering::Relaxed) && alt_pressed.load(Ordering::Relaxed) =>
+                {
+                    if let Some(slot) = slot_key(key) {
+                        println!("Slot shortcut detected (Ctrl+Alt+{})", slot);
+                        let _ = hotkey_tx.send(Hotkey::Slot(slot));
+                    }
+                }
+                _ => {}
+            }
+        };
+
+        if let Err(error) = listen(callback) {
+            eprintln!("❌ Hotkey listener error: {:?}", error);
+        }
+    });
+
+    hotkey_rx
 }
 
 // Helper function to map rdev Key to u32 code
-fn rdev_key_to_code(key: Key) -> u32 {
+/// Keys with no free VK/ASCII code. They are numbered from `EXTENDED_KEY_BASE`
+/// in this order, so only append to the list.
+pub const EXTENDED_KEYS: [Key; 36] = [
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6,
+    Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::Home, Key::End, Key::Insert, Key::Delete, Key::PrintScreen,
+    Key::Kp0, Key::Kp1, Key::Kp2, Key::Kp3, Key::Kp4,
+    Key::Kp5, Key::Kp6, Key::Kp7, Key::Kp8, Key::Kp9,
+    Key::KpReturn, Key::KpMinus, Key::KpPlus, Key::KpMultiply, Key::KpDivide, Key::KpDelete,
+    Key::IntlBackslash,
+    // VK_PRIOR/VK_NEXT (33/34) are '!' and '"' here
+    Key::PageUp, Key::PageDown,
+];
+
+/// First code of the extended range; above any Unicode scalar, so it never
+/// clashes with codes derived from characters sent by the frontend
+pub const EXTENDED_KEY_BASE: u32 = 0x110000;
+
+/// Code sent in `Message::KeyPress`; `input_simulator::map_key_code` is the inverse.
+/// Returns 0 for keys we can't transmit.
+pub fn rdev_key_to_code(key: Key) -> u32 {
     match key {
         // Letters
         Key::KeyA => 65, Key::KeyB => 66, Key::KeyC => 67, Key::KeyD => 68,
@@ -273,24 +1050,24 @@ fn rdev_key_to_code(key: Key) -> u32 {
         Key::Space => 32,
         Key::Backspace => 8,
         Key::Tab => 9,
+        Key::Pause => 19,
+        Key::CapsLock => 20,
+        Key::NumLock => 144,
+        Key::ScrollLock => 145,
         
         // Punctuation
         Key::Minus => 45,
         Key::Equal => 61,
-        Key::LeftBracket => 91,
+        Key::LeftBracket => 219, // VK_OEM_4; 91 is MetaLeft
         Key::RightBracket => 93,
-        Key::BackSlash => 92,
+        Key::BackSlash => 220, // VK_OEM_5; 92 is MetaRight
         Key::SemiColon => 59,
-        Key::Quote => 39,
+        Key::Quote => 222, // VK_OEM_7; 39 is RightArrow
         Key::Comma => 44,
         Key::Dot => 46,
         Key::Slash => 47,
         Key::BackQuote => 96,
 
-        // Function Keys (Mapped to custom range or standard VK codes if needed)
-        // For now, we map them to 0 or specific codes if the simulator supports them
-        // Adding F1-F12 support would require updating simulator as well
-        
         // Modifiers
         Key::ShiftLeft => 160,
         Key::ShiftRight => 161,
@@ -306,6 +1083,10 @@ fn rdev_key_to_code(key: Key) -> u32 {
         Key::LeftArrow => 37,
         Key::RightArrow => 39,
 
-        _ => 0,
+        // Function keys, Home/End, keypad...
+        other => EXTENDED_KEYS
+            .iter()
+            .position(|k| *k == other)
+            .map_or(0, |i| EXTENDED_KEY_BASE + i as u32),
     }
 }
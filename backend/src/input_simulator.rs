@@ -1,14 +1,291 @@
-use rdev::{simulate, EventType, Key, Button};
+use crate::input_capture::{EXTENDED_KEYS, EXTENDED_KEY_BASE};
+use crate::protocol::{LockState, MediaAction, TargetUnavailable};
+use crate::screens::Topology;
+use crate::screenshot::Screenshot;
+#[cfg(feature = "inject")]
+use crate::protocol::{BUTTON_BACK, BUTTON_FORWARD};
+#[cfg(feature = "inject")]
+use rdev::Button;
+use rdev::{EventType, Key};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 #[cfg(not(windows))]
 use rdev::Button;
 
+#[cfg(feature = "inject")]
 pub struct InputSimulator;
 
+/// dwExtraInfo of the events we inject with SendInput, for hooks that can
+/// read it. rdev doesn't pass it on, so our own hooks go by the counts below.
+#[cfg(feature = "inject")]
+pub const INJECTED_SIGNATURE: usize = 0x5346_4C57; // "SFLW"
+
+/// A hook on this machine that has to tell our injected events from physical input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectionWatcher {
+    /// LocalInputLock lets injected events through and blocks the rest
+    LocalLock = 0,
+    /// InputCapture keeps injected events here instead of sending them back
+    Capture = 1,
+}
+
+#[derive(Clone, Copy)]
+enum Injected {
+    Mouse = 0,
+    Key = 1,
+}
+
+// Injected events each watching hook hasn't seen yet, by watcher and kind.
+// Every hook sees every event, so each needs its own count.
+static WATCHING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static PENDING: [[AtomicUsize; 2]; 2] = [
+    [AtomicUsize::new(0), AtomicUsize::new(0)],
+    [AtomicUsize::new(0), AtomicUsize::new(0)],
+];
+
+/// Start or stop counting injected events for `watcher` (only needed while its hook runs)
+pub fn track_injected(watcher: InjectionWatcher, enabled: bool) {
+    for pending in &PENDING[watcher as usize] {
+        pending.store(0, Ordering::SeqCst);
+    }
+    WATCHING[watcher as usize].store(enabled, Ordering::SeqCst);
+}
+
+#[cfg(feature = "inject")]
+fn note_injected(kind: Injected) {
+    // uinput devices are never grabbed, so no hook sees what goes through them
+    #[cfg(target_os = "linux")]
+    if crate::wayland::injector().is_some() {
+        return;
+    }
+    for (watching, pending) in WATCHING.iter().zip(&PENDING) {
+        if watching.load(Ordering::SeqCst) {
+            pending[kind as usize].fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn claim(watcher: InjectionWatcher, kind: Injected) -> bool {
+    PENDING[watcher as usize][kind as usize]
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+/// Whether an event seen by `watcher`'s hook is one we injected
+pub fn claim_injected(watcher: InjectionWatcher, event_type: &EventType) -> bool {
+    match event_type {
+        EventType::KeyPress(_) | EventType::KeyRelease(_) => claim(watcher, Injected::Key),
+        _ => claim(watcher, Injected::Mouse),
+    }
+}
+
+/// Re-creates the controller's spacing between button events, so clicks
+/// bunched together by the network still register as a double-click
+pub struct ClickPacer {
+    last_click: Option<Instant>,
+}
+
+impl Default for ClickPacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClickPacer {
+    // Longer gaps can't form a double-click, no point in waiting for them
+    const MAX_PACED_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new() -> Self {
+        Self { last_click: None }
+    }
+
+    /// How long to wait before injecting a button event that happened
+    /// `elapsed_ms` after the previous one on the controller
+    pub fn delay(&mut self, elapsed_ms: u32) -> Option<Duration> {
+        let now = Instant::now();
+        let interval = Duration::from_millis(elapsed_ms as u64);
+        let wait = match self.last_click {
+            Some(last) if elapsed_ms > 0 && interval <= Self::MAX_PACED_INTERVAL => {
+                (last + interval).checked_duration_since(now)
+            }
+            _ => None,
+        };
+        self.last_click = Some(now + wait.unwrap_or_default());
+        wait.filter(|d| !d.is_zero())
+    }
+}
+
+/// Adds up sub-notch wheel deltas, which trackpads send many of, into whole
+/// notches for backends that can only scroll by notch; truncating each one
+/// would drop slow scrolling altogether
+#[derive(Default)]
+pub struct WheelAccumulator {
+    remainder: (i32, i32),
+    last: Option<Instant>,
+}
+
+impl WheelAccumulator {
+    // A new gesture starts from zero, or a partial notch left from the last one makes it jump
+    const RESET_AFTER: Duration = Duration::from_millis(500);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The whole notches (in WHEEL_DELTA units) that are due after adding this delta
+    pub fn add(&mut self, delta_x: i32, delta_y: i32) -> (i32, i32) {
+        let now = Instant::now();
+        if self.last.is_some_and(|last| now.duration_since(last) > Self::RESET_AFTER) {
+            self.remainder = (0, 0);
+        }
+        self.last = Some(now);
+        (Self::axis(&mut self.remainder.0, delta_x), Self::axis(&mut self.remainder.1, delta_y))
+    }
+
+    fn axis(remainder: &mut i32, delta: i32) -> i32 {
+        use crate::protocol::WHEEL_DELTA;
+        // Reversing drops what was collected in the other direction
+        if remainder.signum() * delta.signum() < 0 {
+            *remainder = 0;
+        }
+        *remainder += delta;
+        let whole = *remainder / WHEEL_DELTA * WHEEL_DELTA;
+        *remainder -= whole;
+        whole
+    }
+}
+
+/// Where injected input goes: the OS via InputSimulator, or a recorder in tests
+pub trait InputBackend: Send + Sync {
+    fn mouse_move(&self, dx: i32, dy: i32);
+    fn mouse_click(&self, button: u8, state: bool);
+    /// Deltas in WHEEL_DELTA units (120 = one notch)
+    fn mouse_wheel(&self, delta_x: i32, delta_y: i32);
+    fn key_press(&self, key_code: u32, is_down: bool);
+    fn cursor_position(&self) -> Option<(i32, i32)> {
+        None
+    }
+    fn screen_size(&self) -> Option<(u32, u32)> {
+        None
+    }
+    /// All monitors; the main display alone where they can't be listed
+    fn screens(&self) -> Option<Topology> {
+        self.screen_size().map(Topology::single)
+    }
+    /// Put the cursor at an absolute position on the virtual desktop
+    fn move_to(&self, _x: i32, _y: i32) {}
+    /// Press and release a volume or playback key
+    fn media(&self, _action: MediaAction) {}
+    /// Scrolls by fractions of a notch, so wheel deltas needn't be accumulated first
+    fn fine_wheel(&self) -> bool {
+        false
+    }
+    /// Why injected input wouldn't reach the screen in front of the machine, None if it would
+    fn target_unavailable(&self) -> Option<TargetUnavailable> {
+        None
+    }
+    /// Lock keys as the keyboard LEDs show them, None where they can't be read
+    fn lock_state(&self) -> Option<LockState> {
+        None
+    }
+    /// The desktop scaled down for a peer, None where it can't be read
+    fn screenshot(&self) -> Option<Screenshot> {
+        None
+    }
+    /// Ring the cursor for a moment; returns at once
+    fn highlight(&self) {}
+}
+
+#[cfg(feature = "inject")]
+impl InputBackend for InputSimulator {
+    fn mouse_move(&self, dx: i32, dy: i32) {
+        InputSimulator::mouse_move(self, dx, dy)
+    }
+
+    fn mouse_click(&self, button: u8, state: bool) {
+        InputSimulator::mouse_click(self, button, state)
+    }
+
+    fn mouse_wheel(&self, delta_x: i32, delta_y: i32) {
+        InputSimulator::mouse_wheel(self, delta_x, delta_y)
+    }
+
+    fn key_press(&self, key_code: u32, is_down: bool) {
+        InputSimulator::key_press(self, key_code, is_down)
+    }
+
+    fn cursor_position(&self) -> Option<(i32, i32)> {
+        InputSimulator::cursor_position(self)
+    }
+
+    fn screen_size(&self) -> Option<(u32, u32)> {
+        InputSimulator::screen_size(self)
+    }
+
+    fn screens(&self) -> Option<Topology> {
+        Topology::local().or_else(|| self.screen_size().map(Topology::single))
+    }
+
+    fn move_to(&self, x: i32, y: i32) {
+        InputSimulator::move_to(self, x, y)
+    }
+
+    fn media(&self, action: MediaAction) {
+        InputSimulator::media(self, action)
+    }
+
+    fn target_unavailable(&self) -> Option<TargetUnavailable> {
+        InputSimulator::target_unavailable(self)
+    }
+
+    fn lock_state(&self) -> Option<LockState> {
+        InputSimulator::lock_state(self)
+    }
+
+    fn screenshot(&self) -> Option<Screenshot> {
+        crate::screenshot::capture()
+    }
+
+    fn highlight(&self) {
+        if let Some((x, y)) = self.cursor_position() {
+            crate::highlight::show(x, y);
+        }
+    }
+
+    fn fine_wheel(&self) -> bool {
+        // SendInput takes any multiple of 1/120 notch; rdev elsewhere only whole notches
+        cfg!(windows)
+    }
+}
+
+/// Stands in for InputSimulator in builds without the inject feature:
+/// this machine only controls others, so nothing is ever injected here
+#[cfg(not(feature = "inject"))]
+pub struct NoInjection;
+
+#[cfg(not(feature = "inject"))]
+impl InputBackend for NoInjection {
+    fn mouse_move(&self, _dx: i32, _dy: i32) {}
+    fn mouse_click(&self, _button: u8, _state: bool) {}
+    fn mouse_wheel(&self, _delta_x: i32, _delta_y: i32) {}
+    fn key_press(&self, _key_code: u32, _is_down: bool) {}
+}
+
 // InputSimulator 是无状态的，可以安全地在多线程中使用
+#[cfg(feature = "inject")]
 unsafe impl Send for InputSimulator {}
+#[cfg(feature = "inject")]
 unsafe impl Sync for InputSimulator {}
 
+#[cfg(feature = "inject")]
+impl Default for InputSimulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "inject")]
 impl InputSimulator {
     pub fn new() -> Self {
         Self
@@ -61,32 +338,204 @@ impl InputSimulator {
                             mouse_data: 0,
                             dw_flags: MOUSEEVENTF_MOVE,
                             time: 0,
-                            dw_extra_info: 0,
+                            dw_extra_info: INJECTED_SIGNATURE,
                         },
                     },
                 };
                 
+                note_injected(Injected::Mouse);
                 SendInput(1, &input, mem::size_of::<INPUT>() as i32);
             }
         }
         
+        // rdev only moves to absolute positions; XTest can move relative to the pointer
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(device) = crate::wayland::injector() {
+                let _ = device.move_relative(dx, dy);
+                return;
+            }
+            note_injected(Injected::Mouse);
+            xtest::move_relative(dx, dy);
+        }
+
+        // Same for CGEventPost, but a posted event can carry the deltas games read
+        #[cfg(target_os = "macos")]
+        {
+            note_injected(Injected::Mouse);
+            quartz::move_relative(dx, dy);
+        }
+    }
+
+    /// Current cursor position, if the platform lets us read it
+    pub fn cursor_position(&self) -> Option<(i32, i32)> {
+        #[cfg(windows)]
+        {
+            #[repr(C)]
+            struct POINT {
+                x: i32,
+                y: i32,
+            }
+            
+            extern "system" {
+                fn GetCursorPos(point: *mut POINT) -> i32;
+            }
+            
+            let mut point = POINT { x: 0, y: 0 };
+            if unsafe { GetCursorPos(&mut point) } != 0 {
+                Some((point.x, point.y))
+            } else {
+                None
+            }
+        }
+
+        // XWayland only knows where the pointer was over its own windows
+        #[cfg(target_os = "linux")]
+        {
+            if crate::wayland::injector().is_some() {
+                return None;
+            }
+            xtest::pointer_position()
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            quartz::pointer_position()
+        }
+
+        #[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+        {
+            None
+        }
+    }
+
+    /// Absolute cursor placement, e.g. to wrap around a screen edge
+    pub fn move_to(&self, x: i32, y: i32) {
+        #[cfg(windows)]
+        {
+            extern "system" {
+                fn SetCursorPos(x: i32, y: i32) -> i32;
+            }
+            unsafe {
+                SetCursorPos(x, y);
+            }
+        }
+
         #[cfg(not(windows))]
         {
-            // Non-Windows systems use rdev (requires absolute coordinate conversion if needed, but relative is tricky with rdev)
-            // For now, we might skip or implement basic relative move if rdev supports it (it doesn't natively support relative move easily without current pos)
+            note_injected(Injected::Mouse);
+            let _ = simulate(&EventType::MouseMove { x: x as f64, y: y as f64 });
         }
     }
 
+    /// Lock key toggles, read like the LEDs show them
+    pub fn lock_state(&self) -> Option<LockState> {
+        #[cfg(windows)]
+        {
+            extern "system" {
+                fn GetKeyState(v_key: i32) -> i16;
+            }
+            // The low bit is the toggle
+            let on = |vk: i32| unsafe { GetKeyState(vk) } & 1 != 0;
+            Some(LockState { caps: on(0x14), num: on(0x90), scroll: on(0x91) })
+        }
+
+        #[cfg(not(windows))]
+        {
+            None
+        }
+    }
+
+    /// SendInput goes to the desktop of our own session. Under RDP, or once
+    /// that session is no longer the one on the console, that is not the
+    /// screen anyone at the machine is looking at.
+    pub fn target_unavailable(&self) -> Option<TargetUnavailable> {
+        #[cfg(windows)]
+        {
+            const SM_REMOTESESSION: i32 = 0x1000;
+            const WTS_CURRENT_SERVER_HANDLE: isize = 0;
+            const WTS_CONNECT_STATE: u32 = 8;
+            const WTS_ACTIVE: i32 = 0;
+            const NO_CONSOLE_SESSION: u32 = 0xFFFF_FFFF;
+
+            extern "system" {
+                fn GetSystemMetrics(index: i32) -> i32;
+                fn GetCurrentProcessId() -> u32;
+                fn ProcessIdToSessionId(process_id: u32, session_id: *mut u32) -> i32;
+                fn WTSGetActiveConsoleSessionId() -> u32;
+            }
+            #[link(name = "wtsapi32")]
+            extern "system" {
+                fn WTSQuerySessionInformationW(
+                    server: isize,
+                    session_id: u32,
+                    info_class: u32,
+                    buffer: *mut *mut i32,
+                    bytes: *mut u32,
+                ) -> i32;
+                fn WTSFreeMemory(memory: *mut i32);
+            }
+
+            unsafe {
+                if GetSystemMetrics(SM_REMOTESESSION) != 0 {
+                    return Some(TargetUnavailable::RemoteSession);
+                }
+                let mut session = 0;
+                if ProcessIdToSessionId(GetCurrentProcessId(), &mut session) == 0 {
+                    return None;
+                }
+                let mut state: *mut i32 = std::ptr::null_mut();
+                let mut bytes = 0;
+                if WTSQuerySessionInformationW(WTS_CURRENT_SERVER_HANDLE, session, WTS_CONNECT_STATE, &mut state, &mut bytes) != 0
+                    && !state.is_null()
+                {
+                    let active = *state == WTS_ACTIVE;
+                    WTSFreeMemory(state);
+                    if !active {
+                        return Some(TargetUnavailable::ConsoleDisconnected);
+                    }
+                }
+                // Active but not on the console can only be a remote session the metric missed
+                let console = WTSGetActiveConsoleSessionId();
+                if console != NO_CONSOLE_SESSION && console != session {
+                    return Some(TargetUnavailable::RemoteSession);
+                }
+                None
+            }
+        }
+
+        #[cfg(not(windows))]
+        {
+            None
+        }
+    }
+
+    /// Size of the main display
+    pub fn screen_size(&self) -> Option<(u32, u32)> {
+        rdev::display_size()
+            .ok()
+            .map(|(width, height)| (width as u32, height as u32))
+    }
+
     pub fn mouse_click(&self, button: u8, state: bool) {
         let btn = match button {
+            0 => Button::Left,
             1 => Button::Right,
             2 => Button::Middle,
-            _ => Button::Left,
+            // XBUTTON1/2 on Windows, buttons 8/9 on X11; rdev can't send them on macOS
+            BUTTON_BACK if cfg!(windows) => Button::Unknown(1),
+            BUTTON_FORWARD if cfg!(windows) => Button::Unknown(2),
+            BUTTON_BACK => Button::Unknown(8),
+            BUTTON_FORWARD => Button::Unknown(9),
+            // From a newer peer; better nothing than the wrong button
+            _ => return,
         };
         let event_type = if state { EventType::ButtonPress(btn) } else { EventType::ButtonRelease(btn) };
+        note_injected(Injected::Mouse);
         let _ = simulate(&event_type);
     }
 
+    /// Scroll by deltas in WHEEL_DELTA units (120 = one notch)
     pub fn mouse_wheel(&self, delta_x: i32, delta_y: i32) {
         #[cfg(windows)]
         {
@@ -132,13 +581,14 @@ impl InputSimulator {
                             mi: MOUSEINPUT {
                                 dx: 0,
                                 dy: 0,
-                                mouse_data: (delta_y * 120) as u32, // Windows expects multiples of 120
+                                mouse_data: delta_y as u32, // Fractions of WHEEL_DELTA give smooth scrolling
                                 dw_flags: MOUSEEVENTF_WHEEL,
                                 time: 0,
-                                dw_extra_info: 0,
+                                dw_extra_info: INJECTED_SIGNATURE,
                             },
                         },
                     };
+                    note_injected(Injected::Mouse);
                     SendInput(1, &input, mem::size_of::<INPUT>() as i32);
                 }
                 
@@ -150,13 +600,14 @@ impl InputSimulator {
                             mi: MOUSEINPUT {
                                 dx: 0,
                                 dy: 0,
-                                mouse_data: (delta_x * 120) as u32,
+                                mouse_data: delta_x as u32,
                                 dw_flags: MOUSEEVENTF_HWHEEL,
                                 time: 0,
-                                dw_extra_info: 0,
+                                dw_extra_info: INJECTED_SIGNATURE,
                             },
                         },
                     };
+                    note_injected(Injected::Mouse);
                     SendInput(1, &input, mem::size_of::<INPUT>() as i32);
                 }
             }
@@ -164,18 +615,37 @@ impl InputSimulator {
         
         #[cfg(not(windows))]
         {
-            // rdev simulation for wheel
-            let event_type = EventType::Wheel { 
-                delta_x: delta_x as i64, 
-                delta_y: delta_y as i64 
-            };
+            use crate::protocol::WHEEL_DELTA;
+            
+            // rdev simulation for wheel only knows whole notches
+            let notches_x = delta_x / WHEEL_DELTA;
+            let notches_y = delta_y / WHEEL_DELTA;
+            if notches_x != 0 || notches_y != 0 {
+                let event_type = EventType::Wheel { 
+                    delta_x: notches_x as i64, 
+                    delta_y: notches_y as i64 
+                };
+                note_injected(Injected::Mouse);
+                let _ = simulate(&event_type);
+            }
+        }
+    }
+
+    /// Media keys have no rdev Key, so they go out as platform key codes
+    pub fn media(&self, action: MediaAction) {
+        let Some(code) = media_key_code(action) else {
+            println!("此平台不支持媒体按键: {:?}", action);
+            return;
+        };
+        for event_type in [EventType::KeyPress(Key::Unknown(code)), EventType::KeyRelease(Key::Unknown(code))] {
+            note_injected(Injected::Key);
             let _ = simulate(&event_type);
         }
     }
 
     pub fn key_press(&self, key_code: u32, is_down: bool) {
         // 将字符码转换为 rdev Key
-        let key = self.map_key_code(key_code);
+        let key = map_key_code(key_code);
         
         if let Some(rdev_key) = key {
             let event_type = if is_down {
@@ -184,98 +654,315 @@ impl InputSimulator {
                 EventType::KeyRelease(rdev_key)
             };
 
+            note_injected(Injected::Key);
             let _ = simulate(&event_type);
         }
     }
+}
 
-    fn map_key_code(&self, code: u32) -> Option<Key> {
-        // 键码映射 - 支持大小写字母
-        match code {
-            // 字母 A-Z (大写 ASCII 65-90)
-            65 => Some(Key::KeyA), 66 => Some(Key::KeyB), 67 => Some(Key::KeyC),
-            68 => Some(Key::KeyD), 69 => Some(Key::KeyE), 70 => Some(Key::KeyF),
-            71 => Some(Key::KeyG), 72 => Some(Key::KeyH), 73 => Some(Key::KeyI),
-            74 => Some(Key::KeyJ), 75 => Some(Key::KeyK), 76 => Some(Key::KeyL),
-            77 => Some(Key::KeyM), 78 => Some(Key::KeyN), 79 => Some(Key::KeyO),
-            80 => Some(Key::KeyP), 81 => Some(Key::KeyQ), 82 => Some(Key::KeyR),
-            83 => Some(Key::KeyS), 84 => Some(Key::KeyT), 85 => Some(Key::KeyU),
-            86 => Some(Key::KeyV), 87 => Some(Key::KeyW), 88 => Some(Key::KeyX),
-            89 => Some(Key::KeyY), 90 => Some(Key::KeyZ),
-            
-            // 字母 a-z (小写 ASCII 97-122)
-            97 => Some(Key::KeyA), 98 => Some(Key::KeyB), 99 => Some(Key::KeyC),
-            100 => Some(Key::KeyD), 101 => Some(Key::KeyE), 102 => Some(Key::KeyF),
-            103 => Some(Key::KeyG), 104 => Some(Key::KeyH), 105 => Some(Key::KeyI),
-            106 => Some(Key::KeyJ), 107 => Some(Key::KeyK), 108 => Some(Key::KeyL),
-            109 => Some(Key::KeyM), 110 => Some(Key::KeyN), 111 => Some(Key::KeyO),
-            112 => Some(Key::KeyP), 113 => Some(Key::KeyQ), 114 => Some(Key::KeyR),
-            115 => Some(Key::KeyS), 116 => Some(Key::KeyT), 117 => Some(Key::KeyU),
-            118 => Some(Key::KeyV), 119 => Some(Key::KeyW), 120 => Some(Key::KeyX),
-            121 => Some(Key::KeyY), 122 => Some(Key::KeyZ),
-            
-            // 数字 0-9
-            48 => Some(Key::Num0), 49 => Some(Key::Num1), 50 => Some(Key::Num2),
-            51 => Some(Key::Num3), 52 => Some(Key::Num4), 53 => Some(Key::Num5),
-            54 => Some(Key::Num6), 55 => Some(Key::Num7), 56 => Some(Key::Num8),
-            57 => Some(Key::Num9),
-            
-            // 特殊键
-            13 => Some(Key::Return),
-            10 => Some(Key::Return), // 换行符
-            27 => Some(Key::Escape),
-            32 => Some(Key::Space),
-            8 => Some(Key::Backspace),
-            9 => Some(Key::Tab),
-
-            // 标点符号
-            33 => Some(Key::Num1),      // !
-            64 => Some(Key::Num2),      // @
-            35 => Some(Key::Num3),      // #
-            36 => Some(Key::Num4),      // $
-            // 37 => Some(Key::Num5),      // %
-            // 38 => Some(Key::Num7),      // &
-            // 39 => Some(Key::Quote),         // '
-            // 40 => Some(Key::Num9),      // (
-            45 => Some(Key::Minus),     // -
-            95 => Some(Key::Minus),     // _
-            61 => Some(Key::Equal),     // =
-            43 => Some(Key::Equal),     // +
-            // 91 => Some(Key::LeftBracket),   // [ - Conflict with MetaLeft
-            93 => Some(Key::RightBracket),  // ]
-            // 92 => Some(Key::BackSlash),     // \ - Conflict with MetaRight
-            59 => Some(Key::SemiColon),     // ;
-            58 => Some(Key::SemiColon),     // :
-            // 39 => Some(Key::Quote),         // '
-            34 => Some(Key::Quote),         // "
-            44 => Some(Key::Comma),         // ,
-            60 => Some(Key::Comma),         // <
-            46 => Some(Key::Dot),           // .
-            62 => Some(Key::Dot),           // >
-            47 => Some(Key::Slash),         // /
-            63 => Some(Key::Slash),         // ?
-            96 => Some(Key::BackQuote),     // `
-            126 => Some(Key::BackQuote),    // ~
-            
-            // Modifiers
-            16 => Some(Key::ShiftLeft),
-            160 => Some(Key::ShiftLeft),
-            161 => Some(Key::ShiftRight),
-            17 => Some(Key::ControlLeft),
-            162 => Some(Key::ControlLeft),
-            163 => Some(Key::ControlRight),
-            18 => Some(Key::Alt),
-            164 => Some(Key::Alt),
-            165 => Some(Key::AltGr),
-            91 => Some(Key::MetaLeft),
-            92 => Some(Key::MetaRight),
-
-            // Arrow keys
-            38 => Some(Key::UpArrow),
-            40 => Some(Key::DownArrow),
-            37 => Some(Key::LeftArrow),
-            39 => Some(Key::RightArrow),
+/// Key code rdev passes through for a media key; None on macOS, where
+/// media keys are system-defined events rather than key codes
+#[cfg(feature = "inject")]
+fn media_key_code(action: MediaAction) -> Option<u32> {
+    if cfg!(windows) {
+        // Virtual-key codes
+        Some(match action {
+            MediaAction::VolumeUp => 0xAF,
+            MediaAction::VolumeDown => 0xAE,
+            MediaAction::Mute => 0xAD,
+            MediaAction::PlayPause => 0xB3,
+        })
+    } else if cfg!(target_os = "macos") {
+        None
+    } else {
+        // X keycodes of XF86AudioRaiseVolume etc. (evdev code + 8)
+        Some(match action {
+            MediaAction::VolumeUp => 123,
+            MediaAction::VolumeDown => 122,
+            MediaAction::Mute => 121,
+            MediaAction::PlayPause => 172,
+        })
+    }
+}
 
-            _ => None,
+/// rdev's simulate, or the uinput device in a Wayland session
+#[cfg(feature = "inject")]
+fn simulate(event_type: &EventType) -> Result<(), rdev::SimulateError> {
+    #[cfg(target_os = "linux")]
+    if let Some(device) = crate::wayland::injector() {
+        return device.simulate(event_type).map_err(|_| rdev::SimulateError);
+    }
+    rdev::simulate(event_type)
+}
+
+/// Key for a code from `Message::KeyPress`: the codes `input_capture::rdev_key_to_code`
+/// produces, plus ASCII characters typed in the frontend
+pub fn map_key_code(code: u32) -> Option<Key> {
+    // 键码映射 - 支持大小写字母
+    match code {
+        // 字母 A-Z (大写 ASCII 65-90)
+        65 => Some(Key::KeyA), 66 => Some(Key::KeyB), 67 => Some(Key::KeyC),
+        68 => Some(Key::KeyD), 69 => Some(Key::KeyE), 70 => Some(Key::KeyF),
+        71 => Some(Key::KeyG), 72 => Some(Key::KeyH), 73 => Some(Key::KeyI),
+        74 => Some(Key::KeyJ), 75 => Some(Key::KeyK), 76 => Some(Key::KeyL),
+        77 => Some(Key::KeyM), 78 => Some(Key::KeyN), 79 => Some(Key::KeyO),
+        80 => Some(Key::KeyP), 81 => Some(Key::KeyQ), 82 => Some(Key::KeyR),
+        83 => Some(Key::KeyS), 84 => Some(Key::KeyT), 85 => Some(Key::KeyU),
+        86 => Some(Key::KeyV), 87 => Some(Key::KeyW), 88 => Some(Key::KeyX),
+        89 => Some(Key::KeyY), 90 => Some(Key::KeyZ),
+        
+        // 字母 a-z (小写 ASCII 97-122)
+        97 => Some(Key::KeyA), 98 => Some(Key::KeyB), 99 => Some(Key::KeyC),
+        100 => Some(Key::KeyD), 101 => Some(Key::KeyE), 102 => Some(Key::KeyF),
+        103 => Some(Key::KeyG), 104 => Some(Key::KeyH), 105 => Some(Key::KeyI),
+        106 => Some(Key::KeyJ), 107 => Some(Key::KeyK), 108 => Some(Key::KeyL),
+        109 => Some(Key::KeyM), 110 => Some(Key::KeyN), 111 => Some(Key::KeyO),
+        112 => Some(Key::KeyP), 113 => Some(Key::KeyQ), 114 => Some(Key::KeyR),
+        115 => Some(Key::KeyS), 116 => Some(Key::KeyT), 117 => Some(Key::KeyU),
+        118 => Some(Key::KeyV), 119 => Some(Key::KeyW), 120 => Some(Key::KeyX),
+        121 => Some(Key::KeyY), 122 => Some(Key::KeyZ),
+        
+        // 数字 0-9
+        48 => Some(Key::Num0), 49 => Some(Key::Num1), 50 => Some(Key::Num2),
+        51 => Some(Key::Num3), 52 => Some(Key::Num4), 53 => Some(Key::Num5),
+        54 => Some(Key::Num6), 55 => Some(Key::Num7), 56 => Some(Key::Num8),
+        57 => Some(Key::Num9),
+        
+        // 特殊键
+        13 => Some(Key::Return),
+        10 => Some(Key::Return), // 换行符
+        27 => Some(Key::Escape),
+        32 => Some(Key::Space),
+        8 => Some(Key::Backspace),
+        9 => Some(Key::Tab),
+
+        // 标点符号
+        33 => Some(Key::Num1),      // !
+        64 => Some(Key::Num2),      // @
+        35 => Some(Key::Num3),      // #
+        36 => Some(Key::Num4),      // $
+        // 37 => Some(Key::Num5),      // %
+        // 38 => Some(Key::Num7),      // &
+        // 39 => Some(Key::Quote),         // '
+        // 40 => Some(Key::Num9),      // (
+        45 => Some(Key::Minus),     // -
+        95 => Some(Key::Minus),     // _
+        61 => Some(Key::Equal),     // =
+        43 => Some(Key::Equal),     // +
+        // 91 => Some(Key::LeftBracket),   // [ - Conflict with MetaLeft
+        93 => Some(Key::RightBracket),  // ]
+        // 92 => Some(Key::BackSlash),     // \ - Conflict with MetaRight
+        59 => Some(Key::SemiColon),     // ;
+        58 => Some(Key::SemiColon),     // :
+        // 39 => Some(Key::Quote),         // '
+        34 => Some(Key::Quote),         // "
+        44 => Some(Key::Comma),         // ,
+        60 => Some(Key::Comma),         // <
+        46 => Some(Key::Dot),           // .
+        62 => Some(Key::Dot),           // >
+        47 => Some(Key::Slash),         // /
+        63 => Some(Key::Slash),         // ?
+        96 => Some(Key::BackQuote),     // `
+        126 => Some(Key::BackQuote),    // ~
+        
+        // Modifiers
+        16 => Some(Key::ShiftLeft),
+        160 => Some(Key::ShiftLeft),
+        161 => Some(Key::ShiftRight),
+        17 => Some(Key::ControlLeft),
+        162 => Some(Key::ControlLeft),
+        163 => Some(Key::ControlRight),
+        18 => Some(Key::Alt),
+        164 => Some(Key::Alt),
+        165 => Some(Key::AltGr),
+        91 => Some(Key::MetaLeft),
+        92 => Some(Key::MetaRight),
+
+        // Arrow keys
+        38 => Some(Key::UpArrow),
+        40 => Some(Key::DownArrow),
+        37 => Some(Key::LeftArrow),
+        39 => Some(Key::RightArrow),
+
+        // Lock keys
+        19 => Some(Key::Pause),
+        20 => Some(Key::CapsLock),
+        144 => Some(Key::NumLock),
+        145 => Some(Key::ScrollLock),
+
+        // Punctuation without a free ASCII code (VK_OEM_*)
+        219 => Some(Key::LeftBracket),
+        220 => Some(Key::BackSlash),
+        222 => Some(Key::Quote),
+
+        // Function keys, Home/End, keypad...
+        code if code >= EXTENDED_KEY_BASE => {
+            EXTENDED_KEYS.get((code - EXTENDED_KEY_BASE) as usize).copied()
+        }
+
+        _ => None,
+    }
+}
+
+/// The X11 calls rdev doesn't wrap: relative motion and reading the pointer.
+/// One connection to $DISPLAY is kept open for them, mouse moves come often.
+#[cfg(all(feature = "inject", target_os = "linux"))]
+mod xtest {
+    use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+    use std::sync::Mutex;
+
+    #[link(name = "X11")]
+    extern "C" {
+        fn XOpenDisplay(name: *const c_char) -> *mut c_void;
+        fn XFlush(display: *mut c_void) -> c_int;
+        fn XDefaultRootWindow(display: *mut c_void) -> c_ulong;
+        fn XQueryPointer(
+            display: *mut c_void,
+            window: c_ulong,
+            root: *mut c_ulong,
+            child: *mut c_ulong,
+            root_x: *mut c_int,
+            root_y: *mut c_int,
+            window_x: *mut c_int,
+            window_y: *mut c_int,
+            mask: *mut c_uint,
+        ) -> c_int;
+    }
+
+    #[link(name = "Xtst")]
+    extern "C" {
+        fn XTestFakeRelativeMotionEvent(display: *mut c_void, dx: c_int, dy: c_int, delay: c_ulong) -> c_int;
+    }
+
+    struct Display(*mut c_void);
+
+    // Only used with the mutex held
+    unsafe impl Send for Display {}
+
+    static DISPLAY: Mutex<Option<Display>> = Mutex::new(None);
+
+    /// Runs `f` on the connection, opening it first if needed; None without
+    /// an X server, e.g. in a Wayland session without XWayland
+    fn with_display<T>(f: impl FnOnce(*mut c_void) -> T) -> Option<T> {
+        let mut display = DISPLAY.lock().unwrap_or_else(|e| e.into_inner());
+        if display.is_none() {
+            let opened = unsafe { XOpenDisplay(std::ptr::null()) };
+            if opened.is_null() {
+                return None;
+            }
+            *display = Some(Display(opened));
+        }
+        let display = display.as_ref()?.0;
+        let result = f(display);
+        unsafe {
+            XFlush(display);
         }
+        Some(result)
+    }
+
+    pub fn move_relative(dx: i32, dy: i32) {
+        with_display(|display| unsafe { XTestFakeRelativeMotionEvent(display, dx, dy, 0) });
+    }
+
+    pub fn pointer_position() -> Option<(i32, i32)> {
+        with_display(|display| {
+            let (mut root, mut child, mut mask) = (0, 0, 0);
+            let (mut x, mut y, mut window_x, mut window_y) = (0, 0, 0, 0);
+            let on_screen = unsafe {
+                XQueryPointer(
+                    display,
+                    XDefaultRootWindow(display),
+                    &mut root,
+                    &mut child,
+                    &mut x,
+                    &mut y,
+                    &mut window_x,
+                    &mut window_y,
+                    &mut mask,
+                )
+            };
+            (on_screen != 0).then_some((x, y))
+        })
+        .flatten()
+    }
+}
+
+/// Relative moves on macOS through Quartz event services. Needs the
+/// Accessibility permission; without it the events are silently dropped.
+#[cfg(all(feature = "inject", target_os = "macos"))]
+mod quartz {
+    use std::os::raw::c_void;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGPoint {
+        x: f64,
+        y: f64,
+    }
+
+    const MOUSE_MOVED: u32 = 5;
+    const LEFT_MOUSE_DRAGGED: u32 = 6;
+    const RIGHT_MOUSE_DRAGGED: u32 = 7;
+    const OTHER_MOUSE_DRAGGED: u32 = 27;
+    const HID_EVENT_TAP: u32 = 0;
+    const COMBINED_SESSION_STATE: i32 = 0;
+    const MOUSE_EVENT_DELTA_X: u32 = 4;
+    const MOUSE_EVENT_DELTA_Y: u32 = 5;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGEventCreate(source: *const c_void) -> *mut c_void;
+        fn CGEventGetLocation(event: *const c_void) -> CGPoint;
+        fn CGEventCreateMouseEvent(source: *const c_void, kind: u32, position: CGPoint, button: u32) -> *mut c_void;
+        fn CGEventSetIntegerValueField(event: *mut c_void, field: u32, value: i64);
+        fn CGEventPost(tap: u32, event: *mut c_void);
+        fn CGEventSourceButtonState(state: i32, button: u32) -> bool;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFRelease(object: *const c_void);
+    }
+
+    fn location() -> Option<CGPoint> {
+        unsafe {
+            let event = CGEventCreate(std::ptr::null());
+            if event.is_null() {
+                return None;
+            }
+            let point = CGEventGetLocation(event);
+            CFRelease(event);
+            Some(point)
+        }
+    }
+
+    pub fn move_relative(dx: i32, dy: i32) {
+        let Some(from) = location() else { return };
+        let to = CGPoint { x: from.x + dx as f64, y: from.y + dy as f64 };
+        // A move with a button held is a drag, or apps never see the drag
+        let held = |button| unsafe { CGEventSourceButtonState(COMBINED_SESSION_STATE, button) };
+        let (kind, button) = if held(0) {
+            (LEFT_MOUSE_DRAGGED, 0)
+        } else if held(1) {
+            (RIGHT_MOUSE_DRAGGED, 1)
+        } else if held(2) {
+            (OTHER_MOUSE_DRAGGED, 2)
+        } else {
+            (MOUSE_MOVED, 0)
+        };
+        unsafe {
+            let event = CGEventCreateMouseEvent(std::ptr::null(), kind, to, button);
+            if event.is_null() {
+                return;
+            }
+            CGEventSetIntegerValueField(event, MOUSE_EVENT_DELTA_X, dx as i64);
+            CGEventSetIntegerValueField(event, MOUSE_EVENT_DELTA_Y, dy as i64);
+            CGEventPost(HID_EVENT_TAP, event);
+            CFRelease(event);
+        }
+    }
+
+    pub fn pointer_position() -> Option<(i32, i32)> {
+        location().map(|point| (point.x.round() as i32, point.y.round() as i32))
     }
 }
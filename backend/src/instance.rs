@@ -0,0 +1,93 @@
+//! One backend per machine. A second one would fail to bind its ports halfway
+//! through startup, or broadcast the same device twice from one PC.
+//!
+//! A lock file marks the running instance; the frontend port is probed as
+//! well, for builds from before the lock file.
+
+use crate::websocket::Command;
+use anyhow::{bail, Result};
+use futures_util::SinkExt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+/// How long a takeover waits for the old instance to exit
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Held for the lifetime of the process; the OS releases it on exit, even after a crash
+pub struct InstanceLock {
+    _file: File,
+}
+
+pub fn lock_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("ShareFlow")
+        .join("instance.lock")
+}
+
+/// None if another process holds the lock
+pub fn try_lock(path: &Path) -> Result<Option<InstanceLock>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    match file.try_lock() {
+        Ok(()) => {
+            // For whoever wonders which process it is
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+            Ok(Some(InstanceLock { _file: file }))
+        }
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Something already answers on the frontend port
+pub fn port_in_use(ws_port: u16) -> bool {
+    std::net::TcpStream::connect_timeout(&([127, 0, 0, 1], ws_port).into(), Duration::from_millis(500)).is_ok()
+}
+
+/// Make this the only instance on the machine. With `takeover` a running one is
+/// asked to shut down over its WebSocket API first, otherwise finding one is an error.
+pub async fn claim(lock_path: &Path, ws_port: u16, takeover: bool) -> Result<InstanceLock> {
+    if let Some(lock) = try_lock(lock_path)? {
+        if !port_in_use(ws_port) {
+            return Ok(lock);
+        }
+    }
+    if !takeover {
+        bail!(
+            "ShareFlow is already running on this machine (ws://127.0.0.1:{}). Quit it from its tray icon, or start with --takeover to replace it",
+            ws_port
+        );
+    }
+
+    println!("ShareFlow 已在运行，请求其退出...");
+    request_shutdown(ws_port).await?;
+    let deadline = tokio::time::Instant::now() + TAKEOVER_TIMEOUT;
+    loop {
+        if let Some(lock) = try_lock(lock_path)? {
+            if !port_in_use(ws_port) {
+                println!("  ✓ 旧实例已退出");
+                return Ok(lock);
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            bail!("the running ShareFlow did not shut down within {}s; quit it from its tray icon", TAKEOVER_TIMEOUT.as_secs());
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+async fn request_shutdown(ws_port: u16) -> Result<()> {
+    let url = format!("ws://127.0.0.1:{}", ws_port);
+    let Ok((mut ws, _)) = connect_async(&url).await else {
+        bail!("the running ShareFlow does not answer on {}; quit it by hand", url);
+    };
+    ws.send(Message::Text(serde_json::to_string(&Command::Shutdown)?)).await?;
+    Ok(())
+}
@@ -0,0 +1,259 @@
+//! Baseline JPEG encoding for screen thumbnails: 8-bit YCbCr without chroma
+//! subsampling, the standard (Annex K) quantization and Huffman tables.
+//! Just enough for small pictures of a desktop, no dependency for it.
+
+// Natural-order index of each coefficient in zigzag order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21,
+    28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61,
+    54, 47, 55, 62, 63,
+];
+
+// Annex K.1, natural order
+const LUMA_QUANT: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51,
+    87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99,
+];
+
+// Annex K.3: code counts per length 1..=16, then the symbols
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14,
+    0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09,
+    0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a,
+    0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65,
+    0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88,
+    0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9,
+    0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca,
+    0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea,
+    0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32,
+    0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16,
+    0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39,
+    0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86,
+    0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8,
+    0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9,
+    0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+/// (code, length) by symbol, from a table's code counts and symbols
+struct Huffman {
+    codes: [(u16, u8); 256],
+}
+
+impl Huffman {
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut values = values.iter();
+        for (length, count) in (1..=16u8).zip(bits) {
+            for _ in 0..*count {
+                codes[*values.next().unwrap() as usize] = (code, length);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        Huffman { codes }
+    }
+}
+
+/// Entropy-coded data, with a 0 stuffed after every 0xFF byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u16, len: u8) {
+        self.bits = self.bits << len | (value as u32 & ((1 << len) - 1));
+        self.len += len;
+        while self.len >= 8 {
+            self.len -= 8;
+            let byte = (self.bits >> self.len) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+        }
+    }
+
+    fn symbol(&mut self, table: &Huffman, symbol: u8) {
+        let (code, len) = table.codes[symbol as usize];
+        self.write(code, len);
+    }
+
+    /// Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.write(0x7F, 8 - self.len);
+        }
+        self.out
+    }
+}
+
+struct Component {
+    quant: [u16; 64],
+    dc: Huffman,
+    ac: Huffman,
+    previous_dc: i32,
+}
+
+impl Component {
+    fn new(base: &[u16; 64], quality: u8, dc: Huffman, ac: Huffman) -> Self {
+        // Same scaling as libjpeg
+        let quality = quality.clamp(1, 100) as u32;
+        let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+        let quant = base.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16);
+        Component { quant, dc, ac, previous_dc: 0 }
+    }
+
+    fn encode(&mut self, block: &[f32; 64], cos: &[[f32; 8]; 8], writer: &mut BitWriter) {
+        let coefficients = dct(block, cos);
+        let quantized: Vec<i32> = ZIGZAG
+            .iter()
+            .map(|&i| (coefficients[i] / self.quant[i] as f32).round() as i32)
+            .collect();
+
+        let diff = quantized[0] - self.previous_dc;
+        self.previous_dc = quantized[0];
+        let (category, bits) = magnitude(diff);
+        writer.symbol(&self.dc, category);
+        writer.write(bits, category);
+
+        let mut run = 0;
+        for &value in &quantized[1..] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                // 16 zeros
+                writer.symbol(&self.ac, 0xF0);
+                run -= 16;
+            }
+            let (category, bits) = magnitude(value);
+            writer.symbol(&self.ac, run << 4 | category);
+            writer.write(bits, category);
+            run = 0;
+        }
+        if run > 0 {
+            // End of block
+            writer.symbol(&self.ac, 0x00);
+        }
+    }
+}
+
+/// Bit count of `value` and the bits that stand for it
+fn magnitude(value: i32) -> (u8, u16) {
+    let category = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (category, bits as u16)
+}
+
+/// Forward DCT of an 8x8 block, rows first and then columns
+fn dct(block: &[f32; 64], cos: &[[f32; 8]; 8]) -> [f32; 64] {
+    let scale = |u: usize| if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| block[y * 8 + x] * cos[x][u]).sum::<f32>() * scale(u) / 2.0;
+        }
+    }
+    let mut out = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| rows[y * 8 + u] * cos[y][v]).sum::<f32>() * scale(v) / 2.0;
+        }
+    }
+    out
+}
+
+/// `rgb` (three bytes per pixel, rows from the top) as a JPEG file;
+/// `quality` from 1 to 100 like other encoders
+pub fn encode(width: u32, height: u32, rgb: &[u8], quality: u8) -> Vec<u8> {
+    assert_eq!(rgb.len(), (width * height * 3) as usize);
+    assert!(width > 0 && height > 0 && width <= u16::MAX as u32 && height <= u16::MAX as u32);
+
+    let mut components = [
+        Component::new(&LUMA_QUANT, quality, Huffman::new(&LUMA_DC_BITS, &DC_VALUES), Huffman::new(&LUMA_AC_BITS, &LUMA_AC_VALUES)),
+        Component::new(&CHROMA_QUANT, quality, Huffman::new(&CHROMA_DC_BITS, &DC_VALUES), Huffman::new(&CHROMA_AC_BITS, &CHROMA_AC_VALUES)),
+        Component::new(&CHROMA_QUANT, quality, Huffman::new(&CHROMA_DC_BITS, &DC_VALUES), Huffman::new(&CHROMA_AC_BITS, &CHROMA_AC_VALUES)),
+    ];
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    // JFIF 1.01, no density
+    out.extend_from_slice(&[0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0]);
+    for (id, component) in components.iter().take(2).enumerate() {
+        out.extend_from_slice(&[0xFF, 0xDB, 0, 67, id as u8]);
+        out.extend(ZIGZAG.iter().map(|&i| component.quant[i] as u8));
+    }
+    out.extend_from_slice(&[0xFF, 0xC0, 0, 17, 8]);
+    out.extend_from_slice(&(height as u16).to_be_bytes());
+    out.extend_from_slice(&(width as u16).to_be_bytes());
+    out.extend_from_slice(&[3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+        (0x00, &LUMA_DC_BITS, &DC_VALUES),
+        (0x10, &LUMA_AC_BITS, &LUMA_AC_VALUES),
+        (0x01, &CHROMA_DC_BITS, &DC_VALUES),
+        (0x11, &CHROMA_AC_BITS, &CHROMA_AC_VALUES),
+    ];
+    for (class_id, bits, values) in tables {
+        out.extend_from_slice(&[0xFF, 0xC4]);
+        out.extend_from_slice(&(3 + 16 + values.len() as u16).to_be_bytes());
+        out.push(class_id);
+        out.extend_from_slice(bits);
+        out.extend_from_slice(values);
+    }
+    out.extend_from_slice(&[0xFF, 0xDA, 0, 12, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+
+    let mut cos = [[0.0f32; 8]; 8];
+    for (x, row) in cos.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+
+    let mut writer = BitWriter { out, bits: 0, len: 0 };
+    let mut blocks = [[0.0f32; 64]; 3];
+    for block_y in (0..height).step_by(8) {
+        for block_x in (0..width).step_by(8) {
+            for i in 0..64 {
+                // Past the right or bottom edge the last pixel repeats
+                let x = (block_x + i as u32 % 8).min(width - 1);
+                let y = (block_y + i as u32 / 8).min(height - 1);
+                let pixel = ((y * width + x) * 3) as usize;
+                let [r, g, b] = [rgb[pixel], rgb[pixel + 1], rgb[pixel + 2]].map(|c| c as f32);
+                let ycbcr = [
+                    0.299 * r + 0.587 * g + 0.114 * b - 128.0,
+                    -0.168_736 * r - 0.331_264 * g + 0.5 * b,
+                    0.5 * r - 0.418_688 * g - 0.081_312 * b,
+                ];
+                for (block, value) in blocks.iter_mut().zip(ycbcr) {
+                    block[i] = value;
+                }
+            }
+            for (component, block) in components.iter_mut().zip(&blocks) {
+                component.encode(block, &cos, &mut writer);
+            }
+        }
+    }
+    let mut out = writer.finish();
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
@@ -0,0 +1,38 @@
+pub mod protocol;
+pub mod error;
+pub mod discovery;
+pub mod transport;
+pub mod websocket;
+pub mod input_capture;
+pub mod input_simulator;
+pub mod web_server;
+pub mod forwarder;
+pub mod session;
+pub mod service;
+pub mod diagnostics;
+pub mod capture_watch;
+pub mod audit;
+pub mod capabilities;
+pub mod stats;
+pub mod self_check;
+pub mod firewall;
+pub mod settings;
+pub mod clipboard;
+pub mod secret_store;
+pub mod lockout;
+pub mod instance;
+pub mod privacy;
+pub mod persist;
+pub mod pairing;
+pub mod config;
+pub mod screens;
+pub mod file_transfer;
+pub mod screenshot;
+pub mod jpeg;
+pub mod accessibility;
+pub mod highlight;
+#[cfg(target_os = "linux")]
+pub mod wayland;
+pub mod echo;
+
+pub use service::{run_backend, BackendConfig};
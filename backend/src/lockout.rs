@@ -0,0 +1,138 @@
+//! Failed connection and pairing attempts, per source address and per device.
+//!
+//! A few failures are free, after that each one makes the source wait twice
+//! as long before its next attempt, and enough of them ban it for a while.
+//! That keeps a 6-digit PIN out of reach of a brute force from the LAN, and
+//! stops a peer from flooding the user with connection prompts.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Failures before any waiting is imposed
+pub const FREE_ATTEMPTS: u32 = 3;
+/// Wait after the first failure past the free ones, doubled for each further one
+pub const BASE_BACKOFF: Duration = Duration::from_secs(5);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// Failures after which the source is banned instead of slowed down
+pub const BAN_AFTER: u32 = 10;
+pub const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+/// A source that stays quiet this long starts over
+pub const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// What happened, for the frontend's security notice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LockoutKind {
+    /// Attempt refused because the source has to wait
+    Throttled,
+    /// Too many failures, the source is banned
+    Banned,
+}
+
+#[derive(Debug)]
+struct Source {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct AttemptLimiter {
+    sources: HashMap<IpAddr, Source>,
+}
+
+impl AttemptLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Err(why, time left) while `source` may not try again
+    pub fn check(&mut self, source: IpAddr, now: Instant) -> Result<(), (LockoutKind, Duration)> {
+        self.forget_stale(now);
+        match self.sources.get(&source) {
+            Some(Source { failures, blocked_until: Some(until), .. }) if *until > now => {
+                let kind = if *failures >= BAN_AFTER { LockoutKind::Banned } else { LockoutKind::Throttled };
+                Err((kind, *until - now))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failed attempt; returns how long the source now has to wait, if at all
+    pub fn fail(&mut self, source: IpAddr, now: Instant) -> Option<(LockoutKind, Duration)> {
+        self.forget_stale(now);
+        let entry = self.sources.entry(source).or_insert(Source { failures: 0, last_failure: now, blocked_until: None });
+        entry.failures += 1;
+        entry.last_failure = now;
+        let (kind, wait) = if entry.failures >= BAN_AFTER {
+            (LockoutKind::Banned, BAN_DURATION)
+        } else if entry.failures > FREE_ATTEMPTS {
+            let doublings = entry.failures - FREE_ATTEMPTS - 1;
+            (LockoutKind::Throttled, BASE_BACKOFF.saturating_mul(1 << doublings).min(MAX_BACKOFF))
+        } else {
+            return None;
+        };
+        entry.blocked_until = Some(now + wait);
+        Some((kind, wait))
+    }
+
+    /// A successful attempt clears the source's record
+    pub fn succeed(&mut self, source: IpAddr) {
+        self.sources.remove(&source);
+    }
+
+    pub fn failures(&self, source: IpAddr) -> u32 {
+        self.sources.get(&source).map_or(0, |s| s.failures)
+    }
+
+    fn forget_stale(&mut self, now: Instant) {
+        self.sources.retain(|_, s| {
+            s.blocked_until.is_some_and(|until| until > now) || now.duration_since(s.last_failure) < FORGET_AFTER
+        });
+    }
+}
+
+/// Declines after which a device's connection requests stop prompting the user
+pub const MUTE_AFTER: u32 = 3;
+pub const MUTE_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Devices whose connection requests the user keeps declining, so one peer
+/// can't pop dialogs endlessly. Keyed by device ID rather than address: this
+/// is about annoyance from a known peer, AttemptLimiter about hostile sources.
+#[derive(Debug, Default)]
+pub struct PromptMuter {
+    /// device ID -> (declines, last decline, muted until)
+    devices: HashMap<String, (u32, Instant, Option<Instant>)>,
+}
+
+impl PromptMuter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_muted(&self, device_id: &str, now: Instant) -> bool {
+        self.devices.get(device_id).and_then(|(_, _, until)| *until).is_some_and(|until| until > now)
+    }
+
+    /// Record a declined request; true if that mutes the device
+    pub fn declined(&mut self, device_id: &str, now: Instant) -> bool {
+        let entry = self.devices.entry(device_id.to_string()).or_insert((0, now, None));
+        if now.duration_since(entry.1) >= FORGET_AFTER || entry.2.is_some_and(|until| until <= now) {
+            *entry = (0, now, None);
+        }
+        entry.0 += 1;
+        entry.1 = now;
+        if entry.0 >= MUTE_AFTER && entry.2.is_none() {
+            entry.2 = Some(now + MUTE_DURATION);
+            return true;
+        }
+        false
+    }
+
+    /// Accepting a request or unmuting by hand starts over
+    pub fn unmute(&mut self, device_id: &str) {
+        self.devices.remove(device_id);
+    }
+}
@@ -0,0 +1,169 @@
+//! Pairing: every device has a long-lived Noise key, proven in each handshake.
+//! The first time two devices meet, both show a 6-digit code derived from the
+//! handshake; a user who confirms that the codes match trusts the peer's key
+//! for its device ID from then on, and later requests from it skip the prompt.
+//!
+//! Our own key is kept in the secret store, paired keys in a JSON file.
+
+use crate::error::PairingError;
+use crate::persist;
+use crate::secret_store::SecretStore;
+use crate::transport::{Identity, PeerStream};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Layout of the paired devices file
+const PAIRED_DEVICES_VERSION: u32 = 1;
+
+/// Our key pair, created on first start; one per device ID, so test instances
+/// sharing a machine stay apart
+pub fn load_identity(store: &SecretStore, device_id: &str) -> Result<Identity> {
+    let name = format!("noise-key-{}", device_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"));
+    if let Some(secret) = store.load(&name)? {
+        // private key, then public key
+        if secret.len() != 64 {
+            bail!("保存的设备密钥已损坏");
+        }
+        let (private, public) = secret.split_at(32);
+        return Ok(Identity { private: private.to_vec(), public: public.to_vec() });
+    }
+    let identity = Identity::generate()?;
+    store.save(&name, &[identity.private.as_slice(), identity.public.as_slice()].concat()).context("无法保存设备密钥")?;
+    println!("已生成本设备的配对密钥");
+    Ok(identity)
+}
+
+/// The code both sides show for one handshake; equal codes mean nobody sat in between
+pub fn pairing_code(handshake_hash: &[u8]) -> String {
+    let mut head = [0u8; 4];
+    head.copy_from_slice(&handshake_hash[..4]);
+    format!("{:06}", u32::from_be_bytes(head) % 1_000_000)
+}
+
+fn hex(key: &[u8]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairedDevice {
+    pub name: String,
+    /// Noise static key, hex
+    pub public_key: String,
+    /// When the user confirmed the code, Unix ms
+    pub paired_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PairedDevicesFile {
+    version: u32,
+    devices: BTreeMap<String, PairedDevice>,
+}
+
+/// What a handshake's key means for a device ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trust {
+    /// The key it was paired with
+    Paired,
+    /// Not paired yet; `code` is what the user compares with the other screen
+    Unpaired { code: String },
+    /// Paired under another key: a reinstalled device, or someone posing as it
+    Changed,
+}
+
+/// A key seen in a handshake, waiting for the user to confirm its code
+struct Candidate {
+    name: String,
+    public_key: String,
+    code: String,
+}
+
+pub struct Pairing {
+    path: PathBuf,
+    devices: BTreeMap<String, PairedDevice>,
+    /// Device ID -> its last unpaired handshake
+    candidates: HashMap<String, Candidate>,
+}
+
+impl Pairing {
+    /// A missing file means nothing is paired; an unusable one is set aside like the settings
+    pub fn load(path: &Path) -> Self {
+        let devices = match std::fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<PairedDevicesFile>(&text) {
+                Ok(file) if file.version <= PAIRED_DEVICES_VERSION => file.devices,
+                result => {
+                    let why = result.map_or_else(|e| e.to_string(), |file| format!("版本 {} 比本程序支持的新", file.version));
+                    eprintln!("配对设备文件无法使用 {}: {}", path.display(), why);
+                    if let Some(kept) = persist::set_aside(path) {
+                        eprintln!("  原文件已保留为 {}", kept.display());
+                    }
+                    BTreeMap::new()
+                }
+            },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    eprintln!("读取配对设备失败 {}: {}", path.display(), e);
+                }
+                BTreeMap::new()
+            }
+        };
+        Pairing { path: path.to_path_buf(), devices, candidates: HashMap::new() }
+    }
+
+    pub fn devices(&self) -> &BTreeMap<String, PairedDevice> {
+        &self.devices
+    }
+
+    /// What to make of the key `device_id` proved on `stream`; None without encryption.
+    /// An unpaired key is kept until its code is confirmed or another handshake replaces it.
+    pub fn check(&mut self, device_id: &str, name: &str, stream: &PeerStream) -> Option<Trust> {
+        let (key, handshake_hash) = stream.peer_key()?;
+        let public_key = hex(key);
+        match self.devices.get(device_id) {
+            Some(paired) if paired.public_key == public_key => Some(Trust::Paired),
+            Some(_) => Some(Trust::Changed),
+            None => {
+                let code = pairing_code(handshake_hash);
+                self.candidates.insert(device_id.to_string(), Candidate { name: name.to_string(), public_key, code: code.clone() });
+                Some(Trust::Unpaired { code })
+            }
+        }
+    }
+
+    /// Whether `stream`'s key is the one `device_id` is paired with
+    pub fn is_paired(&self, device_id: &str, stream: &PeerStream) -> bool {
+        let Some((key, _)) = stream.peer_key() else {
+            return false;
+        };
+        self.devices.get(device_id).is_some_and(|paired| paired.public_key == hex(key))
+    }
+
+    /// The user saw `code` on both screens: trust the key from that handshake
+    pub fn confirm(&mut self, device_id: &str, code: &str, now_ms: u64) -> Result<(), PairingError> {
+        let candidate = self.candidates.get(device_id).ok_or(PairingError::NotPending)?;
+        if candidate.code != code {
+            return Err(PairingError::CodeMismatch);
+        }
+        let candidate = self.candidates.remove(device_id).ok_or(PairingError::NotPending)?;
+        let device = PairedDevice { name: candidate.name, public_key: candidate.public_key, paired_at: now_ms };
+        self.devices.insert(device_id.to_string(), device);
+        self.save()
+    }
+
+    /// Forget `device_id`'s key; false if it wasn't paired
+    pub fn unpair(&mut self, device_id: &str) -> Result<bool, PairingError> {
+        if self.devices.remove(device_id).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<(), PairingError> {
+        let file = PairedDevicesFile { version: PAIRED_DEVICES_VERSION, devices: self.devices.clone() };
+        let text = serde_json::to_string_pretty(&file).map_err(|e| PairingError::Save(e.to_string()))?;
+        persist::write_atomic(&self.path, text.as_bytes()).map_err(|e| PairingError::Save(e.to_string()))
+    }
+}
@@ -0,0 +1,48 @@
+//! Writing state files so that a crash or power loss in the middle of a save
+//! leaves either the old file or the new one, never a mix of both.
+
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Write `data` to a temporary file next to `path`, get it onto the disk, then rename
+/// it over `path`. Readable by this user only: these files decide whom we trust.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    let name = path.file_name().with_context(|| format!("{} 不是文件路径", path.display()))?;
+    let temp = dir.join(format!(".{}.tmp", name.to_string_lossy()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp, path)?;
+    // The rename is only durable once the directory entry is
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Move a file that can't be used out of the way as `<name>.bak`, so the next save
+/// doesn't overwrite what could still be recovered by hand
+pub fn set_aside(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let kept = path.with_file_name(format!("{}.bak", name.to_string_lossy()));
+    match std::fs::rename(path, &kept) {
+        Ok(()) => Some(kept),
+        Err(e) => {
+            eprintln!("无法保留 {}: {}", path.display(), e);
+            None
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! What input details may show up in logs.
+//!
+//! Key codes, mouse buttons and clipboard or hand-off text are left out of
+//! every log line unless unsafe debug logging is switched on
+//! (`--unsafe-debug` or SHAREFLOW_UNSAFE_DEBUG=1). Log input through the
+//! wrappers here rather than printing it directly.
+
+use crate::protocol::Message;
+use crate::websocket::Command;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static UNSAFE_DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_unsafe_debug(enabled: bool) {
+    if enabled {
+        eprintln!("⚠ 不安全调试日志已开启: 日志会包含按键、鼠标按钮和剪贴板内容");
+    }
+    UNSAFE_DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn unsafe_debug() -> bool {
+    UNSAFE_DEBUG.load(Ordering::Relaxed)
+}
+
+/// A value that is only printed with unsafe debug logging on
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if unsafe_debug() {
+            self.0.fmt(f)
+        } else {
+            f.write_str("<redacted>")
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if unsafe_debug() {
+            self.0.fmt(f)
+        } else {
+            f.write_str("<redacted>")
+        }
+    }
+}
+
+/// A peer message as it may be logged
+pub struct LoggedMessage<'a>(pub &'a Message);
+
+impl fmt::Debug for LoggedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if unsafe_debug() {
+            return self.0.fmt(f);
+        }
+        match self.0 {
+            Message::MouseClick { state, .. } => {
+                f.debug_struct("MouseClick").field("button", &Redacted(())).field("state", state).finish_non_exhaustive()
+            }
+            Message::KeyPress { state, .. } => {
+                f.debug_struct("KeyPress").field("key", &Redacted(())).field("state", state).finish()
+            }
+            Message::Clipboard(update) => f
+                .debug_struct("Clipboard")
+                .field("origin", &update.origin)
+                .field("seq", &update.seq)
+                .field("text", &Redacted(()))
+                .finish_non_exhaustive(),
+            Message::ClipboardChunk { seq, data } => {
+                f.debug_struct("ClipboardChunk").field("seq", seq).field("len", &data.len()).finish_non_exhaustive()
+            }
+            Message::ScreenshotData { width, height, offset, data } => f
+                .debug_struct("ScreenshotData")
+                .field("width", width)
+                .field("height", height)
+                .field("offset", offset)
+                .field("len", &data.len())
+                .finish(),
+            Message::HandOff { .. } => f.debug_struct("HandOff").field("text", &Redacted(())).finish(),
+            other => other.fmt(f),
+        }
+    }
+}
+
+/// A frontend command as it may be logged
+pub struct LoggedCommand<'a>(pub &'a Command);
+
+impl fmt::Debug for LoggedCommand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if unsafe_debug() {
+            return self.0.fmt(f);
+        }
+        match self.0 {
+            // Which kind of input is fine, which key or button is not
+            Command::SendInput { event, target_device_id } => f
+                .debug_struct("SendInput")
+                .field("type", &event.event_type)
+                .field("target_device_id", target_device_id)
+                .finish_non_exhaustive(),
+            Command::SendToDevice { target_device_id, .. } => f
+                .debug_struct("SendToDevice")
+                .field("text", &Redacted(()))
+                .field("target_device_id", target_device_id)
+                .finish(),
+            other => other.fmt(f),
+        }
+    }
+}
@@ -1,5 +1,29 @@
+use anyhow::{bail, Result};
+use bincode::Options;
+use crate::screens::Monitor;
 use serde::{Deserialize, Serialize};
 
+/// Largest frame a peer may send; anything bigger is malformed or hostile
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Wheel deltas on the wire are in 1/120 notch units, like Windows' WHEEL_DELTA
+pub const WHEEL_DELTA: i32 = 120;
+
+/// Wire protocol spoken by this build, sent in Message::Hello.
+/// 1 is every build from before Hello.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest peer version this build still talks to; older ones are refused
+/// with RejectReason::VersionMismatch instead of misreading their frames.
+/// Version 1 is out: messages were added in the middle of the enum since,
+/// and bincode numbers variants by position, so only Discovery and MouseMove
+/// kept their tags. New variants go at the end.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Side buttons in `Message::MouseClick` (XBUTTON1/XBUTTON2), for peers with `PeerFeature::SideButtons`
+pub const BUTTON_BACK: u8 = 3;
+pub const BUTTON_FORWARD: u8 = 4;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     /// Broadcast message to find other peers
@@ -13,27 +37,585 @@ pub enum Message {
         x: i32,
         y: i32,
     },
-    /// Mouse wheel scroll
+    /// Burst of mouse movement deltas sent as one frame when coalescing is enabled
+    MouseMoveBatch(Vec<(i16, i16)>),
+    /// Mouse wheel scroll, in WHEEL_DELTA units (120 = one notch) so
+    /// touchpads and free-spinning wheels keep sub-notch precision
     MouseWheel {
         delta_x: i32,
         delta_y: i32,
     },
     /// Mouse button state change
     MouseClick {
-        button: u8, // 0: Left, 1: Right, 2: Middle, etc.
+        button: u8, // 0: Left, 1: Right, 2: Middle, 3: Back, 4: Forward
         state: bool, // true: Down, false: Up
+        elapsed_ms: u32, // Time since the previous button event on the controller (0: unknown)
     },
     /// Keyboard key state change
     KeyPress {
         key: u32, // Virtual key code
         state: bool, // true: Down, false: Up
     },
-    /// Request to establish a control connection
-    ConnectRequest,
+    /// Controlled side's actual cursor position, reported back periodically.
+    /// Relative to the box around all its monitors, which is screen_width x screen_height.
+    CursorPos {
+        x: i32,
+        y: i32,
+        screen_width: u32,
+        screen_height: u32,
+    },
+    /// Request to establish a control connection, carrying the initiator's identity
+    ConnectRequest {
+        id: String,
+        name: String,
+        public_key: Option<Vec<u8>>, // Sender's Noise static key; the handshake is what proves it
+        /// Permission names the initiator asks for, shown to the user before accepting
+        permissions: Vec<String>,
+    },
     /// Response to connection request
     ConnectResponse {
         success: bool,
+        reason: Option<RejectReason>, // Set when success is false
+        /// Permission names the user granted, never more than were requested
+        granted: Vec<String>,
     },
     /// Notify peer that we are disconnecting
     Disconnect,
+    /// Controlled side granted time-boxed control: seconds left, 0 when it just ran out
+    TimeLimit {
+        remaining_secs: u32,
+    },
+    /// Sent by both sides when a session starts. Names rather than PeerFeature,
+    /// so a newer peer can announce features this build doesn't know.
+    Features {
+        features: Vec<String>,
+    },
+    /// Controlled side: injected movement pushed the cursor against this edge
+    /// (only with EdgeBehavior::Notify)
+    EdgeHit {
+        edge: ScreenEdge,
+    },
+    /// Text copied on either side, only sent when the peer announced the
+    /// clipboard feature and the session was granted the clipboard permission
+    Clipboard(ClipboardUpdate),
+    /// Clipboard text too large for one frame follows in ClipboardChunk messages,
+    /// so input keeps flowing in between
+    ClipboardBegin {
+        origin: String,
+        seq: u64,
+        timestamp_ms: u64,
+        len: u64,
+    },
+    /// Next bytes of the text announced by the ClipboardBegin with this seq
+    ClipboardChunk {
+        seq: u64,
+        data: Vec<u8>,
+    },
+    /// Either side gives up on a streamed transfer; origin tells whose it was
+    ClipboardCancel {
+        origin: String,
+        seq: u64,
+    },
+    /// Controller, with Permission::HandOff: "continue on the other PC", a link
+    /// to offer or text to put in the clipboard, whether or not clipboard sync is on
+    HandOff {
+        text: String,
+    },
+    /// Volume and playback, needs Permission::Media rather than Input
+    Media {
+        action: MediaAction,
+    },
+    /// Sent every HEARTBEAT_INTERVAL once the peer announced the heartbeat feature,
+    /// so a peer that vanished without closing the socket is noticed
+    Heartbeat {
+        /// Sender's session clock, in µs
+        sent_us: u64,
+        /// The last sent_us from the other side and how long (µs) it was held
+        /// before this reply, for the other side to work out the round trip
+        echo: Option<(u64, u64)>,
+    },
+    /// Controlled side, once the peer announced the targetStatus feature: whether
+    /// injected input can reach the screen in front of the machine. None when it
+    /// can again; sent only on change.
+    TargetStatus {
+        unavailable: Option<TargetUnavailable>,
+    },
+    /// Acceptor: the request is waiting for the user, who has `timeout_secs` left
+    /// to answer; the ConnectResponse follows
+    ConnectPending {
+        timeout_secs: u32,
+    },
+    /// The sender's user compared the pairing code and now trusts the key this
+    /// side proved in the handshake; later requests from it skip the prompt there
+    PairingConfirmed,
+    /// Controller, screen-edge switching: our cursor left our desktop over `edge`
+    /// at (x, y) and control moves to the peer, whose cursor comes in over the
+    /// opposite edge. Position and size are those of the box around all our
+    /// monitors, as in CursorPos. Only for PeerFeature::EdgeSwitch.
+    HandControl {
+        edge: ScreenEdge,
+        x: i32,
+        y: i32,
+        screen_width: u32,
+        screen_height: u32,
+    },
+    /// Controlled side: the cursor that came in with HandControl was pushed back
+    /// out over `edge` of our desktop at (x, y); control goes back to the controller
+    ReturnControl {
+        edge: ScreenEdge,
+        x: i32,
+        y: i32,
+        screen_width: u32,
+        screen_height: u32,
+    },
+    /// Sent by both sides once the peer announced PeerFeature::Screens: how our
+    /// monitors are arranged
+    Screens {
+        monitors: Vec<Monitor>,
+    },
+    /// Offer to send a file, needs Permission::FileTransfer. The receiver answers with
+    /// FileAck, or FileCancel to decline. `id` is the same whenever the same file is
+    /// offered, so an interrupted transfer can resume.
+    FileOffer {
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    /// Receiver: send from `offset` on, what it already has; `offset` == size once
+    /// the whole file arrived
+    FileAck {
+        id: u64,
+        offset: u64,
+    },
+    /// Next bytes of an offered file, starting at `offset`
+    FileChunk {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Either side gives up on the transfer with this id
+    FileCancel {
+        id: u64,
+    },
+    /// Controlled side, once the peer announced PeerFeature::LockState: our lock
+    /// keys, sent at the start and whenever they change
+    LockState(LockState),
+    /// Controller, with Permission::Screenshot: send a picture of your desktop
+    ScreenshotRequest,
+    /// Controlled side: the next part of the screenshot's RGB pixels, starting at `offset`
+    ScreenshotData {
+        width: u32,
+        height: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    /// Controlled side: no screenshot can be taken here, or it wasn't permitted
+    ScreenshotUnavailable,
+    /// Controller, with Permission::Screenshot and PeerFeature::Thumbnails: send
+    /// a JPEG thumbnail of your desktop `fps` times a second until ThumbnailStop
+    ThumbnailStart {
+        fps: u8,
+    },
+    /// Either side: the thumbnail stream ends; from the controlled side when it
+    /// can't be sent (not permitted, or the screen can't be read)
+    ThumbnailStop,
+    /// Controlled side: the next bytes of thumbnail `seq`, a JPEG of `total` bytes
+    ThumbnailFrame {
+        seq: u32,
+        offset: u32,
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// Controller, with PeerFeature::Highlight: briefly ring your cursor, so
+    /// whoever sits at your machine sees where the controller points
+    Highlight,
+    /// Controller, with PeerFeature::InputEcho: answer each input message
+    /// with InputEcho once it was injected, or stop doing so
+    EchoInput {
+        enabled: bool,
+    },
+    /// Controlled side: `echo::digest` of an input message it just injected
+    InputEcho {
+        digest: u32,
+    },
+    /// First message both sides send once the connection is up, before the
+    /// ConnectRequest. A peer that starts with the ConnectRequest is version 1
+    /// and refused.
+    Hello {
+        version: u16,
+        /// `PeerFeature::capabilities` of the sender
+        capabilities: u64,
+    },
+}
+
+/// What a media PC's remote would do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaAction {
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    PlayPause,
+}
+
+/// Caps/Num/Scroll Lock, as the keyboard LEDs show them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LockState {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl LockState {
+    /// Key codes to press and release to get from this state to `target`
+    pub fn toggles_to(&self, target: LockState) -> Vec<u32> {
+        // VK_CAPITAL, VK_NUMLOCK, VK_SCROLL
+        [(self.caps != target.caps, 20), (self.num != target.num, 144), (self.scroll != target.scroll, 145)]
+            .into_iter()
+            .filter(|(differs, _)| *differs)
+            .map(|(_, key)| key)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClipboardUpdate {
+    /// Device the text was copied on
+    pub origin: String,
+    /// Counts up per origin, so a repeated update is recognized
+    pub seq: u64,
+    /// When it was copied (Unix ms); the newer copy wins a conflict
+    pub timestamp_ms: u64,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum ScreenEdge {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl ScreenEdge {
+    pub fn opposite(&self) -> ScreenEdge {
+        match self {
+            ScreenEdge::Left => ScreenEdge::Right,
+            ScreenEdge::Right => ScreenEdge::Left,
+            ScreenEdge::Top => ScreenEdge::Bottom,
+            ScreenEdge::Bottom => ScreenEdge::Top,
+        }
+    }
+
+    /// Whether `pos` lies on this edge of a `screen`-sized display
+    pub fn touches(&self, pos: (i32, i32), screen: (u32, u32)) -> bool {
+        match self {
+            ScreenEdge::Left => pos.0 <= 0,
+            ScreenEdge::Right => pos.0 >= screen.0 as i32 - 1,
+            ScreenEdge::Top => pos.1 <= 0,
+            ScreenEdge::Bottom => pos.1 >= screen.1 as i32 - 1,
+        }
+    }
+
+    /// Where a cursor that left a `from`-sized screen at `pos` comes in over this
+    /// edge of a `to`-sized one, at the same relative position along the edge
+    pub fn entry_point(&self, pos: (i32, i32), from: (u32, u32), to: (u32, u32)) -> (i32, i32) {
+        let scale = |value: i32, from: u32, to: u32| (value as i64 * to as i64 / from.max(1) as i64) as i32;
+        let (right, bottom) = (to.0 as i32 - 1, to.1 as i32 - 1);
+        match self {
+            ScreenEdge::Left => (0, scale(pos.1, from.1, to.1)),
+            ScreenEdge::Right => (right, scale(pos.1, from.1, to.1)),
+            ScreenEdge::Top => (scale(pos.0, from.0, to.0), 0),
+            ScreenEdge::Bottom => (scale(pos.0, from.0, to.0), bottom),
+        }
+    }
+}
+
+/// Optional abilities a peer may or may not have
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerFeature {
+    Wheel,
+    Clipboard,
+    /// Understands Message::FileOffer and the other file messages
+    FileTransfer,
+    /// Mouse positions instead of deltas
+    AbsoluteMouse,
+    /// Understands Message::HandOff
+    HandOff,
+    /// Sends and expects Message::Heartbeat
+    Heartbeat,
+    /// Understands Message::TargetStatus
+    TargetStatus,
+    /// Injects BUTTON_BACK / BUTTON_FORWARD; older builds press the left button instead
+    SideButtons,
+    /// Understands Message::HandControl and answers with Message::ReturnControl
+    EdgeSwitch,
+    /// Understands Message::Screens
+    Screens,
+    /// Understands Message::LockState
+    LockState,
+    /// Understands Message::ThumbnailStart and streams thumbnails when permitted
+    Thumbnails,
+    /// Understands Message::Highlight
+    Highlight,
+    /// Understands Message::EchoInput and echoes injected input
+    InputEcho,
+}
+
+/// What this build supports, announced in Message::Features
+pub const LOCAL_FEATURES: &[PeerFeature] = &[
+    PeerFeature::Wheel,
+    PeerFeature::Clipboard,
+    PeerFeature::HandOff,
+    PeerFeature::Heartbeat,
+    PeerFeature::TargetStatus,
+    PeerFeature::SideButtons,
+    PeerFeature::EdgeSwitch,
+    PeerFeature::Screens,
+    PeerFeature::FileTransfer,
+    PeerFeature::LockState,
+    PeerFeature::Thumbnails,
+    PeerFeature::Highlight,
+    PeerFeature::InputEcho,
+];
+
+impl PeerFeature {
+    /// Every feature, in declaration order
+    pub const ALL: [PeerFeature; 14] = [
+        PeerFeature::Wheel,
+        PeerFeature::Clipboard,
+        PeerFeature::FileTransfer,
+        PeerFeature::AbsoluteMouse,
+        PeerFeature::HandOff,
+        PeerFeature::Heartbeat,
+        PeerFeature::TargetStatus,
+        PeerFeature::SideButtons,
+        PeerFeature::EdgeSwitch,
+        PeerFeature::Screens,
+        PeerFeature::LockState,
+        PeerFeature::Thumbnails,
+        PeerFeature::Highlight,
+        PeerFeature::InputEcho,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerFeature::Wheel => "wheel",
+            PeerFeature::Clipboard => "clipboard",
+            PeerFeature::FileTransfer => "fileTransfer",
+            PeerFeature::AbsoluteMouse => "absoluteMouse",
+            PeerFeature::HandOff => "handOff",
+            PeerFeature::Heartbeat => "heartbeat",
+            PeerFeature::TargetStatus => "targetStatus",
+            PeerFeature::SideButtons => "sideButtons",
+            PeerFeature::EdgeSwitch => "edgeSwitch",
+            PeerFeature::Screens => "screens",
+            PeerFeature::LockState => "lockState",
+            PeerFeature::Thumbnails => "thumbnails",
+            PeerFeature::Highlight => "highlight",
+            PeerFeature::InputEcho => "inputEcho",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PeerFeature::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+
+    /// Its bit in Message::Hello's capabilities: the position in the enum,
+    /// so like the variants themselves bits are only ever appended
+    pub fn bit(&self) -> u64 {
+        1 << *self as u32
+    }
+
+    pub fn capabilities(features: &[PeerFeature]) -> u64 {
+        features.iter().fold(0, |bits, feature| bits | feature.bit())
+    }
+
+    /// Bits this build doesn't know are ignored; Message::Features still names them
+    pub fn from_capabilities(bits: u64) -> Vec<PeerFeature> {
+        PeerFeature::ALL.into_iter().filter(|feature| bits & feature.bit() != 0).collect()
+    }
+}
+
+/// What a peer said about itself in Message::Hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: u64,
+}
+
+impl Hello {
+    /// This build's own
+    pub fn local() -> Self {
+        Hello { version: PROTOCOL_VERSION, capabilities: PeerFeature::capabilities(LOCAL_FEATURES) }
+    }
+
+    pub fn message(&self) -> Message {
+        Message::Hello { version: self.version, capabilities: self.capabilities }
+    }
+
+    /// Whether we can talk to a peer of this version at all
+    pub fn compatible(&self) -> bool {
+        self.version >= MIN_PROTOCOL_VERSION
+    }
+
+    pub fn features(&self) -> Vec<PeerFeature> {
+        PeerFeature::from_capabilities(self.capabilities)
+    }
+}
+
+impl Message {
+    /// Keyboard or mouse input, as opposed to control and status messages
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            Message::MouseMove { .. }
+                | Message::MouseMoveBatch(_)
+                | Message::MouseWheel { .. }
+                | Message::MouseClick { .. }
+                | Message::KeyPress { .. }
+        )
+    }
+}
+
+/// What a controller may do on the machine that accepted it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    /// Keyboard and mouse; without it the session is view-only
+    Input,
+    Clipboard,
+    FileTransfer,
+    /// Volume and play/pause only, for controlling a media PC without full input
+    Media,
+    /// Small pictures of the desktop on request
+    Screenshot,
+    /// Links and text handed over with SendToDevice, without clipboard sync
+    HandOff,
+}
+
+impl Permission {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Input => "input",
+            Permission::Clipboard => "clipboard",
+            Permission::FileTransfer => "fileTransfer",
+            Permission::Media => "media",
+            Permission::Screenshot => "screenshot",
+            Permission::HandOff => "handOff",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Permission::Input, Permission::Clipboard, Permission::FileTransfer, Permission::Media, Permission::Screenshot, Permission::HandOff]
+            .into_iter()
+            .find(|permission| permission.name() == name)
+    }
+
+    /// Wire form; names so a newer peer can ask for permissions this build doesn't know
+    pub fn names(permissions: &[Permission]) -> Vec<String> {
+        permissions.iter().map(|permission| permission.name().to_string()).collect()
+    }
+
+    /// Unknown names are dropped, they can't be granted here anyway
+    pub fn parse(names: &[String]) -> Vec<Permission> {
+        names.iter().filter_map(|name| Permission::from_name(name)).collect()
+    }
+}
+
+/// Requested when the frontend doesn't say: everything this build can use
+pub const DEFAULT_PERMISSIONS: &[Permission] = &[Permission::Input, Permission::Clipboard, Permission::Media, Permission::HandOff];
+
+/// Why a connection request was rejected
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RejectReason {
+    /// Peer is already handling another request or session
+    Busy,
+    /// Peer refuses connections from this device
+    Blocked,
+    /// Nobody answered the request in time
+    Timeout,
+    /// Handshake did not match the peer's protocol version
+    VersionMismatch,
+    /// The user on the peer declined
+    UserDeclined,
+    /// No frontend is open on the peer, so nobody could be asked
+    NoOperator,
+    /// The peer was built without the simulator and can only control others
+    NotControllable,
+    /// The peer paired with this device ID under another key
+    IdentityChanged,
+    /// The request carried no device ID, so there was nothing to ask the user about
+    MissingDeviceId,
+    /// The connection wasn't encrypted and the peer doesn't allow plaintext
+    EncryptionRequired,
+}
+
+impl RejectReason {
+    /// Human-readable text for logs and the UI
+    pub fn describe(&self) -> &'static str {
+        match self {
+            RejectReason::Busy => "对方正忙",
+            RejectReason::Blocked => "对方已屏蔽本设备",
+            RejectReason::Timeout => "对方未及时响应",
+            RejectReason::VersionMismatch => "协议版本不匹配",
+            RejectReason::UserDeclined => "对方拒绝连接",
+            RejectReason::NoOperator => "对方无人值守（界面未打开）",
+            RejectReason::NotControllable => "对方设备不支持被控制",
+            RejectReason::IdentityChanged => "本设备的密钥与对方保存的配对不符",
+            RejectReason::MissingDeviceId => "连接请求缺少设备 ID",
+            RejectReason::EncryptionRequired => "对方要求加密连接",
+        }
+    }
+}
+
+/// Why input injected on the controlled side would go nowhere visible
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TargetUnavailable {
+    /// Our session isn't attached to the console: disconnected, locked
+    /// away by fast user switching, or left behind by a closed RDP client
+    ConsoleDisconnected,
+    /// We run inside an RDP session, so input lands there and not on the
+    /// physical screen
+    RemoteSession,
+}
+
+impl TargetUnavailable {
+    /// Human-readable text for logs and the UI
+    pub fn describe(&self) -> &'static str {
+        match self {
+            TargetUnavailable::ConsoleDisconnected => "对方会话未连接到控制台，输入无法到达屏幕",
+            TargetUnavailable::RemoteSession => "对方运行在远程桌面会话中，输入不会出现在本地屏幕上",
+        }
+    }
+}
+
+/// Net displacement of a MouseMoveBatch
+pub fn batch_displacement(deltas: &[(i16, i16)]) -> (i32, i32) {
+    deltas.iter().fold((0, 0), |(x, y), &(dx, dy)| (x + dx as i32, y + dy as i32))
+}
+
+/// Serialize a message for the wire
+pub fn encode(message: &Message) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(message)?)
+}
+
+/// Decode a frame payload from an untrusted peer.
+/// Same format as `bincode::deserialize`, but lengths inside the payload
+/// can't make us allocate more than the frame itself.
+pub fn decode(data: &[u8]) -> Result<Message> {
+    if data.len() > MAX_FRAME_LEN {
+        bail!("frame too large: {} bytes", data.len());
+    }
+    let message = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_FRAME_LEN as u64)
+        .deserialize(data)?;
+    Ok(message)
 }
@@ -0,0 +1,214 @@
+//! Screen topology: a machine's monitors and how they are arranged.
+//!
+//! Positions are virtual-desktop pixels, the coordinates the cursor APIs use:
+//! the main monitor starts at (0, 0) and the others may lie at negative ones.
+//! On the wire positions are relative to the desktop's bounding box instead,
+//! so a peer that only knows its main display reads them the same way.
+
+use crate::protocol::ScreenEdge;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Beyond any real arrangement, and small enough that sums of them can't overflow
+const MAX_EXTENT: u32 = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Monitor {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+}
+
+impl Monitor {
+    pub fn contains(&self, pos: (i32, i32)) -> bool {
+        pos.0 >= self.x && pos.0 < self.right() && pos.1 >= self.y && pos.1 < self.bottom()
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        (self.x + self.width as i32 / 2, self.y + self.height as i32 / 2)
+    }
+
+    // One past the last column / row
+    fn right(&self) -> i32 {
+        self.x + self.width as i32
+    }
+
+    fn bottom(&self) -> i32 {
+        self.y + self.height as i32
+    }
+
+    /// The closest point to `pos` on this monitor
+    fn clamp(&self, pos: (i32, i32)) -> (i32, i32) {
+        (pos.0.clamp(self.x, self.right() - 1), pos.1.clamp(self.y, self.bottom() - 1))
+    }
+
+    fn distance_sq(&self, pos: (i32, i32)) -> i64 {
+        let (x, y) = self.clamp(pos);
+        let (dx, dy) = ((pos.0 - x) as i64, (pos.1 - y) as i64);
+        dx * dx + dy * dy
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub monitors: Vec<Monitor>,
+}
+
+impl Topology {
+    /// Just a `screen`-sized main display, for when nothing else is known
+    pub fn single(screen: (u32, u32)) -> Self {
+        Self { monitors: vec![Monitor { x: 0, y: 0, width: screen.0, height: screen.1, primary: true }] }
+    }
+
+    /// This machine's monitors; None where they can't be listed, the caller
+    /// then falls back to the main display's size
+    pub fn local() -> Option<Self> {
+        #[cfg(windows)]
+        {
+            let monitors = enumerate_monitors();
+            (!monitors.is_empty()).then_some(Self { monitors })
+        }
+
+        #[cfg(not(windows))]
+        {
+            None
+        }
+    }
+
+    /// Drops monitors without an area or too far out to be real (a peer's list
+    /// could say anything); None if none are left
+    pub fn validated(mut self) -> Option<Self> {
+        self.monitors.retain(|monitor| {
+            (1..=MAX_EXTENT).contains(&monitor.width)
+                && (1..=MAX_EXTENT).contains(&monitor.height)
+                && monitor.x.unsigned_abs() <= MAX_EXTENT
+                && monitor.y.unsigned_abs() <= MAX_EXTENT
+        });
+        (!self.monitors.is_empty()).then_some(self)
+    }
+
+    pub fn primary(&self) -> Option<&Monitor> {
+        self.monitors.iter().find(|monitor| monitor.primary).or(self.monitors.first())
+    }
+
+    /// Top-left corner and size of the box around all monitors
+    pub fn bounds(&self) -> ((i32, i32), (u32, u32)) {
+        let left = self.monitors.iter().map(|m| m.x).min().unwrap_or(0);
+        let top = self.monitors.iter().map(|m| m.y).min().unwrap_or(0);
+        let right = self.monitors.iter().map(Monitor::right).max().unwrap_or(0);
+        let bottom = self.monitors.iter().map(Monitor::bottom).max().unwrap_or(0);
+        ((left, top), ((right - left) as u32, (bottom - top) as u32))
+    }
+
+    /// The monitor `pos` is on, or the nearest one when it is in a gap between them
+    pub fn monitor_at(&self, pos: (i32, i32)) -> Option<&Monitor> {
+        self.monitors
+            .iter()
+            .find(|monitor| monitor.contains(pos))
+            .or_else(|| self.monitors.iter().min_by_key(|monitor| monitor.distance_sq(pos)))
+    }
+
+    /// Whether `pos` lies on `edge` of its monitor with no monitor beyond, i.e.
+    /// on the outside of the desktop rather than where two monitors meet
+    pub fn touches(&self, edge: ScreenEdge, pos: (i32, i32)) -> bool {
+        let Some(monitor) = self.monitor_at(pos) else {
+            return false;
+        };
+        let (on_edge, beyond) = match edge {
+            ScreenEdge::Left => (pos.0 <= monitor.x, (monitor.x - 1, pos.1)),
+            ScreenEdge::Right => (pos.0 >= monitor.right() - 1, (monitor.right(), pos.1)),
+            ScreenEdge::Top => (pos.1 <= monitor.y, (pos.0, monitor.y - 1)),
+            ScreenEdge::Bottom => (pos.1 >= monitor.bottom() - 1, (pos.0, monitor.bottom())),
+        };
+        on_edge && !self.monitors.iter().any(|other| other.contains(beyond))
+    }
+
+    /// Desktop position as sent to peers: relative to the bounding box
+    pub fn to_relative(&self, pos: (i32, i32)) -> (i32, i32) {
+        let (origin, _) = self.bounds();
+        (pos.0 - origin.0, pos.1 - origin.1)
+    }
+
+    pub fn from_relative(&self, pos: (i32, i32)) -> (i32, i32) {
+        let (origin, _) = self.bounds();
+        (pos.0 + origin.0, pos.1 + origin.1)
+    }
+
+    /// Where a cursor that left a `from`-sized desktop at `pos` (relative to it)
+    /// comes in over `edge` of this one. It keeps its relative position along the
+    /// edge, moved onto the outermost monitor on that side nearest to it.
+    pub fn entry_point(&self, edge: ScreenEdge, pos: (i32, i32), from: (u32, u32)) -> Option<(i32, i32)> {
+        let (_, size) = self.bounds();
+        let target = self.from_relative(edge.entry_point(pos, from, size));
+        let outermost = |monitor: &&Monitor| match edge {
+            ScreenEdge::Left => -monitor.x,
+            ScreenEdge::Right => monitor.right(),
+            ScreenEdge::Top => -monitor.y,
+            ScreenEdge::Bottom => monitor.bottom(),
+        };
+        let outer = self.monitors.iter().map(|monitor| outermost(&monitor)).max()?;
+        self.monitors
+            .iter()
+            .filter(|monitor| outermost(monitor) == outer)
+            .min_by_key(|monitor| monitor.distance_sq(target))
+            .map(|monitor| monitor.clamp(target))
+    }
+}
+
+#[cfg(windows)]
+fn enumerate_monitors() -> Vec<Monitor> {
+    #[repr(C)]
+    #[derive(Default)]
+    struct Rect {
+        left: i32,
+        top: i32,
+        right: i32,
+        bottom: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct MonitorInfo {
+        cb_size: u32,
+        monitor: Rect,
+        _work: Rect,
+        flags: u32,
+    }
+
+    extern "system" {
+        fn EnumDisplayMonitors(
+            hdc: isize,
+            clip: *const Rect,
+            callback: extern "system" fn(isize, isize, *mut Rect, isize) -> i32,
+            data: isize,
+        ) -> i32;
+        fn GetMonitorInfoW(monitor: isize, info: *mut MonitorInfo) -> i32;
+    }
+
+    const MONITORINFOF_PRIMARY: u32 = 1;
+
+    extern "system" fn add(monitor: isize, _hdc: isize, _rect: *mut Rect, data: isize) -> i32 {
+        let monitors = unsafe { &mut *(data as *mut Vec<Monitor>) };
+        let mut info = MonitorInfo { cb_size: std::mem::size_of::<MonitorInfo>() as u32, ..Default::default() };
+        if unsafe { GetMonitorInfoW(monitor, &mut info) } != 0 {
+            let rect = &info.monitor;
+            monitors.push(Monitor {
+                x: rect.left,
+                y: rect.top,
+                width: (rect.right - rect.left).max(0) as u32,
+                height: (rect.bottom - rect.top).max(0) as u32,
+                primary: info.flags & MONITORINFOF_PRIMARY != 0,
+            });
+        }
+        1
+    }
+
+    let mut monitors: Vec<Monitor> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(0, std::ptr::null(), add, &mut monitors as *mut Vec<Monitor> as isize);
+    }
+    monitors
+}
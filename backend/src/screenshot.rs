@@ -0,0 +1,345 @@
+//! Screenshots on demand: a small picture of a controlled machine's desktop,
+//! enough to see where its cursor should go without streaming the screen.
+//!
+//! The controlled side scales the whole virtual desktop down to at most
+//! MAX_SIDE pixels and sends it as RGB in Message::ScreenshotData chunks; the
+//! controller puts them back together and hands the frontend a BMP data: URL.
+//!
+//! For a machine without a monitor of its own the same pictures can also come
+//! as a stream of JPEG thumbnails, one or two a second. They go out like other
+//! bulk data: between input, and no faster than the connection's bulk cap.
+
+use crate::forwarder::WeakMessageSender;
+use crate::input_simulator::InputBackend;
+use crate::jpeg;
+use crate::protocol::Message;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Longer side of a screenshot in pixels
+pub const MAX_SIDE: u32 = 480;
+/// Thumbnails per second at most
+pub const MAX_THUMBNAIL_FPS: u8 = 2;
+// Well below MAX_FRAME_LEN
+const CHUNK_LEN: usize = 32 * 1024;
+// Sharp enough to read window titles at MAX_SIDE
+const THUMBNAIL_QUALITY: u8 = 50;
+// A thumbnail is some tens of KiB; larger ones are not accepted
+const MAX_THUMBNAIL_LEN: u32 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// Three bytes per pixel, row by row from the top
+    pub rgb: Vec<u8>,
+}
+
+impl Screenshot {
+    /// The messages that carry it to the controller
+    pub fn chunks(&self) -> impl Iterator<Item = Message> + '_ {
+        let (width, height) = (self.width, self.height);
+        self.rgb.chunks(CHUNK_LEN).enumerate().map(move |(i, data)| Message::ScreenshotData {
+            width,
+            height,
+            offset: (i * CHUNK_LEN) as u32,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn jpeg(&self) -> Vec<u8> {
+        jpeg::encode(self.width, self.height, &self.rgb, THUMBNAIL_QUALITY)
+    }
+
+    /// As an image the frontend can show directly
+    pub fn data_url(&self) -> String {
+        format!("data:image/bmp;base64,{}", base64(&self.bmp()))
+    }
+
+    fn bmp(&self) -> Vec<u8> {
+        const HEADER_LEN: u32 = 14 + 40;
+        // Rows are padded to whole 4-byte words
+        let row_len = (self.width * 3).div_ceil(4) * 4;
+        let file_len = HEADER_LEN + row_len * self.height;
+        let mut bmp = Vec::with_capacity(file_len as usize);
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&file_len.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&HEADER_LEN.to_le_bytes());
+        // BITMAPINFOHEADER; a negative height means the rows go top-down
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(self.width as i32).to_le_bytes());
+        bmp.extend_from_slice(&(-(self.height as i32)).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&[0; 24]);
+        let padding = (row_len - self.width * 3) as usize;
+        for row in self.rgb.chunks(self.width as usize * 3) {
+            bmp.extend(row.chunks(3).flat_map(|pixel| [pixel[2], pixel[1], pixel[0]]));
+            bmp.resize(bmp.len() + padding, 0);
+        }
+        bmp
+    }
+}
+
+/// The ThumbnailFrame messages carrying JPEG `jpeg` as thumbnail `seq`
+pub fn thumbnail_chunks(seq: u32, jpeg: &[u8]) -> impl Iterator<Item = Message> + '_ {
+    let total = jpeg.len() as u32;
+    jpeg.chunks(CHUNK_LEN).enumerate().map(move |(i, data)| Message::ThumbnailFrame {
+        seq,
+        offset: (i * CHUNK_LEN) as u32,
+        total,
+        data: data.to_vec(),
+    })
+}
+
+/// A thumbnail as an image the frontend can show directly
+pub fn jpeg_data_url(jpeg: &[u8]) -> String {
+    format!("data:image/jpeg;base64,{}", base64(jpeg))
+}
+
+/// Controlled side: a screenshot as a JPEG thumbnail `fps` times a second,
+/// until the connection closes or the task is aborted. Each chunk waits for
+/// the connection's queue to empty, and for `cap` bytes per second if set.
+pub async fn stream_thumbnails(tx: WeakMessageSender, simulator: Arc<dyn InputBackend>, fps: u8, cap: Option<u64>) {
+    let fps = fps.clamp(1, MAX_THUMBNAIL_FPS);
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / fps as u64));
+    // A slow connection gets fewer thumbnails, not a burst of old ones
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let started = Instant::now();
+    let mut sent: u64 = 0;
+    for seq in 0u32.. {
+        interval.tick().await;
+        let simulator = Arc::clone(&simulator);
+        let jpeg = tokio::task::spawn_blocking(move || simulator.screenshot().map(|screenshot| screenshot.jpeg()))
+            .await
+            .ok()
+            .flatten();
+        let Some(jpeg) = jpeg else {
+            println!("无法读取屏幕，停止发送缩略图");
+            if let Some(tx) = tx.upgrade() {
+                let _ = tx.send(Message::ThumbnailStop);
+            }
+            return;
+        };
+        for msg in thumbnail_chunks(seq, &jpeg) {
+            if let Some(cap) = cap.filter(|cap| *cap > 0) {
+                let due = Duration::from_secs_f64(sent as f64 / cap as f64);
+                tokio::time::sleep(due.saturating_sub(started.elapsed())).await;
+            }
+            let Some(tx) = tx.upgrade() else {
+                return;
+            };
+            while tx.backlog() > 0 {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            if let Message::ThumbnailFrame { data, .. } = &msg {
+                sent += data.len() as u64;
+            }
+            if tx.send(msg).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Puts the chunks of a peer's thumbnails back together
+#[derive(Debug, Default)]
+pub struct ThumbnailAssembler {
+    // (seq, total, bytes so far)
+    pending: Option<(u32, u32, Vec<u8>)>,
+}
+
+impl ThumbnailAssembler {
+    /// Adds a ThumbnailFrame chunk; the JPEG once it is complete. A chunk at
+    /// offset 0 starts a new thumbnail, anything out of order drops it.
+    pub fn add(&mut self, seq: u32, offset: u32, total: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+        if offset == 0 {
+            self.pending = (1..=MAX_THUMBNAIL_LEN).contains(&total).then(|| (seq, total, Vec::with_capacity(total as usize)));
+        }
+        let (pending_seq, pending_total, jpeg) = self.pending.as_mut()?;
+        if (*pending_seq, *pending_total) != (seq, total) || offset as usize != jpeg.len() || jpeg.len() + data.len() > total as usize {
+            self.pending = None;
+            return None;
+        }
+        jpeg.extend_from_slice(&data);
+        if jpeg.len() < total as usize {
+            return None;
+        }
+        self.pending.take().map(|(_, _, jpeg)| jpeg)
+    }
+}
+
+/// Puts the chunks of a peer's screenshot back together
+#[derive(Debug, Default)]
+pub struct Assembler {
+    pending: Option<Screenshot>,
+}
+
+impl Assembler {
+    /// Adds a ScreenshotData chunk; the screenshot once it is complete. A chunk
+    /// at offset 0 starts a new one, anything out of order or too big drops it.
+    pub fn add(&mut self, width: u32, height: u32, offset: u32, data: Vec<u8>) -> Option<Screenshot> {
+        if offset == 0 {
+            let valid = (1..=MAX_SIDE).contains(&width) && (1..=MAX_SIDE).contains(&height);
+            self.pending = valid.then(|| Screenshot { width, height, rgb: Vec::with_capacity((width * height * 3) as usize) });
+        }
+        let pending = self.pending.as_mut()?;
+        let len = (pending.width * pending.height * 3) as usize;
+        if (pending.width, pending.height) != (width, height) || offset as usize != pending.rgb.len() || pending.rgb.len() + data.len() > len {
+            self.pending = None;
+            return None;
+        }
+        pending.rgb.extend_from_slice(&data);
+        if pending.rgb.len() < len {
+            return None;
+        }
+        self.pending.take()
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Size to scale a `size` desktop to, keeping its aspect ratio
+pub fn scaled_size(size: (u32, u32)) -> (u32, u32) {
+    let longer = size.0.max(size.1).max(1);
+    if longer <= MAX_SIDE {
+        return size;
+    }
+    let scale = |side: u32| ((side as u64 * MAX_SIDE as u64 / longer as u64) as u32).max(1);
+    (scale(size.0), scale(size.1))
+}
+
+/// The whole virtual desktop, scaled down; None where it can't be read.
+/// Only asked of a machine being controlled, so only with the inject feature.
+#[cfg(feature = "inject")]
+pub fn capture() -> Option<Screenshot> {
+    #[cfg(windows)]
+    {
+        capture_gdi()
+    }
+
+    #[cfg(not(windows))]
+    {
+        None
+    }
+}
+
+#[cfg(all(feature = "inject", windows))]
+fn capture_gdi() -> Option<Screenshot> {
+    #[repr(C)]
+    struct BitmapInfoHeader {
+        size: u32,
+        width: i32,
+        height: i32,
+        planes: u16,
+        bit_count: u16,
+        compression: u32,
+        size_image: u32,
+        x_pels_per_meter: i32,
+        y_pels_per_meter: i32,
+        clr_used: u32,
+        clr_important: u32,
+    }
+
+    extern "system" {
+        fn GetSystemMetrics(index: i32) -> i32;
+        fn GetDC(window: isize) -> isize;
+        fn ReleaseDC(window: isize, dc: isize) -> i32;
+    }
+    #[link(name = "gdi32")]
+    extern "system" {
+        fn CreateCompatibleDC(dc: isize) -> isize;
+        fn CreateCompatibleBitmap(dc: isize, width: i32, height: i32) -> isize;
+        fn SelectObject(dc: isize, object: isize) -> isize;
+        fn SetStretchBltMode(dc: isize, mode: i32) -> i32;
+        fn SetBrushOrgEx(dc: isize, x: i32, y: i32, previous: *mut [i32; 2]) -> i32;
+        fn StretchBlt(
+            dest: isize,
+            dest_x: i32,
+            dest_y: i32,
+            dest_width: i32,
+            dest_height: i32,
+            src: isize,
+            src_x: i32,
+            src_y: i32,
+            src_width: i32,
+            src_height: i32,
+            rop: u32,
+        ) -> i32;
+        fn GetDIBits(dc: isize, bitmap: isize, start: u32, lines: u32, bits: *mut u8, info: *mut BitmapInfoHeader, usage: u32) -> i32;
+        fn DeleteObject(object: isize) -> i32;
+        fn DeleteDC(dc: isize) -> i32;
+    }
+
+    const SM_XVIRTUALSCREEN: i32 = 76;
+    const SM_YVIRTUALSCREEN: i32 = 77;
+    const SM_CXVIRTUALSCREEN: i32 = 78;
+    const SM_CYVIRTUALSCREEN: i32 = 79;
+    const HALFTONE: i32 = 4;
+    const SRCCOPY: u32 = 0x00CC_0020;
+    // Include layered windows
+    const CAPTUREBLT: u32 = 0x4000_0000;
+
+    unsafe {
+        let (x, y) = (GetSystemMetrics(SM_XVIRTUALSCREEN), GetSystemMetrics(SM_YVIRTUALSCREEN));
+        let desktop = (GetSystemMetrics(SM_CXVIRTUALSCREEN), GetSystemMetrics(SM_CYVIRTUALSCREEN));
+        if desktop.0 <= 0 || desktop.1 <= 0 {
+            return None;
+        }
+        let (width, height) = scaled_size((desktop.0 as u32, desktop.1 as u32));
+
+        let screen = GetDC(0);
+        if screen == 0 {
+            return None;
+        }
+        let memory = CreateCompatibleDC(screen);
+        let bitmap = CreateCompatibleBitmap(screen, width as i32, height as i32);
+        let previous = SelectObject(memory, bitmap);
+        SetStretchBltMode(memory, HALFTONE);
+        SetBrushOrgEx(memory, 0, 0, std::ptr::null_mut());
+        let copied = StretchBlt(memory, 0, 0, width as i32, height as i32, screen, x, y, desktop.0, desktop.1, SRCCOPY | CAPTUREBLT);
+        SelectObject(memory, previous);
+
+        // 32 bits per pixel has no row padding; top-down like Screenshot
+        let mut info = BitmapInfoHeader {
+            size: std::mem::size_of::<BitmapInfoHeader>() as u32,
+            width: width as i32,
+            height: -(height as i32),
+            planes: 1,
+            bit_count: 32,
+            compression: 0,
+            size_image: 0,
+            x_pels_per_meter: 0,
+            y_pels_per_meter: 0,
+            clr_used: 0,
+            clr_important: 0,
+        };
+        let mut bgra = vec![0u8; (width * height * 4) as usize];
+        let lines = if copied != 0 { GetDIBits(memory, bitmap, 0, height, bgra.as_mut_ptr(), &mut info, 0) } else { 0 };
+
+        DeleteObject(bitmap);
+        DeleteDC(memory);
+        ReleaseDC(0, screen);
+
+        if lines != height as i32 {
+            return None;
+        }
+        let rgb = bgra.chunks(4).flat_map(|pixel| [pixel[2], pixel[1], pixel[0]]).collect();
+        Some(Screenshot { width, height, rgb })
+    }
+}
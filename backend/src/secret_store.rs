@@ -0,0 +1,265 @@
+//! Secrets at rest (peer keys, resume tokens, pairing data), kept where the OS
+//! protects them for the logged-in user, so a config directory copied off a
+//! stolen laptop doesn't carry them along.
+//!
+//! Windows: DPAPI-encrypted files. macOS: the login Keychain. Linux: the
+//! Secret Service via secret-tool, or an owner-only file when there is none.
+
+use crate::persist;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Keychain service name all secrets are filed under
+#[cfg(not(windows))]
+const SERVICE: &str = "ShareFlow";
+
+/// Where a secret ended up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Protection {
+    Dpapi,
+    Keychain,
+    SecretService,
+    /// No OS store was available: a file only this user can read, but unencrypted
+    Plaintext,
+}
+
+pub struct SecretStore {
+    dir: PathBuf,
+}
+
+impl SecretStore {
+    /// `dir` holds DPAPI blobs and the plaintext fallback
+    pub fn new(dir: PathBuf) -> Self {
+        SecretStore { dir }
+    }
+
+    pub fn save(&self, name: &str, secret: &[u8]) -> Result<Protection> {
+        check_name(name)?;
+        #[cfg(windows)]
+        {
+            let blob = dpapi::protect(secret)?;
+            persist::write_atomic(&self.dir.join(format!("{}.dpapi", name)), &blob)?;
+            Ok(Protection::Dpapi)
+        }
+        #[cfg(not(windows))]
+        {
+            match keychain::store(name, &hex_encode(secret)) {
+                Ok(protection) => {
+                    // An older plaintext copy must not outlive the protected one
+                    let _ = std::fs::remove_file(self.plaintext_path(name));
+                    Ok(protection)
+                }
+                Err(e) => {
+                    eprintln!("⚠ 无法使用系统钥匙串保存 {}，改用仅本用户可读的明文文件: {}", name, e);
+                    persist::write_atomic(&self.plaintext_path(name), hex_encode(secret).as_bytes())?;
+                    Ok(Protection::Plaintext)
+                }
+            }
+        }
+    }
+
+    /// None if nothing was saved under `name`
+    pub fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        check_name(name)?;
+        #[cfg(windows)]
+        {
+            let path = self.dir.join(format!("{}.dpapi", name));
+            match std::fs::read(&path) {
+                Ok(blob) => Ok(Some(dpapi::unprotect(&blob)?)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e).with_context(|| format!("cannot read {}", path.display())),
+            }
+        }
+        #[cfg(not(windows))]
+        {
+            if let Some(hex) = keychain::lookup(name) {
+                return hex_decode(&hex).map(Some);
+            }
+            match std::fs::read_to_string(self.plaintext_path(name)) {
+                Ok(hex) => hex_decode(&hex).map(Some),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        #[cfg(windows)]
+        let path = self.dir.join(format!("{}.dpapi", name));
+        #[cfg(not(windows))]
+        let path = {
+            keychain::remove(name);
+            self.plaintext_path(name)
+        };
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn plaintext_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.secret", name))
+    }
+}
+
+/// Names become file names and keychain accounts
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("invalid secret name: {:?}", name);
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(not(windows))]
+fn hex_decode(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) {
+        bail!("corrupt secret");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("corrupt secret"))
+        .collect()
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use anyhow::{bail, Result};
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    struct DataBlob {
+        len: u32,
+        data: *mut u8,
+    }
+
+    #[link(name = "crypt32")]
+    extern "system" {
+        fn CryptProtectData(
+            data_in: *const DataBlob,
+            description: *const u16,
+            entropy: *const DataBlob,
+            reserved: *mut c_void,
+            prompt: *mut c_void,
+            flags: u32,
+            data_out: *mut DataBlob,
+        ) -> i32;
+        fn CryptUnprotectData(
+            data_in: *const DataBlob,
+            description: *mut *mut u16,
+            entropy: *const DataBlob,
+            reserved: *mut c_void,
+            prompt: *mut c_void,
+            flags: u32,
+            data_out: *mut DataBlob,
+        ) -> i32;
+    }
+
+    extern "system" {
+        fn LocalFree(mem: *mut c_void) -> *mut c_void;
+    }
+
+    const CRYPTPROTECT_UI_FORBIDDEN: u32 = 0x1;
+
+    /// Encrypted for the current Windows user
+    pub fn protect(data: &[u8]) -> Result<Vec<u8>> {
+        transform(data, true)
+    }
+
+    pub fn unprotect(blob: &[u8]) -> Result<Vec<u8>> {
+        transform(blob, false)
+    }
+
+    fn transform(data: &[u8], protect: bool) -> Result<Vec<u8>> {
+        let input = DataBlob { len: data.len() as u32, data: data.as_ptr() as *mut u8 };
+        let mut output = DataBlob { len: 0, data: std::ptr::null_mut() };
+        let null = std::ptr::null_mut();
+        let ok = unsafe {
+            if protect {
+                CryptProtectData(&input, std::ptr::null(), std::ptr::null(), null, null, CRYPTPROTECT_UI_FORBIDDEN, &mut output)
+            } else {
+                CryptUnprotectData(&input, std::ptr::null_mut(), std::ptr::null(), null, null, CRYPTPROTECT_UI_FORBIDDEN, &mut output)
+            }
+        };
+        if ok == 0 {
+            // Unprotecting fails as well for blobs from another user or machine
+            bail!("DPAPI failed: {}", std::io::Error::last_os_error());
+        }
+        let result = unsafe { std::slice::from_raw_parts(output.data, output.len as usize).to_vec() };
+        unsafe {
+            LocalFree(output.data as *mut c_void);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(not(windows))]
+mod keychain {
+    use super::{Protection, SERVICE};
+    use anyhow::{bail, Context, Result};
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    /// Run `program` with `input` on stdin, so the secret never shows up in a process list
+    fn run(program: &str, args: &[&str], input: &str) -> Result<String> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("cannot start {}", program))?;
+        child.stdin.take().context("no stdin")?.write_all(input.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("{} exited with {}", program, output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn store(name: &str, hex: &str) -> Result<Protection> {
+        // `security -i` reads commands from stdin
+        let command = format!("add-generic-password -U -s {} -a {} -w {}\n", SERVICE, name, hex);
+        run("security", &["-i"], &command)?;
+        Ok(Protection::Keychain)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn lookup(name: &str) -> Option<String> {
+        run("security", &["find-generic-password", "-s", SERVICE, "-a", name, "-w"], "").ok()
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn remove(name: &str) {
+        let _ = run("security", &["delete-generic-password", "-s", SERVICE, "-a", name], "");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn store(name: &str, hex: &str) -> Result<Protection> {
+        let label = format!("{} {}", SERVICE, name);
+        run("secret-tool", &["store", "--label", &label, "service", SERVICE, "account", name], hex)?;
+        Ok(Protection::SecretService)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn lookup(name: &str) -> Option<String> {
+        run("secret-tool", &["lookup", "service", SERVICE, "account", name], "")
+            .ok()
+            .filter(|hex| !hex.trim().is_empty())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn remove(name: &str) {
+        let _ = run("secret-tool", &["clear", "service", SERVICE, "account", name], "");
+    }
+}
@@ -0,0 +1,311 @@
+use crate::capabilities::{self, Capability};
+use crate::discovery::Discovery;
+use crate::firewall::{self, FirewallState};
+use crate::input_simulator::InputBackend;
+use crate::websocket::{Command, Event};
+use anyhow::{bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but something is likely to get in the way
+    Warn,
+    Fail,
+    /// Not applicable on this platform or in this situation
+    Skipped,
+}
+
+/// One line of the self-check report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: Option<String>,
+    /// What the user can do about a warning or failure
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Check { name: name.to_string(), status, detail: Some(detail.into()), hint: None }
+    }
+
+    fn hint(mut self, hint: &str) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfCheckReport {
+    pub platform: String,
+    pub checks: Vec<Check>,
+}
+
+/// Which ports to check and who holds them
+pub struct SelfCheckTarget {
+    pub peer_port: u16,
+    pub ws_port: u16,
+    pub web_port: Option<u16>,
+    /// Running inside the backend, which has already bound the ports itself
+    pub in_process: bool,
+}
+
+/// Run every check; takes about a second because of the broadcast echo and the simulator round trip
+pub async fn run(target: &SelfCheckTarget, simulator: &dyn InputBackend) -> SelfCheckReport {
+    let mut checks = Vec::new();
+    checks.extend(check_ports(target).await);
+    checks.push(check_broadcast().await);
+
+    let capabilities = capabilities::probe();
+    checks.push(from_capability("capture", &capabilities.capture));
+    checks.push(from_capability("injection", &capabilities.injection));
+    checks.push(from_capability("hotkeys", &capabilities.hotkeys));
+    checks.push(check_elevation());
+    checks.push(check_firewall(target.peer_port));
+    checks.push(check_simulator(simulator, capabilities.injection.available).await);
+
+    SelfCheckReport { platform: capabilities.platform, checks }
+}
+
+async fn check_ports(target: &SelfCheckTarget) -> Vec<Check> {
+    let mut ports = vec![
+        ("tcp", "peer connections", target.peer_port),
+        ("udp", "discovery", target.peer_port),
+        ("tcp", "frontend websocket", target.ws_port),
+    ];
+    if let Some(web_port) = target.web_port {
+        ports.push(("tcp", "web ui", web_port));
+    }
+
+    let mut checks = Vec::new();
+    for (protocol, purpose, port) in ports {
+        let name = format!("port {}/{} ({})", protocol, port, purpose);
+        if target.in_process {
+            checks.push(Check::new(&name, CheckStatus::Pass, "bound by this instance"));
+            continue;
+        }
+        let addr = format!("0.0.0.0:{}", port);
+        let result = match protocol {
+            "tcp" => TcpListener::bind(&addr).await.map(drop),
+            _ => UdpSocket::bind(&addr).await.map(drop),
+        };
+        checks.push(match result {
+            Ok(()) => Check::new(&name, CheckStatus::Pass, "free"),
+            Err(e) => Check::new(&name, CheckStatus::Fail, e.to_string())
+                .hint("another program (or another ShareFlow) is using this port"),
+        });
+    }
+    checks
+}
+
+/// Broadcast to every interface the discovery would use and see whether it comes back to us
+async fn check_broadcast() -> Check {
+    const NAME: &str = "broadcast";
+    let listener = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => return Check::new(NAME, CheckStatus::Fail, format!("cannot bind a UDP socket: {}", e)),
+    };
+    let port = match listener.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(e) => return Check::new(NAME, CheckStatus::Fail, e.to_string()),
+    };
+    let discovery = match Discovery::new(port).await {
+        Ok(discovery) => discovery,
+        Err(e) => return Check::new(NAME, CheckStatus::Fail, format!("cannot create a broadcast socket: {}", e)),
+    };
+    if discovery.broadcast_addrs().is_empty() {
+        return Check::new(NAME, CheckStatus::Fail, "no usable network interface")
+            .hint("connect to the same LAN as the other machine, or add it as a static peer");
+    }
+
+    let sender = match UdpSocket::bind("0.0.0.0:0").await.and_then(|s| s.set_broadcast(true).map(|_| s)) {
+        Ok(socket) => socket,
+        Err(e) => return Check::new(NAME, CheckStatus::Fail, e.to_string()),
+    };
+    let mut failed = Vec::new();
+    for (i, addr) in discovery.broadcast_addrs().iter().enumerate() {
+        if let Err(e) = sender.send_to(&[i as u8], addr).await {
+            failed.push(format!("{}: {}", addr.ip(), e));
+        }
+    }
+
+    let mut echoed = Vec::new();
+    let mut buf = [0u8; 16];
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(500);
+    while echoed.len() + failed.len() < discovery.broadcast_addrs().len() {
+        match tokio::time::timeout_at(deadline, listener.recv_from(&mut buf)).await {
+            Ok(Ok((1, _))) => {
+                if let Some(addr) = discovery.broadcast_addrs().get(buf[0] as usize) {
+                    echoed.push(addr.ip().to_string());
+                }
+            }
+            Ok(_) => continue,
+            Err(_) => break,
+        }
+    }
+
+    let missing: Vec<String> = discovery
+        .broadcast_addrs()
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .filter(|ip| !echoed.contains(ip) && !failed.iter().any(|f| f.starts_with(ip.as_str())))
+        .collect();
+    let mut detail = format!("echoed: {}", if echoed.is_empty() { "none".to_string() } else { echoed.join(", ") });
+    if !missing.is_empty() {
+        detail.push_str(&format!("; no echo: {}", missing.join(", ")));
+    }
+    if !failed.is_empty() {
+        detail.push_str(&format!("; send failed: {}", failed.join(", ")));
+    }
+
+    if echoed.is_empty() {
+        Check::new(NAME, CheckStatus::Fail, detail)
+            .hint("broadcasts are blocked on this machine, most likely by a firewall or VPN")
+    } else if missing.is_empty() && failed.is_empty() {
+        Check::new(NAME, CheckStatus::Pass, detail)
+    } else {
+        Check::new(NAME, CheckStatus::Warn, detail)
+            .hint("discovery only works on the interfaces that echoed")
+    }
+}
+
+fn from_capability(name: &str, capability: &Capability) -> Check {
+    let status = match (capability.available, &capability.reason) {
+        (false, _) => CheckStatus::Fail,
+        (true, Some(_)) => CheckStatus::Warn,
+        (true, None) => CheckStatus::Pass,
+    };
+    Check { name: name.to_string(), status, detail: capability.reason.clone(), hint: None }
+}
+
+#[cfg(windows)]
+fn check_elevation() -> Check {
+    #[link(name = "shell32")]
+    extern "system" {
+        fn IsUserAnAdmin() -> i32;
+    }
+    // SAFETY: no arguments, only reads the current process token
+    if unsafe { IsUserAnAdmin() } != 0 {
+        Check::new("elevation", CheckStatus::Pass, "running as administrator")
+    } else {
+        Check::new("elevation", CheckStatus::Warn, "not running as administrator")
+            .hint("Windows blocks injected input into elevated windows (installers, Task Manager) unless ShareFlow runs as administrator too")
+    }
+}
+
+#[cfg(not(windows))]
+fn check_elevation() -> Check {
+    // Unix permissions are covered by the capture/injection checks
+    Check { name: "elevation".to_string(), status: CheckStatus::Skipped, detail: None, hint: None }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program).args(args).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// On Windows the rules are checked for our port; elsewhere only a hint whether a firewall is on at all
+fn check_firewall(port: u16) -> Check {
+    const NAME: &str = "firewall";
+    let status = firewall::status(port);
+    let detail = status.detail.clone().unwrap_or_default();
+    match status.state {
+        FirewallState::Off => return Check::new(NAME, CheckStatus::Pass, "firewall is off"),
+        FirewallState::Allowed => return Check::new(NAME, CheckStatus::Pass, format!("port {} is allowed", port)),
+        FirewallState::Blocked => {
+            return Check::new(NAME, CheckStatus::Fail, detail).hint("let ShareFlow add the firewall rules (needs administrator approval)")
+        }
+        FirewallState::Unknown => return Check::new(NAME, CheckStatus::Skipped, detail),
+        FirewallState::Unsupported => {}
+    }
+
+    let enabled = if cfg!(target_os = "macos") {
+        command_output("/usr/libexec/ApplicationFirewall/socketfilterfw", &["--getglobalstate"])
+            .map(|out| out.contains("enabled"))
+    } else {
+        let ufw = std::fs::read_to_string("/etc/ufw/ufw.conf")
+            .map(|conf| conf.lines().any(|line| line.trim() == "ENABLED=yes"))
+            .unwrap_or(false);
+        let firewalld = command_output("firewall-cmd", &["--state"]).is_some_and(|out| out.trim() == "running");
+        Some(ufw || firewalld)
+    };
+
+    match enabled {
+        Some(true) => Check::new(NAME, CheckStatus::Warn, "a firewall is enabled")
+            .hint(&format!("allow inbound UDP and TCP port {} for ShareFlow, or devices will not find each other", port)),
+        Some(false) => Check::new(NAME, CheckStatus::Pass, "no firewall enabled"),
+        None => Check { name: NAME.to_string(), status: CheckStatus::Skipped, detail: Some("could not query the firewall".to_string()), hint: None },
+    }
+}
+
+/// Nudge the cursor one pixel and back, and see that it actually moved
+async fn check_simulator(simulator: &dyn InputBackend, injection_available: bool) -> Check {
+    const NAME: &str = "simulator";
+    if !injection_available {
+        return Check { name: NAME.to_string(), status: CheckStatus::Skipped, detail: Some("injection unavailable".to_string()), hint: None };
+    }
+    let Some(before) = simulator.cursor_position() else {
+        return Check::new(NAME, CheckStatus::Fail, "cannot read the cursor position");
+    };
+    // Move away from the right screen edge, where +1 would be clamped
+    let dx = if simulator.screen_size().is_some_and(|(w, _)| before.0 + 1 >= w as i32) { -1 } else { 1 };
+    simulator.mouse_move(dx, 0);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let after = simulator.cursor_position();
+    simulator.mouse_move(-dx, 0);
+
+    match after {
+        Some(after) if after != before => Check::new(NAME, CheckStatus::Pass, "injected mouse movement arrived"),
+        _ => Check::new(NAME, CheckStatus::Fail, "injected mouse movement had no effect")
+            .hint("the OS is discarding synthetic input; check accessibility/input permissions"),
+    }
+}
+
+/// Text report for the `selfcheck` command
+pub fn render(report: &SelfCheckReport) -> String {
+    let mut out = format!("ShareFlow self-check ({})\n", report.platform);
+    for check in &report.checks {
+        let mark = match check.status {
+            CheckStatus::Pass => "✓",
+            CheckStatus::Warn => "!",
+            CheckStatus::Fail => "✗",
+            CheckStatus::Skipped => "-",
+        };
+        match &check.detail {
+            Some(detail) => out.push_str(&format!("{} {:<32} {}\n", mark, check.name, detail)),
+            None => out.push_str(&format!("{} {}\n", mark, check.name)),
+        }
+        if let Some(hint) = &check.hint {
+            out.push_str(&format!("  → {}\n", hint));
+        }
+    }
+    out
+}
+
+/// Ask a running backend for its report; None when nothing is listening on `ws_port`
+pub async fn request(ws_port: u16) -> Result<Option<SelfCheckReport>> {
+    let url = format!("ws://127.0.0.1:{}", ws_port);
+    let Ok((mut ws, _)) = connect_async(&url).await else {
+        return Ok(None);
+    };
+    ws.send(Message::Text(serde_json::to_string(&Command::RunDiagnostics)?)).await?;
+    loop {
+        let Some(msg) = ws.next().await else {
+            bail!("ShareFlow closed the connection");
+        };
+        if let Message::Text(text) = msg? {
+            if let Ok(Event::DiagnosticsReport { report }) = serde_json::from_str(&text) {
+                return Ok(Some(report));
+            }
+        }
+    }
+}
@@ -0,0 +1,2272 @@
+use anyhow::Result;
+use crate::diagnostics::{self, Stage};
+use crate::echo::EchoTracker;
+use crate::error::{PairingError, SessionError, TransportError};
+use crate::discovery::{BroadcastDiscovery, DiscoveryBackend, DiscoverySource, ManualPeers, Sighting, StaticPeers};
+use crate::protocol::{self, Message, PeerFeature, Permission, RejectReason, MIN_PROTOCOL_VERSION, WHEEL_DELTA};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+// use tokio::time::Duration;
+use crate::transport::{FlushStrategy, Identity, PeerStream, MAX_FLUSH_INTERVAL_MS};
+use crate::websocket::{CaptureStopReason, Command, DeviceInfo, Event, InputEvent, VisualizationFilter, WebSocketServer};
+use crate::audit::AuditLog;
+use crate::capabilities;
+use crate::clipboard::{self, ClipboardEvent};
+use crate::file_transfer::{self, FileEvent};
+use crate::config::{self, AppConfig};
+use crate::capture_watch::CaptureWatch;
+use crate::firewall::{self, FirewallState};
+use crate::accessibility::{self, AccessibilityState};
+use crate::forwarder::{ActiveConnections, InputForwarder};
+use crate::input_capture::{self, CaptureControl, EdgeWatch, GrabExclusions, Hotkey, InputCapture, LocalInputLock, LockMirror, Shortcut};
+use crate::input_simulator::InputBackend;
+#[cfg(not(feature = "inject"))]
+use crate::input_simulator::NoInjection;
+#[cfg(feature = "inject")]
+use crate::input_simulator::InputSimulator;
+use crate::lockout::{AttemptLimiter, PromptMuter, MUTE_DURATION};
+use crate::pairing::{self, Pairing, Trust};
+use crate::privacy::{LoggedCommand, LoggedMessage, Redacted};
+use crate::secret_store::SecretStore;
+use crate::screenshot;
+use crate::self_check::{self, SelfCheckTarget};
+use crate::settings::{self, Direction, Settings};
+use crate::session::{self, ControlGrant, EdgeReturn, Lifecycle, Role, SessionContext, SessionState};
+use crate::stats::ConnectionStats;
+use crate::web_server;
+
+/// Everything that differs between backend instances, so several can run in one process
+pub struct BackendConfig {
+    pub device_id: String,
+    pub device_name: String,
+    /// TCP port for peer connections, also used for UDP discovery
+    pub peer_port: u16,
+    pub ws_port: u16,
+    /// Also serve the frontend API on this Unix socket or named pipe (`\\.\pipe\...`),
+    /// for machines where local TCP listeners are blocked
+    pub ipc: Option<PathBuf>,
+    /// None skips the bundled web UI and the browser launch
+    pub web_port: Option<u16>,
+    pub discovery: bool,
+    /// How often discovery announces us
+    pub broadcast_interval: std::time::Duration,
+    /// Global hotkeys need an OS input hook, which headless instances may not have
+    pub hotkeys: bool,
+    /// Starts capture toward the connected peer
+    pub capture_hotkey: Shortcut,
+    /// Where the audit log of injected input goes once it is switched on
+    pub audit_log: PathBuf,
+    /// JSON file with the user's settings (device slots, transport options)
+    pub settings: PathBuf,
+    /// JSON file with the keys of paired devices; our own key goes to the secret
+    /// store, with its file fallback in the same directory
+    pub paired_devices: PathBuf,
+    /// TOML config file (see `config`); its auto-accept list is read at startup,
+    /// everything else was already applied by whoever built this config
+    pub config_file: PathBuf,
+    /// Where files received from peers are saved
+    pub downloads: PathBuf,
+    /// Peers that can be connected to directly without waiting for a broadcast
+    pub static_peers: Vec<DeviceInfo>,
+    /// Where input received from a controller is injected
+    pub simulator: Arc<dyn InputBackend>,
+}
+
+impl BackendConfig {
+    /// Defaults for the desktop app: identity from the hostname, then the config
+    /// file, then SHAREFLOW_NAME / SHAREFLOW_ID
+    pub fn from_host() -> Self {
+        let hostname = hostname::get()
+            .ok()
+            .and_then(|h| h.into_string().ok())
+            .unwrap_or_else(|| "Unknown".to_string());
+        let env = |name| std::env::var(name).ok().filter(|value: &String| !value.is_empty());
+        
+        let mut config = Self {
+            // Create unique ID from hostname (you can also use MAC address or UUID)
+            device_id: device_id_for(&hostname),
+            // Use hostname as device name
+            device_name: hostname,
+            peer_port: 8080,
+            ws_port: 4000,
+            ipc: std::env::var_os("SHAREFLOW_IPC").map(PathBuf::from),
+            web_port: Some(3000),
+            discovery: true,
+            broadcast_interval: std::time::Duration::from_secs(1),
+            hotkeys: true,
+            capture_hotkey: Shortcut::default(),
+            static_peers: Vec::new(),
+            audit_log: dirs::data_local_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("ShareFlow")
+                .join("audit.log"),
+            settings: dirs::config_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("ShareFlow")
+                .join("settings.json"),
+            paired_devices: dirs::config_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("ShareFlow")
+                .join("paired_devices.json"),
+            config_file: config::default_path(),
+            downloads: dirs::download_dir().unwrap_or_else(std::env::temp_dir).join("ShareFlow"),
+            #[cfg(feature = "inject")]
+            simulator: Arc::new(InputSimulator::new()),
+            #[cfg(not(feature = "inject"))]
+            simulator: Arc::new(NoInjection),
+        };
+        AppConfig::load(&config.config_file).apply(&mut config);
+        config.with_identity(env("SHAREFLOW_NAME"), env("SHAREFLOW_ID"))
+    }
+
+    /// Override the broadcast identity, for containers and VMs whose hostnames
+    /// are meaningless or shared. A new name without an ID also derives the ID from it.
+    pub fn with_identity(mut self, name: Option<String>, id: Option<String>) -> Self {
+        if let Some(name) = name {
+            self.device_id = device_id_for(&name);
+            self.device_name = name;
+        }
+        if let Some(id) = id {
+            self.device_id = id;
+        }
+        self
+    }
+}
+
+/// Devices meant to skip the prompt: auto-accept groups and the config file's list.
+/// Their requests are only accepted unasked when the handshake proved their paired key.
+fn auto_accepted(settings: &Settings, app_config: &AppConfig) -> std::collections::HashSet<String> {
+    let mut devices = settings.auto_accepted();
+    devices.extend(app_config.auto_accept.iter().cloned());
+    devices
+}
+
+fn device_id_for(name: &str) -> String {
+    format!("device-{}", name.replace(" ", "-").to_lowercase())
+}
+
+// Outgoing connection attempts: target device ID -> (attempt ID, cancel sender)
+type OutgoingRequests = HashMap<String, (u64, tokio::sync::oneshot::Sender<()>)>;
+
+/// How long open connection requests survive without any frontend, e.g. while the page reloads
+const FRONTEND_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Forget a finished outgoing attempt, unless a newer attempt to the same device replaced it
+async fn finish_outgoing_attempt(requests: &Mutex<OutgoingRequests>, device_id: &str, attempt_id: u64) -> bool {
+    let mut requests = requests.lock().await;
+    if requests.get(device_id).map(|(id, _)| *id) == Some(attempt_id) {
+        requests.remove(device_id);
+        return true;
+    }
+    false
+}
+
+// The peer's expiry sweep runs every 5 seconds, so its Timeout answer can come that much late
+const PENDING_GRACE: std::time::Duration = std::time::Duration::from_secs(6);
+
+async fn connect_tcp(addr: &str) -> Result<TcpStream, SessionError> {
+    let stream = match tokio::time::timeout(session::CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(SessionError::Unreachable(e)),
+        Err(_) => return Err(SessionError::ConnectTimeout),
+    };
+    println!("  ✓ TCP 连接成功: {}", addr);
+    if let Err(e) = stream.set_nodelay(true) {
+        eprintln!("Failed to set TCP_NODELAY: {}", e);
+    }
+    Ok(stream)
+}
+
+/// Outgoing connection setup, first half: connect, run the Noise handshake and
+/// exchange Hellos, for each of which the peer has `wait`
+async fn open_peer_stream(addr: &str, identity: &Identity, wait: std::time::Duration, allow_plaintext: bool) -> Result<PeerStream, SessionError> {
+    let stream = connect_tcp(addr).await?;
+    println!("  建立加密通道...");
+    let mut stream = match tokio::time::timeout(wait, PeerStream::initiate(stream, identity)).await {
+        Ok(Ok(stream)) => stream,
+        // Peers that don't encrypt hang up on a first frame they can't decode
+        Ok(Err(TransportError::Closed)) if allow_plaintext => {
+            println!("  ⚠ 对方不支持加密，改用明文连接");
+            PeerStream::plaintext(connect_tcp(addr).await?)
+        }
+        Ok(Err(e)) => return Err(SessionError::Handshake(e)),
+        Err(_) => return Err(SessionError::NotAcknowledged),
+    };
+    match tokio::time::timeout(wait, stream.hello()).await {
+        Ok(Ok(hello)) if !hello.compatible() => Err(SessionError::IncompatibleVersion(hello.version)),
+        Ok(Ok(hello)) => {
+            println!("  对方协议版本 {}", hello.version);
+            Ok(stream)
+        }
+        // So do peers from before Hello, whose messages are numbered differently
+        Ok(Err(TransportError::Closed)) => {
+            println!("  ⚠ 对方不支持版本握手 (协议版本 1)，无法互通");
+            Err(SessionError::IncompatibleVersion(1))
+        }
+        Ok(Err(e)) => Err(SessionError::Handshake(e)),
+        Err(_) => Err(SessionError::NotAcknowledged),
+    }
+}
+
+/// Outgoing connection setup, second half: send our request and wait for the user on
+/// the other side to answer. Ok(None) if the attempt was cancelled meanwhile.
+/// The peer has `wait` to acknowledge the request, then as long as it says its
+/// prompt stays up, which `on_pending` is told.
+async fn connect_to_peer(
+    mut stream: PeerStream,
+    handshake: &Message,
+    cancel_rx: &mut tokio::sync::oneshot::Receiver<()>,
+    wait: std::time::Duration,
+    on_pending: impl Fn(u32),
+) -> Result<Option<(PeerStream, Vec<Permission>)>, SessionError> {
+    println!("  发送连接请求握手...");
+    stream.send(handshake).await?;
+    
+    // Wait for response, giving the user on the other side time to accept
+    println!("  等待握手响应（等待对方用户确认）...");
+    let mut deadline = tokio::time::Instant::now() + wait;
+    let mut acknowledged = false;
+    loop {
+        let response = tokio::select! {
+            _ = &mut *cancel_rx => {
+                println!("  收到取消信号，关闭连接");
+                return Ok(None);
+            }
+            result = tokio::time::timeout_at(deadline, stream.recv()) => result,
+        };
+        return match response {
+            Ok(Ok(Message::ConnectPending { timeout_secs })) => {
+                println!("  对方已显示连接请求，等待确认最多 {} 秒", timeout_secs);
+                deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout_secs.into()) + PENDING_GRACE;
+                acknowledged = true;
+                on_pending(timeout_secs);
+                continue;
+            }
+            Ok(Ok(Message::ConnectResponse { success: true, granted, .. })) => Ok(Some((stream, Permission::parse(&granted)))),
+            Ok(Ok(Message::ConnectResponse { success: false, reason, .. })) => Err(SessionError::Rejected(reason)),
+            Ok(Ok(msg)) => {
+                eprintln!("  ❌ 收到意外响应: {:?}", LoggedMessage(&msg));
+                Err(SessionError::UnexpectedResponse)
+            }
+            Ok(Err(e)) => Err(SessionError::Handshake(e)),
+            // Nobody answered the prompt, or there never was one
+            Err(_) if acknowledged => Err(SessionError::HandshakeTimeout),
+            Err(_) => Err(SessionError::NotAcknowledged),
+        };
+    }
+}
+
+/// Send captured input to the device in `slot`; false if the slot is empty or its device isn't connected
+fn switch_to_slot(
+    settings: &Settings,
+    forwarder: &mut InputForwarder,
+    connections: &ActiveConnections,
+    ws_server: &WebSocketServer,
+    slot: u8,
+) -> bool {
+    let Some(device_id) = settings.slots.get(&slot) else {
+        println!("  槽位 {} 未分配设备", slot);
+        return false;
+    };
+    if !connections.values().any(|(_, _, id)| id == device_id) {
+        println!("  槽位 {} 的设备 {} 未连接", slot, device_id);
+        return false;
+    }
+    println!("  切换到槽位 {}: {}", slot, device_id);
+    forwarder.set_target(connections, Some(device_id.clone()));
+    ws_server.broadcast(Event::ActiveTargetChanged { device_id: Some(device_id.clone()) });
+    true
+}
+
+/// Count a failed connection or pairing attempt from `ip`, telling the frontend when it locks the source out
+fn record_failed_attempt(limiter: &std::sync::Mutex<AttemptLimiter>, ws_server: &WebSocketServer, ip: IpAddr, device_id: Option<String>) {
+    let mut limiter = limiter.lock().unwrap();
+    if let Some((kind, wait)) = limiter.fail(ip, std::time::Instant::now()) {
+        let failures = limiter.failures(ip);
+        println!("  ⚠ {} 已失败 {} 次，{} 秒内拒绝其请求", ip, failures, wait.as_secs());
+        ws_server.broadcast(Event::SecurityEvent { kind, ip: ip.to_string(), device_id, failures, retry_after_secs: wait.as_secs() });
+    }
+}
+
+fn report_pairing_error(ws_server: &WebSocketServer, device_id: String, e: PairingError) {
+    eprintln!("  ❌ {}", e);
+    ws_server.broadcast(Event::PairingFailed { device_id, code: e.code(), reason: e.to_string() });
+}
+
+/// Keep the last session with `device_id` for the device list
+fn remember_session(settings: &mut Settings, path: &std::path::Path, stats: &ConnectionStats, device_id: &str) {
+    let Some(session) = stats.last_session(device_id) else {
+        return;
+    };
+    let direction = if session.role == Role::Controller.name() { Direction::Outgoing } else { Direction::Incoming };
+    settings.record_session(device_id, session.started, direction, session.avg_rtt_ms);
+    if let Err(e) = settings.save(path) {
+        eprintln!("  ❌ 保存设置失败: {}", e);
+    }
+}
+
+/// Send a known device again, with its current slot and history
+fn refresh_device(ws_server: &WebSocketServer, settings: &Settings, device: &DeviceInfo, source: DiscoverySource) {
+    let slot = settings.slot_of(&device.id);
+    let history = settings.devices.get(&device.id).cloned();
+    ws_server.broadcast(Event::DeviceFound { device: device.clone(), source, slot, history });
+}
+
+/// Mouse move coalescing: the global setting, unless the device captured
+/// moves go to (the target, or the only peer) has its own
+#[derive(Default)]
+struct Coalescing {
+    global_ms: u64,
+    // What the forwarder runs with now
+    applied_ms: u64,
+}
+
+impl Coalescing {
+    fn refresh(
+        &mut self,
+        settings: &Settings,
+        forwarder: &mut InputForwarder,
+        flush_interval: &mut tokio::time::Interval,
+        connections: &ActiveConnections,
+    ) {
+        let device = match forwarder.target() {
+            Some(target) => Some(target),
+            None if connections.len() == 1 => connections.values().next().map(|(_, _, device_id)| device_id.as_str()),
+            None => None,
+        };
+        let interval_ms = device
+            .and_then(|device_id| settings.overrides_of(device_id).coalescing_ms)
+            .unwrap_or(self.global_ms);
+        if interval_ms == self.applied_ms {
+            return;
+        }
+        self.applied_ms = interval_ms;
+        forwarder.flush(connections);
+        if interval_ms == 0 {
+            forwarder.set_coalesce_interval(None);
+        } else {
+            let interval = tokio::time::Duration::from_millis(interval_ms);
+            forwarder.set_coalesce_interval(Some(interval));
+            *flush_interval = tokio::time::interval(interval);
+            flush_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        }
+    }
+}
+
+/// Drag-lock: move buttons still held on the peer back to the local machine
+fn return_held_buttons(forwarder: &mut InputForwarder, connections: &ActiveConnections, simulator: &dyn InputBackend) {
+    for button in forwarder.release_held(connections) {
+        simulator.mouse_click(button, true);
+    }
+}
+
+fn get_local_ip() -> String {
+    // Try to get all network interfaces
+    if let Ok(interfaces) = local_ip_address::list_afinet_netifas() {
+        let mut candidates = Vec::new();
+        
+        for (name, ip) in interfaces.iter() {
+            if let IpAddr::V4(ipv4) = ip {
+                let octets = ipv4.octets();
+                let name_lower = name.to_lowercase();
+                
+                // Skip loopback
+                if ipv4.is_loopback() {
+                    continue;
+                }
+                
+                // Skip common virtual adapters
+                if name_lower.contains("virtualbox") 
+                    || name_lower.contains("vmware")
+                    || name_lower.contains("hyper-v")
+                    || name_lower.contains("vethernet")
+                    || name_lower.contains("docker")
+                    || name_lower.contains("wsl")
+                    || octets[0] == 198 && octets[1] == 18  // Skip 198.18.x.x (Windows ICS)
+                    || octets[0] == 169 && octets[1] == 254 // Skip 169.254.x.x (APIPA)
+                {
+                    println!("Skipping virtual adapter {}: {}", name, ip);
+                    continue;
+                }
+                
+                // Prioritize 192.168.x.x (most common home/office networks)
+                if octets[0] == 192 && octets[1] == 168 {
+                    println!("Found preferred local IP on interface {}: {}", name, ip);
+                    return ip.to_string();
+                }
+                
+                // Store other private IPs as candidates
+                if octets[0] == 10 || (octets[0] == 172 && octets[1] >= 16 && octets[1] <= 31) {
+                    candidates.push((name.clone(), ip.to_string()));
+                }
+            }
+        }
+        
+        // Use first candidate if no 192.168.x.x found
+        if let Some((name, ip)) = candidates.first() {
+            println!("Using local IP on interface {}: {}", name, ip);
+            return ip.clone();
+        }
+    }
+    
+    // Final fallback
+    local_ip_address::local_ip()
+        .unwrap_or_else(|_| "127.0.0.1".parse().unwrap())
+        .to_string()
+}
+
+pub async fn run_backend(config: BackendConfig) -> Result<()> {
+    let udp_port = config.peer_port;
+    let ws_port = config.ws_port;
+    let device_name = config.device_name.clone();
+    let device_id = config.device_id.clone();
+
+    println!("Starting ShareFlow Service");
+    println!("  UDP Discovery: port {}", udp_port);
+    println!("  WebSocket API: ws://127.0.0.1:{}", ws_port);
+
+    // WebSocket Server
+    let (ws_server, mut command_rx) = WebSocketServer::new(ws_port, config.web_port);
+    let ws_server = Arc::new(ws_server);
+    
+    // Start WebSocket server
+    let ws_server_clone = Arc::clone(&ws_server);
+    tokio::spawn(async move {
+        if let Err(e) = ws_server_clone.start().await {
+            eprintln!("WebSocket server error: {}", e);
+        }
+    });
+    if let Some(path) = config.ipc.clone() {
+        println!("  Local IPC: {}", path.display());
+        let ws_server_clone = Arc::clone(&ws_server);
+        tokio::spawn(async move {
+            if let Err(e) = ws_server_clone.start_ipc(path).await {
+                eprintln!("IPC server error: {}", e);
+            }
+        });
+    }
+
+    if let Some(web_port) = config.web_port {
+        // Start Web Server
+        println!("  Web Server: http://127.0.0.1:{}", web_port);
+        
+        let ws_server = Arc::clone(&ws_server);
+        tokio::spawn(async move {
+            // The API has no authentication, so it stays on this machine
+            let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", web_port)).await.unwrap();
+            axum::serve(listener, web_server::app(ws_server)).await.unwrap();
+        });
+
+        // Open Browser
+        // Give the server a moment to start
+        #[cfg(feature = "web-ui")]
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+            if let Err(e) = webbrowser::open(&format!("http://127.0.0.1:{}", web_port)) {
+                eprintln!("Failed to open browser: {}", e);
+            }
+        });
+    }
+
+    // Discovered devices with last seen timestamp and the backend that found them
+    type DiscoveredDevice = (DeviceInfo, std::time::Instant, DiscoverySource);
+    let discovered_devices = Arc::new(Mutex::new(HashMap::<String, DiscoveredDevice>::new()));
+
+    // Input capture state
+    let is_capturing = Arc::new(Mutex::new(false));
+    // Keys capture leaves alone so assistive tech keeps working
+    let grab_exclusions = Arc::new(std::sync::RwLock::new(GrabExclusions::default()));
+    // One hook for the whole run, attached and detached as capture starts and stops
+    let input_capture = InputCapture::new(Arc::clone(&grab_exclusions));
+
+    // Input classes the frontend wants to visualize (skip building JSON nobody renders)
+    let visualization = Arc::new(VisualizationFilter::new());
+
+    // Optional lock on our own keyboard/mouse while being controlled
+    let (local_input_lock, mut local_unlock_rx) = LocalInputLock::new();
+    let local_input_lock = Arc::new(local_input_lock);
+    let mut pause_local_input = false;
+    
+    // Find out what rdev can actually do here before anything relies on it
+    let mut capabilities = capabilities::probe();
+    capabilities.log();
+
+    // Without it macOS drops our events without an error; the frontend explains where to grant it
+    let accessibility_status = accessibility::status();
+    if accessibility_status.state == AccessibilityState::Denied {
+        eprintln!("⚠ 未获得辅助功能权限，无法捕获或模拟输入");
+        ws_server.broadcast(Event::AccessibilityStatus { status: accessibility_status });
+    }
+
+    // Silent firewall drops are the usual reason devices never show up; warn early
+    let firewall_ws = Arc::clone(&ws_server);
+    tokio::task::spawn_blocking(move || {
+        let status = firewall::status(udp_port);
+        if status.state == FirewallState::Blocked {
+            eprintln!("⚠ 防火墙可能阻止了端口 {}: {}", udp_port, status.detail.as_deref().unwrap_or("-"));
+            firewall_ws.broadcast(Event::FirewallStatus { status });
+        }
+    });
+
+    // The capture shortcut starts capture toward the connected peer, Ctrl+Alt+<digit> toward a slot's device
+    #[cfg(feature = "capture")]
+    let mut hotkey_rx = if config.hotkeys && capabilities.hotkeys.available {
+        Some(input_capture::spawn_hotkeys(config.capture_hotkey))
+    } else {
+        None
+    };
+    #[cfg(not(feature = "capture"))]
+    let mut hotkey_rx: Option<mpsc::UnboundedReceiver<Hotkey>> = None;
+
+    // Channel for discovery events, fed by every backend at once
+    let (tx, mut rx) = mpsc::channel::<Sighting>(32);
+    let (manual_peers, manual_peers_tx) = ManualPeers::new();
+    let mut backends: Vec<Box<dyn DiscoveryBackend>> = vec![
+        Box::new(StaticPeers(config.static_peers.clone())),
+        Box::new(manual_peers),
+    ];
+    if config.discovery {
+        backends.push(Box::new(BroadcastDiscovery {
+            device_id: device_id.to_string(),
+            device_name: device_name.to_string(),
+            port: udp_port,
+            interval: config.broadcast_interval,
+        }));
+    }
+    for backend in backends {
+        let source = backend.source();
+        // One broken backend (e.g. the UDP port is taken) shouldn't take the others down
+        if let Err(e) = backend.start(tx.clone()).await {
+            eprintln!("❌ 发现后端 {:?} 启动失败: {}", source, e);
+            ws_server.broadcast(Event::BackendError { code: e.code(), message: e.to_string() });
+        }
+    }
+
+    let active_connections = Arc::new(Mutex::new(ActiveConnections::new()));
+    let (session_ended_tx, mut session_ended_rx) = mpsc::unbounded_channel::<String>();
+    let (edge_return_tx, mut edge_return_rx) = mpsc::unbounded_channel::<EdgeReturn>();
+    let (clipboard_tx, clipboard_rx) = mpsc::unbounded_channel();
+    let (file_tx, file_rx) = mpsc::unbounded_channel();
+    let mut settings = Settings::load(&config.settings);
+    let secret_dir = config.paired_devices.parent().map(PathBuf::from).unwrap_or_default();
+    let identity = Arc::new(pairing::load_identity(&SecretStore::new(secret_dir), &device_id)?);
+    let pairing = Arc::new(std::sync::Mutex::new(Pairing::load(&config.paired_devices)));
+    // The config file as the frontend sees it, and as it was when this run started
+    let started_with = AppConfig::load(&config.config_file);
+    let mut app_config = started_with.clone();
+    // For the connection listener, which doesn't see the settings
+    let auto_accept = Arc::new(std::sync::RwLock::new(auto_accepted(&settings, &app_config)));
+    let request_timeouts = Arc::new(std::sync::RwLock::new(settings.request_timeouts));
+    let session_context = SessionContext {
+        ws_server: Arc::clone(&ws_server),
+        active_connections: Arc::clone(&active_connections),
+        visualization: Arc::clone(&visualization),
+        local_input_lock: Arc::clone(&local_input_lock),
+        simulator: Arc::clone(&config.simulator),
+        ended_tx: session_ended_tx,
+        controllers: Arc::new(Mutex::new(HashMap::new())),
+        audit: Arc::new(AuditLog::new(config.audit_log.clone())),
+        stats: Arc::new(ConnectionStats::new()),
+        peer_features: Arc::new(Mutex::new(HashMap::new())),
+        permissions: Arc::new(Mutex::new(HashMap::new())),
+        clipboard_tx,
+        file_tx,
+        edge_behavior: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        return_edge: Arc::new(std::sync::RwLock::new(None)),
+        edge_return_tx,
+        relay_edges: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        peer_cursors: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        peer_screens: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        peer_lock_states: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        cursor_prediction: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        target_status: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        lifecycle: Arc::new(Lifecycle::new(Arc::clone(&ws_server))),
+        input_echo: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        transport: Arc::new(std::sync::RwLock::new(settings.transport)),
+    };
+    tokio::spawn(clipboard::run(session_context.clone(), device_id.clone(), clipboard_rx));
+    tokio::spawn(file_transfer::run(session_context.clone(), config.downloads.clone(), file_rx));
+    
+    // Pending connection requests (addr -> (stream, device_info, timestamp))
+    type PendingConnection = (PeerStream, Option<DeviceInfo>, std::time::Instant, Vec<Permission>);
+    let pending_connections = Arc::new(Mutex::new(HashMap::<String, PendingConnection>::new()));
+    
+    // Latest connection request to show to frontend (only one at a time)
+    // (device, requested permissions, pairing code if it isn't paired)
+    let latest_connection_request = Arc::new(Mutex::new(Option::<(DeviceInfo, Vec<Permission>, Option<String>)>::None));
+    
+    // Outgoing connection requests (when we are the initiator), keyed by target device ID
+    let outgoing_requests = Arc::new(Mutex::new(OutgoingRequests::new()));
+    let mut next_attempt_id: u64 = 0;
+    
+    // Failed (declined or unanswered) connection attempts per source address
+    let attempt_limiter = Arc::new(std::sync::Mutex::new(AttemptLimiter::new()));
+    // Devices whose requests the user keeps declining
+    let prompt_muter = Arc::new(std::sync::Mutex::new(PromptMuter::new()));
+    
+    // Start TCP Listener for peer connections
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", udp_port)).await?;
+    let pending_connections_clone = Arc::clone(&pending_connections);
+    let latest_request_clone = Arc::clone(&latest_connection_request);
+    let ws_server_for_tcp = Arc::clone(&ws_server);
+    let discovered_devices_for_tcp = Arc::clone(&discovered_devices);
+    let attempt_limiter_for_tcp = Arc::clone(&attempt_limiter);
+    let prompt_muter_for_tcp = Arc::clone(&prompt_muter);
+    let auto_accept_for_tcp = Arc::clone(&auto_accept);
+    let request_timeouts_for_tcp = Arc::clone(&request_timeouts);
+    let lifecycle_for_tcp = Arc::clone(&session_context.lifecycle);
+    let transport_for_tcp = Arc::clone(&session_context.transport);
+    let identity_for_tcp = Arc::clone(&identity);
+    let pairing_for_tcp = Arc::clone(&pairing);
+    
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    println!("\n>>> 收到 TCP 连接来自: {}", addr);
+                    if let Err(e) = stream.set_nodelay(true) {
+                        eprintln!("Failed to set TCP_NODELAY: {}", e);
+                    }
+                    
+                    let ws_server_clone = Arc::clone(&ws_server_for_tcp);
+                    let pending_conns = Arc::clone(&pending_connections_clone);
+                    let latest_req = Arc::clone(&latest_request_clone);
+                    let devices = Arc::clone(&discovered_devices_for_tcp);
+                    let limiter = Arc::clone(&attempt_limiter_for_tcp);
+                    let muter = Arc::clone(&prompt_muter_for_tcp);
+                    let auto_accept = Arc::clone(&auto_accept_for_tcp);
+                    let request_timeouts = Arc::clone(&request_timeouts_for_tcp);
+                    let lifecycle = Arc::clone(&lifecycle_for_tcp);
+                    let transport = Arc::clone(&transport_for_tcp);
+                    let identity = Arc::clone(&identity_for_tcp);
+                    let pairing = Arc::clone(&pairing_for_tcp);
+                    
+                    tokio::spawn(async move {
+                        // Read handshake message
+                        match PeerStream::accept(stream, &identity).await {
+                            Ok((mut stream, Message::ConnectRequest { id, name, permissions, .. })) => {
+                                println!("  收到连接请求握手");
+                                
+                                // Peers that don't encrypt would send our input in the clear
+                                if !stream.is_encrypted() && !transport.read().unwrap().allow_plaintext {
+                                    println!("  ⚠ 对方未加密连接，拒绝 (可在传输选项中允许明文)");
+                                    let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::EncryptionRequired), granted: Vec::new() }).await;
+                                    return;
+                                }
+                                
+                                // Too old to understand what we would send it. Without a Hello it's
+                                // version 1, and if its request decoded at all that was luck.
+                                let version = stream.peer_hello().map_or(1, |hello| hello.version);
+                                if version < MIN_PROTOCOL_VERSION {
+                                    println!("  ⚠ 对方协议版本 {} 过旧，拒绝", version);
+                                    let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::VersionMismatch), granted: Vec::new() }).await;
+                                    return;
+                                }
+                                let permissions = Permission::parse(&permissions);
+                                
+                                // Too many failed attempts from this address: refuse without asking the user
+                                let lockout = limiter.lock().unwrap().check(addr.ip(), std::time::Instant::now());
+                                if let Err((kind, wait)) = lockout {
+                                    println!("  ⚠ {} 失败次数过多 ({:?})，{} 秒内拒绝其请求", addr.ip(), kind, wait.as_secs());
+                                    let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::Blocked), granted: Vec::new() }).await;
+                                    return;
+                                }
+                                
+                                // The handshake identifies the peer; discovery only fills in details.
+                                // Source IPs are unreliable behind NAT or with several devices per host.
+                                let device_info = if id.is_empty() {
+                                    None
+                                } else {
+                                    let devs = devices.lock().await;
+                                    let known = devs.get(&id).map(|(dev, _, _)| dev);
+                                    Some(DeviceInfo {
+                                        port: known.map_or(0, |dev| dev.port),
+                                        device_type: known.map_or_else(|| "DESKTOP".to_string(), |dev| dev.device_type.clone()),
+                                        id,
+                                        name,
+                                        ip: addr.ip().to_string(),
+                                    })
+                                };
+                                
+                                if let Some(device) = device_info {
+                                    println!("  来自设备: {} ({})", device.name, device.id);
+                                    
+                                    // Nothing to inject with, so don't bother the user with a dialog
+                                    if !cfg!(feature = "inject") {
+                                        println!("  此版本只能控制其他设备，拒绝被控请求");
+                                        let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::NotControllable), granted: Vec::new() }).await;
+                                        return;
+                                    }
+                                    
+                                    // The user kept declining this device: no more dialogs until the cooldown ends
+                                    if muter.lock().unwrap().is_muted(&device.id, std::time::Instant::now()) {
+                                        println!("  该设备的请求已被静音，自动拒绝");
+                                        let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::UserDeclined), granted: Vec::new() }).await;
+                                        return;
+                                    }
+                                    
+                                    // Someone else posing as a paired device, or the device was reinstalled
+                                    // and has to be unpaired here first; never worth a prompt
+                                    let trust = pairing.lock().unwrap().check(&device.id, &device.name, &stream);
+                                    if trust == Some(Trust::Changed) {
+                                        println!("  ⚠ 该设备的密钥与配对时不同，拒绝");
+                                        let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::IdentityChanged), granted: Vec::new() }).await;
+                                        let e = SessionError::IdentityChanged;
+                                        ws_server_clone.broadcast(Event::PairingFailed { device_id: device.id.clone(), code: e.code(), reason: e.to_string() });
+                                        record_failed_attempt(&limiter, &ws_server_clone, addr.ip(), Some(device.id));
+                                        return;
+                                    }
+                                    let paired = trust == Some(Trust::Paired);
+                                    let pairing_code = match trust {
+                                        Some(Trust::Unpaired { code }) => Some(code),
+                                        _ => None,
+                                    };
+                                    
+                                    // Only the key proved in the handshake skips the prompt: anybody can claim
+                                    // an ID that is on an auto-accept list
+                                    let auto_accepted = paired;
+                                    if !paired && auto_accept.read().unwrap().contains(&device.id) {
+                                        println!("  该设备在自动接受列表中，但尚未配对，仍需用户确认");
+                                    }
+                                    
+                                    // Without a frontend the prompt would go nowhere and the peer would wait for the timeout
+                                    if !auto_accepted && !ws_server_clone.has_clients() {
+                                        println!("  没有打开的前端，无法询问用户，立即拒绝");
+                                        let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::NoOperator), granted: Vec::new() }).await;
+                                        return;
+                                    }
+                                    
+                                    // Check if there's already a pending request
+                                    let mut pending = pending_conns.lock().await;
+                                    let now = std::time::Instant::now();
+                                    let approval = std::time::Duration::from_secs(request_timeouts.read().unwrap().approval_secs);
+                                    
+                                    // Clean up expired pending connections
+                                    let expired: Vec<String> = pending.iter()
+                                        .filter(|(_, (_, _, timestamp, _))| now.duration_since(*timestamp) > approval)
+                                        .map(|(addr, _)| addr.clone())
+                                        .collect();
+                                    
+                                    for old_addr in expired {
+                                        if let Some((mut old_stream, old_device, _, _)) = pending.remove(&old_addr) {
+                                            println!("  清理过期的待处理连接: {}", old_addr);
+                                            let _ = old_stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::Timeout), granted: Vec::new() }).await;
+                                            if let Some(old_device) = &old_device {
+                                                lifecycle.transition(&old_device.id, SessionState::Idle);
+                                            }
+                                            if let Ok(old_addr) = old_addr.parse::<std::net::SocketAddr>() {
+                                                record_failed_attempt(&limiter, &ws_server_clone, old_addr.ip(), old_device.map(|d| d.id));
+                                            }
+                                        }
+                                    }
+                                    
+                                    // The same device asking again replaces its own request without a new
+                                    // dialog, unless it now wants other permissions; the first deadline still holds
+                                    let repeat = pending.iter()
+                                        .find(|(_, (_, dev, _, _))| dev.as_ref().map(|d| &d.id) == Some(&device.id))
+                                        .map(|(old_addr, _)| old_addr.clone());
+                                    let mut since = now;
+                                    let mut prompt = true;
+                                    if let Some((mut old_stream, _, timestamp, old_permissions)) = repeat.and_then(|old_addr| pending.remove(&old_addr)) {
+                                        println!("  合并来自同一设备的重复请求");
+                                        let _ = old_stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::Busy), granted: Vec::new() }).await;
+                                        since = timestamp;
+                                        prompt = old_permissions != permissions;
+                                    }
+                                    
+                                    // Reject other pending connections (only keep the latest)
+                                    if !pending.is_empty() {
+                                        println!("  ⚠ 已有待处理的连接请求，拒绝旧请求");
+                                        for (old_addr, (mut old_stream, old_device, _, _)) in pending.drain() {
+                                            println!("    拒绝来自 {} 的旧请求", old_addr);
+                                            let _ = old_stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::Busy), granted: Vec::new() }).await;
+                                            if let Some(old_device) = old_device {
+                                                lifecycle.transition(&old_device.id, SessionState::Idle);
+                                            }
+                                        }
+                                    }
+                                    
+                                    // Tell the requester how long the prompt stays up, so its countdown matches ours
+                                    if !auto_accepted {
+                                        let remaining = approval.saturating_sub(now.duration_since(since)).as_secs_f64().round() as u32;
+                                        if let Err(e) = stream.send(&Message::ConnectPending { timeout_secs: remaining }).await {
+                                            println!("  请求方已断开: {}", e);
+                                            // Its earlier request may have just been replaced
+                                            lifecycle.transition(&device.id, SessionState::Idle);
+                                            return;
+                                        }
+                                    }
+                                    
+                                    // Store new pending connection with timestamp
+                                    pending.insert(addr.to_string(), (stream, Some(device.clone()), since, permissions.clone()));
+                                    lifecycle.transition(&device.id, SessionState::PendingApproval);
+                                    drop(pending);
+                                    
+                                    // Accepted by the main loop like a click on the dialog, with the group's defaults
+                                    if auto_accepted {
+                                        println!("  已配对设备，自动接受连接");
+                                        ws_server_clone.send_command(Command::AcceptConnection { target_device_id: device.id, time_limit_secs: None, permissions: None });
+                                        return;
+                                    }
+                                    
+                                    if !prompt {
+                                        println!("  连接请求弹窗已在显示，不再重复通知");
+                                        return;
+                                    }
+                                    
+                                    // Save as latest request
+                                    *latest_req.lock().await = Some((device.clone(), permissions.clone(), pairing_code.clone()));
+                                    
+                                    // Notify frontend
+                                    println!("  通知前端显示连接请求弹窗，请求的权限: {:?}", permissions);
+                                    ws_server_clone.broadcast(Event::ConnectionRequest { device, permissions, pairing_code });
+                                } else {
+                                    println!("  ⚠ 握手缺少设备 ID，自动拒绝");
+                                    let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::MissingDeviceId), granted: Vec::new() }).await;
+                                }
+                            }
+                            Ok((_, msg)) => {
+                                println!("  收到意外消息: {:?}", LoggedMessage(&msg));
+                            }
+                            Err(e) => {
+                                println!("  读取握手消息失败: {}", e);
+                                
+                                // Check if this was a pending connection that got cancelled
+                                let mut pending = pending_conns.lock().await;
+                                if let Some((_, dev_opt, _, _)) = pending.remove(&addr.to_string()) {
+                                    if let Some(device) = dev_opt {
+                                        println!("  连接被取消，通知前端");
+                                        let device_id = device.id.clone();
+                                        lifecycle.transition(&device_id, SessionState::Idle);
+                                        ws_server_clone.broadcast(Event::ConnectionRequestCancelled { 
+                                            device_id: device_id.clone()
+                                        });
+                                        
+                                        // Clear latest request if it matches
+                                        let mut latest = latest_req.lock().await;
+                                        if latest.as_ref().map(|(d, _, _)| &d.id) == Some(&device_id) {
+                                            *latest = None;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    });
+                }
+                Err(e) => println!("TCP accept error: {}", e),
+            }
+        }
+    });
+
+    println!("Service is running. Waiting for events...");
+
+    // Start periodic cleanup task for expired pending connections
+    let pending_conns_cleanup = Arc::clone(&pending_connections);
+    let limiter_cleanup = Arc::clone(&attempt_limiter);
+    let ws_server_cleanup = Arc::clone(&ws_server);
+    let lifecycle_cleanup = Arc::clone(&session_context.lifecycle);
+    let request_timeouts_cleanup = Arc::clone(&request_timeouts);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            
+            let mut pending = pending_conns_cleanup.lock().await;
+            let now = std::time::Instant::now();
+            let approval = std::time::Duration::from_secs(request_timeouts_cleanup.read().unwrap().approval_secs);
+            
+            let expired: Vec<String> = pending.iter()
+                .filter(|(_, (_, _, timestamp, _))| now.duration_since(*timestamp) > approval)
+                .map(|(addr, _)| addr.clone())
+                .collect();
+            
+            for addr in expired {
+                if let Some((mut stream, dev, _, _)) = pending.remove(&addr) {
+                    if let Some(device) = &dev {
+                        println!("\n⏰ 清理超时的待处理连接: {} (来自 {})", addr, device.name);
+                    } else {
+                        println!("\n⏰ 清理超时的待处理连接: {}", addr);
+                    }
+                    let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::Timeout), granted: Vec::new() }).await;
+                    if let Some(device) = &dev {
+                        lifecycle_cleanup.transition(&device.id, SessionState::Idle);
+                    }
+                    // Unanswered prompts count too, or a peer could keep one up around the clock
+                    if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
+                        record_failed_attempt(&limiter_cleanup, &ws_server_cleanup, addr.ip(), dev.map(|d| d.id));
+                    }
+                }
+            }
+        }
+    });
+
+    // The last frontend went away: nobody can answer the prompts still open.
+    // A short grace period lets a reloading page keep them.
+    let pending_conns_unattended = Arc::clone(&pending_connections);
+    let latest_request_unattended = Arc::clone(&latest_connection_request);
+    let lifecycle_unattended = Arc::clone(&session_context.lifecycle);
+    let mut frontend_clients = ws_server.watch_clients();
+    tokio::spawn(async move {
+        while frontend_clients.changed().await.is_ok() {
+            if *frontend_clients.borrow_and_update() > 0 {
+                continue;
+            }
+            tokio::time::sleep(FRONTEND_GRACE).await;
+            if *frontend_clients.borrow() > 0 {
+                continue;
+            }
+            let mut pending = pending_conns_unattended.lock().await;
+            for (addr, (mut stream, device, _, _)) in pending.drain() {
+                println!("\n前端已全部断开，拒绝待处理的连接请求: {}", addr);
+                let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::NoOperator), granted: Vec::new() }).await;
+                if let Some(device) = device {
+                    lifecycle_unattended.transition(&device.id, SessionState::Idle);
+                }
+            }
+            *latest_request_unattended.lock().await = None;
+        }
+    });
+
+    // Get local IP address - prefer 192.168.x.x or 10.x.x.x
+    let local_ip = get_local_ip();
+
+    println!("Local IP: {}", local_ip);
+    println!("Device name: {}", device_name);
+    println!("Device ID: {}", device_id);
+
+    // Input capture receiver (will be initialized when capture starts)
+    let mut input_rx: Option<mpsc::UnboundedReceiver<CaptureControl>> = None;
+
+    // Forwards input to the peer (session mode filter, held buttons for drag-lock, move coalescing)
+    let mut forwarder = InputForwarder::new();
+    let mut mouse_flush_interval = tokio::time::interval(tokio::time::Duration::from_millis(8));
+    mouse_flush_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut coalescing = Coalescing::default();
+    // Used to hand a drag back to this machine when control returns
+    let local_simulator = Arc::clone(&config.simulator);
+    // Sessions end in their own tasks, so capture vs connections is polled
+    let mut capture_watch = CaptureWatch::new();
+    let mut lock_mirror = LockMirror::default();
+    let mut capture_watch_interval = tokio::time::interval(tokio::time::Duration::from_millis(250));
+    // Screen-edge switching: where our cursor is while capture is off
+    let mut edge_watch = EdgeWatch::default();
+    let mut edge_poll = tokio::time::interval(tokio::time::Duration::from_millis(15));
+    edge_poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    // Main event loop
+    loop {
+        tokio::select! {
+            // A session ended: check right away instead of on the next poll, so a
+            // controller whose peer vanished gets its keyboard and mouse back at once
+            Some(device_id) = session_ended_rx.recv() => {
+                println!("会话已结束: {}", device_id);
+                remember_session(&mut settings, &config.settings, &session_context.stats, &device_id);
+                if let Some((device, _, source)) = discovered_devices.lock().await.get(&device_id) {
+                    refresh_device(&ws_server, &settings, device, *source);
+                }
+                if forwarder.target() == Some(device_id.as_str()) {
+                    forwarder.set_target(&*active_connections.lock().await, None);
+                    ws_server.broadcast(Event::ActiveTargetChanged { device_id: None });
+                }
+                capture_watch_interval.reset_immediately();
+            }
+
+            // Our cursor on the peer came back over the return edge: give the mouse back locally
+            Some(edge_return) = edge_return_rx.recv() => {
+                let mut capturing = is_capturing.lock().await;
+                if !*capturing {
+                    continue;
+                }
+                println!("远端光标到达 {:?} 边缘 ({})，交还控制权", edge_return.edge, edge_return.device_id);
+                input_capture.stop_capture();
+                input_rx = None;
+                *capturing = false;
+                return_held_buttons(&mut forwarder, &*active_connections.lock().await, &*local_simulator);
+                // Come out of the local edge facing the peer, at the same relative position
+                let entry = edge_return.edge.opposite();
+                if let Some((x, y)) = local_simulator.screens().and_then(|local| local.entry_point(entry, edge_return.pos, edge_return.screen)) {
+                    local_simulator.move_to(x, y);
+                }
+                // That's on an edge, maybe one that switches back
+                edge_watch.disarm();
+                ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::RemoteEdge });
+            }
+
+            // Screen-edge switching: our cursor reached an edge with a connected device beyond it
+            _ = edge_poll.tick(), if !settings.screen_layout.is_empty() => {
+                if *is_capturing.lock().await {
+                    continue;
+                }
+                let (Some(pos), Some(screens)) = (local_simulator.cursor_position(), local_simulator.screens()) else {
+                    continue;
+                };
+                let Some(edge) = edge_watch.update(pos, &screens, settings.screen_layout.keys().copied()) else {
+                    continue;
+                };
+                let device_id = settings.screen_layout[&edge].clone();
+                let connections = active_connections.lock().await;
+                let Some((key, (sender, _, _))) = connections.iter().find(|(_, (_, _, id))| *id == device_id) else {
+                    continue;
+                };
+                // An older peer wouldn't know where to put its cursor, nor when to hand control back
+                let features = session_context.peer_features.lock().await;
+                if !features.get(key).is_some_and(|(_, features)| features.contains(&PeerFeature::EdgeSwitch)) {
+                    println!("{} 不支持屏幕边缘切换", device_id);
+                    continue;
+                }
+                drop(features);
+                println!("本地光标到达 {:?} 边缘，控制权交给 {}", edge, device_id);
+                let (x, y) = screens.to_relative(pos);
+                let (_, (screen_width, screen_height)) = screens.bounds();
+                let _ = sender.send(Message::HandControl { edge, x, y, screen_width, screen_height });
+                forwarder.set_target(&connections, Some(device_id.clone()));
+                ws_server.broadcast(Event::ActiveTargetChanged { device_id: Some(device_id) });
+                // Same path as the hotkeys
+                ws_server.send_command(Command::StartCapture);
+            }
+
+            // Report capture/connection mismatches, stop capture if the last peer left
+            _ = capture_watch_interval.tick() => {
+                // Safety net: a state whose owner went away without moving it on
+                session_context.lifecycle.expire(std::time::Instant::now());
+                let mut capturing = is_capturing.lock().await;
+                // Also when a device with its own coalescing connected or became the target
+                coalescing.refresh(&settings, &mut forwarder, &mut mouse_flush_interval, &*active_connections.lock().await);
+                // Our LEDs show the lock keys of whoever we type on
+                let peer_lock = if *capturing {
+                    let connections = active_connections.lock().await;
+                    let device = match forwarder.target() {
+                        Some(target) => Some(target),
+                        None if connections.len() == 1 => connections.values().next().map(|(_, _, device_id)| device_id.as_str()),
+                        None => None,
+                    };
+                    device.and_then(|device_id| session_context.peer_lock_states.read().unwrap().get(device_id).copied())
+                } else {
+                    None
+                };
+                lock_mirror.update(peer_lock, &*local_simulator);
+                let connection_count = active_connections.lock().await.len();
+                let Some(state) = capture_watch.update(*capturing, connection_count) else {
+                    continue;
+                };
+                println!("捕获/连接状态: {:?}", state);
+                ws_server.broadcast(Event::CaptureStateChanged { state });
+                if capture_watch.should_stop() {
+                    println!("  最后一个连接已断开，自动停止输入捕获");
+                    input_capture.stop_capture();
+                    input_rx = None;
+                    *capturing = false;
+                    return_held_buttons(&mut forwarder, &*active_connections.lock().await, &*local_simulator);
+                    ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::ConnectionLost });
+                }
+            }
+
+            // Periodic flush of coalesced and held-back mouse moves
+            _ = mouse_flush_interval.tick(), if forwarder.has_pending_moves() => {
+                forwarder.tick(&*active_connections.lock().await);
+            }
+            
+            // Handle discovery events from all backends
+            Some(Sighting { device, source }) = rx.recv() => {
+                let now = std::time::Instant::now();
+                
+                // Only log and notify if this is a new device
+                let mut devices = discovered_devices.lock().await;
+                match devices.get(&device.id) {
+                    // Broadcast refreshes don't replace what the user or the config set up
+                    Some((_, _, known)) if *known != source && source.expires() => {
+                        if let Some(entry) = devices.get_mut(&device.id) {
+                            entry.1 = now;
+                        }
+                    }
+                    Some(_) => {
+                        // Update timestamp silently
+                        devices.insert(device.id.clone(), (device, now, source));
+                    }
+                    None => {
+                        println!("\n✓ 发现新设备 [{:?}]: {} ({}) at {}:{}", source, device.name, device.id, device.ip, device.port);
+                        devices.insert(device.id.clone(), (device.clone(), now, source));
+                        
+                        // Notify frontend
+                        let slot = settings.slot_of(&device.id);
+                        let history = settings.devices.get(&device.id).cloned();
+                        ws_server.broadcast(Event::DeviceFound { device, source, slot, history });
+                    }
+                }
+            }
+            
+            // Commands from frontends (and from ourselves, e.g. auto-accept)
+            Some(command) = command_rx.recv() => {
+                println!("\n[WS] 收到前端命令: {:?}", LoggedCommand(&command));
+                match command {
+                    Command::GetLocalInfo => {
+                        println!("Frontend requested local device info");
+                        let local_device = DeviceInfo {
+                            id: device_id.to_string(),
+                            name: device_name.clone(),
+                            ip: local_ip.clone(),
+                            port: udp_port,
+                            device_type: "DESKTOP".to_string(),
+                        };
+                        ws_server.broadcast(Event::LocalInfo { device: local_device });
+                        ws_server.broadcast(Event::CapabilityStatus { capabilities: capabilities.clone() });
+                        
+                        // Check if there's a pending connection request
+                        let latest_req = latest_connection_request.lock().await;
+                        if let Some((ref device, ref permissions, ref pairing_code)) = *latest_req {
+                            println!("  检测到待处理的连接请求，重新发送给前端");
+                            ws_server.broadcast(Event::ConnectionRequest { device: device.clone(), permissions: permissions.clone(), pairing_code: pairing_code.clone() });
+                        }
+                        drop(latest_req);
+                        
+                        // A reconnecting frontend also needs to know about our own pending request
+                        ws_server.broadcast(Event::ConnectionStatus {
+                            outgoing_requests: outgoing_requests.lock().await.keys().cloned().collect(),
+                            active_connections: active_connections.lock().await.len(),
+                            capturing: *is_capturing.lock().await,
+                            capture_hook: input_capture.state(),
+                        });
+                        session_context.announce_controllers().await;
+                        session_context.announce_peer_features().await;
+                        session_context.announce_target_status();
+                        session_context.lifecycle.announce();
+                    }
+                    Command::GetConnectionStatus => {
+                        ws_server.broadcast(Event::ConnectionStatus {
+                            outgoing_requests: outgoing_requests.lock().await.keys().cloned().collect(),
+                            active_connections: active_connections.lock().await.len(),
+                            capturing: *is_capturing.lock().await,
+                            capture_hook: input_capture.state(),
+                        });
+                        session_context.announce_controllers().await;
+                        session_context.announce_peer_features().await;
+                        session_context.announce_target_status();
+                        session_context.lifecycle.announce();
+                    }
+                    Command::GetCapabilities => {
+                        ws_server.broadcast(Event::CapabilityStatus { capabilities: capabilities.clone() });
+                    }
+                    Command::ResetStats => {
+                        println!("\n>>> 前端重置连接统计");
+                        session_context.stats.reset();
+                    }
+                    Command::ExportStats => {
+                        ws_server.broadcast(Event::StatsExport { stats: session_context.stats.export() });
+                    }
+                    Command::SendMedia { action, target_device_id } => {
+                        println!("\n>>> 前端发送媒体控制 {:?} 到 {:?}", action, target_device_id);
+                        let connections = active_connections.lock().await;
+                        let permissions = session_context.permissions.lock().await;
+                        for (key, (sender, _, peer_id)) in connections.iter() {
+                            if target_device_id.as_ref().is_some_and(|target| target != peer_id) {
+                                continue;
+                            }
+                            if !permissions.get(key).is_some_and(|granted| granted.contains(&Permission::Media)) {
+                                println!("  {} 未授予媒体控制权限，跳过", peer_id);
+                                continue;
+                            }
+                            let _ = sender.send(Message::Media { action });
+                        }
+                    }
+                    Command::SendToDevice { text, target_device_id } => {
+                        println!("\n>>> 前端发送接力内容 ({} 字节) 到 {:?}", text.len(), target_device_id);
+                        if text.len() > clipboard::INLINE_LEN {
+                            eprintln!("  ❌ 内容过大，无法发送");
+                            continue;
+                        }
+                        let connections = active_connections.lock().await;
+                        let permissions = session_context.permissions.lock().await;
+                        let features = session_context.peer_features.lock().await;
+                        for (key, (sender, _, peer_id)) in connections.iter() {
+                            if target_device_id.as_ref().is_some_and(|target| target != peer_id) {
+                                continue;
+                            }
+                            if !permissions.get(key).is_some_and(|granted| granted.contains(&Permission::HandOff)) {
+                                println!("  {} 未授予接力权限，跳过", peer_id);
+                                continue;
+                            }
+                            // An older peer could not decode the message at all
+                            if !features.get(key).is_some_and(|(_, features)| features.contains(&PeerFeature::HandOff)) {
+                                println!("  {} 不支持接力，跳过", peer_id);
+                                continue;
+                            }
+                            let _ = sender.send(Message::HandOff { text: text.clone() });
+                        }
+                    }
+                    Command::CancelClipboardTransfer { origin, seq } => {
+                        println!("\n>>> 前端取消剪贴板传输: {} #{}", origin, seq);
+                        let _ = session_context.clipboard_tx.send(ClipboardEvent::Cancel { origin, seq });
+                    }
+                    Command::SendFile { device_id, path } => {
+                        println!("\n>>> 前端请求发送文件到 {}: {}", device_id, path.display());
+                        let _ = session_context.file_tx.send(FileEvent::Send { device_id, path });
+                    }
+                    Command::CancelFileTransfer { id } => {
+                        println!("\n>>> 前端取消文件传输: {:016x}", id);
+                        let _ = session_context.file_tx.send(FileEvent::Cancel { id });
+                    }
+                    Command::RequestScreenshot { device_id } => {
+                        println!("\n>>> 前端请求 {} 的截图", device_id);
+                        let connections = active_connections.lock().await;
+                        let permissions = session_context.permissions.lock().await;
+                        let granted = connections.iter().find(|(key, (_, _, peer_id))| {
+                            *peer_id == device_id && permissions.get(*key).is_some_and(|granted| granted.contains(&Permission::Screenshot))
+                        });
+                        match granted {
+                            Some((_, (sender, _, _))) => {
+                                let _ = sender.send(Message::ScreenshotRequest);
+                            }
+                            None => {
+                                println!("  {} 未连接或未授予截图权限", device_id);
+                                ws_server.broadcast(Event::PeerScreenshot { device_id, width: 0, height: 0, image: None });
+                            }
+                        }
+                    }
+                    Command::StartThumbnails { device_id, fps } => {
+                        let fps = fps.unwrap_or(1).clamp(1, screenshot::MAX_THUMBNAIL_FPS);
+                        println!("\n>>> 前端请求 {} 的缩略图 ({} fps)", device_id, fps);
+                        let connections = active_connections.lock().await;
+                        let permissions = session_context.permissions.lock().await;
+                        let features = session_context.peer_features.lock().await;
+                        let streaming = connections.iter().find(|(key, (_, _, peer_id))| {
+                            *peer_id == device_id
+                                && permissions.get(*key).is_some_and(|granted| granted.contains(&Permission::Screenshot))
+                                && features.get(*key).is_some_and(|(_, features)| features.contains(&PeerFeature::Thumbnails))
+                        });
+                        match streaming {
+                            Some((_, (sender, _, _))) => {
+                                let _ = sender.send(Message::ThumbnailStart { fps });
+                            }
+                            None => {
+                                println!("  {} 未连接、未授予截图权限或不支持缩略图", device_id);
+                                ws_server.broadcast(Event::PeerThumbnail { device_id, image: None });
+                            }
+                        }
+                    }
+                    Command::StopThumbnails { device_id } => {
+                        println!("\n>>> 前端停止 {} 的缩略图", device_id);
+                        let connections = active_connections.lock().await;
+                        if let Some((sender, _, _)) = connections.values().find(|(_, _, peer_id)| *peer_id == device_id) {
+                            let _ = sender.send(Message::ThumbnailStop);
+                        }
+                    }
+                    Command::HighlightPointer { device_id } => {
+                        let connections = active_connections.lock().await;
+                        let features = session_context.peer_features.lock().await;
+                        let highlighting = connections.iter().find(|(key, (_, _, peer_id))| {
+                            *peer_id == device_id
+                                && features.get(*key).is_some_and(|(_, features)| features.contains(&PeerFeature::Highlight))
+                        });
+                        match highlighting {
+                            Some((_, (sender, _, _))) => {
+                                let _ = sender.send(Message::Highlight);
+                            }
+                            None => println!("  {} 未连接或不支持光标高亮", device_id),
+                        }
+                    }
+                    Command::SetInputEcho { device_id, enabled } => {
+                        println!("\n>>> 前端{} {} 的输入回显校验", if enabled { "开启" } else { "关闭" }, device_id);
+                        let connections = active_connections.lock().await;
+                        let features = session_context.peer_features.lock().await;
+                        let echoing = connections.iter().find(|(key, (_, _, peer_id))| {
+                            *peer_id == device_id
+                                && features.get(*key).is_some_and(|(_, features)| features.contains(&PeerFeature::InputEcho))
+                        });
+                        let Some((_, (sender, _, _))) = echoing else {
+                            println!("  {} 未连接或不支持输入回显", device_id);
+                            continue;
+                        };
+                        let input_echo = Arc::clone(&session_context.input_echo);
+                        if !enabled {
+                            let _ = sender.send(Message::EchoInput { enabled: false });
+                            // Echoes still on their way are ignored from here on
+                            if let Some(tracker) = input_echo.lock().unwrap().remove(&device_id) {
+                                ws_server.broadcast(Event::InputEchoStats { device_id, stats: tracker.stats() });
+                            }
+                            continue;
+                        }
+                        // Counting starts over when the peer gets this; a reporter is already running if it was on
+                        let _ = sender.send(Message::EchoInput { enabled: true });
+                        if input_echo.lock().unwrap().insert(device_id.clone(), EchoTracker::default()).is_some() {
+                            continue;
+                        }
+                        let ws_server = Arc::clone(&ws_server);
+                        tokio::spawn(async move {
+                            let mut report = tokio::time::interval(std::time::Duration::from_secs(1));
+                            report.tick().await;
+                            loop {
+                                report.tick().await;
+                                let stats = {
+                                    let mut input_echo = input_echo.lock().unwrap();
+                                    // Disabled, or the session ended
+                                    let Some(tracker) = input_echo.get_mut(&device_id) else {
+                                        break;
+                                    };
+                                    tracker.expire(std::time::Instant::now());
+                                    tracker.stats()
+                                };
+                                ws_server.broadcast(Event::InputEchoStats { device_id: device_id.clone(), stats });
+                            }
+                        });
+                    }
+                    Command::GetFirewallStatus => {
+                        let ws_server = Arc::clone(&ws_server);
+                        tokio::task::spawn_blocking(move || {
+                            ws_server.broadcast(Event::FirewallStatus { status: firewall::status(udp_port) });
+                        });
+                    }
+                    Command::AddFirewallRules => {
+                        println!("\n>>> 前端请求添加防火墙规则 (端口 {})", udp_port);
+                        let ws_server = Arc::clone(&ws_server);
+                        // Waits for the UAC prompt to be answered
+                        tokio::task::spawn_blocking(move || {
+                            let (success, reason) = match firewall::add_rules(udp_port) {
+                                Ok(()) => {
+                                    println!("✓ 防火墙规则已添加");
+                                    (true, None)
+                                }
+                                Err(e) => {
+                                    eprintln!("❌ 添加防火墙规则失败: {}", e);
+                                    (false, Some(e.to_string()))
+                                }
+                            };
+                            ws_server.broadcast(Event::FirewallRulesResult { success, reason });
+                            ws_server.broadcast(Event::FirewallStatus { status: firewall::status(udp_port) });
+                        });
+                    }
+                    Command::GetAccessibilityStatus | Command::RequestAccessibility => {
+                        let status = if matches!(command, Command::RequestAccessibility) {
+                            println!("\n>>> 前端请求辅助功能权限");
+                            accessibility::prompt()
+                        } else {
+                            accessibility::status()
+                        };
+                        // Granted since startup: capture and injection work without a restart
+                        if status.state == AccessibilityState::Granted && !capabilities.capture.available {
+                            capabilities = capabilities::probe();
+                            capabilities.log();
+                            #[cfg(feature = "capture")]
+                            if hotkey_rx.is_none() && config.hotkeys && capabilities.hotkeys.available {
+                                hotkey_rx = Some(input_capture::spawn_hotkeys(config.capture_hotkey));
+                            }
+                            ws_server.broadcast(Event::CapabilityStatus { capabilities: capabilities.clone() });
+                        }
+                        ws_server.broadcast(Event::AccessibilityStatus { status });
+                    }
+                    Command::RunDiagnostics => {
+                        println!("\n>>> 前端请求运行自检");
+                        let target = SelfCheckTarget { peer_port: udp_port, ws_port, web_port: config.web_port, in_process: true };
+                        let simulator = Arc::clone(&local_simulator);
+                        let ws_server = Arc::clone(&ws_server);
+                        // Takes about a second, don't hold up the loop
+                        tokio::spawn(async move {
+                            let report = self_check::run(&target, &*simulator).await;
+                            ws_server.broadcast(Event::DiagnosticsReport { report });
+                        });
+                    }
+                    Command::AddManualPeer { ip, port, name } => {
+                        println!("\n>>> 前端手动添加设备: {}:{}", ip, port);
+                        // Unknown until the handshake, so the address stands in for the ID
+                        let _ = manual_peers_tx.send(DeviceInfo {
+                            id: format!("manual-{}:{}", ip, port),
+                            name: name.unwrap_or_else(|| ip.clone()),
+                            ip,
+                            port,
+                            device_type: "DESKTOP".to_string(),
+                        });
+                    }
+                    Command::StartDiscovery => {
+                        println!("\n>>> 前端请求开始发现设备");
+                        
+                        // Clean up stale devices (not seen in the last 10 seconds, or three broadcasts if those are slower)
+                        let stale_after = (config.broadcast_interval * 3).as_secs().max(10);
+                        let mut devices = discovered_devices.lock().await;
+                        let now = std::time::Instant::now();
+                        devices.retain(|id, (_, last_seen, source)| {
+                            let age = now.duration_since(*last_seen).as_secs();
+                            if age > stale_after && source.expires() {
+                                println!("  移除过期设备: {} ({}秒未见)", id, age);
+                                ws_server.broadcast(Event::DeviceLost { device_id: id.clone() });
+                                false
+                            } else {
+                                true
+                            }
+                        });
+                        
+                        let device_count = devices.len();
+                        
+                        if device_count > 0 {
+                            println!("  发送 {} 个已发现的设备到前端", device_count);
+                            for (device, _, source) in devices.values() {
+                                let slot = settings.slot_of(&device.id);
+                                let history = settings.devices.get(&device.id).cloned();
+                                ws_server.broadcast(Event::DeviceFound { device: device.clone(), source: *source, slot, history });
+                            }
+                        } else {
+                            println!("  当前没有已发现的设备");
+                        }
+                        
+                        ws_server.broadcast(Event::DeviceSlots { slots: settings.slots.clone() });
+                        ws_server.broadcast(Event::DeviceGroups { groups: settings.groups.clone() });
+                        ws_server.broadcast(Event::DeviceOverrides { overrides: settings.overrides.clone() });
+                        ws_server.broadcast(Event::PairedDevices { devices: pairing.lock().unwrap().devices().clone() });
+                        println!("  发现服务持续运行中...");
+                    }
+                    Command::StartCapture => {
+                        println!("Frontend requested to start input capture");
+                        if !capabilities.capture.available {
+                            eprintln!("  ❌ 本机不支持输入捕获: {}", capabilities.capture.reason.as_deref().unwrap_or("-"));
+                            ws_server.broadcast(Event::CapabilityStatus { capabilities: capabilities.clone() });
+                            ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::Unsupported });
+                            continue;
+                        }
+                        let mut capturing = is_capturing.lock().await;
+                        if !*capturing {
+                            // Drag-lock: buttons held locally move over to the peer
+                            let held = input_capture::pressed_mouse_buttons();
+                            for &button in &held {
+                                local_simulator.mouse_click(button, false);
+                            }
+                            
+                            input_rx = Some(input_capture.start_capture());
+                            *capturing = true;
+                            
+                            if !held.is_empty() {
+                                forwarder.press_held(&*active_connections.lock().await, &held);
+                            }
+                            
+                            println!("Input capture started");
+                            ws_server.broadcast(Event::CaptureStarted);
+                        }
+                    }
+                    Command::StopCapture => {
+                        println!("Frontend requested to stop input capture");
+                        let mut capturing = is_capturing.lock().await;
+                        if *capturing {
+                            input_capture.stop_capture();
+                            input_rx = None;
+                            *capturing = false;
+                            return_held_buttons(&mut forwarder, &*active_connections.lock().await, &*local_simulator);
+                            println!("Input capture stopped");
+                            ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::UserRequest });
+                        }
+                    }
+                    Command::RequestConnection { target_device_id, permissions } => {
+                        println!("\n>>> 前端请求连接到设备: {}", target_device_id);
+                        let permissions = permissions.unwrap_or_else(|| protocol::DEFAULT_PERMISSIONS.to_vec());
+                        
+                        // Get target device info
+                        let devices = discovered_devices.lock().await;
+                        if let Some((device, _, _)) = devices.get(&target_device_id) {
+                            let target_ip = device.ip.clone();
+                            let target_port = device.port;
+                            let target_name = device.name.clone();
+                            let target_device = device.clone();
+                            drop(devices);
+                            
+                            // Create cancel channel and save it with the attempt
+                            let (cancel_tx, mut cancel_rx) = tokio::sync::oneshot::channel::<()>();
+                            next_attempt_id += 1;
+                            let attempt_id = next_attempt_id;
+                            let previous = outgoing_requests.lock().await
+                                .insert(target_device_id.clone(), (attempt_id, cancel_tx));
+                            if let Some((_, previous_cancel)) = previous {
+                                // A new attempt to the same device supersedes the old one
+                                println!("  取消对该设备的上一次连接请求");
+                                let _ = previous_cancel.send(());
+                            }
+                            session_context.lifecycle.transition(&target_device_id, SessionState::Requesting);
+                            
+                            println!("  目标设备: {} ({})", target_name, target_ip);
+                            println!("  尝试建立 TCP 连接到 {}:{}", target_ip, target_port);
+                            
+                            let ws_server_clone = Arc::clone(&ws_server);
+                            let device_id_clone = target_device_id.clone();
+                            let outgoing_req = Arc::clone(&outgoing_requests);
+                            let session_ctx = session_context.clone();
+                            let wait = std::time::Duration::from_secs(request_timeouts.read().unwrap().wait_secs);
+                            let allow_plaintext = session_context.transport.read().unwrap().allow_plaintext;
+                            let handshake = Message::ConnectRequest {
+                                id: device_id.clone(),
+                                name: device_name.clone(),
+                                public_key: Some(identity.public.clone()),
+                                permissions: Permission::names(&permissions),
+                            };
+                            let identity = Arc::clone(&identity);
+                            let pairing = Arc::clone(&pairing);
+                            
+                            tokio::spawn(async move {
+                                let stats = Arc::clone(&session_ctx.stats);
+                                let started = std::time::Instant::now();
+                                let on_pending = |timeout_secs| {
+                                    ws_server_clone.broadcast(Event::ConnectionPending { device_id: device_id_clone.clone(), timeout_secs });
+                                };
+                                let addr = format!("{}:{}", target_ip, target_port);
+                                let result = match open_peer_stream(&addr, &identity, wait, allow_plaintext).await {
+                                    // Never send our request to something posing as a paired device
+                                    Ok(stream) => match pairing.lock().unwrap().check(&device_id_clone, &target_name, &stream) {
+                                        Some(Trust::Changed) => Err(SessionError::IdentityChanged),
+                                        Some(Trust::Unpaired { code }) => {
+                                            println!("  对方尚未配对，配对码: {}", code);
+                                            ws_server_clone.broadcast(Event::PairingCode { device_id: device_id_clone.clone(), code });
+                                            Ok(stream)
+                                        }
+                                        _ => Ok(stream),
+                                    },
+                                    Err(e) => Err(e),
+                                };
+                                let result = match result {
+                                    Ok(stream) => connect_to_peer(stream, &handshake, &mut cancel_rx, wait, on_pending).await,
+                                    Err(e) => Err(e),
+                                };
+                                let current = finish_outgoing_attempt(&outgoing_req, &device_id_clone, attempt_id).await;
+                                match result {
+                                    Ok(Some((stream, granted))) => {
+                                        println!("  ✓ 握手成功，连接已建立，获得的权限: {:?}", granted);
+                                        stats.handshake(&device_id_clone, started.elapsed());
+                                        
+                                        let conn_key = format!("{}:{}", target_ip, target_port);
+                                        session::start_session(
+                                            session_ctx,
+                                            stream,
+                                            conn_key,
+                                            target_device,
+                                            Role::Controller,
+                                            ControlGrant { permissions: granted, ..Default::default() },
+                                        ).await;
+                                    }
+                                    // Cancelled; the connection closes when the stream is dropped
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        eprintln!("  ❌ {}", e);
+                                        stats.error(&device_id_clone, &e.stats_kind());
+                                        // A newer attempt to the same device is still Requesting
+                                        if current {
+                                            session_ctx.lifecycle.transition(&device_id_clone, SessionState::Idle);
+                                        }
+                                        ws_server_clone.broadcast(Event::ConnectionFailed { 
+                                            device_id: device_id_clone,
+                                            reason: e.to_string(),
+                                            reject_reason: e.reject_reason(),
+                                            code: e.code(),
+                                        });
+                                    }
+                                }
+                            });
+                        } else {
+                            eprintln!("  ❌ 未找到设备: {}", target_device_id);
+                            let e = SessionError::DeviceNotFound;
+                            ws_server.broadcast(Event::ConnectionFailed {
+                                device_id: target_device_id,
+                                reason: e.to_string(),
+                                reject_reason: None,
+                                code: e.code(),
+                            });
+                        }
+                    }
+                    Command::RejectConnection { target_device_id } => {
+                        println!("\n>>> 前端拒绝了来自 {} 的连接", target_device_id);
+                        
+                        // Clear latest request
+                        *latest_connection_request.lock().await = None;
+                        
+                        // Find and reject pending connection
+                        let mut pending = pending_connections.lock().await;
+                        let pending_addr = pending.iter()
+                            .find(|(_, (_, dev, _, _))| dev.as_ref().map(|d| &d.id) == Some(&target_device_id))
+                            .map(|(addr, _)| addr.clone());
+                        
+                        if let Some(addr) = pending_addr {
+                            if let Some((mut stream, device, _, _)) = pending.remove(&addr) {
+                                println!("  找到待处理连接: {}", addr);
+                                println!("  发送拒绝响应");
+                                let _ = stream.send(&Message::ConnectResponse { success: false, reason: Some(RejectReason::UserDeclined), granted: Vec::new() }).await;
+                                session_context.lifecycle.transition(&target_device_id, SessionState::Idle);
+                                if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
+                                    record_failed_attempt(&attempt_limiter, &ws_server, addr.ip(), Some(target_device_id.clone()));
+                                }
+                                if prompt_muter.lock().unwrap().declined(&target_device_id, std::time::Instant::now()) {
+                                    println!("  ⚠ 已多次拒绝 {}，{} 分钟内自动拒绝其请求", target_device_id, MUTE_DURATION.as_secs() / 60);
+                                    if let Some(device) = device {
+                                        ws_server.broadcast(Event::ConnectionRequestsMuted { device, muted_secs: MUTE_DURATION.as_secs() });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Command::UnmuteDevice { target_device_id } => {
+                        println!("\n>>> 前端取消了对 {} 的静音", target_device_id);
+                        prompt_muter.lock().unwrap().unmute(&target_device_id);
+                    }
+                    Command::ConfirmPairing { device_id: peer_id, code } => {
+                        println!("\n>>> 前端确认了与 {} 的配对码", peer_id);
+                        let now_ms = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap()
+                            .as_millis() as u64;
+                        let result = pairing.lock().unwrap().confirm(&peer_id, &code, now_ms);
+                        match result {
+                            Ok(()) => {
+                                println!("  ✓ 已与 {} 配对", peer_id);
+                                ws_server.broadcast(Event::PairedDevices { devices: pairing.lock().unwrap().devices().clone() });
+                                // While the prompt is still up the acceptance tells it instead
+                                for (sender, _, connected_id) in active_connections.lock().await.values() {
+                                    if *connected_id == peer_id {
+                                        let _ = sender.send(Message::PairingConfirmed);
+                                    }
+                                }
+                            }
+                            Err(e) => report_pairing_error(&ws_server, peer_id, e),
+                        }
+                    }
+                    Command::Unpair { device_id: peer_id } => {
+                        println!("\n>>> 前端取消了与 {} 的配对", peer_id);
+                        let result = pairing.lock().unwrap().unpair(&peer_id);
+                        match result {
+                            Ok(true) => ws_server.broadcast(Event::PairedDevices { devices: pairing.lock().unwrap().devices().clone() }),
+                            Ok(false) => println!("  该设备未配对"),
+                            Err(e) => report_pairing_error(&ws_server, peer_id, e),
+                        }
+                    }
+                    Command::GetPairedDevices => {
+                        ws_server.broadcast(Event::PairedDevices { devices: pairing.lock().unwrap().devices().clone() });
+                    }
+                    Command::CancelConnection { target_device_id } => {
+                        println!("\n>>> 前端取消了连接请求");
+                        
+                        // Take the cancel senders: one target, or every pending attempt
+                        let mut outgoing = outgoing_requests.lock().await;
+                        let requests: Vec<(String, (u64, _))> = match target_device_id {
+                            Some(target) => outgoing.remove_entry(&target).into_iter().collect(),
+                            None => outgoing.drain().collect(),
+                        };
+                        drop(outgoing);
+                        
+                        if requests.is_empty() {
+                            println!("  没有正在进行的连接请求");
+                        }
+                        for (device_id, (_, cancel_tx)) in requests {
+                            println!("  取消对 {} 的连接请求", device_id);
+                            
+                            // Send cancel signal
+                            let _ = cancel_tx.send(());
+                            println!("  已发送取消信号");
+                            session_context.lifecycle.transition(&device_id, SessionState::Idle);
+                        }
+                    }
+                    Command::AcceptConnection { target_device_id, time_limit_secs, permissions } => {
+                        println!("\n>>> 前端接受了来自 {} 的连接", target_device_id);
+                        if let Some(secs) = time_limit_secs {
+                            println!("  限时控制: {} 秒", secs);
+                        }
+                        
+                        // Clear latest request
+                        *latest_connection_request.lock().await = None;
+                        
+                        // Find pending connection by device ID
+                        let mut pending = pending_connections.lock().await;
+                        let pending_addr = pending.iter()
+                            .find(|(_, (_, dev, _, _))| dev.as_ref().map(|d| &d.id) == Some(&target_device_id))
+                            .map(|(addr, _)| addr.clone());
+                        
+                        if let Some(addr) = pending_addr {
+                            if let Some((mut stream, Some(device), _, requested)) = pending.remove(&addr) {
+                                println!("  找到待处理连接: {}", addr);
+                                if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
+                                    attempt_limiter.lock().unwrap().succeed(addr.ip());
+                                }
+                                prompt_muter.lock().unwrap().unmute(&device.id);
+                                
+                                // The user, or else the device's own or its group's defaults, can only take away from what was asked for
+                                let group = settings.group_of(&device.id).cloned().unwrap_or_default();
+                                let overrides = settings.overrides_of(&device.id);
+                                let chosen = permissions.as_ref().or(overrides.permissions.as_ref()).or(group.permissions.as_ref());
+                                let granted: Vec<Permission> = match chosen {
+                                    Some(chosen) => requested.into_iter().filter(|p| chosen.contains(p)).collect(),
+                                    None => requested,
+                                };
+                                println!("  授予的权限: {:?}", granted);
+                                
+                                // Send accept response
+                                let response = Message::ConnectResponse { success: true, reason: None, granted: Permission::names(&granted) };
+                                match stream.send(&response).await {
+                                    Ok(_) => {
+                                        println!("  ✓ 已发送接受响应");
+                                        // Paired before or while the prompt was up: the requester's UI can say so
+                                        if pairing.lock().unwrap().is_paired(&device.id, &stream) {
+                                            let _ = stream.send(&Message::PairingConfirmed).await;
+                                        }
+                                        println!("  ✓ 连接已建立，开始接收输入事件");
+                                        session::start_session(
+                                            session_context.clone(),
+                                            stream,
+                                            addr.clone(),
+                                            device,
+                                            Role::Controlled,
+                                            ControlGrant {
+                                                pause_local_input,
+                                                time_limit: time_limit_secs.map(tokio::time::Duration::from_secs),
+                                                permissions: granted,
+                                                pointer_speed: Some(overrides.pointer_speed.unwrap_or(group.pointer_speed)),
+                                                invert_scroll: overrides.invert_scroll.unwrap_or(false),
+                                            },
+                                        ).await;
+                                    }
+                                    Err(e) => {
+                                        eprintln!("  ❌ 发送响应失败: {}", e);
+                                        session_context.lifecycle.transition(&device.id, SessionState::Idle);
+                                    }
+                                }
+                            }
+                        } else {
+                            eprintln!("  ❌ 未找到待处理的连接");
+                        }
+                    }
+                    Command::Disconnect => {
+                        println!("\n>>> 前端请求断开连接");
+                        
+                        // Stop input capture when disconnecting
+                        let mut capturing = is_capturing.lock().await;
+                        if *capturing {
+                            input_capture.stop_capture();
+                            input_rx = None;
+                            *capturing = false;
+                            println!("  输入捕获已停止");
+                            ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::UserRequest });
+                        }
+                        
+                        // Close all active connections
+                        let mut connections = active_connections.lock().await;
+                        let conn_count = connections.len();
+                        
+                        // Don't leave a button stuck down on the peer
+                        forwarder.release_held(&connections);
+                        
+                        // Tell peers we are leaving, then abort all receiving tasks
+                        for (_, (sender, abort_handle, peer_id)) in connections.iter() {
+                            let _ = sender.send(Message::Disconnect);
+                            abort_handle.abort();
+                            // Aborted receive tasks never reach their own bookkeeping
+                            session_context.stats.session_ended(peer_id, "localDisconnect");
+                        }
+                        let ended: Vec<String> = connections.values().map(|(_, _, peer_id)| peer_id.clone()).collect();
+                        
+                        connections.clear();
+                        session_context.end_all_control().await;
+                        println!("  已关闭 {} 个连接", conn_count);
+                        
+                        // Clear pending connections
+                        pending_connections.lock().await.clear();
+                        
+                        // Receive tasks were aborted, so give local input back here
+                        if local_input_lock.is_active() {
+                            local_input_lock.deactivate();
+                            ws_server.broadcast(Event::LocalInputPaused { paused: false });
+                        }
+                        
+                        ws_server.broadcast(Event::Disconnected);
+                        drop(connections);
+                        let devices = discovered_devices.lock().await;
+                        for peer_id in ended {
+                            remember_session(&mut settings, &config.settings, &session_context.stats, &peer_id);
+                            if let Some((device, _, source)) = devices.get(&peer_id) {
+                                refresh_device(&ws_server, &settings, device, *source);
+                            }
+                        }
+                        println!("  ✓ 断开完成");
+                    }
+                    Command::Shutdown => {
+                        println!("\n>>> 收到退出请求，正在关闭");
+                        input_capture.stop_capture();
+                        
+                        // Tell peers we are leaving, so they don't wait for a timeout
+                        let connections = active_connections.lock().await;
+                        forwarder.release_held(&connections);
+                        for (_, (sender, _, peer_id)) in connections.iter() {
+                            let _ = sender.send(Message::Disconnect);
+                            session_context.stats.session_ended(peer_id, "shutdown");
+                            remember_session(&mut settings, &config.settings, &session_context.stats, peer_id);
+                        }
+                        drop(connections);
+                        if local_input_lock.is_active() {
+                            local_input_lock.deactivate();
+                        }
+                        
+                        // Small delay to ensure message is sent
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        return Ok(());
+                    }
+                    Command::SetEdgeBehavior { device_id, behavior } => {
+                        println!("\n>>> 前端设置屏幕边缘行为: {} -> {:?}", device_id, behavior);
+                        session_context.edge_behavior.write().unwrap().insert(device_id, behavior);
+                    }
+                    Command::SetDeviceSlot { slot, device_id } => {
+                        if !(1..=settings::SLOT_COUNT).contains(&slot) {
+                            eprintln!("  ❌ 无效的槽位: {}", slot);
+                            continue;
+                        }
+                        println!("\n>>> 前端设置槽位 {}: {:?}", slot, device_id);
+                        settings.assign_slot(slot, device_id);
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        ws_server.broadcast(Event::DeviceSlots { slots: settings.slots.clone() });
+                    }
+                    Command::SetActiveTarget { device_id } => {
+                        println!("\n>>> 前端切换输入目标: {:?}", device_id);
+                        forwarder.set_target(&*active_connections.lock().await, device_id.clone());
+                        ws_server.broadcast(Event::ActiveTargetChanged { device_id });
+                    }
+                    Command::SetReturnEdge { edge } => {
+                        println!("\n>>> 前端设置交还控制的边缘: {:?}", edge);
+                        *session_context.return_edge.write().unwrap() = edge;
+                    }
+                    Command::SetScreenLayout { edge, device_id } => {
+                        println!("\n>>> 前端设置屏幕边缘切换: {:?} -> {:?}", edge, device_id);
+                        match device_id {
+                            Some(device_id) => settings.screen_layout.insert(edge, device_id),
+                            None => settings.screen_layout.remove(&edge),
+                        };
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        // Wherever the cursor is now, it has to get to the edge first
+                        edge_watch.disarm();
+                        ws_server.broadcast(Event::ScreenLayout { layout: settings.screen_layout.clone() });
+                    }
+                    Command::GetScreenLayout => {
+                        ws_server.broadcast(Event::ScreenLayout { layout: settings.screen_layout.clone() });
+                    }
+                    Command::SetRelayEdge { edge, device_id } => {
+                        println!("\n>>> 前端设置转发边缘: {:?} -> {:?}", edge, device_id);
+                        let mut relay_edges = session_context.relay_edges.write().unwrap();
+                        match device_id {
+                            Some(device_id) => relay_edges.insert(edge, device_id),
+                            None => relay_edges.remove(&edge),
+                        };
+                        ws_server.broadcast(Event::RelayEdges { edges: relay_edges.clone() });
+                    }
+                    Command::SetSessionMode { mode } => {
+                        println!("\n>>> 前端切换会话模式: {:?}", mode);
+                        forwarder.mode = mode;
+                        ws_server.broadcast(Event::SessionModeChanged { mode });
+                    }
+                    Command::SetMouseCoalescing { interval_ms } => {
+                        println!("\n>>> 前端设置鼠标合并间隔: {} ms", interval_ms);
+                        coalescing.global_ms = interval_ms;
+                        coalescing.refresh(&settings, &mut forwarder, &mut mouse_flush_interval, &*active_connections.lock().await);
+                    }
+                    Command::SetDeviceGroup { name, group } => {
+                        println!("\n>>> 前端设置设备分组 {}: {:?}", name, group);
+                        settings.set_group(&name, group);
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        *auto_accept.write().unwrap() = auto_accepted(&settings, &app_config);
+                        ws_server.broadcast(Event::DeviceGroups { groups: settings.groups.clone() });
+                    }
+                    Command::GetDeviceGroups => {
+                        ws_server.broadcast(Event::DeviceGroups { groups: settings.groups.clone() });
+                    }
+                    Command::SetDeviceOverrides { device_id, overrides } => {
+                        println!("\n>>> 前端设置设备 {} 的单独设置: {:?}", device_id, overrides);
+                        settings.set_overrides(&device_id, overrides);
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        // Sessions already running keep their grant, only coalescing follows at once
+                        coalescing.refresh(&settings, &mut forwarder, &mut mouse_flush_interval, &*active_connections.lock().await);
+                        ws_server.broadcast(Event::DeviceOverrides { overrides: settings.overrides.clone() });
+                    }
+                    Command::GetDeviceOverrides => {
+                        ws_server.broadcast(Event::DeviceOverrides { overrides: settings.overrides.clone() });
+                    }
+                    Command::GetTransportOptions => {
+                        ws_server.broadcast(Event::TransportOptions { options: settings.transport });
+                    }
+                    Command::GetConfig => {
+                        let restart_required = app_config.restart_required(&started_with);
+                        ws_server.broadcast(Event::Config { config: app_config.clone(), path: config.config_file.display().to_string(), restart_required });
+                    }
+                    Command::SetConfig { config: new_config } => {
+                        println!("\n>>> 前端修改配置文件: {:?}", new_config);
+                        match new_config.validate().and_then(|()| new_config.save(&config.config_file)) {
+                            Ok(()) => {
+                                app_config = new_config;
+                                *auto_accept.write().unwrap() = auto_accepted(&settings, &app_config);
+                            }
+                            // The frontend gets the file as it still is
+                            Err(e) => eprintln!("  ❌ 无法保存配置: {}", e),
+                        }
+                        let restart_required = app_config.restart_required(&started_with);
+                        ws_server.broadcast(Event::Config { config: app_config.clone(), path: config.config_file.display().to_string(), restart_required });
+                    }
+                    Command::GetRequestTimeouts => {
+                        ws_server.broadcast(Event::RequestTimeouts { timeouts: settings.request_timeouts });
+                    }
+                    Command::SetRequestTimeouts { timeouts } => {
+                        let timeouts = timeouts.clamped();
+                        println!("\n>>> 前端设置请求超时: 等待确认 {} 秒, 等待对方 {} 秒", timeouts.approval_secs, timeouts.wait_secs);
+                        // Prompts already up keep counting from when they appeared
+                        *request_timeouts.write().unwrap() = timeouts;
+                        settings.request_timeouts = timeouts;
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        ws_server.broadcast(Event::RequestTimeouts { timeouts });
+                    }
+                    Command::SetTransportOptions { options } => {
+                        println!("\n>>> 前端设置传输选项: {:?}", options);
+                        if let FlushStrategy::Periodic { interval_ms } = options.flush {
+                            if !(1..=MAX_FLUSH_INTERVAL_MS).contains(&interval_ms) {
+                                eprintln!("  ❌ 无效的刷新间隔: {} ms (1-{})", interval_ms, MAX_FLUSH_INTERVAL_MS);
+                                continue;
+                            }
+                        }
+                        if options.bulk_cap_kib_per_sec == Some(0) {
+                            eprintln!("  ❌ 无效的批量传输限速: 0 KiB/s (不限速请设为 null)");
+                            continue;
+                        }
+                        // Running sessions keep what they started with
+                        *session_context.transport.write().unwrap() = options;
+                        settings.transport = options;
+                        if let Err(e) = settings.save(&config.settings) {
+                            eprintln!("  ❌ 保存设置失败: {}", e);
+                        }
+                        ws_server.broadcast(Event::TransportOptions { options });
+                    }
+                    Command::SetLocalInputPause { enabled } => {
+                        println!("\n>>> 前端设置被控时暂停本地输入: {}", enabled);
+                        pause_local_input = enabled;
+                        if !enabled && local_input_lock.is_active() {
+                            local_input_lock.deactivate();
+                            ws_server.broadcast(Event::LocalInputPaused { paused: false });
+                        }
+                    }
+                    Command::SetCursorPrediction { enabled } => {
+                        println!("\n>>> 前端设置光标预测 (实验性): {}", enabled);
+                        // Running sessions keep what they started with
+                        session_context.cursor_prediction.store(enabled, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    Command::SetVisualization { mouse, keyboard } => {
+                        println!("\n>>> 前端设置输入可视化: mouse={}, keyboard={}", mouse, keyboard);
+                        visualization.set(mouse, keyboard);
+                    }
+                    Command::SetAutoStopCapture { enabled } => {
+                        println!("\n>>> 前端设置断开后自动停止捕获: {}", enabled);
+                        capture_watch.set_auto_stop(enabled);
+                    }
+                    Command::SetGrabExclusions { key_classes, keys, processes, devices } => {
+                        println!("\n>>> 前端设置捕获排除: {:?} {:?} {:?} {:?}", key_classes, keys, processes, devices);
+                        if !processes.is_empty() && !cfg!(windows) {
+                            println!("  ⚠ 按进程排除仅支持 Windows");
+                        }
+                        if !devices.is_empty() && !capabilities.device_filter.available {
+                            println!("  ⚠ 按设备排除仅支持 Linux Wayland 会话");
+                        }
+                        *grab_exclusions.write().unwrap() = GrabExclusions { key_classes, keys, processes, devices };
+                    }
+                    Command::SetAuditLog { mode } => {
+                        println!("\n>>> 前端设置审计日志: {:?}", mode);
+                        if let Err(e) = session_context.audit.set_mode(mode) {
+                            eprintln!("  ❌ 无法打开审计日志 {}: {}", session_context.audit.path().display(), e);
+                        }
+                        ws_server.broadcast(Event::AuditLogStatus {
+                            mode: session_context.audit.mode(),
+                            path: session_context.audit.path().display().to_string(),
+                        });
+                    }
+                    Command::SetDiagnostics { enabled } => {
+                        println!("\n>>> 前端{}流水线计时", if enabled { "开启" } else { "关闭" });
+                        diagnostics::set_enabled(enabled);
+                    }
+                    Command::GetDiagnostics => {
+                        ws_server.broadcast(Event::Diagnostics { stages: diagnostics::snapshot() });
+                    }
+                    Command::SendInput { event, target_device_id } => {
+                        // Forward input to connected peer via TCP (lock-free)
+                        let connections = active_connections.lock().await;
+                        let target = target_device_id.as_deref();
+                        
+                        if connections.is_empty() {
+                            // No active connection, ignore
+                            continue;
+                        }
+                        
+                        match event.event_type.as_str() {
+                            "mousemove" => {
+                                // Send mouse move immediately (no accumulation)
+                                if let (Some(dx), Some(dy)) = (event.dx, event.dy) {
+                                    let dx_int = dx as i32;
+                                    let dy_int = dy as i32;
+                                    
+                                    if dx_int != 0 || dy_int != 0 {
+                                        forwarder.forward_client(&connections, target, Message::MouseMove { x: dx_int, y: dy_int });
+                                    }
+                                }
+                            }
+                            "wheel" => {
+                                let (dx_int, dy_int) = event.wheel_delta();
+                                if dx_int != 0 || dy_int != 0 {
+                                    forwarder.forward_client(&connections, target, Message::MouseWheel { delta_x: dx_int, delta_y: dy_int });
+                                }
+                            }
+                            _ => {
+                                // For other events (clicks, keys), send immediately
+                                let msg = match event.event_type.as_str() {
+                                    "mousedown" => {
+                                        let button = event.button.unwrap_or(match event.key.as_deref() {
+                                            Some("button1") => 1, // Right
+                                            Some("button2") => 2, // Middle
+                                            Some("button3") => protocol::BUTTON_BACK,
+                                            Some("button4") => protocol::BUTTON_FORWARD,
+                                            _ => 0, // Left
+                                        });
+                                        Some(Message::MouseClick { button, state: true, elapsed_ms: 0 })
+                                    }
+                                    "mouseup" => {
+                                        let button = event.button.unwrap_or(match event.key.as_deref() {
+                                            Some("button1") => 1, // Right
+                                            Some("button2") => 2, // Middle
+                                            Some("button3") => protocol::BUTTON_BACK,
+                                            Some("button4") => protocol::BUTTON_FORWARD,
+                                            _ => 0, // Left
+                                        });
+                                        Some(Message::MouseClick { button, state: false, elapsed_ms: 0 })
+                                    }
+                                    "keydown" => {
+                                        // Exact code when the client sent one, else the key's first char
+                                        event.key_code
+                                            .or_else(|| event.key.as_ref().map(|key| key.chars().next().unwrap_or('\0') as u32))
+                                            .map(|key| Message::KeyPress { key, state: true })
+                                    }
+                                    "keyup" => {
+                                        // Exact code when the client sent one, else the key's first char
+                                        event.key_code
+                                            .or_else(|| event.key.as_ref().map(|key| key.chars().next().unwrap_or('\0') as u32))
+                                            .map(|key| Message::KeyPress { key, state: false })
+                                    }
+                                    "wheel" => None, // Already handled above
+                                    _ => None,
+                                };
+
+                                if let Some(msg) = msg {
+                                    forwarder.forward_client(&connections, target, msg);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            
+            // Hotkeys: go through the same path as the frontend's StartCapture
+            Some(hotkey) = async {
+                if let Some(ref mut rx) = hotkey_rx {
+                    rx.recv().await
+                } else {
+                    std::future::pending().await
+                }
+            } => {
+                if *is_capturing.lock().await {
+                    continue;
+                }
+                let connections = active_connections.lock().await;
+                if connections.is_empty() {
+                    println!("  没有已连接的设备，忽略开始捕获快捷键");
+                    continue;
+                }
+                // A slot picks the device first, then control moves over to it
+                if let Hotkey::Slot(slot) = hotkey {
+                    if !switch_to_slot(&settings, &mut forwarder, &connections, &ws_server, slot) {
+                        continue;
+                    }
+                }
+                ws_server.send_command(Command::StartCapture);
+            }
+            
+            // Emergency hotkey broke the local input lock
+            Some(()) = local_unlock_rx.recv() => {
+                ws_server.broadcast(Event::LocalInputPaused { paused: false });
+            }
+            
+            // Handle captured input events
+            control_msg = async {
+                if let Some(ref mut rx) = input_rx {
+                    rx.recv().await
+                } else {
+                    std::future::pending().await
+                }
+            } => {
+                let Some(control_msg) = control_msg else {
+                    // Capture thread went away without being asked to
+                    eprintln!("Input capture ended unexpectedly (hook {:?})", input_capture.state());
+                    input_rx = None;
+                    *is_capturing.lock().await = false;
+                    return_held_buttons(&mut forwarder, &*active_connections.lock().await, &*local_simulator);
+                    ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::Error });
+                    continue;
+                };
+                match control_msg {
+                    CaptureControl::InputEvent(input_event) => {
+                        // Convert to WebSocket message and broadcast to frontend for visualization
+                        // Optimization: Skip mousemove events to prevent frontend crash due to high frequency
+                        if input_event.event_type != "mousemove" && visualization.wants(&input_event.event_type) {
+                            let ws_event = InputEvent {
+                                event_type: input_event.event_type.clone(),
+                                x: input_event.x,
+                                y: input_event.y,
+                                dx: input_event.dx,
+                                dy: input_event.dy,
+                                key: input_event.key.clone(),
+                                key_code: input_event.key_code,
+                                button: input_event.button,
+                                modifiers: Some(input_event.modifiers),
+                                delta_mode: None,
+                                timestamp: std::time::SystemTime::now()
+                                    .duration_since(std::time::UNIX_EPOCH)
+                                    .unwrap()
+                                    .as_millis() as u64,
+                            };
+                            ws_server.broadcast(Event::LocalInput { event: ws_event });
+                        }
+                        
+                        // Forward to connected peer via TCP
+                        let connections = active_connections.lock().await;
+                        if !connections.is_empty() {
+                            let _span = tracing::trace_span!("forward", event = %input_event.event_type).entered();
+                            match input_event.event_type.as_str() {
+                                "mousemove" => {
+                                    // Send mouse move immediately (no accumulation)
+                                    if let (Some(dx), Some(dy)) = (input_event.dx, input_event.dy) {
+                                        let dx_int = dx as i32;
+                                        let dy_int = dy as i32;
+                                        
+                                        if dx_int != 0 || dy_int != 0 {
+                                            forwarder.forward(&connections, Message::MouseMove { x: dx_int, y: dy_int });
+                                        }
+                                    }
+                                }
+                                "wheel" => {
+                                    if let (Some(dx), Some(dy)) = (input_event.dx, input_event.dy) {
+                                        let dx_int = (dx * WHEEL_DELTA as f64).round() as i32;
+                                        let dy_int = (dy * WHEEL_DELTA as f64).round() as i32;
+                                        
+                                        if dx_int != 0 || dy_int != 0 {
+                                            forwarder.forward(&connections, Message::MouseWheel { delta_x: dx_int, delta_y: dy_int });
+                                        }
+                                    }
+                                }
+                                "mousedown" | "mouseup" => {
+                                    // The hook only reports buttons with a protocol number, back and forward included
+                                    if let Some(click) = input_event.click() {
+                                        println!("[主控端] 捕获到鼠标点击: {:?}", LoggedMessage(&click));
+                                        forwarder.forward(&connections, click);
+                                    }
+                                }
+                                "longpress" => {
+                                    // Handle long-press events
+                                    if let Some(key) = input_event.key {
+                                        println!("[主控端] 检测到长按: key={}", Redacted(&key));
+                                        // Long-press is just informational, no need to send to peer
+                                        // The peer already received keydown and will handle it
+                                    }
+                                }
+                                "keydown" | "keyup" => {
+                                    if let Some(code) = input_event.key_code {
+                                        let state = input_event.event_type == "keydown";
+                                        // println!("[主控端] 捕获到按键: code={}, state={}", code, state);
+                                        
+                                        if code != 0 {
+                                            forwarder.forward(&connections, Message::KeyPress { key: code, state });
+                                        }
+                                    } else if let Some(key_str) = input_event.key {
+                                        // Fallback for legacy support or unmapped keys
+                                        // Convert rdev key format (e.g., "KeyA") to character
+                                        let key_code = if key_str.starts_with("Key") && key_str.len() == 4 {
+                                            // Single letter key like "KeyA" -> 'A'
+                                            key_str.chars().nth(3).unwrap_or('\0') as u32
+                                        } else if key_str.starts_with("Num") && key_str.len() == 4 {
+                                            // Number key like "Num0" -> '0'
+                                            key_str.chars().nth(3).unwrap_or('\0') as u32
+                                        } else {
+                                            // Special keys
+                                            match key_str.as_str() {
+                                                "Return" => 13,
+                                                "Space" => 32,
+                                                "Backspace" => 8,
+                                                "Tab" => 9,
+                                                "Escape" => 27,
+                                                _ => 0,
+                                            }
+                                        };
+                                        
+                                        if key_code != 0 {
+                                            let state = input_event.event_type == "keydown";
+                                            println!("[主控端] 捕获到按键(Fallback): key_str={}, key_code={}, state={}", Redacted(&key_str), Redacted(key_code), state);
+                                            forwarder.forward(&connections, Message::KeyPress { key: key_code, state });
+                                        }
+                                    }
+                                }
+                                _ => {}
+                            }
+                            diagnostics::record(Stage::Capture, input_event.captured_at.elapsed());
+                        }
+                    }
+                    CaptureControl::SwitchSlot(slot) => {
+                        switch_to_slot(&settings, &mut forwarder, &*active_connections.lock().await, &ws_server, slot);
+                    }
+                    CaptureControl::ExitRequested => {
+                        println!("Exit requested from input capture - stopping capture and disconnecting");
+                        
+                        // Stop input capture
+                        let mut capturing = is_capturing.lock().await;
+                        if *capturing {
+                            input_capture.stop_capture();
+                            input_rx = None;
+                            *capturing = false;
+                            ws_server.broadcast(Event::CaptureStopped { reason: CaptureStopReason::Hotkey });
+                        }
+                        
+                        // Close all active connections (this will notify remote peers)
+                        let connections = active_connections.lock().await;
+                        let conn_count = connections.len();
+                        println!("  准备关闭 {} 个连接...", conn_count);
+                        
+                        // Finish an in-progress drag locally instead of on the peer
+                        return_held_buttons(&mut forwarder, &connections, &*local_simulator);
+                        
+                        // Send disconnect message to all peers and abort receiving tasks
+                        for (addr, (sender, abort_handle, peer_id)) in connections.iter() {
+                            println!("  发送断开消息到: {}", addr);
+                            let _ = sender.send(Message::Disconnect);
+                            abort_handle.abort();
+                            session_context.stats.session_ended(peer_id, "hotkeyExit");
+                            remember_session(&mut settings, &config.settings, &session_context.stats, peer_id);
+                        }
+                        drop(connections);
+                        
+                        // Small delay to ensure message is sent
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        
+                        // Now clear connections
+                        active_connections.lock().await.clear();
+                        session_context.end_all_control().await;
+                        println!("  ✓ 已关闭所有连接");
+                        
+                        // Clear pending connections
+                        pending_connections.lock().await.clear();
+                        
+                        // Notify frontend to disconnect
+                        ws_server.broadcast(Event::Disconnected);
+                        println!("  ✓ 断开完成");
+                    }
+                }
+            }
+        }
+    }
+}
+
@@ -0,0 +1,1531 @@
+use crate::audit::AuditLog;
+use crate::diagnostics::{self, Stage};
+use crate::echo::{self, EchoTracker};
+use crate::forwarder::{ActiveConnections, MessageSender, WeakMessageSender};
+use crate::input_capture::LocalInputLock;
+use crate::input_simulator::{ClickPacer, InputBackend, WheelAccumulator};
+use crate::clipboard::ClipboardEvent;
+use crate::file_transfer::FileEvent;
+use crate::privacy::LoggedMessage;
+use crate::screens::Topology;
+use crate::screenshot::{self, Assembler, ThumbnailAssembler};
+use crate::settings;
+use crate::protocol::{self, LockState, Message, PeerFeature, Permission, ScreenEdge, TargetUnavailable};
+use crate::stats::ConnectionStats;
+use crate::error::TransportError;
+use crate::transport::{FlushStrategy, PeerStream, TransportOptions};
+use crate::websocket::{DeviceInfo, EdgeBehavior, Event, InputEvent, VisualizationFilter, WebSocketServer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::Instrument;
+
+/// Which side of the handshake we were on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// We sent the ConnectRequest and forward our input
+    Controller,
+    /// We accepted the request and inject the peer's input
+    Controlled,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Controller => "controller",
+            Role::Controlled => "controlled",
+        }
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            Role::Controller => "[主控端]",
+            Role::Controlled => "[被控端]",
+        }
+    }
+}
+
+/// How long the controller waits for the TCP connection itself
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a session with one peer stands, from either side.
+///
+/// Idle → Requesting (we asked) or PendingApproval (they asked) → Established
+/// → Closing → Idle; a request that fails or is declined goes straight back to Idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SessionState {
+    Idle,
+    Requesting,
+    PendingApproval,
+    Established,
+    Closing,
+}
+
+impl SessionState {
+    pub fn can_become(self, next: SessionState) -> bool {
+        use SessionState::*;
+        matches!(
+            (self, next),
+            (Idle, Requesting | PendingApproval)
+                | (Requesting | PendingApproval, Established | Idle)
+                | (Established, Closing)
+                | (Closing, Idle)
+        )
+    }
+
+    /// How long the state may last before something was lost along the way;
+    /// the owners of each state normally leave it well before, whatever the
+    /// configured request timeouts
+    fn timeout(self) -> Option<Duration> {
+        let longest_request = Duration::from_secs(settings::MAX_REQUEST_TIMEOUT_SECS);
+        match self {
+            SessionState::Idle | SessionState::Established => None,
+            SessionState::Requesting => Some(CONNECT_TIMEOUT + longest_request + Duration::from_secs(5)),
+            // The expired-request sweep runs every 5 seconds
+            SessionState::PendingApproval => Some(longest_request + Duration::from_secs(10)),
+            SessionState::Closing => Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// The session state of every peer that isn't Idle, announced to the frontend on each change
+pub struct Lifecycle {
+    states: std::sync::Mutex<HashMap<String, (SessionState, Instant)>>,
+    ws_server: Arc<WebSocketServer>,
+}
+
+impl Lifecycle {
+    pub fn new(ws_server: Arc<WebSocketServer>) -> Self {
+        Lifecycle { states: std::sync::Mutex::new(HashMap::new()), ws_server }
+    }
+
+    pub fn state(&self, device_id: &str) -> SessionState {
+        self.states.lock().unwrap().get(device_id).map_or(SessionState::Idle, |(state, _)| *state)
+    }
+
+    /// Move `device_id` to `next`; false (and logged) if that skips a step.
+    /// Staying in the same state is allowed and keeps its deadline.
+    pub fn transition(&self, device_id: &str, next: SessionState) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let current = states.get(device_id).map_or(SessionState::Idle, |(state, _)| *state);
+        if current == next {
+            return true;
+        }
+        if !current.can_become(next) {
+            eprintln!("  ⚠ 会话状态无效转换 ({}): {:?} -> {:?}", device_id, current, next);
+            return false;
+        }
+        if next == SessionState::Idle {
+            states.remove(device_id);
+        } else {
+            states.insert(device_id.to_string(), (next, Instant::now()));
+        }
+        self.ws_server.broadcast(Event::SessionStateChanged { device_id: device_id.to_string(), state: next });
+        true
+    }
+
+    /// For teardowns that abort sessions: every Established peer closes and goes Idle
+    pub fn close_all(&self) {
+        let established: Vec<String> = self
+            .states
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (state, _))| *state == SessionState::Established)
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+        for device_id in established {
+            self.transition(&device_id, SessionState::Closing);
+            self.transition(&device_id, SessionState::Idle);
+        }
+    }
+
+    /// Send back to Idle whatever outstayed its state's timeout; returns those device IDs
+    pub fn expire(&self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.states.lock().unwrap().retain(|device_id, (state, since)| {
+            let overdue = state.timeout().is_some_and(|timeout| now.duration_since(*since) > timeout);
+            if overdue {
+                println!("⏰ 会话状态超时 ({}): {:?} -> Idle", device_id, state);
+                expired.push(device_id.clone());
+            }
+            !overdue
+        });
+        for device_id in &expired {
+            self.ws_server.broadcast(Event::SessionStateChanged { device_id: device_id.clone(), state: SessionState::Idle });
+        }
+        expired
+    }
+
+    /// Repeat SessionStateChanged for a frontend that (re)connected
+    pub fn announce(&self) {
+        for (device_id, (state, _)) in self.states.lock().unwrap().iter() {
+            self.ws_server.broadcast(Event::SessionStateChanged { device_id: device_id.clone(), state: *state });
+        }
+    }
+}
+
+/// What each peer announced it supports, per connection: (device ID, features)
+pub type PeerFeatures = HashMap<String, (String, Vec<PeerFeature>)>;
+
+/// Shared state every session needs, whichever side opened it
+#[derive(Clone)]
+pub struct SessionContext {
+    pub ws_server: Arc<WebSocketServer>,
+    pub active_connections: Arc<Mutex<ActiveConnections>>,
+    pub visualization: Arc<VisualizationFilter>,
+    pub local_input_lock: Arc<LocalInputLock>,
+    pub simulator: Arc<dyn InputBackend>,
+    /// Device ID of every session that ends, whichever side noticed first
+    pub ended_tx: mpsc::UnboundedSender<String>,
+    /// Who controls this machine, per connection, and since when (Unix ms)
+    pub controllers: Arc<Mutex<HashMap<String, (DeviceInfo, u64)>>>,
+    pub audit: Arc<AuditLog>,
+    pub stats: Arc<ConnectionStats>,
+    pub peer_features: Arc<Mutex<PeerFeatures>>,
+    /// What the controlled side granted, per connection
+    pub permissions: Arc<Mutex<HashMap<String, Vec<Permission>>>>,
+    /// Clipboard messages from peers, for the task that owns the OS clipboard
+    pub clipboard_tx: mpsc::UnboundedSender<ClipboardEvent>,
+    /// File messages from peers, for the task that owns the transfers
+    pub file_tx: mpsc::UnboundedSender<FileEvent>,
+    /// Edge behavior for the cursor each controller (by device ID) moves here; Stop if unset
+    pub edge_behavior: Arc<std::sync::RwLock<HashMap<String, EdgeBehavior>>>,
+    /// Controller side: the peer screen edge that hands control back to us, None to disable
+    pub return_edge: Arc<std::sync::RwLock<Option<ScreenEdge>>>,
+    pub edge_return_tx: mpsc::UnboundedSender<EdgeReturn>,
+    /// Relay layout: input from a controller that leaves our screen over an edge
+    /// is passed on to this device, for A -> B -> C desks with only adjacent pairings
+    pub relay_edges: Arc<std::sync::RwLock<HashMap<ScreenEdge, String>>>,
+    /// Last cursor position and screen size each peer reported, by device ID
+    pub peer_cursors: Arc<std::sync::RwLock<HashMap<String, PeerCursor>>>,
+    /// How each peer's monitors are arranged, by device ID, once it sent Message::Screens
+    pub peer_screens: Arc<std::sync::RwLock<HashMap<String, Topology>>>,
+    /// Lock keys of each peer we control, by device ID, once it sent Message::LockState
+    pub peer_lock_states: Arc<std::sync::RwLock<HashMap<String, LockState>>>,
+    /// Nagle and flushing, read when a session starts
+    pub transport: Arc<std::sync::RwLock<TransportOptions>>,
+    /// Controlled side, experimental: run the injected cursor ahead by the
+    /// link's one-way latency (CursorPredictor); read when a session starts
+    pub cursor_prediction: Arc<AtomicBool>,
+    /// Controller side: peers whose input currently goes nowhere, by device ID
+    pub target_status: Arc<std::sync::RwLock<HashMap<String, TargetUnavailable>>>,
+    /// Where the session with each peer stands, whichever side asked
+    pub lifecycle: Arc<Lifecycle>,
+    /// Controller side: peers whose injected input is being verified, by device ID
+    pub input_echo: Arc<std::sync::Mutex<HashMap<String, EchoTracker>>>,
+}
+
+pub type PeerCursor = ((i32, i32), (u32, u32));
+
+/// Controller side: the cursor on a peer reached the return edge, or the peer handed control back
+#[derive(Debug)]
+pub struct EdgeReturn {
+    pub device_id: String,
+    pub edge: ScreenEdge,
+    pub pos: (i32, i32),
+    pub screen: (u32, u32),
+}
+
+impl SessionContext {
+    /// Repeat BeingControlled for a frontend that (re)connected mid-session
+    pub async fn announce_controllers(&self) {
+        for (device, since) in self.controllers.lock().await.values() {
+            self.ws_server.broadcast(Event::BeingControlled { device: device.clone(), since: *since });
+        }
+    }
+
+    /// Forget who controlled us over `conn_key` and tell the frontend
+    async fn end_control(&self, conn_key: &str) {
+        if let Some((device, _)) = self.controllers.lock().await.remove(conn_key) {
+            self.ws_server.broadcast(Event::ControlEnded { device_id: device.id });
+        }
+    }
+
+    /// For teardowns that abort sessions before they can clean up after themselves
+    pub async fn end_all_control(&self) {
+        for (_, (device, _)) in self.controllers.lock().await.drain() {
+            self.ws_server.broadcast(Event::ControlEnded { device_id: device.id });
+        }
+        self.peer_features.lock().await.clear();
+        self.permissions.lock().await.clear();
+        self.target_status.write().unwrap().clear();
+        self.peer_screens.write().unwrap().clear();
+        self.peer_lock_states.write().unwrap().clear();
+        self.lifecycle.close_all();
+    }
+
+    /// Repeat PeerFeatures and PeerScreens for a frontend that (re)connected mid-session
+    pub async fn announce_peer_features(&self) {
+        for (device_id, features) in self.peer_features.lock().await.values() {
+            self.ws_server.broadcast(Event::PeerFeatures { device_id: device_id.clone(), features: features.clone() });
+        }
+        for (device_id, screens) in self.peer_screens.read().unwrap().iter() {
+            self.ws_server.broadcast(Event::PeerScreens { device_id: device_id.clone(), monitors: screens.monitors.clone() });
+        }
+    }
+
+    /// Repeat TargetStatus for a frontend that (re)connected mid-session
+    pub fn announce_target_status(&self) {
+        for (device_id, reason) in self.target_status.read().unwrap().iter() {
+            self.ws_server.broadcast(Event::TargetStatus { device_id: device_id.clone(), unavailable: Some(*reason) });
+        }
+    }
+
+    fn set_target_status(&self, device_id: &str, unavailable: Option<TargetUnavailable>) {
+        match unavailable {
+            Some(reason) => {
+                println!("  ⚠ {}", reason.describe());
+                self.target_status.write().unwrap().insert(device_id.to_string(), reason);
+            }
+            None => {
+                println!("  ✓ 对方输入目标已恢复");
+                self.target_status.write().unwrap().remove(device_id);
+            }
+        }
+        self.ws_server.broadcast(Event::TargetStatus { device_id: device_id.to_string(), unavailable });
+    }
+
+    async fn set_peer_features(&self, conn_key: &str, device_id: &str, names: &[String]) {
+        let features: Vec<PeerFeature> = names.iter().filter_map(|name| PeerFeature::from_name(name)).collect();
+        println!("  对方支持的功能: {:?}", names);
+        self.peer_features
+            .lock()
+            .await
+            .insert(conn_key.to_string(), (device_id.to_string(), features.clone()));
+        self.ws_server.broadcast(Event::PeerFeatures { device_id: device_id.to_string(), features });
+    }
+}
+
+/// What the controlled side agreed to when it accepted
+#[derive(Debug, Clone, Default)]
+pub struct ControlGrant {
+    /// Controlled side: block this machine's own keyboard/mouse meanwhile
+    pub pause_local_input: bool,
+    pub time_limit: Option<Duration>,
+    pub permissions: Vec<Permission>,
+    /// Controlled side: multiplier for the peer's pointer movement (None: 1)
+    pub pointer_speed: Option<f64>,
+    /// Controlled side: flip the direction the peer scrolls in
+    pub invert_scroll: bool,
+}
+
+/// How often each side sends Message::Heartbeat
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Silence after which a peer that announced heartbeats counts as gone
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+/// OS-level TCP keepalive for idle sessions: first probe after KEEPALIVE_IDLE
+/// without traffic, then every KEEPALIVE_INTERVAL
+pub const KEEPALIVE_IDLE: Duration = Duration::from_secs(30);
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the controlled side checks whether injected input can reach its screen
+pub const TARGET_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long before a time limit runs out both sides get warned (at most half the limit)
+const TIME_LIMIT_WARNING: Duration = Duration::from_secs(60);
+
+/// Time-boxed control granted by the controlled side
+struct TimeLimit {
+    deadline: tokio::time::Instant,
+    warn_at: tokio::time::Instant,
+    warned: bool,
+}
+
+impl TimeLimit {
+    fn new(limit: Duration) -> Self {
+        let deadline = tokio::time::Instant::now() + limit;
+        TimeLimit { deadline, warn_at: deadline - TIME_LIMIT_WARNING.min(limit / 2), warned: false }
+    }
+
+    fn remaining_secs(&self) -> u64 {
+        self.deadline.saturating_duration_since(tokio::time::Instant::now()).as_secs_f64().round() as u64
+    }
+
+    /// Waits for the warning point, then for the deadline; true once expired
+    async fn next(&mut self) -> bool {
+        if !self.warned {
+            tokio::time::sleep_until(self.warn_at).await;
+            self.warned = true;
+            return false;
+        }
+        tokio::time::sleep_until(self.deadline).await;
+        true
+    }
+}
+
+// Pending forever without a limit, so it can sit in a select!
+async fn next_time_limit_event(limit: &mut Option<TimeLimit>) -> bool {
+    match limit {
+        Some(limit) => limit.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tell our frontend and the peer how long the session has left
+fn announce_time_limit(ws_server: &WebSocketServer, tx: &WeakMessageSender, device_id: &str, remaining_secs: u64) {
+    ws_server.broadcast(Event::SessionTimeLimit { device_id: device_id.to_string(), remaining_secs });
+    if let Some(tx) = tx.upgrade() {
+        let _ = tx.send(Message::TimeLimit { remaining_secs: remaining_secs.min(u32::MAX as u64) as u32 });
+    }
+}
+
+/// Run an established peer connection: spawn its sender and receiver tasks
+/// and register it in the active connections under `conn_key`.
+/// Both roles go through here so session features apply to either side.
+/// `time_limit` only makes sense on the controlled side, which enforces it.
+pub async fn start_session(
+    ctx: SessionContext,
+    stream: PeerStream,
+    conn_key: String,
+    peer: DeviceInfo,
+    role: Role,
+    grant: ControlGrant,
+) {
+    let tag = role.tag();
+    let device_id = peer.id.clone();
+
+    // Create channel for lock-free sending
+    let (msg_tx, mut msg_rx) = MessageSender::channel();
+    // Goes out first, before any input
+    let features = protocol::LOCAL_FEATURES.iter().map(|feature| feature.name().to_string()).collect();
+    let _ = msg_tx.send(Message::Features { features });
+
+    ctx.stats.session_started(&device_id, role.name());
+    ctx.lifecycle.transition(&device_id, SessionState::Established);
+
+    // Notify frontend
+    ctx.ws_server.broadcast(Event::ConnectionEstablished {
+        device_id: device_id.clone(),
+    });
+    ctx.ws_server.broadcast(Event::PermissionsGranted {
+        device_id: device_id.clone(),
+        permissions: grant.permissions.clone(),
+    });
+    ctx.permissions.lock().await.insert(conn_key.clone(), grant.permissions.clone());
+
+    if role == Role::Controlled {
+        let since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        ctx.controllers.lock().await.insert(conn_key.clone(), (peer.clone(), since));
+        ctx.ws_server.broadcast(Event::BeingControlled { device: peer, since });
+    }
+
+    if role == Role::Controlled && grant.pause_local_input {
+        ctx.local_input_lock.activate();
+        ctx.ws_server.broadcast(Event::LocalInputPaused { paused: true });
+    }
+
+    let simulator = Arc::clone(&ctx.simulator);
+    if role == Role::Controlled {
+        spawn_cursor_reporter(msg_tx.downgrade(), Arc::clone(&simulator));
+    }
+
+    let transport = *ctx.transport.read().unwrap();
+    if let Err(e) = stream.tcp().set_nodelay(transport.nodelay) {
+        eprintln!("Failed to set TCP_NODELAY: {}", e);
+    }
+    // Some routers and VPNs forget an idle connection after a few minutes and the next
+    // move stalls. Heartbeats keep it busy, but only with peers that announced them.
+    let keepalive = socket2::TcpKeepalive::new().with_time(KEEPALIVE_IDLE).with_interval(KEEPALIVE_INTERVAL);
+    if let Err(e) = socket2::SockRef::from(stream.tcp()).set_tcp_keepalive(&keepalive) {
+        eprintln!("Failed to set TCP keepalive: {}", e);
+    }
+    
+    if !stream.is_encrypted() {
+        println!("{} ⚠ 对方版本不支持加密，本次会话以明文传输", tag);
+    }
+    
+    // Capabilities from the Hello hold from the first message on; the peer's
+    // Features follow, replace them and are what the frontend gets told
+    let hello_features = stream.peer_hello().map(|hello| hello.features()).unwrap_or_default();
+    if let Some(hello) = stream.peer_hello() {
+        println!("{} 对方协议版本 {}，功能位 {:#x}", tag, hello.version, hello.capabilities);
+        ctx.peer_features.lock().await.insert(conn_key.clone(), (device_id.clone(), hello_features.clone()));
+    }
+
+    // Split stream for concurrent read/write
+    let (mut reader, mut writer) = stream.into_split();
+    // Set once the peer announced it; until then side buttons aren't sent
+    let peer_side_buttons = Arc::new(AtomicBool::new(hello_features.contains(&PeerFeature::SideButtons)));
+
+    // Spawn dedicated sender task
+    let active_conns = Arc::clone(&ctx.active_connections);
+    let key = conn_key.clone();
+    let ws_server = Arc::clone(&ctx.ws_server);
+    let ended_tx = ctx.ended_tx.clone();
+    let stats = Arc::clone(&ctx.stats);
+    let peer_id = device_id.clone();
+    let side_buttons = Arc::clone(&peer_side_buttons);
+    let input_echo = Arc::clone(&ctx.input_echo);
+    tokio::spawn(async move {
+        println!("{} 发送任务已启动", tag);
+        // Periodic flushing: when the oldest unflushed frame has to go out
+        let mut flush_due: Option<tokio::time::Instant> = None;
+        let result: Result<(), TransportError> = async {
+            loop {
+                let next = match flush_due {
+                    Some(due) => match tokio::time::timeout_at(due, msg_rx.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            writer.flush().await?;
+                            flush_due = None;
+                            continue;
+                        }
+                    },
+                    None => msg_rx.recv().await,
+                };
+                let Some((msg, queued_at)) = next else {
+                    writer.flush().await?;
+                    return Ok(());
+                };
+                // An older peer would press its left button instead
+                if let Message::MouseClick { button: protocol::BUTTON_BACK | protocol::BUTTON_FORWARD, .. } = msg {
+                    if !side_buttons.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                }
+                let span = tracing::trace_span!("send", peer = %key);
+                let len = match transport.flush {
+                    FlushStrategy::Immediate => writer.send(&msg).instrument(span).await?,
+                    FlushStrategy::Periodic { interval_ms } => {
+                        flush_due.get_or_insert_with(|| tokio::time::Instant::now() + Duration::from_millis(interval_ms));
+                        writer.write_frame(&msg).instrument(span).await?
+                    }
+                };
+                msg_rx.written(queued_at);
+                // What is on the wire is what the echoes are checked against. Input
+                // written before the peer was asked to echo won't be, so counting starts here.
+                if let Some(tracker) = input_echo.lock().unwrap().get_mut(&peer_id) {
+                    match msg {
+                        Message::EchoInput { enabled: true } => *tracker = EchoTracker::default(),
+                        _ if msg.is_input() => tracker.sent(echo::digest(&msg), Instant::now()),
+                        _ => {}
+                    }
+                }
+                stats.message_sent(&peer_id, len);
+                diagnostics::record(Stage::Send, queued_at.elapsed());
+            }
+        }
+        .await;
+        match result {
+            // Channel closed: whoever removed the connection already notified the frontend
+            Ok(()) => println!("{} 发送通道关闭", tag),
+            Err(e) => {
+                eprintln!("{} 发送失败: {}", tag, e);
+                stats.error(&peer_id, "sendFailed");
+                stats.session_ended(&peer_id, "sendFailed");
+                active_conns.lock().await.remove(&key);
+                ws_server.broadcast(Event::Disconnected);
+                let _ = ended_tx.send(peer_id);
+            }
+        }
+    });
+
+    // Start receiving - BATCHED DIRECT MODE
+    let ctx_recv = ctx.clone();
+    let key = conn_key.clone();
+    let device_id_recv = device_id.clone();
+    let weak_tx = msg_tx.downgrade();
+    let mut time_limit = grant.time_limit.map(TimeLimit::new);
+    if let Some(limit) = &time_limit {
+        announce_time_limit(&ctx.ws_server, &weak_tx, &device_id, limit.remaining_secs());
+    }
+    let recv_task = tokio::spawn(async move {
+        println!("{} 接收循环启动 (批处理直接模式)", tag);
+
+        // Use a larger channel for batching to avoid blocking TCP receiver
+        let (tcp_tx, mut tcp_rx) = mpsc::channel::<(Message, Instant)>(100);
+
+        // Spawn TCP receiver; it dies with this task so the socket gets closed
+        let stats = Arc::clone(&ctx_recv.stats);
+        let peer_id = device_id_recv.clone();
+        let _reader = AbortOnDrop(tokio::spawn(async move {
+            loop {
+                let span = tracing::trace_span!("receive");
+                match reader.recv().instrument(span).await {
+                    Ok((msg, len)) => {
+                        stats.message_received(&peer_id, len);
+                        if tcp_tx.send((msg, Instant::now())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        println!("{} 连接断开: {}", tag, e);
+                        stats.error(&peer_id, "receiveFailed");
+                        break;
+                    }
+                }
+            }
+        }).abort_handle());
+
+        let mut applier = InputApplier {
+            role,
+            injector: Injector::spawn(Arc::clone(&simulator)),
+            simulator,
+            click_pacer: ClickPacer::new(),
+            wheel: WheelAccumulator::new(),
+            invert_scroll: grant.invert_scroll,
+            mouse_accumulator: (0, 0),
+            pointer_speed: PointerSpeed::new(grant.pointer_speed.unwrap_or(1.0)),
+            predictor: (role == Role::Controlled && ctx_recv.cursor_prediction.load(Ordering::Relaxed))
+                .then(CursorPredictor::new),
+            ws_server: Arc::clone(&ctx_recv.ws_server),
+            visualization: Arc::clone(&ctx_recv.visualization),
+            device_id: device_id_recv,
+            edge_behavior: Arc::clone(&ctx_recv.edge_behavior),
+            peer_tx: weak_tx.clone(),
+            at_edge: None,
+            return_edge: Arc::clone(&ctx_recv.return_edge),
+            edge_return_tx: ctx_recv.edge_return_tx.clone(),
+            return_armed: false,
+            entry_edge: None,
+            active_connections: Arc::clone(&ctx_recv.active_connections),
+            relay_edges: Arc::clone(&ctx_recv.relay_edges),
+            peer_cursors: Arc::clone(&ctx_recv.peer_cursors),
+            peer_screens: Arc::clone(&ctx_recv.peer_screens),
+            peer_lock_states: Arc::clone(&ctx_recv.peer_lock_states),
+            relay: None,
+            // The controller side never granted anything, so the controlled peer's input is dropped
+            input_allowed: role == Role::Controlled && grant.permissions.contains(&Permission::Input),
+            clipboard_allowed: grant.permissions.contains(&Permission::Clipboard),
+            file_transfer_allowed: grant.permissions.contains(&Permission::FileTransfer),
+            media_allowed: grant.permissions.contains(&Permission::Media),
+            screenshot_allowed: grant.permissions.contains(&Permission::Screenshot),
+            hand_off_allowed: grant.permissions.contains(&Permission::HandOff),
+            screenshot_in_flight: Arc::new(AtomicBool::new(false)),
+            screenshot: Assembler::default(),
+            thumbnail: ThumbnailAssembler::default(),
+            echo_inputs: false,
+            echoed_moves: Vec::new(),
+        };
+
+        // Heartbeats only start once the peer said it understands them
+        let mut peer_heartbeats = false;
+        let mut last_heard = tokio::time::Instant::now();
+        // Heartbeat clock, and the peer's last one with when it came, to echo back
+        let clock = tokio::time::Instant::now();
+        let mut peer_clock: Option<(u64, tokio::time::Instant)> = None;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // Controlled side: what we last told a peer that understands TargetStatus
+        let mut peer_target_status = false;
+        let mut target_unavailable: Option<TargetUnavailable> = None;
+        let mut target_check = tokio::time::interval(TARGET_CHECK_INTERVAL);
+        // Controlled side: the thumbnail stream the peer asked for
+        let mut thumbnails: Option<tokio::task::AbortHandle> = None;
+        target_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let mut end_reason = "connectionLost";
+        'session: loop {
+            let received = tokio::select! {
+                // Frames that already arrived go first, so a busy loop can't mistake them for silence
+                biased;
+                received = tcp_rx.recv() => received,
+                _ = heartbeat.tick() => {
+                    if !peer_heartbeats {
+                        continue;
+                    }
+                    if last_heard.elapsed() > HEARTBEAT_TIMEOUT {
+                        println!("{} 💔 {} 秒未收到对方心跳，断开连接", tag, HEARTBEAT_TIMEOUT.as_secs());
+                        ctx_recv.stats.error(&applier.device_id, "heartbeatTimeout");
+                        end_reason = "heartbeatTimeout";
+                        break 'session;
+                    }
+                    if let Some(tx) = weak_tx.upgrade() {
+                        let sent_us = clock.elapsed().as_micros() as u64;
+                        let echo = peer_clock.map(|(peer_us, at)| (peer_us, at.elapsed().as_micros() as u64));
+                        let _ = tx.send(Message::Heartbeat { sent_us, echo });
+                    }
+                    continue;
+                }
+                _ = target_check.tick(), if role == Role::Controlled && peer_target_status => {
+                    let unavailable = applier.simulator.target_unavailable();
+                    if unavailable != target_unavailable {
+                        match unavailable {
+                            Some(reason) => println!("{} ⚠ 注入的输入无法到达屏幕: {}", tag, reason.describe()),
+                            None => println!("{} ✓ 注入的输入可以到达屏幕了", tag),
+                        }
+                        target_unavailable = unavailable;
+                        if let Some(tx) = weak_tx.upgrade() {
+                            let _ = tx.send(Message::TargetStatus { unavailable });
+                        }
+                    }
+                    continue;
+                }
+                _ = next_settle(&applier.predictor) => {
+                    applier.settle_prediction().await;
+                    continue;
+                }
+                expired = next_time_limit_event(&mut time_limit) => {
+                    let remaining = time_limit.as_ref().map_or(0, |limit| limit.remaining_secs());
+                    announce_time_limit(&applier.ws_server, &weak_tx, &applier.device_id, remaining);
+                    if expired {
+                        println!("{} ⏱ 限时控制已到期，断开连接", tag);
+                        end_reason = "timeLimit";
+                        if let Some(tx) = weak_tx.upgrade() {
+                            let _ = tx.send(Message::Disconnect);
+                        }
+                        break 'session;
+                    }
+                    println!("{} ⏱ 限时控制还剩 {} 秒", tag, remaining);
+                    continue;
+                }
+            };
+            let Some(received) = received else {
+                break;
+            };
+            last_heard = tokio::time::Instant::now();
+            // Batch all mouse moves that are already available, then flush
+            // them before anything else so ordering is preserved
+            let mut next = Some(received);
+            while let Some((msg, received_at)) = next.take() {
+                diagnostics::record(Stage::Receive, received_at.elapsed());
+                ctx_recv.audit.record(&applier.device_id, &msg);
+                if !applier.input_allowed && msg.is_input() {
+                    continue;
+                }
+                let echo_digest = (applier.echo_inputs && msg.is_input()).then(|| echo::digest(&msg));
+                match msg {
+                    Message::MouseMove { x, y } => {
+                        applier.accumulate(x, y);
+                        applier.echoed_moves.extend(echo_digest);
+                        next = tcp_rx.try_recv().ok();
+                    }
+                    // A batch envelope is just several moves in one frame
+                    Message::MouseMoveBatch(deltas) => {
+                        let (x, y) = protocol::batch_displacement(&deltas);
+                        applier.accumulate(x, y);
+                        applier.echoed_moves.extend(echo_digest);
+                        next = tcp_rx.try_recv().ok();
+                    }
+                    Message::EchoInput { enabled } if role == Role::Controlled => {
+                        println!("{} 🔁 {}输入回显校验", tag, if enabled { "开始" } else { "停止" });
+                        applier.flush_moves().await;
+                        applier.echo_inputs = enabled;
+                    }
+                    Message::InputEcho { digest } if role == Role::Controller => {
+                        if let Some(tracker) = ctx_recv.input_echo.lock().unwrap().get_mut(&applier.device_id) {
+                            tracker.echoed(digest, Instant::now());
+                        }
+                    }
+                    Message::Features { features } => {
+                        peer_heartbeats = features.iter().any(|name| name == PeerFeature::Heartbeat.name());
+                        peer_target_status = features.iter().any(|name| name == PeerFeature::TargetStatus.name());
+                        peer_side_buttons.store(features.iter().any(|name| name == PeerFeature::SideButtons.name()), Ordering::Relaxed);
+                        if features.iter().any(|name| name == PeerFeature::Screens.name()) {
+                            applier.send_screens();
+                        }
+                        if role == Role::Controlled && features.iter().any(|name| name == PeerFeature::LockState.name()) {
+                            spawn_lock_reporter(applier.peer_tx.clone(), Arc::clone(&applier.simulator));
+                        }
+                        ctx_recv.set_peer_features(&key, &applier.device_id, &features).await;
+                    }
+                    Message::Heartbeat { sent_us, echo } => {
+                        peer_clock = Some((sent_us, tokio::time::Instant::now()));
+                        if let Some((our_us, held_us)) = echo {
+                            let rtt_us = (clock.elapsed().as_micros() as u64).saturating_sub(our_us).saturating_sub(held_us);
+                            ctx_recv.stats.rtt(&applier.device_id, Duration::from_micros(rtt_us));
+                            if let Some(predictor) = &mut applier.predictor {
+                                predictor.set_latency(Duration::from_micros(rtt_us / 2));
+                            }
+                        }
+                    }
+                    msg @ (Message::Clipboard(_)
+                    | Message::ClipboardBegin { .. }
+                    | Message::ClipboardChunk { .. }
+                    | Message::ClipboardCancel { .. }) => {
+                        if applier.clipboard_allowed {
+                            let device_id = applier.device_id.clone();
+                            let _ = ctx_recv.clipboard_tx.send(ClipboardEvent::Peer { conn_key: key.clone(), device_id, msg });
+                        } else {
+                            println!("{} 📋 未授予剪贴板权限，忽略对方的剪贴板内容", tag);
+                        }
+                    }
+                    msg @ (Message::FileOffer { .. }
+                    | Message::FileAck { .. }
+                    | Message::FileChunk { .. }
+                    | Message::FileCancel { .. }) => {
+                        let device_id = applier.device_id.clone();
+                        if applier.file_transfer_allowed {
+                            let _ = ctx_recv.file_tx.send(FileEvent::Peer { conn_key: key.clone(), device_id, msg });
+                        } else if let Message::FileOffer { id, .. } = msg {
+                            println!("{} 📁 未授予文件传输权限，拒绝对方的文件", tag);
+                            if let Some(tx) = applier.peer_tx.upgrade() {
+                                let _ = tx.send(Message::FileCancel { id });
+                            }
+                        }
+                    }
+                    // Like screenshots, only the controlled side's screen is streamed
+                    Message::ThumbnailStart { fps } if role == Role::Controlled => {
+                        if let Some(task) = thumbnails.take() {
+                            task.abort();
+                        }
+                        if applier.screenshot_allowed {
+                            println!("{} 🖼 开始发送缩略图 ({} fps)", tag, fps.clamp(1, screenshot::MAX_THUMBNAIL_FPS));
+                            let cap = ctx_recv.transport.read().unwrap().bulk_cap_kib_per_sec.map(|kib| kib * 1024);
+                            let stream = screenshot::stream_thumbnails(applier.peer_tx.clone(), Arc::clone(&applier.simulator), fps, cap);
+                            thumbnails = Some(tokio::spawn(stream).abort_handle());
+                        } else {
+                            println!("{} 🖼 未授予截图权限，拒绝发送缩略图", tag);
+                            if let Some(tx) = applier.peer_tx.upgrade() {
+                                let _ = tx.send(Message::ThumbnailStop);
+                            }
+                        }
+                    }
+                    // In order with the moves before it, so the ring is where the controller stopped
+                    Message::Highlight if role == Role::Controlled && applier.input_allowed => {
+                        println!("{} 👉 对方请求高亮光标", tag);
+                        applier.flush_moves().await;
+                        applier.inject("highlight", |simulator| simulator.highlight()).await;
+                    }
+                    Message::ThumbnailStop if role == Role::Controlled => {
+                        if let Some(task) = thumbnails.take() {
+                            println!("{} 🖼 停止发送缩略图", tag);
+                            task.abort();
+                        }
+                    }
+                    Message::TargetStatus { unavailable } => {
+                        ctx_recv.set_target_status(&applier.device_id, unavailable);
+                    }
+                    Message::PairingConfirmed => {
+                        println!("{} 🔑 对方已确认配对码", tag);
+                        ctx_recv.ws_server.broadcast(Event::PeerConfirmedPairing { device_id: applier.device_id.clone() });
+                    }
+                    Message::HandOff { text } if role == Role::Controlled => {
+                        if applier.hand_off_allowed {
+                            let device_id = applier.device_id.clone();
+                            let _ = ctx_recv.clipboard_tx.send(ClipboardEvent::HandOff { device_id, text });
+                        } else {
+                            println!("{} 📤 未授予接力权限，忽略对方的接力内容", tag);
+                        }
+                    }
+                    Message::Disconnect => {
+                        println!("{} 🔴 收到对方断开消息", tag);
+                        end_reason = "peerDisconnected";
+                        break 'session;
+                    }
+                    other => {
+                        applier.flush_moves().await;
+                        applier.apply(other).await;
+                        if let Some(digest) = echo_digest {
+                            applier.echo(digest);
+                        }
+                    }
+                }
+            }
+            applier.flush_moves().await;
+        }
+
+        println!("{} 接收循环结束", tag);
+        if let Some(task) = thumbnails {
+            task.abort();
+        }
+        ctx_recv.lifecycle.transition(&applier.device_id, SessionState::Closing);
+        ctx_recv.stats.session_ended(&applier.device_id, end_reason);
+        ctx_recv.audit.flush();
+        ctx_recv.active_connections.lock().await.remove(&key);
+        ctx_recv.end_control(&key).await;
+        ctx_recv.peer_features.lock().await.remove(&key);
+        ctx_recv.permissions.lock().await.remove(&key);
+        ctx_recv.peer_cursors.write().unwrap().remove(&applier.device_id);
+        ctx_recv.peer_screens.write().unwrap().remove(&applier.device_id);
+        ctx_recv.peer_lock_states.write().unwrap().remove(&applier.device_id);
+        ctx_recv.input_echo.lock().unwrap().remove(&applier.device_id);
+        ctx_recv.target_status.write().unwrap().remove(&applier.device_id);
+        applier.stop_relay();
+        if role == Role::Controlled && ctx_recv.local_input_lock.is_active() {
+            ctx_recv.local_input_lock.deactivate();
+            ctx_recv.ws_server.broadcast(Event::LocalInputPaused { paused: false });
+        }
+        ctx_recv.ws_server.broadcast(Event::Disconnected);
+        ctx_recv.lifecycle.transition(&applier.device_id, SessionState::Idle);
+        let _ = ctx_recv.ended_tx.send(applier.device_id);
+    });
+
+    // Insert into active connections with abort handle
+    ctx.active_connections
+        .lock()
+        .await
+        .insert(conn_key.clone(), (msg_tx, recv_task.abort_handle(), device_id));
+    println!("  连接已存储: {}", conn_key);
+}
+
+/// Scales relative movement, carrying the fractions so slow movement isn't lost
+struct PointerSpeed {
+    factor: f64,
+    remainder: (f64, f64),
+}
+
+impl PointerSpeed {
+    fn new(factor: f64) -> Self {
+        PointerSpeed { factor, remainder: (0.0, 0.0) }
+    }
+
+    fn scale(&mut self, dx: i32, dy: i32) -> (i32, i32) {
+        if self.factor == 1.0 {
+            return (dx, dy);
+        }
+        let x = dx as f64 * self.factor + self.remainder.0;
+        let y = dy as f64 * self.factor + self.remainder.1;
+        self.remainder = (x.fract(), y.fract());
+        (x.trunc() as i32, y.trunc() as i32)
+    }
+}
+
+/// Longest one-way latency extrapolated over; beyond it the guess is worse than the lag
+const MAX_PREDICTION_LATENCY: Duration = Duration::from_millis(100);
+/// Furthest the cursor is run ahead of where the controller put it, in pixels
+pub const MAX_PREDICTION_PX: f64 = 60.0;
+/// No moves for this long means the pointer stopped, and the lead is taken back
+pub const PREDICTION_SETTLE: Duration = Duration::from_millis(40);
+
+/// Experimental receiver-side prediction: while the pointer moves, the
+/// injected cursor runs ahead by velocity × one-way latency, so it lands
+/// about where the controller's hand already is. Every real move corrects the
+/// lead and `settle` takes it back, so where the cursor ends up is unchanged.
+pub struct CursorPredictor {
+    latency: Duration,
+    /// Pixels per second, smoothed over the last few moves
+    velocity: (f64, f64),
+    last_move: Option<Instant>,
+    /// Predicted displacement injected on top of the real moves so far
+    ahead: (i32, i32),
+}
+
+impl Default for CursorPredictor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CursorPredictor {
+    pub fn new() -> Self {
+        CursorPredictor { latency: Duration::ZERO, velocity: (0.0, 0.0), last_move: None, ahead: (0, 0) }
+    }
+
+    /// One-way latency to extrapolate over, e.g. half the heartbeat round trip
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency.min(MAX_PREDICTION_LATENCY);
+    }
+
+    /// What to inject for a real move of (dx, dy) arriving at `at`: the move
+    /// itself plus the change in lead
+    pub fn on_move(&mut self, dx: i32, dy: i32, at: Instant) -> (i32, i32) {
+        let elapsed = self.last_move.map(|last| at.saturating_duration_since(last));
+        self.last_move = Some(at);
+        match elapsed {
+            Some(elapsed) if elapsed < PREDICTION_SETTLE => {
+                let secs = elapsed.as_secs_f64().max(0.001);
+                let (vx, vy) = (dx as f64 / secs, dy as f64 / secs);
+                self.velocity = ((self.velocity.0 + vx) / 2.0, (self.velocity.1 + vy) / 2.0);
+            }
+            // First move after a pause: no speed to go by yet
+            _ => self.velocity = (0.0, 0.0),
+        }
+        let latency = self.latency.as_secs_f64();
+        let lead = |v: f64| (v * latency).clamp(-MAX_PREDICTION_PX, MAX_PREDICTION_PX).round() as i32;
+        let target = (lead(self.velocity.0), lead(self.velocity.1));
+        let correction = (target.0 - self.ahead.0, target.1 - self.ahead.1);
+        self.ahead = target;
+        (dx + correction.0, dy + correction.1)
+    }
+
+    /// The pointer stopped: the move that takes the lead back, if there is one
+    pub fn settle(&mut self) -> Option<(i32, i32)> {
+        self.velocity = (0.0, 0.0);
+        self.last_move = None;
+        let ahead = std::mem::take(&mut self.ahead);
+        (ahead != (0, 0)).then_some((-ahead.0, -ahead.1))
+    }
+
+    /// When to call `settle` if no other move comes in
+    fn settle_at(&self) -> Option<Instant> {
+        self.last_move.filter(|_| self.ahead != (0, 0)).map(|last| last + PREDICTION_SETTLE)
+    }
+}
+
+async fn next_settle(predictor: &Option<CursorPredictor>) {
+    match predictor.as_ref().and_then(CursorPredictor::settle_at) {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Aborts a task when dropped, including when the owning task is aborted
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Report our cursor position back so the controller can show it
+fn spawn_cursor_reporter(tx: WeakMessageSender, simulator: Arc<dyn InputBackend>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(100));
+        let mut last_pos = None;
+        loop {
+            interval.tick().await;
+            // Weak sender: must not keep the connection's send channel open
+            let Some(tx) = tx.upgrade() else {
+                break;
+            };
+            let Some(pos) = simulator.cursor_position() else {
+                continue;
+            };
+            if last_pos == Some(pos) {
+                continue;
+            }
+            last_pos = Some(pos);
+            let ((x, y), (screen_width, screen_height)) = match simulator.screens() {
+                Some(screens) => (screens.to_relative(pos), screens.bounds().1),
+                None => (pos, (0, 0)),
+            };
+            if tx.send(Message::CursorPos { x, y, screen_width, screen_height }).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Controlled side: tell the controller what our lock keys are, so its keyboard
+/// LEDs can show them while typing goes here
+fn spawn_lock_reporter(tx: WeakMessageSender, simulator: Arc<dyn InputBackend>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(100));
+        let mut last = None;
+        loop {
+            interval.tick().await;
+            let Some(tx) = tx.upgrade() else {
+                break;
+            };
+            let Some(state) = simulator.lock_state() else {
+                continue;
+            };
+            if last == Some(state) {
+                continue;
+            }
+            last = Some(state);
+            if tx.send(Message::LockState(state)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+type Injection = Box<dyn FnOnce(&dyn InputBackend) + Send>;
+
+/// One blocking thread per session that injects in arrival order: slow OS
+/// calls stay off the runtime, and a click or key chord can't be reordered
+struct Injector {
+    tx: std::sync::mpsc::Sender<(Injection, oneshot::Sender<()>)>,
+}
+
+impl Injector {
+    fn spawn(simulator: Arc<dyn InputBackend>) -> Self {
+        let (tx, rx) = std::sync::mpsc::channel::<(Injection, oneshot::Sender<()>)>();
+        // Ends when the session drops its Injector
+        std::thread::spawn(move || {
+            for (injection, done) in rx {
+                injection(&*simulator);
+                let _ = done.send(());
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queue `f` and wait until it ran, so what is read back afterwards
+    /// (cursor position) already includes it
+    async fn run(&self, f: impl FnOnce(&dyn InputBackend) + Send + 'static) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send((Box::new(f), done_tx)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+/// Applies what the peer sends: injects input and relays reports to the frontend
+struct InputApplier {
+    role: Role,
+    // For reading state back; injection goes through `injector`
+    simulator: Arc<dyn InputBackend>,
+    injector: Injector,
+    // Keeps button events spaced like on the controller
+    click_pacer: ClickPacer,
+    // Sub-notch scrolling for backends that only scroll by notch
+    wheel: WheelAccumulator,
+    invert_scroll: bool,
+    // Mouse movement accumulator for smoothing
+    mouse_accumulator: (i32, i32),
+    pointer_speed: PointerSpeed,
+    // Set when cursor prediction was on as the session started
+    predictor: Option<CursorPredictor>,
+    ws_server: Arc<WebSocketServer>,
+    visualization: Arc<VisualizationFilter>,
+    device_id: String,
+    edge_behavior: Arc<std::sync::RwLock<HashMap<String, EdgeBehavior>>>,
+    peer_tx: WeakMessageSender,
+    // Edge the cursor was pushed against by the last move, so Notify fires once per hit
+    at_edge: Option<ScreenEdge>,
+    return_edge: Arc<std::sync::RwLock<Option<ScreenEdge>>>,
+    edge_return_tx: mpsc::UnboundedSender<EdgeReturn>,
+    // Set once the peer's cursor was seen away from the return edge, so a
+    // cursor parked there doesn't hand control straight back
+    return_armed: bool,
+    // Controlled side: the edge the controller's cursor came in over with
+    // HandControl; pushing it back out there hands control back
+    entry_edge: Option<ScreenEdge>,
+    active_connections: Arc<Mutex<ActiveConnections>>,
+    relay_edges: Arc<std::sync::RwLock<HashMap<ScreenEdge, String>>>,
+    peer_cursors: Arc<std::sync::RwLock<HashMap<String, PeerCursor>>>,
+    peer_screens: Arc<std::sync::RwLock<HashMap<String, Topology>>>,
+    peer_lock_states: Arc<std::sync::RwLock<HashMap<String, LockState>>>,
+    // Set while this controller's input crossed over to another peer
+    relay: Option<Relay>,
+    // False for view-only sessions and always on the controller: keyboard and mouse from the peer are dropped
+    input_allowed: bool,
+    clipboard_allowed: bool,
+    file_transfer_allowed: bool,
+    media_allowed: bool,
+    screenshot_allowed: bool,
+    hand_off_allowed: bool,
+    // Controlled side: set while a screenshot is being taken, so a burst of requests takes one
+    screenshot_in_flight: Arc<AtomicBool>,
+    // Controller side: the peer's screenshot and thumbnails coming in
+    screenshot: Assembler,
+    thumbnail: ThumbnailAssembler,
+    // Controlled side: the controller verifies input (Message::EchoInput), and
+    // digests of the moves waiting in the accumulator, echoed once they are injected
+    echo_inputs: bool,
+    echoed_moves: Vec<u32>,
+}
+
+/// Input passed on to the next peer in a chain
+struct Relay {
+    device_id: String,
+    /// Our edge the cursor left over; the next peer's opposite edge brings it back
+    edge: ScreenEdge,
+    tx: WeakMessageSender,
+    // Same idea as return_armed, for the next peer's cursor
+    armed: bool,
+}
+
+/// Outer edge of the desktop a move of (dx, dy) that ended at `pos` pushed against, if any
+fn pushed_edge(screens: &Topology, pos: (i32, i32), dx: i32, dy: i32) -> Option<ScreenEdge> {
+    let toward = [(ScreenEdge::Left, dx < 0), (ScreenEdge::Right, dx > 0), (ScreenEdge::Top, dy < 0), (ScreenEdge::Bottom, dy > 0)];
+    toward
+        .into_iter()
+        .find(|(edge, moving)| *moving && screens.touches(*edge, pos))
+        .map(|(edge, _)| edge)
+}
+
+/// A peer's desktop and a position it reported (relative, as on the wire) on it.
+/// Peers that didn't send Message::Screens only have the reported box.
+fn peer_desktop(
+    peer_screens: &std::sync::RwLock<HashMap<String, Topology>>,
+    device_id: &str,
+    pos: (i32, i32),
+    screen: (u32, u32),
+) -> (Topology, (i32, i32)) {
+    match peer_screens.read().unwrap().get(device_id) {
+        Some(screens) => (screens.clone(), screens.from_relative(pos)),
+        None => (Topology::single(screen), pos),
+    }
+}
+
+impl InputApplier {
+    /// Tell the peer how our monitors are arranged
+    fn send_screens(&self) {
+        let (Some(screens), Some(tx)) = (self.simulator.screens(), self.peer_tx.upgrade()) else {
+            return;
+        };
+        let _ = tx.send(Message::Screens { monitors: screens.monitors });
+    }
+
+    fn accumulate(&mut self, dx: i32, dy: i32) {
+        let (dx, dy) = self.pointer_speed.scale(dx, dy);
+        self.mouse_accumulator.0 += dx;
+        self.mouse_accumulator.1 += dy;
+    }
+
+    async fn flush_moves(&mut self) {
+        self.move_cursor().await;
+        for digest in std::mem::take(&mut self.echoed_moves) {
+            self.echo(digest);
+        }
+    }
+
+    fn echo(&self, digest: u32) {
+        if let Some(tx) = self.peer_tx.upgrade() {
+            let _ = tx.send(Message::InputEcho { digest });
+        }
+    }
+
+    async fn move_cursor(&mut self) {
+        if self.mouse_accumulator == (0, 0) {
+            return;
+        }
+        let (dx, dy) = std::mem::take(&mut self.mouse_accumulator);
+        if self.relay.is_some() {
+            self.relay_move(dx, dy);
+            return;
+        }
+        let (dx, dy) = match &mut self.predictor {
+            Some(predictor) => predictor.on_move(dx, dy, Instant::now()),
+            None => (dx, dy),
+        };
+        self.inject("mousemove", move |simulator| simulator.mouse_move(dx, dy)).await;
+        if self.return_control(dx, dy) || self.start_relay(dx, dy).await {
+            return;
+        }
+        self.handle_edge(dx, dy).await;
+    }
+
+    /// Screen-edge switching: hand control back if the move pushed the cursor
+    /// out over the edge it came in by
+    fn return_control(&mut self, dx: i32, dy: i32) -> bool {
+        let Some(edge) = self.entry_edge else {
+            return false;
+        };
+        let (Some(pos), Some(screens)) = (self.simulator.cursor_position(), self.simulator.screens()) else {
+            return false;
+        };
+        let outward = match edge {
+            ScreenEdge::Left => dx < 0,
+            ScreenEdge::Right => dx > 0,
+            ScreenEdge::Top => dy < 0,
+            ScreenEdge::Bottom => dy > 0,
+        };
+        if !outward || !screens.touches(edge, pos) {
+            return false;
+        }
+        println!("↩ 光标从 {:?} 边缘回到 {}，交还控制权", edge, self.device_id);
+        self.entry_edge = None;
+        if let Some(tx) = self.peer_tx.upgrade() {
+            let (x, y) = screens.to_relative(pos);
+            let (_, (screen_width, screen_height)) = screens.bounds();
+            let _ = tx.send(Message::ReturnControl { edge, x, y, screen_width, screen_height });
+        }
+        true
+    }
+
+    /// Take back the predicted lead once the pointer stopped
+    async fn settle_prediction(&mut self) {
+        if let Some((dx, dy)) = self.predictor.as_mut().and_then(CursorPredictor::settle) {
+            self.inject("mousemove", move |simulator| simulator.mouse_move(dx, dy)).await;
+        }
+    }
+
+    /// Hand this controller's input on if the move pushed against a relay edge
+    async fn start_relay(&mut self, dx: i32, dy: i32) -> bool {
+        if self.relay_edges.read().unwrap().is_empty() {
+            return false;
+        }
+        let (Some(pos), Some(screens)) = (self.simulator.cursor_position(), self.simulator.screens()) else {
+            return false;
+        };
+        let Some(edge) = pushed_edge(&screens, pos, dx, dy) else {
+            return false;
+        };
+        let Some(target) = self.relay_edges.read().unwrap().get(&edge).cloned() else {
+            return false;
+        };
+        // Never back to where the input came from
+        if target == self.device_id {
+            return false;
+        }
+        let tx = self
+            .active_connections
+            .lock()
+            .await
+            .values()
+            .find(|(_, _, device_id)| *device_id == target)
+            .map(|(tx, _, _)| tx.downgrade());
+        let Some(tx) = tx else {
+            return false;
+        };
+        println!("🔀 {} 的输入经 {:?} 边缘转发到 {}", self.device_id, edge, target);
+        self.ws_server.broadcast(Event::RelayChanged {
+            controller_id: self.device_id.clone(),
+            target_id: Some(target.clone()),
+        });
+        self.relay = Some(Relay { device_id: target, edge, tx, armed: false });
+        true
+    }
+
+    /// Our cursor stays parked at the relay edge meanwhile, which is where
+    /// the input should resume when it comes back
+    fn relay_move(&mut self, dx: i32, dy: i32) {
+        if !self.relay_send(Message::MouseMove { x: dx, y: dy }) {
+            return;
+        }
+        let Some(relay) = &mut self.relay else {
+            return;
+        };
+        let Some((pos, screen)) = self.peer_cursors.read().unwrap().get(&relay.device_id).copied() else {
+            return;
+        };
+        let (screens, pos) = peer_desktop(&self.peer_screens, &relay.device_id, pos, screen);
+        let back = relay.edge.opposite();
+        if !screens.touches(back, pos) {
+            relay.armed = true;
+        } else if relay.armed && pushed_edge(&screens, pos, dx, dy) == Some(back) {
+            self.stop_relay();
+        }
+    }
+
+    /// False (and the relay is over) if the next peer went away
+    fn relay_send(&mut self, msg: Message) -> bool {
+        let Some(tx) = self.relay.as_ref().and_then(|relay| relay.tx.upgrade()) else {
+            self.stop_relay();
+            return false;
+        };
+        let _ = tx.send(msg);
+        true
+    }
+
+    fn stop_relay(&mut self) {
+        if let Some(relay) = self.relay.take() {
+            println!("🔀 {} 的输入不再转发到 {}", self.device_id, relay.device_id);
+            self.ws_server.broadcast(Event::RelayChanged { controller_id: self.device_id.clone(), target_id: None });
+        }
+    }
+
+    async fn handle_edge(&mut self, dx: i32, dy: i32) {
+        let behavior = self.edge_behavior.read().unwrap().get(&self.device_id).copied().unwrap_or_default();
+        // Stop is what the OS does anyway, no need to read the cursor back
+        if behavior == EdgeBehavior::Stop {
+            return;
+        }
+        let (Some(pos), Some(screens)) = (self.simulator.cursor_position(), self.simulator.screens()) else {
+            return;
+        };
+        let edge = pushed_edge(&screens, pos, dx, dy);
+        match (behavior, edge) {
+            (EdgeBehavior::Wrap, Some(edge)) => {
+                // Across the whole desktop, onto the far side's outer monitor
+                let (_, size) = screens.bounds();
+                if let Some((x, y)) = screens.entry_point(edge.opposite(), screens.to_relative(pos), size) {
+                    self.inject("wrap", move |simulator| simulator.move_to(x, y)).await;
+                }
+            }
+            (EdgeBehavior::Notify, Some(edge)) if self.at_edge != Some(edge) => {
+                if let Some(tx) = self.peer_tx.upgrade() {
+                    let _ = tx.send(Message::EdgeHit { edge });
+                }
+            }
+            _ => {}
+        }
+        self.at_edge = edge;
+    }
+
+    /// Run one simulator call on the injector, timed as the inject stage
+    async fn inject(&self, kind: &'static str, f: impl FnOnce(&dyn InputBackend) + Send + 'static) {
+        let started = Instant::now();
+        self.injector.run(f).instrument(tracing::trace_span!("inject", kind)).await;
+        diagnostics::record(Stage::Inject, started.elapsed());
+    }
+
+    async fn apply(&mut self, msg: Message) {
+        // Moves never get here, they are batched in the receive loop
+        if msg.is_input() && self.relay.is_some() && self.relay_send(msg.clone()) {
+            return;
+        }
+        match msg {
+            Message::MouseClick { button, state, elapsed_ms } => {
+                if let Some(wait) = self.click_pacer.delay(elapsed_ms) {
+                    tokio::time::sleep(wait).await;
+                }
+                self.inject("click", move |simulator| simulator.mouse_click(button, state)).await;
+                if self.visualization.mouse() {
+                    let event_type = if state { "mousedown" } else { "mouseup" };
+                    self.show_remote_input(event_type, format!("button{}", button), None, Some(button));
+                }
+            }
+            Message::MouseWheel { delta_x, delta_y } => {
+                let (delta_x, delta_y) = if self.invert_scroll { (-delta_x, -delta_y) } else { (delta_x, delta_y) };
+                let (delta_x, delta_y) = if self.simulator.fine_wheel() {
+                    (delta_x, delta_y)
+                } else {
+                    self.wheel.add(delta_x, delta_y)
+                };
+                if delta_x != 0 || delta_y != 0 {
+                    self.inject("wheel", move |simulator| simulator.mouse_wheel(delta_x, delta_y)).await;
+                }
+            }
+            Message::KeyPress { key, state } => {
+                self.inject("key", move |simulator| simulator.key_press(key, state)).await;
+                if self.visualization.keyboard() {
+                    let event_type = if state { "keydown" } else { "keyup" };
+                    self.show_remote_input(event_type, char::from_u32(key).unwrap_or('?').to_string(), Some(key), None);
+                }
+            }
+            Message::TimeLimit { remaining_secs } => {
+                self.ws_server.broadcast(Event::SessionTimeLimit {
+                    device_id: self.device_id.clone(),
+                    remaining_secs: remaining_secs as u64,
+                });
+            }
+            // Not input: it works in view-only sessions too. Only for the controlled side to play
+            Message::Media { action } if self.role == Role::Controlled => {
+                if self.media_allowed {
+                    self.inject("media", move |simulator| simulator.media(action)).await;
+                } else {
+                    println!("未授予媒体控制权限，忽略来自 {} 的 {:?}", self.device_id, action);
+                }
+            }
+            Message::EdgeHit { edge } => {
+                self.ws_server.broadcast(Event::RemoteEdgeHit { device_id: self.device_id.clone(), edge });
+            }
+            // Like input: a view-only session doesn't get to move our cursor
+            Message::HandControl { edge, x, y, screen_width, screen_height } if self.input_allowed => {
+                let entry = edge.opposite();
+                let Some((x, y)) = self.simulator.screens().and_then(|screens| screens.entry_point(entry, (x, y), (screen_width, screen_height))) else {
+                    println!("未知屏幕尺寸，无法从 {} 接管光标", self.device_id);
+                    return;
+                };
+                println!("↪ {} 的光标从 {:?} 边缘进入", self.device_id, entry);
+                self.inject("enter", move |simulator| simulator.move_to(x, y)).await;
+                self.entry_edge = Some(entry);
+            }
+            Message::ReturnControl { edge, x, y, screen_width, screen_height } => {
+                let _ = self.edge_return_tx.send(EdgeReturn {
+                    device_id: self.device_id.clone(),
+                    edge,
+                    pos: (x, y),
+                    screen: (screen_width, screen_height),
+                });
+            }
+            Message::CursorPos { x, y, screen_width, screen_height } => {
+                if screen_width > 0 && screen_height > 0 {
+                    self.peer_cursors
+                        .write()
+                        .unwrap()
+                        .insert(self.device_id.clone(), ((x, y), (screen_width, screen_height)));
+                }
+                let return_edge = *self.return_edge.read().unwrap();
+                if let Some(edge) = return_edge.filter(|_| screen_width > 0 && screen_height > 0) {
+                    let (pos, screen) = ((x, y), (screen_width, screen_height));
+                    let (screens, desktop_pos) = peer_desktop(&self.peer_screens, &self.device_id, pos, screen);
+                    if !screens.touches(edge, desktop_pos) {
+                        self.return_armed = true;
+                    } else if self.return_armed {
+                        self.return_armed = false;
+                        let _ = self.edge_return_tx.send(EdgeReturn { device_id: self.device_id.clone(), edge, pos, screen });
+                    }
+                }
+                // Feed the frontend's remote pointer minimap
+                self.ws_server.broadcast(Event::RemoteCursor {
+                    device_id: self.device_id.clone(),
+                    x,
+                    y,
+                    screen_width,
+                    screen_height,
+                });
+            }
+            // The controlled side's screen only; the controller shows none of its own
+            Message::ScreenshotRequest if self.role == Role::Controlled => {
+                if !self.screenshot_allowed {
+                    println!("{} 未授予截图权限，拒绝截图请求", self.device_id);
+                    if let Some(tx) = self.peer_tx.upgrade() {
+                        let _ = tx.send(Message::ScreenshotUnavailable);
+                    }
+                    return;
+                }
+                // The one being taken answers this request too
+                if self.screenshot_in_flight.swap(true, Ordering::AcqRel) {
+                    return;
+                }
+                let in_flight = Arc::clone(&self.screenshot_in_flight);
+                let simulator = Arc::clone(&self.simulator);
+                let tx = self.peer_tx.clone();
+                // Capturing and scaling takes a moment
+                tokio::task::spawn_blocking(move || {
+                    if let Some(tx) = tx.upgrade() {
+                        match simulator.screenshot() {
+                            Some(screenshot) => {
+                                for msg in screenshot.chunks() {
+                                    if tx.send(msg).is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                            None => {
+                                let _ = tx.send(Message::ScreenshotUnavailable);
+                            }
+                        }
+                    }
+                    in_flight.store(false, Ordering::Release);
+                });
+            }
+            Message::ScreenshotData { width, height, offset, data } => {
+                if let Some(screenshot) = self.screenshot.add(width, height, offset, data) {
+                    println!("收到 {} 的截图 {}x{}", self.device_id, screenshot.width, screenshot.height);
+                    self.ws_server.broadcast(Event::PeerScreenshot {
+                        device_id: self.device_id.clone(),
+                        width: screenshot.width,
+                        height: screenshot.height,
+                        image: Some(screenshot.data_url()),
+                    });
+                }
+            }
+            Message::ThumbnailFrame { seq, offset, total, data } => {
+                if let Some(jpeg) = self.thumbnail.add(seq, offset, total, data) {
+                    self.ws_server.broadcast(Event::PeerThumbnail {
+                        device_id: self.device_id.clone(),
+                        image: Some(screenshot::jpeg_data_url(&jpeg)),
+                    });
+                }
+            }
+            // From the controlled side: its stream ended
+            Message::ThumbnailStop => {
+                println!("{} 停止了缩略图", self.device_id);
+                self.ws_server.broadcast(Event::PeerThumbnail { device_id: self.device_id.clone(), image: None });
+            }
+            Message::ScreenshotUnavailable => {
+                println!("{} 无法提供截图", self.device_id);
+                self.ws_server.broadcast(Event::PeerScreenshot { device_id: self.device_id.clone(), width: 0, height: 0, image: None });
+            }
+            Message::LockState(state) => {
+                self.peer_lock_states.write().unwrap().insert(self.device_id.clone(), state);
+                self.ws_server.broadcast(Event::PeerLockState { device_id: self.device_id.clone(), state });
+            }
+            Message::Screens { monitors } => {
+                let Some(screens) = (Topology { monitors }).validated() else {
+                    println!("{} 发来的显示器布局无效，忽略", self.device_id);
+                    return;
+                };
+                println!("{} 有 {} 个显示器，桌面 {:?}", self.device_id, screens.monitors.len(), screens.bounds().1);
+                self.ws_server.broadcast(Event::PeerScreens { device_id: self.device_id.clone(), monitors: screens.monitors.clone() });
+                self.peer_screens.write().unwrap().insert(self.device_id.clone(), screens);
+            }
+            other => {
+                println!("收到对方消息: {:?}", LoggedMessage(&other));
+            }
+        }
+    }
+
+    fn show_remote_input(&self, event_type: &str, key: String, key_code: Option<u32>, button: Option<u8>) {
+        let event = InputEvent {
+            event_type: event_type.to_string(),
+            x: None, y: None, dx: None, dy: None,
+            key: Some(key),
+            key_code,
+            button,
+            modifiers: None,
+            delta_mode: None,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+        self.ws_server.broadcast(Event::RemoteInput { event });
+    }
+}
@@ -0,0 +1,326 @@
+use crate::persist;
+use crate::protocol::{Permission, ScreenEdge};
+use crate::transport::TransportOptions;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use utoipa::ToSchema;
+
+pub const SLOT_COUNT: u8 = 9;
+
+/// Layout of the settings file, saved with it as "version"; raising it needs an entry in MIGRATIONS
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// MIGRATIONS[n] turns a version n settings object into version n + 1
+const MIGRATIONS: [fn(&mut Map<String, Value>); SETTINGS_VERSION as usize] = [
+    // Files from before versioning: same layout, only the version field is new
+    |_| {},
+];
+
+/// Limits for DeviceGroup::pointer_speed
+pub const MIN_POINTER_SPEED: f64 = 0.1;
+pub const MAX_POINTER_SPEED: f64 = 10.0;
+
+/// Limits for RequestTimeouts, in seconds
+pub const MIN_REQUEST_TIMEOUT_SECS: u64 = 5;
+pub const MAX_REQUEST_TIMEOUT_SECS: u64 = 300;
+
+/// How long a connection request may wait for the user on the other side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RequestTimeouts {
+    /// Incoming requests: how long the prompt stays up; told to the requester
+    pub approval_secs: u64,
+    /// Outgoing requests: how long to wait for the peer to acknowledge the request
+    /// (Message::ConnectPending) or answer it; after an acknowledgement the
+    /// peer's own timeout applies
+    pub wait_secs: u64,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        RequestTimeouts { approval_secs: 30, wait_secs: 10 }
+    }
+}
+
+impl RequestTimeouts {
+    pub fn clamped(self) -> Self {
+        let range = MIN_REQUEST_TIMEOUT_SECS..=MAX_REQUEST_TIMEOUT_SECS;
+        RequestTimeouts {
+            approval_secs: self.approval_secs.clamp(*range.start(), *range.end()),
+            wait_secs: self.wait_secs.clamp(*range.start(), *range.end()),
+        }
+    }
+}
+
+/// Devices sharing defaults, e.g. "office desk"; a device is in at most one group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DeviceGroup {
+    /// Device IDs
+    pub members: Vec<String>,
+    /// Multiplies the pointer movement members inject here
+    pub pointer_speed: f64,
+    /// Granted to members when accepting without choosing; never more than they ask for
+    pub permissions: Option<Vec<Permission>>,
+    /// Accept members' requests without asking, once they are paired: a device ID alone
+    /// proves nothing, so an unpaired member is still asked about.
+    pub auto_accept: bool,
+}
+
+impl Default for DeviceGroup {
+    fn default() -> Self {
+        DeviceGroup { members: Vec::new(), pointer_speed: 1.0, permissions: None, auto_accept: false }
+    }
+}
+
+/// Per-device settings that win over the device's group and the global ones
+/// when that device connects; None keeps whatever would apply otherwise
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DeviceOverrides {
+    /// Multiplies the pointer movement the device injects here
+    pub pointer_speed: Option<f64>,
+    /// Flip the direction of the device's scrolling here
+    pub invert_scroll: Option<bool>,
+    /// Granted to the device when accepting without choosing; never more than it asks for
+    pub permissions: Option<Vec<Permission>>,
+    /// Mouse move coalescing while controlling the device, in ms (0: send every move)
+    pub coalescing_ms: Option<u64>,
+}
+
+impl DeviceOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == DeviceOverrides::default()
+    }
+}
+
+/// Which side started a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    /// We connected to the device
+    Outgoing,
+    /// The device connected to us
+    Incoming,
+}
+
+/// The last session with a device, for "recently used" sorting and preselecting the usual target
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceHistory {
+    /// When the last successful session started, Unix ms
+    pub last_connected: u64,
+    pub direction: Direction,
+    /// Average round trip of the last session that measured one
+    pub avg_rtt_ms: Option<f64>,
+}
+
+/// User choices that survive a restart, kept as JSON
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// Quick-switch slot (1-9, Ctrl+Alt+<digit>) -> device ID
+    pub slots: BTreeMap<u8, String>,
+    /// Nagle and flushing for new sessions
+    pub transport: TransportOptions,
+    /// Device ID -> last session with it
+    pub devices: BTreeMap<String, DeviceHistory>,
+    /// Group name -> group
+    pub groups: BTreeMap<String, DeviceGroup>,
+    /// Device ID -> what differs for that device
+    pub overrides: BTreeMap<String, DeviceOverrides>,
+    pub request_timeouts: RequestTimeouts,
+    /// Edge of our screen -> device beyond it, for switching by moving the cursor over
+    pub screen_layout: BTreeMap<ScreenEdge, String>,
+}
+
+impl Settings {
+    /// A missing or unusable file gives the defaults, it must not keep the backend from
+    /// starting. An unusable one (broken, or from a newer version) is set aside first, so
+    /// the next save doesn't replace the trust it held for good.
+    pub fn load(path: &Path) -> Self {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Settings::default(),
+            Err(e) => {
+                eprintln!("读取设置失败 {}: {}", path.display(), e);
+                return Settings::default();
+            }
+        };
+        match serde_json::from_str(&text).map_err(anyhow::Error::from).and_then(Settings::from_json) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("设置文件无法使用 {}: {}", path.display(), e);
+                if let Some(kept) = persist::set_aside(path) {
+                    eprintln!("  原文件已保留为 {}", kept.display());
+                }
+                Settings::default()
+            }
+        }
+    }
+
+    /// Replaces the file in one step, a crash mid-save leaves the previous settings
+    pub fn save(&self, path: &Path) -> Result<()> {
+        persist::write_atomic(path, serde_json::to_string_pretty(&self.to_json()?)?.as_bytes())
+    }
+
+    /// Migrate a settings object of any earlier version; newer ones are refused
+    pub fn from_json(value: Value) -> Result<Self> {
+        let Value::Object(mut object) = value else {
+            bail!("设置不是 JSON 对象");
+        };
+        let version = match object.get("version") {
+            None => 0,
+            Some(version) => version.as_u64().and_then(|v| u32::try_from(v).ok()).context("设置版本无效")?,
+        };
+        if version > SETTINGS_VERSION {
+            bail!("设置版本 {} 比本程序支持的 {} 新", version, SETTINGS_VERSION);
+        }
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(&mut object);
+        }
+        object.remove("version");
+        Ok(serde_json::from_value(Value::Object(object))?)
+    }
+
+    /// The settings object with the current version
+    pub fn to_json(&self) -> Result<Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Value::Object(object) = &mut value {
+            object.insert("version".to_string(), SETTINGS_VERSION.into());
+        }
+        Ok(value)
+    }
+
+    pub fn slot_of(&self, device_id: &str) -> Option<u8> {
+        self.slots.iter().find(|(_, id)| *id == device_id).map(|(slot, _)| *slot)
+    }
+
+    pub fn group_of(&self, device_id: &str) -> Option<&DeviceGroup> {
+        self.groups.values().find(|group| group.members.iter().any(|id| id == device_id))
+    }
+
+    /// Devices whose connection requests are accepted without asking once they are paired
+    pub fn auto_accepted(&self) -> HashSet<String> {
+        self.groups.values().filter(|group| group.auto_accept).flat_map(|group| group.members.iter().cloned()).collect()
+    }
+
+    /// Create or replace the group `name` (None deletes it); its members leave any other group
+    pub fn set_group(&mut self, name: &str, group: Option<DeviceGroup>) {
+        match group {
+            Some(mut group) => {
+                group.pointer_speed = group.pointer_speed.clamp(MIN_POINTER_SPEED, MAX_POINTER_SPEED);
+                for (other_name, other) in self.groups.iter_mut() {
+                    if other_name != name {
+                        other.members.retain(|id| !group.members.contains(id));
+                    }
+                }
+                self.groups.insert(name.to_string(), group);
+            }
+            None => {
+                self.groups.remove(name);
+            }
+        }
+    }
+
+    pub fn overrides_of(&self, device_id: &str) -> DeviceOverrides {
+        self.overrides.get(device_id).cloned().unwrap_or_default()
+    }
+
+    /// Replace what is overridden for `device_id`; None or nothing set removes the entry
+    pub fn set_overrides(&mut self, device_id: &str, overrides: Option<DeviceOverrides>) {
+        match overrides.filter(|overrides| !overrides.is_empty()) {
+            Some(mut overrides) => {
+                overrides.pointer_speed = overrides.pointer_speed.map(|speed| speed.clamp(MIN_POINTER_SPEED, MAX_POINTER_SPEED));
+                self.overrides.insert(device_id.to_string(), overrides);
+            }
+            None => {
+                self.overrides.remove(device_id);
+            }
+        }
+    }
+
+    /// Remember a session with `device_id`; a session too short to measure the round trip keeps the old figure
+    pub fn record_session(&mut self, device_id: &str, started: u64, direction: Direction, avg_rtt_ms: Option<f64>) {
+        let avg_rtt_ms = avg_rtt_ms.or_else(|| self.devices.get(device_id).and_then(|history| history.avg_rtt_ms));
+        self.devices.insert(device_id.to_string(), DeviceHistory { last_connected: started, direction, avg_rtt_ms });
+    }
+
+    /// Take over what another machine's settings know; entries in both take `other`'s
+    pub fn merge(&mut self, other: Settings) {
+        for (slot, device_id) in other.slots {
+            self.assign_slot(slot, Some(device_id));
+        }
+        for (device_id, history) in other.devices {
+            let newer = self.devices.get(&device_id).is_none_or(|ours| ours.last_connected < history.last_connected);
+            if newer {
+                self.devices.insert(device_id, history);
+            }
+        }
+        for (name, group) in other.groups {
+            self.set_group(&name, Some(group));
+        }
+        for (device_id, overrides) in other.overrides {
+            self.set_overrides(&device_id, Some(overrides));
+        }
+        self.transport = other.transport;
+        self.request_timeouts = other.request_timeouts.clamped();
+    }
+
+    /// Put `device_id` in `slot` (None empties it); a device only ever has one slot
+    pub fn assign_slot(&mut self, slot: u8, device_id: Option<String>) {
+        match device_id {
+            Some(device_id) => {
+                self.slots.retain(|_, id| *id != device_id);
+                self.slots.insert(slot, device_id);
+            }
+            None => {
+                self.slots.remove(&slot);
+            }
+        }
+    }
+}
+
+const TRUST_FILE_VERSION: u32 = 1;
+
+/// What `shareflow export-trust` writes, for moving to a new machine: the settings
+/// that say which devices are trusted and how, and the device ID peers know us by
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustFile {
+    pub version: u32,
+    pub device_id: String,
+    #[serde(serialize_with = "serialize_versioned", deserialize_with = "deserialize_migrated")]
+    pub settings: Settings,
+}
+
+fn serialize_versioned<S: Serializer>(settings: &Settings, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    settings.to_json().map_err(serde::ser::Error::custom)?.serialize(serializer)
+}
+
+fn deserialize_migrated<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Settings, D::Error> {
+    Settings::from_json(Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Write the settings at `settings_path` to `out` as a TrustFile
+pub fn export_trust(settings_path: &Path, device_id: &str, out: &Path) -> Result<()> {
+    let file = TrustFile { version: TRUST_FILE_VERSION, device_id: device_id.to_string(), settings: Settings::load(settings_path) };
+    persist::write_atomic(out, serde_json::to_string_pretty(&file)?.as_bytes()).with_context(|| format!("写入 {} 失败", out.display()))?;
+    Ok(())
+}
+
+/// Merge the TrustFile at `file` into the settings at `settings_path`; the backend must not be running
+pub fn import_trust(settings_path: &Path, file: &Path) -> Result<TrustFile> {
+    let text = std::fs::read_to_string(file).with_context(|| format!("读取 {} 失败", file.display()))?;
+    let trust: TrustFile = serde_json::from_str(&text).with_context(|| format!("{} 不是有效的信任文件", file.display()))?;
+    if trust.version > TRUST_FILE_VERSION {
+        bail!("信任文件版本 {} 比本程序支持的 {} 新，请先升级", trust.version, TRUST_FILE_VERSION);
+    }
+    let mut settings = Settings::load(settings_path);
+    settings.merge(trust.settings.clone());
+    settings.save(settings_path)?;
+    Ok(trust)
+}
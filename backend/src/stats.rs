@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Past sessions kept per peer
+const MAX_HISTORY: usize = 20;
+
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// One session with a peer, as exported
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRecord {
+    pub role: String,
+    pub started: u64,
+    pub ended: Option<u64>,
+    pub end_reason: Option<String>,
+    /// Round trip from heartbeats, smoothed like TCP's SRTT
+    #[serde(default)]
+    pub avg_rtt_ms: Option<f64>,
+    /// Bytes on the wire in this session, framing and encryption included
+    #[serde(default)]
+    pub bytes_sent: u64,
+    #[serde(default)]
+    pub bytes_received: u64,
+}
+
+/// Everything counted for one peer device since the last reset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerStats {
+    pub sessions: u32,
+    /// Sessions after the first one
+    pub reconnects: u32,
+    /// Outgoing handshakes: TCP connect until the peer's answer, in ms
+    pub handshake_ms: Vec<u64>,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Bytes on the wire over all sessions, framing and encryption included
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Error kind -> count
+    pub errors: BTreeMap<String, u64>,
+    pub history: Vec<SessionRecord>,
+}
+
+/// What `ExportStats` returns, meant to be attached to bug reports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsExport {
+    /// When counting started (Unix ms): startup or the last reset
+    pub since: u64,
+    pub exported: u64,
+    pub peers: BTreeMap<String, PeerStats>,
+}
+
+/// Per-peer connection counters shared by the main loop and the sessions
+pub struct ConnectionStats {
+    inner: Mutex<(u64, HashMap<String, PeerStats>)>,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        ConnectionStats { inner: Mutex::new((unix_ms(), HashMap::new())) }
+    }
+
+    fn with_peer(&self, device_id: &str, f: impl FnOnce(&mut PeerStats)) {
+        let mut inner = self.inner.lock().unwrap();
+        f(inner.1.entry(device_id.to_string()).or_default());
+    }
+
+    pub fn handshake(&self, device_id: &str, elapsed: Duration) {
+        self.with_peer(device_id, |peer| {
+            if peer.handshake_ms.len() == MAX_HISTORY {
+                peer.handshake_ms.remove(0);
+            }
+            peer.handshake_ms.push(elapsed.as_millis() as u64);
+        });
+    }
+
+    pub fn session_started(&self, device_id: &str, role: &str) {
+        self.with_peer(device_id, |peer| {
+            if peer.sessions > 0 {
+                peer.reconnects += 1;
+            }
+            peer.sessions += 1;
+            if peer.history.len() == MAX_HISTORY {
+                peer.history.remove(0);
+            }
+            peer.history.push(SessionRecord {
+                role: role.to_string(),
+                started: unix_ms(),
+                ended: None,
+                end_reason: None,
+                avg_rtt_ms: None,
+                bytes_sent: 0,
+                bytes_received: 0,
+            });
+        });
+    }
+
+    /// Close the peer's open session record; later calls for the same session are ignored
+    pub fn session_ended(&self, device_id: &str, reason: &str) {
+        self.with_peer(device_id, |peer| {
+            if let Some(record) = peer.history.last_mut().filter(|record| record.ended.is_none()) {
+                record.ended = Some(unix_ms());
+                record.end_reason = Some(reason.to_string());
+            }
+        });
+    }
+
+    /// A round trip measured in the peer's open session
+    pub fn rtt(&self, device_id: &str, rtt: Duration) {
+        let sample = rtt.as_secs_f64() * 1000.0;
+        self.with_peer(device_id, |peer| {
+            if let Some(record) = peer.history.last_mut().filter(|record| record.ended.is_none()) {
+                record.avg_rtt_ms = Some(record.avg_rtt_ms.map_or(sample, |avg| avg + (sample - avg) / 8.0));
+            }
+        });
+    }
+
+    /// The peer's latest session, None after a reset
+    pub fn last_session(&self, device_id: &str) -> Option<SessionRecord> {
+        self.inner.lock().unwrap().1.get(device_id)?.history.last().cloned()
+    }
+
+    /// `len`: bytes on the wire, counted for the peer and its open session
+    pub fn message_sent(&self, device_id: &str, len: usize) {
+        self.with_peer(device_id, |peer| {
+            peer.messages_sent += 1;
+            peer.bytes_sent += len as u64;
+            if let Some(record) = peer.history.last_mut().filter(|record| record.ended.is_none()) {
+                record.bytes_sent += len as u64;
+            }
+        });
+    }
+
+    pub fn message_received(&self, device_id: &str, len: usize) {
+        self.with_peer(device_id, |peer| {
+            peer.messages_received += 1;
+            peer.bytes_received += len as u64;
+            if let Some(record) = peer.history.last_mut().filter(|record| record.ended.is_none()) {
+                record.bytes_received += len as u64;
+            }
+        });
+    }
+
+    pub fn error(&self, device_id: &str, kind: &str) {
+        self.with_peer(device_id, |peer| *peer.errors.entry(kind.to_string()).or_default() += 1);
+    }
+
+    pub fn reset(&self) {
+        *self.inner.lock().unwrap() = (unix_ms(), HashMap::new());
+    }
+
+    pub fn export(&self) -> StatsExport {
+        let inner = self.inner.lock().unwrap();
+        StatsExport {
+            since: inner.0,
+            exported: unix_ms(),
+            peers: inner.1.iter().map(|(id, peer)| (id.clone(), peer.clone())).collect(),
+        }
+    }
+}
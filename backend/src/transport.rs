@@ -1,67 +1,348 @@
-use crate::protocol::Message;
-use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::error::TransportError;
+use crate::protocol::{self, Hello, Message, MAX_FRAME_LEN};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf};
 use tokio::net::{TcpStream, UdpSocket};
 
+/// How session frames go out. The defaults send every frame at once, which is
+/// what games want; on high-latency links batching saves packets instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TransportOptions {
+    /// TCP_NODELAY: false lets Nagle's algorithm merge small frames
+    pub nodelay: bool,
+    pub flush: FlushStrategy,
+    /// Talk to peers that don't encrypt, whose input crosses the network in
+    /// the clear; off refuses them. Builds from before encryption are also from
+    /// before Hello and refused either way.
+    pub allow_plaintext: bool,
+    /// Per connection limit for bulk data (clipboard text streamed in chunks), in KiB/s,
+    /// e.g. on a metered hotspot; input is never held back. For transfers started from now on.
+    pub bulk_cap_kib_per_sec: Option<u64>,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        TransportOptions { nodelay: true, flush: FlushStrategy::Immediate, allow_plaintext: false, bulk_cap_kib_per_sec: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum FlushStrategy {
+    /// Flush after every frame
+    Immediate,
+    /// Buffer frames and flush at most `intervalMs` after the first one
+    Periodic {
+        #[serde(rename = "intervalMs")]
+        interval_ms: u64,
+    },
+}
+
+/// Longest periodic flush interval accepted; input lagging more than this is unusable
+pub const MAX_FLUSH_INTERVAL_MS: u64 = 100;
+
 pub struct Transport;
 
 impl Transport {
-    pub async fn send_tcp(stream: &mut TcpStream, message: &Message) -> Result<()> {
-        let data = bincode::serialize(message)?;
-        let len = data.len() as u32;
-        
-        // Coalesce writes: Create a single buffer with length prefix + data
-        // This ensures the OS sends the packet immediately with TCP_NODELAY
-        let mut buffer = Vec::with_capacity(4 + data.len());
-        buffer.extend_from_slice(&len.to_be_bytes());
-        buffer.extend_from_slice(&data);
-        
-        stream.write_all(&buffer).await?;
-        stream.flush().await?; // 立即刷新缓冲区，确保数据立即发送
+    pub async fn send_udp(socket: &UdpSocket, addr: &str, message: &Message) -> Result<(), TransportError> {
+        let data = protocol::encode(message).map_err(|e| TransportError::Malformed(e.to_string()))?;
+        socket.send_to(&data, addr).await?;
         Ok(())
     }
+}
 
-    pub async fn recv_tcp(stream: &mut TcpStream) -> Result<Message> {
-        let mut len_buf = [0u8; 4];
-        stream.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data).await?;
-        
-        let message = bincode::deserialize(&data)?;
-        Ok(message)
+/// Noise pattern for peer connections. In XX both sides prove their static key, which
+/// pairing ties to a device ID; the ID in a ConnectRequest alone proves nothing.
+const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Starts an initiator's first frame when it encrypts; a plaintext frame starts with
+/// a little-endian variant index instead. SFN1 was the unauthenticated NN handshake.
+const NOISE_MAGIC: &[u8] = b"SFN2";
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+/// Plaintext per Noise message; longer frames are sealed in several
+const NOISE_MAX_PAYLOAD: usize = NOISE_MAX_MESSAGE - NOISE_TAG_LEN;
+const MAX_SEALED_FRAME_LEN: usize = MAX_FRAME_LEN + NOISE_TAG_LEN * MAX_FRAME_LEN.div_ceil(NOISE_MAX_PAYLOAD);
+
+fn encryption_error(e: snow::Error) -> TransportError {
+    TransportError::Encryption(e.to_string())
+}
+
+fn noise_builder(identity: &Identity) -> Result<snow::Builder<'_>, TransportError> {
+    let params = NOISE_PATTERN.parse().map_err(encryption_error)?;
+    Ok(snow::Builder::new(params).local_private_key(&identity.private))
+}
+
+/// This device's long-lived Noise key pair
+pub struct Identity {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self, TransportError> {
+        let params = NOISE_PATTERN.parse().map_err(encryption_error)?;
+        let keypair = snow::Builder::new(params).generate_keypair().map_err(encryption_error)?;
+        Ok(Identity { private: keypair.private, public: keypair.public })
     }
+}
 
-    pub async fn send_udp(socket: &UdpSocket, addr: &str, message: &Message) -> Result<()> {
-        let data = bincode::serialize(message)?;
-        socket.send_to(&data, addr).await?;
-        Ok(())
+/// One direction of an encrypted connection; frames are opened in the order they were sealed.
+/// This is also the replay protection: a frame sent again no longer opens once the nonce
+/// moved past it, and one recorded in an earlier session was sealed with that session's keys.
+pub struct Cipher {
+    state: Arc<snow::StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn seal(&mut self, plain: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut sealed = Vec::with_capacity(plain.len() + NOISE_TAG_LEN * plain.len().div_ceil(NOISE_MAX_PAYLOAD));
+        for chunk in plain.chunks(NOISE_MAX_PAYLOAD) {
+            let start = sealed.len();
+            sealed.resize(start + chunk.len() + NOISE_TAG_LEN, 0);
+            let len = self.state.write_message(self.nonce, chunk, &mut sealed[start..]).map_err(encryption_error)?;
+            sealed.truncate(start + len);
+            self.nonce += 1;
+        }
+        Ok(sealed)
+    }
+
+    fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut plain = Vec::with_capacity(sealed.len());
+        for chunk in sealed.chunks(NOISE_MAX_MESSAGE) {
+            let start = plain.len();
+            plain.resize(start + chunk.len(), 0);
+            let len = self.state.read_message(self.nonce, chunk, &mut plain[start..]).map_err(encryption_error)?;
+            plain.truncate(start + len);
+            self.nonce += 1;
+        }
+        Ok(plain)
     }
+}
+
+/// Returns the bytes put on the wire, length prefix included
+async fn write_raw<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<usize, TransportError> {
+    // Coalesce writes: length prefix and data in one buffer, so with TCP_NODELAY they leave as one packet
+    let mut buffer = Vec::with_capacity(4 + data.len());
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(data);
+    writer.write_all(&buffer).await?;
+    Ok(buffer.len())
+}
 
-    // Split stream versions for concurrent read/write
-    pub async fn send_tcp_split<W: AsyncWriteExt + Unpin>(writer: &mut W, message: &Message) -> Result<()> {
-        let data = bincode::serialize(message)?;
-        let len = data.len() as u32;
-        
-        let mut buffer = Vec::with_capacity(4 + data.len());
-        buffer.extend_from_slice(&len.to_be_bytes());
-        buffer.extend_from_slice(&data);
-        
-        writer.write_all(&buffer).await?;
-        writer.flush().await?;
+async fn read_raw<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Vec<u8>, TransportError> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    // Check before allocating: the length comes straight from the peer
+    if len > max_len {
+        return Err(TransportError::FrameTooLarge(len));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, cipher: Option<&mut Cipher>, message: &Message) -> Result<usize, TransportError> {
+    let data = protocol::encode(message).map_err(|e| TransportError::Malformed(e.to_string()))?;
+    match cipher {
+        Some(cipher) => write_raw(writer, &cipher.seal(&data)?).await,
+        None => write_raw(writer, &data).await,
+    }
+}
+
+/// The message and its size on the wire, length prefix included
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, cipher: Option<&mut Cipher>) -> Result<(Message, usize), TransportError> {
+    let (data, len) = match cipher {
+        Some(cipher) => {
+            let sealed = read_raw(reader, MAX_SEALED_FRAME_LEN).await?;
+            (cipher.open(&sealed)?, 4 + sealed.len())
+        }
+        None => {
+            let data = read_raw(reader, MAX_FRAME_LEN).await?;
+            let len = 4 + data.len();
+            (data, len)
+        }
+    };
+    let message = protocol::decode(&data).map_err(|e| TransportError::Malformed(e.to_string()))?;
+    Ok((message, len))
+}
+
+/// What the Noise handshake established
+struct Secured {
+    sending: Cipher,
+    receiving: Cipher,
+    /// The static key the peer proved
+    remote_key: Vec<u8>,
+    handshake_hash: Vec<u8>,
+}
+
+/// A connection to a peer: length-prefixed bincode frames, sealed with Noise
+/// unless the peer is from before encryption and plaintext is allowed
+pub struct PeerStream {
+    stream: TcpStream,
+    secured: Option<Secured>,
+    /// None until exchanged, and for peers from before Message::Hello
+    peer_hello: Option<Hello>,
+}
+
+impl PeerStream {
+    pub fn plaintext(stream: TcpStream) -> Self {
+        PeerStream { stream, secured: None, peer_hello: None }
+    }
+
+    /// Initiator: run the Noise handshake before anything else goes out
+    pub async fn initiate(mut stream: TcpStream, identity: &Identity) -> Result<Self, TransportError> {
+        let mut handshake = noise_builder(identity)?.build_initiator().map_err(encryption_error)?;
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        let len = handshake.write_message(&[], &mut buf).map_err(encryption_error)?;
+        write_raw(&mut stream, &[NOISE_MAGIC, &buf[..len]].concat()).await?;
+        stream.flush().await?;
+        let reply = read_raw(&mut stream, NOISE_MAX_MESSAGE).await?;
+        handshake.read_message(&reply, &mut buf).map_err(encryption_error)?;
+        let len = handshake.write_message(&[], &mut buf).map_err(encryption_error)?;
+        write_raw(&mut stream, &buf[..len]).await?;
+        stream.flush().await?;
+        PeerStream::encrypted(stream, handshake)
+    }
+
+    /// Responder: read the first frame, answering the handshake if it starts one.
+    /// Returns the first message with the stream, which stays plaintext if the peer
+    /// didn't encrypt; whether to go on with such a peer is up to the caller.
+    /// A Message::Hello is answered with ours and the message after it returned;
+    /// whether its version will do is also up to the caller.
+    pub async fn accept(stream: TcpStream, identity: &Identity) -> Result<(Self, Message), TransportError> {
+        let (mut peer, message) = PeerStream::accept_first(stream, identity).await?;
+        let Message::Hello { version, capabilities } = message else {
+            return Ok((peer, message));
+        };
+        peer.peer_hello = Some(Hello { version, capabilities });
+        peer.send(&Hello::local().message()).await?;
+        let message = peer.recv().await?;
+        Ok((peer, message))
+    }
+
+    async fn accept_first(mut stream: TcpStream, identity: &Identity) -> Result<(Self, Message), TransportError> {
+        let first = read_raw(&mut stream, MAX_FRAME_LEN).await?;
+        let Some(hello) = first.strip_prefix(NOISE_MAGIC) else {
+            let message = protocol::decode(&first).map_err(|e| TransportError::Malformed(e.to_string()))?;
+            return Ok((PeerStream::plaintext(stream), message));
+        };
+        let mut handshake = noise_builder(identity)?.build_responder().map_err(encryption_error)?;
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        handshake.read_message(hello, &mut buf).map_err(encryption_error)?;
+        let len = handshake.write_message(&[], &mut buf).map_err(encryption_error)?;
+        write_raw(&mut stream, &buf[..len]).await?;
+        stream.flush().await?;
+        let last = read_raw(&mut stream, NOISE_MAX_MESSAGE).await?;
+        handshake.read_message(&last, &mut buf).map_err(encryption_error)?;
+        let mut peer = PeerStream::encrypted(stream, handshake)?;
+        let message = peer.recv().await?;
+        Ok((peer, message))
+    }
+
+    fn encrypted(stream: TcpStream, handshake: snow::HandshakeState) -> Result<Self, TransportError> {
+        let remote_key = handshake.get_remote_static().map(<[u8]>::to_vec).ok_or_else(|| TransportError::Encryption("peer sent no static key".to_string()))?;
+        let handshake_hash = handshake.get_handshake_hash().to_vec();
+        let state = Arc::new(handshake.into_stateless_transport_mode().map_err(encryption_error)?);
+        let sending = Cipher { state: Arc::clone(&state), nonce: 0 };
+        let receiving = Cipher { state, nonce: 0 };
+        Ok(PeerStream { stream, secured: Some(Secured { sending, receiving, remote_key, handshake_hash }), peer_hello: None })
+    }
+
+    /// Initiator, right after connecting: send our Message::Hello and read the
+    /// peer's. Peers from before Hello hang up on it, which comes back as Closed.
+    pub async fn hello(&mut self) -> Result<Hello, TransportError> {
+        self.send(&Hello::local().message()).await?;
+        match self.recv().await? {
+            Message::Hello { version, capabilities } => {
+                let hello = Hello { version, capabilities };
+                self.peer_hello = Some(hello);
+                Ok(hello)
+            }
+            _ => Err(TransportError::Malformed("expected Hello".to_string())),
+        }
+    }
+
+    /// What the peer said in its Message::Hello, if it sent one
+    pub fn peer_hello(&self) -> Option<Hello> {
+        self.peer_hello
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.secured.is_some()
+    }
+
+    /// The peer's static key and the handshake hash both sides derive pairing codes
+    /// from; None for plaintext
+    pub fn peer_key(&self) -> Option<(&[u8], &[u8])> {
+        self.secured.as_ref().map(|secured| (secured.remote_key.as_slice(), secured.handshake_hash.as_slice()))
+    }
+
+    /// The socket, for its options
+    pub fn tcp(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), TransportError> {
+        write_frame(&mut self.stream, self.secured.as_mut().map(|secured| &mut secured.sending), message).await?;
+        self.stream.flush().await?; // 立即刷新缓冲区，确保数据立即发送
         Ok(())
     }
 
-    pub async fn recv_tcp_split<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Message> {
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        
-        let mut data = vec![0u8; len];
-        reader.read_exact(&mut data).await?;
-        
-        let message = bincode::deserialize(&data)?;
+    pub async fn recv(&mut self) -> Result<Message, TransportError> {
+        let (message, _) = read_frame(&mut self.stream, self.secured.as_mut().map(|secured| &mut secured.receiving)).await?;
         Ok(message)
     }
+
+    /// Halves for a session's concurrent reading and writing; the writer is buffered
+    pub fn into_split(self) -> (FrameReader<ReadHalf<TcpStream>>, FrameWriter<BufWriter<WriteHalf<TcpStream>>>) {
+        let (read_half, write_half) = tokio::io::split(self.stream);
+        let (sending, receiving) = match self.secured {
+            Some(Secured { sending, receiving, .. }) => (Some(sending), Some(receiving)),
+            None => (None, None),
+        };
+        (
+            FrameReader { reader: read_half, cipher: receiving },
+            FrameWriter { writer: BufWriter::new(write_half), cipher: sending },
+        )
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    cipher: Option<Cipher>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// The next message and its size on the wire
+    pub async fn recv(&mut self) -> Result<(Message, usize), TransportError> {
+        read_frame(&mut self.reader, self.cipher.as_mut()).await
+    }
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+    cipher: Option<Cipher>,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Returns the bytes put on the wire
+    pub async fn send(&mut self, message: &Message) -> Result<usize, TransportError> {
+        let len = self.write_frame(message).await?;
+        self.flush().await?;
+        Ok(len)
+    }
+
+    /// Like send but without the flush, for a buffered writer flushed on a schedule
+    pub async fn write_frame(&mut self, message: &Message) -> Result<usize, TransportError> {
+        write_frame(&mut self.writer, self.cipher.as_mut(), message).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), TransportError> {
+        self.writer.flush().await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,444 @@
+//! Input in a Wayland session, where X11 only reaches XWayland windows.
+//!
+//! Injection goes through a uinput virtual keyboard and mouse, which the
+//! compositor treats like any other device. Capture grabs the keyboards and
+//! mice under /dev/input directly and hands what it doesn't keep to a second
+//! virtual device; rdev's own evdev grab can't be used, it needs an X display.
+//! Knowing which device each event came from, it can leave chosen ones alone.
+//! Both need the same access as rdev's grab: the input group and /dev/uinput.
+
+use rdev::{Button, Event, EventType, Key};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::raw::{c_int, c_long, c_ulong};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const KEY_A: u16 = 30;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_SIDE: u16 = 0x113;
+const BTN_EXTRA: u16 = 0x114;
+const KEY_MAX: u16 = 0x2ff;
+const BUS_VIRTUAL: u16 = 0x06;
+
+const UI_SET_EVBIT: c_ulong = 0x4004_5564;
+const UI_SET_KEYBIT: c_ulong = 0x4004_5565;
+const UI_SET_RELBIT: c_ulong = 0x4004_5566;
+const UI_DEV_CREATE: c_ulong = 0x5501;
+const UI_DEV_DESTROY: c_ulong = 0x5502;
+const EVIOCGRAB: c_ulong = 0x4004_4590;
+
+// Names of our own devices, which capture must leave alone
+const NAME_PREFIX: &str = "ShareFlow";
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// EVIOCGBIT: which event types (`ev` 0) or codes of type `ev` a device has
+const fn eviocgbit(ev: u16, len: usize) -> c_ulong {
+    (2 << 30) | ((len as c_ulong) << 16) | (0x45 << 8) | (0x20 + ev as c_ulong)
+}
+
+/// EVIOCGNAME
+const fn eviocgname(len: usize) -> c_ulong {
+    (2 << 30) | ((len as c_ulong) << 16) | (0x45 << 8) | 0x06
+}
+
+/// Whether this is a Wayland session, going by the variables the capability probe reads
+pub fn session() -> bool {
+    std::env::var("XDG_SESSION_TYPE").is_ok_and(|t| t == "wayland") || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// struct input_event: a timeval (the kernel stamps it), type, code and value
+const EVENT_LEN: usize = 2 * std::mem::size_of::<c_long>() + 8;
+
+#[derive(Debug, Clone, Copy)]
+struct RawEvent {
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+impl RawEvent {
+    fn to_bytes(self) -> [u8; EVENT_LEN] {
+        let mut bytes = [0; EVENT_LEN];
+        let at = EVENT_LEN - 8;
+        bytes[at..at + 2].copy_from_slice(&self.kind.to_ne_bytes());
+        bytes[at + 2..at + 4].copy_from_slice(&self.code.to_ne_bytes());
+        bytes[at + 4..].copy_from_slice(&self.value.to_ne_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let at = EVENT_LEN - 8;
+        RawEvent {
+            kind: u16::from_ne_bytes([bytes[at], bytes[at + 1]]),
+            code: u16::from_ne_bytes([bytes[at + 2], bytes[at + 3]]),
+            value: i32::from_ne_bytes([bytes[at + 4], bytes[at + 5], bytes[at + 6], bytes[at + 7]]),
+        }
+    }
+}
+
+/// A uinput keyboard and mouse with relative motion and wheels. Key codes
+/// in the gamepad and tablet ranges are left out, or udev would call it a joystick.
+pub struct VirtualDevice {
+    file: Mutex<File>,
+}
+
+impl VirtualDevice {
+    pub fn create(name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().write(true).open("/dev/uinput")?;
+        let fd = file.as_raw_fd();
+        let set = |request: c_ulong, value: u16| -> io::Result<()> {
+            if unsafe { ioctl(fd, request, value as c_int) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        set(UI_SET_EVBIT, EV_KEY)?;
+        set(UI_SET_EVBIT, EV_REL)?;
+        set(UI_SET_EVBIT, EV_SYN)?;
+        for code in (1..=0xff).chain(BTN_LEFT..=0x117).chain(0x160..=KEY_MAX) {
+            set(UI_SET_KEYBIT, code)?;
+        }
+        for code in [REL_X, REL_Y, REL_HWHEEL, REL_WHEEL] {
+            set(UI_SET_RELBIT, code)?;
+        }
+
+        // struct uinput_user_dev: name, input_id, ff_effects_max, then four
+        // tables of 64 absolute axis limits, unused here
+        let mut setup = vec![0u8; 80 + 8 + 4 + 4 * 64 * 4];
+        let name = name.as_bytes();
+        setup[..name.len().min(79)].copy_from_slice(&name[..name.len().min(79)]);
+        setup[80..82].copy_from_slice(&BUS_VIRTUAL.to_ne_bytes());
+        setup[86..88].copy_from_slice(&1u16.to_ne_bytes());
+        (&file).write_all(&setup)?;
+        if unsafe { ioctl(fd, UI_DEV_CREATE) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(VirtualDevice { file: Mutex::new(file) })
+    }
+
+    /// Writes `events` as one report
+    fn emit(&self, events: &[RawEvent]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity((events.len() + 1) * EVENT_LEN);
+        for event in events.iter().chain([&RawEvent { kind: EV_SYN, code: SYN_REPORT, value: 0 }]) {
+            bytes.extend_from_slice(&event.to_bytes());
+        }
+        self.file.lock().unwrap_or_else(|e| e.into_inner()).write_all(&bytes)
+    }
+
+    pub fn move_relative(&self, dx: i32, dy: i32) -> io::Result<()> {
+        self.emit(&[RawEvent { kind: EV_REL, code: REL_X, value: dx }, RawEvent { kind: EV_REL, code: REL_Y, value: dy }])
+    }
+
+    /// rdev's event types, as rdev would simulate them under X11. Absolute
+    /// moves have no place on a relative device and fail.
+    pub fn simulate(&self, event_type: &EventType) -> io::Result<()> {
+        let unsupported = || io::Error::new(io::ErrorKind::Unsupported, format!("{:?} can't be sent through uinput", event_type));
+        let event = match *event_type {
+            EventType::KeyPress(key) | EventType::KeyRelease(key) => RawEvent {
+                kind: EV_KEY,
+                code: evdev_code(key).ok_or_else(unsupported)?,
+                value: matches!(event_type, EventType::KeyPress(_)) as i32,
+            },
+            EventType::ButtonPress(button) | EventType::ButtonRelease(button) => RawEvent {
+                kind: EV_KEY,
+                code: evdev_button(button).ok_or_else(unsupported)?,
+                value: matches!(event_type, EventType::ButtonPress(_)) as i32,
+            },
+            // Notches, up and right positive on both sides
+            EventType::Wheel { delta_x, delta_y } => {
+                let mut events = Vec::new();
+                if delta_y != 0 {
+                    events.push(RawEvent { kind: EV_REL, code: REL_WHEEL, value: delta_y as i32 });
+                }
+                if delta_x != 0 {
+                    events.push(RawEvent { kind: EV_REL, code: REL_HWHEEL, value: delta_x as i32 });
+                }
+                return self.emit(&events);
+            }
+            EventType::MouseMove { .. } => return Err(unsupported()),
+        };
+        self.emit(&[event])
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        let file = self.file.get_mut().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            ioctl(file.as_raw_fd(), UI_DEV_DESTROY);
+        }
+    }
+}
+
+/// The device injected input goes through, created on first use; None
+/// outside Wayland sessions (XTest does better there) or without /dev/uinput
+pub fn injector() -> Option<&'static VirtualDevice> {
+    static INJECTOR: OnceLock<Option<VirtualDevice>> = OnceLock::new();
+    INJECTOR
+        .get_or_init(|| {
+            if !session() {
+                return None;
+            }
+            match VirtualDevice::create(&format!("{} virtual input", NAME_PREFIX)) {
+                Ok(device) => {
+                    println!("Wayland 会话: 通过 uinput 模拟输入");
+                    Some(device)
+                }
+                Err(e) => {
+                    eprintln!("⚠ 无法创建 uinput 设备，只能通过 XWayland 模拟输入: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+// The evdev codes behind rdev's Linux keys: its X keycodes minus 8
+#[rustfmt::skip]
+const KEYS: &[(Key, u16)] = &[
+    (Key::Escape, 1), (Key::Num1, 2), (Key::Num2, 3), (Key::Num3, 4), (Key::Num4, 5), (Key::Num5, 6),
+    (Key::Num6, 7), (Key::Num7, 8), (Key::Num8, 9), (Key::Num9, 10), (Key::Num0, 11), (Key::Minus, 12),
+    (Key::Equal, 13), (Key::Backspace, 14), (Key::Tab, 15), (Key::KeyQ, 16), (Key::KeyW, 17), (Key::KeyE, 18),
+    (Key::KeyR, 19), (Key::KeyT, 20), (Key::KeyY, 21), (Key::KeyU, 22), (Key::KeyI, 23), (Key::KeyO, 24),
+    (Key::KeyP, 25), (Key::LeftBracket, 26), (Key::RightBracket, 27), (Key::Return, 28), (Key::ControlLeft, 29),
+    (Key::KeyA, 30), (Key::KeyS, 31), (Key::KeyD, 32), (Key::KeyF, 33), (Key::KeyG, 34), (Key::KeyH, 35),
+    (Key::KeyJ, 36), (Key::KeyK, 37), (Key::KeyL, 38), (Key::SemiColon, 39), (Key::Quote, 40),
+    (Key::BackQuote, 41), (Key::ShiftLeft, 42), (Key::BackSlash, 43), (Key::KeyZ, 44), (Key::KeyX, 45),
+    (Key::KeyC, 46), (Key::KeyV, 47), (Key::KeyB, 48), (Key::KeyN, 49), (Key::KeyM, 50), (Key::Comma, 51),
+    (Key::Dot, 52), (Key::Slash, 53), (Key::ShiftRight, 54), (Key::KpMultiply, 55), (Key::Alt, 56),
+    (Key::Space, 57), (Key::CapsLock, 58), (Key::F1, 59), (Key::F2, 60), (Key::F3, 61), (Key::F4, 62),
+    (Key::F5, 63), (Key::F6, 64), (Key::F7, 65), (Key::F8, 66), (Key::F9, 67), (Key::F10, 68),
+    (Key::NumLock, 69), (Key::ScrollLock, 70), (Key::Kp7, 71), (Key::Kp8, 72), (Key::Kp9, 73),
+    (Key::KpMinus, 74), (Key::Kp4, 75), (Key::Kp5, 76), (Key::Kp6, 77), (Key::KpPlus, 78), (Key::Kp1, 79),
+    (Key::Kp2, 80), (Key::Kp3, 81), (Key::Kp0, 82), (Key::KpDelete, 83), (Key::IntlBackslash, 86),
+    (Key::F11, 87), (Key::F12, 88), (Key::KpReturn, 96), (Key::ControlRight, 97), (Key::KpDivide, 98),
+    (Key::PrintScreen, 99), (Key::AltGr, 100), (Key::Home, 102), (Key::UpArrow, 103), (Key::PageUp, 104),
+    (Key::LeftArrow, 105), (Key::RightArrow, 106), (Key::End, 107), (Key::DownArrow, 108),
+    (Key::PageDown, 109), (Key::Insert, 110), (Key::Delete, 111), (Key::Pause, 119), (Key::MetaLeft, 125),
+    (Key::MetaRight, 126),
+];
+
+/// evdev code of a key; Key::Unknown holds an X keycode, as rdev's do on Linux
+pub fn evdev_code(key: Key) -> Option<u16> {
+    match key {
+        Key::Unknown(code) => code.checked_sub(8).and_then(|code| u16::try_from(code).ok()).filter(|code| *code <= KEY_MAX),
+        key => KEYS.iter().find(|(k, _)| *k == key).map(|(_, code)| *code),
+    }
+}
+
+/// The key a grabbed keyboard reports, Key::Unknown with the X keycode if rdev has none
+pub fn key_from_evdev(code: u16) -> Key {
+    KEYS.iter().find(|(_, c)| *c == code).map(|(key, _)| *key).unwrap_or(Key::Unknown(code as u32 + 8))
+}
+
+/// Back and forward are X buttons 8 and 9, like under rdev's X11 backend
+fn evdev_button(button: Button) -> Option<u16> {
+    match button {
+        Button::Left => Some(BTN_LEFT),
+        Button::Right => Some(BTN_RIGHT),
+        Button::Middle => Some(BTN_MIDDLE),
+        Button::Unknown(8) => Some(BTN_SIDE),
+        Button::Unknown(9) => Some(BTN_EXTRA),
+        Button::Unknown(_) => None,
+    }
+}
+
+fn button_from_evdev(code: u16) -> Option<Button> {
+    match code {
+        BTN_LEFT => Some(Button::Left),
+        BTN_RIGHT => Some(Button::Right),
+        BTN_MIDDLE => Some(Button::Middle),
+        BTN_SIDE => Some(Button::Unknown(8)),
+        BTN_EXTRA => Some(Button::Unknown(9)),
+        _ => None,
+    }
+}
+
+/// Where the grab thinks the cursor is: Wayland doesn't tell, so this adds
+/// up the motion from where it was last put and stops at the display's edges
+struct Position {
+    x: f64,
+    y: f64,
+    size: Option<(f64, f64)>,
+}
+
+static POSITION: Mutex<Position> = Mutex::new(Position { x: 0.0, y: 0.0, size: None });
+
+/// Puts the grab's cursor at (x, y), like warping a real one
+pub fn warp(x: i32, y: i32) {
+    let mut position = POSITION.lock().unwrap_or_else(|e| e.into_inner());
+    position.x = x as f64;
+    position.y = y as f64;
+}
+
+/// Keyboards (they have an A key) and mice (relative motion and a left
+/// button). Anything with absolute axes, touchpads and tablets, stays ungrabbed:
+/// passing it on would need a virtual device with the same axes.
+fn grabbable() -> Vec<(String, File)> {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return Vec::new();
+    };
+    let mut devices = Vec::new();
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("event") {
+            continue;
+        }
+        let Ok(file) = File::open(entry.path()) else {
+            continue;
+        };
+        let fd = file.as_raw_fd();
+        let mut name = [0u8; 256];
+        let mut types = [0u8; 4];
+        let mut keys = [0u8; KEY_MAX as usize / 8 + 1];
+        let ok = unsafe {
+            ioctl(fd, eviocgname(name.len()), name.as_mut_ptr()) >= 0
+                && ioctl(fd, eviocgbit(0, types.len()), types.as_mut_ptr()) >= 0
+                && ioctl(fd, eviocgbit(EV_KEY, keys.len()), keys.as_mut_ptr()) >= 0
+        };
+        if !ok {
+            continue;
+        }
+        let name = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap_or_default()).into_owned();
+        let has = |bits: &[u8], bit: u16| bits[bit as usize / 8] & (1 << (bit % 8)) != 0;
+        let keyboard = has(&keys, KEY_A);
+        let mouse = has(&types, EV_REL) && has(&keys, BTN_LEFT);
+        if name.starts_with(NAME_PREFIX) || has(&types, EV_ABS) || !(keyboard || mouse) {
+            continue;
+        }
+        devices.push((name, file));
+    }
+    devices
+}
+
+/// Like rdev's grab: `callback` sees each key, button, wheel and move, and
+/// what it returns is passed on to the compositor. Devices `excluded` names
+/// are still grabbed but act as if they weren't, so they can be named or
+/// dropped while the grab runs. Runs until every grabbed device is gone.
+pub fn grab<T, E>(callback: T, excluded: E) -> io::Result<()>
+where
+    T: Fn(Event) -> Option<Event>,
+    E: Fn(&str) -> bool,
+{
+    let passthrough = VirtualDevice::create(&format!("{} passthrough", NAME_PREFIX))?;
+    let devices = grabbable();
+    if devices.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no keyboard or mouse readable in /dev/input"));
+    }
+    POSITION.lock().unwrap_or_else(|e| e.into_inner()).size =
+        rdev::display_size().ok().map(|(width, height)| (width as f64, height as f64));
+
+    // One reader per device; whole reports go to the loop below, so each is handled at once
+    let (tx, rx) = std::sync::mpsc::channel::<(usize, Vec<RawEvent>)>();
+    let mut names = Vec::with_capacity(devices.len());
+    for (name, mut file) in devices {
+        let exclusive: c_int = 1;
+        if unsafe { ioctl(file.as_raw_fd(), EVIOCGRAB, exclusive) } < 0 {
+            eprintln!("⚠ 无法独占输入设备 {}: {}", name, io::Error::last_os_error());
+            continue;
+        }
+        println!("已接管输入设备: {}", name);
+        let device = names.len();
+        names.push(name);
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; EVENT_LEN * 64];
+            let mut report = Vec::new();
+            // Unplugged: the read fails and the device is dropped
+            while let Ok(len) = file.read(&mut buffer) {
+                for bytes in buffer[..len].chunks_exact(EVENT_LEN) {
+                    let event = RawEvent::from_bytes(bytes);
+                    if event.kind == EV_SYN {
+                        if event.code == SYN_REPORT && tx.send((device, std::mem::take(&mut report))).is_err() {
+                            return;
+                        }
+                    } else {
+                        report.push(event);
+                    }
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let offer = |event_type: EventType| callback(Event { time: SystemTime::now(), name: None, event_type }).is_some();
+    for (device, report) in rx {
+        // Straight through; what the virtual device lacks (scan codes, absolute axes) the kernel drops
+        if excluded(&names[device]) {
+            if !report.is_empty() {
+                passthrough.emit(&report)?;
+            }
+            continue;
+        }
+        let mut passed = Vec::new();
+        let (mut dx, mut dy) = (0, 0);
+        for event in report {
+            let keep = match (event.kind, event.code) {
+                (EV_REL, REL_X) => {
+                    dx += event.value;
+                    continue;
+                }
+                (EV_REL, REL_Y) => {
+                    dy += event.value;
+                    continue;
+                }
+                (EV_REL, REL_WHEEL) => offer(EventType::Wheel { delta_x: 0, delta_y: event.value as i64 }),
+                (EV_REL, REL_HWHEEL) => offer(EventType::Wheel { delta_x: event.value as i64, delta_y: 0 }),
+                // The high-resolution wheel comes with the one above; alone it
+                // would scroll here while capture sends the notches away
+                (EV_REL, _) => false,
+                (EV_KEY, code) => {
+                    let down = event.value != 0;
+                    match button_from_evdev(code) {
+                        Some(button) if down => offer(EventType::ButtonPress(button)),
+                        Some(button) => offer(EventType::ButtonRelease(button)),
+                        // Other buttons are rare on keyboards and mice; they pass untouched
+                        None if (0x100..0x160).contains(&code) => true,
+                        None if down => offer(EventType::KeyPress(key_from_evdev(code))),
+                        None => offer(EventType::KeyRelease(key_from_evdev(code))),
+                    }
+                }
+                // Scan codes and the like, the virtual device has no use for them
+                _ => false,
+            };
+            if keep {
+                passed.push(event);
+            }
+        }
+        if dx != 0 || dy != 0 {
+            let (x, y) = {
+                let mut position = POSITION.lock().unwrap_or_else(|e| e.into_inner());
+                position.x += dx as f64;
+                position.y += dy as f64;
+                if let Some((width, height)) = position.size {
+                    position.x = position.x.clamp(0.0, width - 1.0);
+                    position.y = position.y.clamp(0.0, height - 1.0);
+                }
+                (position.x, position.y)
+            };
+            if offer(EventType::MouseMove { x, y }) {
+                passed.push(RawEvent { kind: EV_REL, code: REL_X, value: dx });
+                passed.push(RawEvent { kind: EV_REL, code: REL_Y, value: dy });
+            }
+        }
+        if !passed.is_empty() {
+            passthrough.emit(&passed)?;
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "every grabbed input device is gone"))
+}
@@ -1,58 +1,294 @@
+use crate::discovery::DiscoverySource;
+use crate::settings::{DeviceHistory, Direction};
+use crate::websocket::{DeviceInfo, Event, WebSocketServer};
+use axum::{
+    extract::State,
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+    routing::get,
+    Json, Router,
+};
+#[cfg(feature = "web-ui")]
 use axum::{
     body::Body,
-    extract::Path,
-    http::{header, StatusCode, Uri},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
 };
+use futures_util::{Stream, StreamExt};
+#[cfg(feature = "web-ui")]
 use rust_embed::RustEmbed;
-use mime_guess;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+use utoipa::{OpenApi, ToSchema};
 
+#[cfg(feature = "web-ui")]
 #[derive(RustEmbed)]
 #[folder = "../frontend/dist"]
 struct Assets;
 
-pub fn app() -> Router {
-    Router::new()
+/// Vite puts a content hash in every file name under assets/, so those never change
+#[cfg(feature = "web-ui")]
+const IMMUTABLE_CACHE: &str = "public, max-age=31536000, immutable";
+/// Everything else (index.html, favicons) is revalidated against its ETag
+#[cfg(feature = "web-ui")]
+const REVALIDATE_CACHE: &str = "no-cache";
+
+/// Variants the frontend build writes next to each file, best first
+#[cfg(feature = "web-ui")]
+const ENCODINGS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+/// The HTTP API; served at /api/openapi.json
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "ShareFlow", description = "Read-only HTTP API of the ShareFlow backend; control goes through the WebSocket API"),
+    paths(events_handler, openapi_handler),
+    components(schemas(DeviceEvent, DeviceInfo, DiscoverySource, DeviceHistory, Direction))
+)]
+pub struct ApiDoc;
+
+/// What /api/events sends. A stable subset of the frontend messages, kept
+/// apart from websocket::Event so that one can change without breaking integrations.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DeviceEvent {
+    DeviceFound { device: DeviceInfo, source: DiscoverySource, slot: Option<u8>, history: Option<DeviceHistory> },
+    /// The device stopped announcing itself
+    DeviceLost {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+    },
+    ConnectionEstablished {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+    },
+    Disconnected,
+}
+
+impl DeviceEvent {
+    pub fn from_ws(event: &Event) -> Option<Self> {
+        Some(match event {
+            Event::DeviceFound { device, source, slot, history } => DeviceEvent::DeviceFound {
+                device: device.clone(),
+                source: *source,
+                slot: *slot,
+                history: history.clone(),
+            },
+            Event::DeviceLost { device_id } => DeviceEvent::DeviceLost { device_id: device_id.clone() },
+            Event::ConnectionEstablished { device_id } => DeviceEvent::ConnectionEstablished { device_id: device_id.clone() },
+            Event::Disconnected => DeviceEvent::Disconnected,
+            _ => return None,
+        })
+    }
+
+    /// SSE event name, same as the `type` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEvent::DeviceFound { .. } => "deviceFound",
+            DeviceEvent::DeviceLost { .. } => "deviceLost",
+            DeviceEvent::ConnectionEstablished { .. } => "connectionEstablished",
+            DeviceEvent::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// Devices announced so far, followed from what frontends are told, so /api/events
+/// can start with them without asking the main loop to tell every frontend again
+#[derive(Default)]
+pub struct KnownDevices {
+    devices: RwLock<BTreeMap<String, DeviceEvent>>,
+}
+
+impl KnownDevices {
+    pub fn update(&self, event: &DeviceEvent) {
+        match event {
+            DeviceEvent::DeviceFound { device, .. } => {
+                self.devices.write().unwrap().insert(device.id.clone(), event.clone());
+            }
+            DeviceEvent::DeviceLost { device_id } => {
+                self.devices.write().unwrap().remove(device_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// A DeviceFound for each device still around
+    pub fn snapshot(&self) -> Vec<DeviceEvent> {
+        self.devices.read().unwrap().values().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+struct ApiState {
+    ws_server: Arc<WebSocketServer>,
+    devices: Arc<KnownDevices>,
+}
+
+/// /api/events passes on part of what `ws_server` tells frontends.
+/// Builds without the web-ui feature serve only the API.
+pub fn app(ws_server: Arc<WebSocketServer>) -> Router {
+    let devices = Arc::new(KnownDevices::default());
+    // Subscribed before returning, so no device announced from now on is missed
+    let mut rx = ws_server.subscribe();
+    let tracked = Arc::clone(&devices);
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    if let Some(event) = DeviceEvent::from_ws(&event) {
+                        tracked.update(&event);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let router = Router::new()
+        .route("/api/events", get(events_handler))
+        .route("/api/openapi.json", get(openapi_handler));
+    #[cfg(feature = "web-ui")]
+    let router = router
         .route("/", get(index_handler))
         .route("/index.html", get(index_handler))
-        .route("/*file", get(static_handler))
+        .route("/*file", get(static_handler));
+    router.with_state(ApiState { ws_server, devices })
+}
+
+fn sse_event(event: &DeviceEvent) -> Option<SseEvent> {
+    let data = serde_json::to_string(event).ok()?;
+    Some(SseEvent::default().event(event.name()).data(data))
+}
+
+/// Device and connection updates as server-sent events, for scripts and
+/// dashboards that don't want to speak the WebSocket protocol. The devices
+/// known so far are sent first.
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    responses((status = 200, description = "One event per update, named after its type", body = DeviceEvent, content_type = "text/event-stream"))
+)]
+async fn events_handler(State(state): State<ApiState>) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    // Subscribed before the snapshot is taken: a device found in between comes twice rather than never
+    let rx = state.ws_server.subscribe();
+    let known: Vec<Result<SseEvent, Infallible>> = state.devices.snapshot().iter().filter_map(sse_event).map(Ok).collect();
+
+    let updates = futures_util::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let Some(event) = DeviceEvent::from_ws(&event) else { continue };
+                    let Some(event) = sse_event(&event) else { continue };
+                    return Some((Ok(event), rx));
+                }
+                // A slow reader misses some updates rather than holding up the bus
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(futures_util::stream::iter(known).chain(updates)).keep_alive(KeepAlive::default())
+}
+
+/// This API as an OpenAPI 3.1 document
+#[utoipa::path(get, path = "/api/openapi.json", responses((status = 200, description = "OpenAPI document", content_type = "application/json")))]
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
 }
 
-async fn index_handler() -> impl IntoResponse {
-    match Assets::get("index.html") {
-        Some(content) => (
-            [(header::CONTENT_TYPE, "text/html")],
-            Body::from(content.data),
-        ).into_response(),
+#[cfg(feature = "web-ui")]
+async fn index_handler(headers: HeaderMap) -> Response {
+    match serve_asset("index.html", &headers) {
+        Some(response) => response,
         None => (StatusCode::NOT_FOUND, "index.html not found").into_response(),
     }
 }
 
-async fn static_handler(uri: Uri) -> impl IntoResponse {
+#[cfg(feature = "web-ui")]
+async fn static_handler(uri: Uri, headers: HeaderMap) -> Response {
     let path = uri.path().trim_start_matches('/');
-    
+
     if path.is_empty() {
-        return index_handler().await.into_response();
+        return index_handler(headers).await;
     }
 
-    match Assets::get(path) {
-        Some(content) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
-            (
-                [(header::CONTENT_TYPE, mime.as_ref())],
-                Body::from(content.data),
-            )
-                .into_response()
-        }
+    match serve_asset(path, &headers) {
+        Some(response) => response,
         None => {
             // Fallback to index.html for SPA routing if file not found
             // But only if it doesn't look like a static asset (e.g. doesn't have an extension)
             if !path.contains('.') {
-                 return index_handler().await.into_response();
+                 return index_handler(headers).await;
             }
             (StatusCode::NOT_FOUND, "404 Not Found").into_response()
         }
     }
 }
+
+#[cfg(feature = "web-ui")]
+/// The embedded file at `path`, pre-compressed if the client takes that, or
+/// 304 if the client's copy is current. None if there is no such file.
+fn serve_asset(path: &str, headers: &HeaderMap) -> Option<Response> {
+    let original = Assets::get(path)?;
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+    let accepted = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let (encoding, content) = ENCODINGS
+        .iter()
+        .filter(|(name, _)| accepts(accepted, name))
+        .find_map(|(name, suffix)| Some((Some(*name), Assets::get(&format!("{}{}", path, suffix))?)))
+        .unwrap_or((None, original));
+
+    // The hash is of the bytes sent, so each encoding has its own tag
+    let hash = content.metadata.sha256_hash();
+    let etag = format!("\"{}\"", hash[..16].iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let cache_control = if path.starts_with("assets/") { IMMUTABLE_CACHE } else { REVALIDATE_CACHE };
+
+    let mut response = if if_none_match(headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = Body::from(content.data).into_response();
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_str(mime.as_ref()).ok()?);
+        if let Some(encoding) = encoding {
+            response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        response
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&etag).ok()?);
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    Some(response)
+}
+
+#[cfg(feature = "web-ui")]
+/// Whether an Accept-Encoding value allows `encoding` (q=0 rules it out)
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+#[cfg(feature = "web-ui")]
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        }))
+}
@@ -1,31 +1,240 @@
+use crate::audit::AuditMode;
+use crate::clipboard::HandOffAction;
+use crate::capabilities::Capabilities;
+use crate::diagnostics::StageTiming;
+use crate::error::ErrorCode;
+use crate::input_capture::{HookState, KeyClass, Modifiers};
+use crate::input_simulator::map_key_code;
+use crate::discovery::DiscoverySource;
+use crate::firewall::FirewallStatus;
+use crate::echo::EchoStats;
+use crate::accessibility::AccessibilityStatus;
+use crate::lockout::LockoutKind;
+use crate::pairing::PairedDevice;
+use crate::screens::Monitor;
+use crate::self_check::SelfCheckReport;
+use crate::session::SessionState;
+use crate::settings::{DeviceGroup, DeviceHistory, DeviceOverrides, RequestTimeouts};
+use crate::stats::StatsExport;
+use crate::transport::TransportOptions;
+use crate::config::AppConfig;
+use crate::protocol::{LockState, MediaAction, Message as PeerMessage, PeerFeature, Permission, RejectReason, ScreenEdge, TargetUnavailable, WHEEL_DELTA};
 use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
+use rdev::Key;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::{accept_hdr_async, tungstenite::Message};
+use utoipa::ToSchema;
 
+/// What a frontend asks the backend to do. Goes only to the main loop,
+/// never back out to other frontends.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
-pub enum WsMessage {
-    // From Frontend
+pub enum Command {
     StartDiscovery,
     StartCapture,
     StopCapture,
-    RequestConnection { target_device_id: String },
-    CancelConnection,
-    AcceptConnection { target_device_id: String },
+    /// Without permissions only input is requested
+    RequestConnection {
+        target_device_id: String,
+        #[serde(default)]
+        permissions: Option<Vec<Permission>>,
+    },
+    /// Without a target this cancels every pending request
+    CancelConnection { target_device_id: Option<String> },
+    /// With a time limit the session ends by itself after that many seconds.
+    /// Permissions can only narrow what was requested; without them all of it is granted.
+    AcceptConnection {
+        target_device_id: String,
+        #[serde(default)]
+        time_limit_secs: Option<u64>,
+        #[serde(default)]
+        permissions: Option<Vec<Permission>>,
+    },
     RejectConnection { target_device_id: String },
+    /// Let a muted device's connection requests prompt again before the cooldown ends
+    UnmuteDevice { target_device_id: String },
+    /// The user saw `code` on both screens: trust the key `device_id` proved in
+    /// that handshake, so its later requests skip the prompt
+    ConfirmPairing { device_id: String, code: String },
+    /// Forget a paired device's key; its next request prompts and shows a code again
+    Unpair { device_id: String },
+    /// Answered with PairedDevices
+    GetPairedDevices,
     Disconnect,
-    SendInput { event: InputEvent },
+    /// Without a target the input goes to every connected peer
+    SendInput { event: InputEvent, target_device_id: Option<String> },
     GetLocalInfo,
-    
-    // To Frontend
+    GetCapabilities,
+    GetConnectionStatus,
+    /// Zero the per-connection counters and history
+    ResetStats,
+    /// Answered with StatsExport, for attaching to bug reports
+    ExportStats,
+    /// Check ports, broadcast, permissions, firewall and injection; answered with DiagnosticsReport
+    RunDiagnostics,
+    /// Disconnect peers and exit, e.g. for a newer instance started with --takeover
+    Shutdown,
+    /// Add a device by address, for networks where broadcasts don't get through
+    AddManualPeer {
+        ip: String,
+        port: u16,
+        name: Option<String>,
+    },
+    /// Volume or playback on peers that granted the media permission (all without a target)
+    SendMedia {
+        action: MediaAction,
+        #[serde(default)]
+        target_device_id: Option<String>,
+    },
+    /// Offer a link or drop text into the clipboard on peers we control that granted
+    /// hand-off (all of them without a target)
+    SendToDevice {
+        text: String,
+        #[serde(default)]
+        target_device_id: Option<String>,
+    },
+    /// Stop streaming large clipboard text, ours (origin is this device) or a peer's
+    CancelClipboardTransfer { origin: String, seq: u64 },
+    /// Send a file to a connected peer whose session was granted file transfer.
+    /// Sending one that was interrupted again resumes it.
+    SendFile { device_id: String, path: PathBuf },
+    /// Stop a file transfer in either direction; what was received is deleted
+    CancelFileTransfer { id: u64 },
+    /// Ask a connected peer that granted the screenshot permission for a
+    /// picture of its desktop; answered with PeerScreenshot
+    RequestScreenshot { device_id: String },
+    /// Have a peer that granted the screenshot permission stream JPEG thumbnails
+    /// of its desktop, 1 (the default) or 2 per second; sent as PeerThumbnail
+    StartThumbnails { device_id: String, fps: Option<u8> },
+    StopThumbnails { device_id: String },
+    /// Briefly ring the cursor of a peer we control, for whoever sits in front of it
+    HighlightPointer { device_id: String },
+    /// Have a peer we control echo every input message it injected; InputEchoStats
+    /// counts what made it, every second until disabled
+    SetInputEcho { device_id: String, enabled: bool },
+    /// Answered with FirewallStatus
+    GetFirewallStatus,
+    /// Allow the peer port through Windows Defender Firewall (shows a UAC prompt)
+    AddFirewallRules,
+    /// Answered with AccessibilityStatus, and CapabilityStatus once it was granted
+    GetAccessibilityStatus,
+    /// Show macOS' Accessibility permission dialog; answered like GetAccessibilityStatus
+    RequestAccessibility,
+    SetSessionMode { mode: SessionMode },
+    /// Controlled side: what the cursor injected for `device_id` does at our screen edges
+    SetEdgeBehavior { device_id: String, behavior: EdgeBehavior },
+    /// Put a device in a quick-switch slot (1-9), or empty the slot with None
+    SetDeviceSlot { slot: u8, device_id: Option<String> },
+    /// Create or replace a device group, or delete it with None
+    SetDeviceGroup { name: String, group: Option<DeviceGroup> },
+    GetDeviceGroups,
+    /// Settings that apply to one device whenever it connects; None clears them.
+    /// Answered with DeviceOverrides
+    SetDeviceOverrides { device_id: String, overrides: Option<DeviceOverrides> },
+    GetDeviceOverrides,
+    /// Send captured input to one connected device only; None sends it to all
+    SetActiveTarget { device_id: Option<String> },
+    /// Controller side: stop capture when our cursor on the peer reaches this
+    /// edge of its screen (the side facing this machine); None disables it
+    SetReturnEdge { edge: Option<ScreenEdge> },
+    /// Screen-edge switching: our cursor reaching `edge` starts capture toward
+    /// `device_id` (a connected peer), pushing it back over the peer's opposite edge
+    /// ends it; None removes the edge. Answered with ScreenLayout
+    SetScreenLayout { edge: ScreenEdge, device_id: Option<String> },
+    GetScreenLayout,
+    /// Relay layout: input from a controller that leaves our screen over `edge`
+    /// goes on to `device_id` (a peer we have a session with); None removes it
+    SetRelayEdge { edge: ScreenEdge, device_id: Option<String> },
+    /// 0 sends every mouse move immediately
+    SetMouseCoalescing { interval_ms: u64 },
+    /// Nagle and flushing for sessions started from now on; answered with TransportOptions
+    SetTransportOptions { options: TransportOptions },
+    GetTransportOptions,
+    /// How long requests wait for an answer, both ways; answered with RequestTimeouts
+    SetRequestTimeouts { timeouts: RequestTimeouts },
+    GetRequestTimeouts,
+    /// Replace the config file; answered with Config, also when it was refused
+    SetConfig { config: AppConfig },
+    GetConfig,
+    /// Block this machine's own keyboard/mouse while it is being controlled
+    SetLocalInputPause { enabled: bool },
+    /// Experimental, off by default: while being controlled, run the cursor ahead
+    /// by the link's latency so it feels less remote. For sessions started from now on.
+    SetCursorPrediction { enabled: bool },
+    /// Choose which input classes are sent as LocalInput/RemoteInput
+    SetVisualization { mouse: bool, keyboard: bool },
+    /// Stop capture by itself when the last connection drops mid-capture (on by default)
+    SetAutoStopCapture { enabled: bool },
+    /// Keys and input devices that capture never grabs, so assistive tech keeps working on this machine
+    SetGrabExclusions {
+        #[serde(default)]
+        key_classes: Vec<KeyClass>,
+        #[serde(default)]
+        keys: Vec<u32>,
+        #[serde(default)]
+        processes: Vec<String>,
+        #[serde(default)]
+        devices: Vec<String>,
+    },
+    /// What to record about input peers inject here; key contents only in Full
+    SetAuditLog { mode: AuditMode },
+    /// Start (from zero) or stop collecting per-stage pipeline timings
+    SetDiagnostics { enabled: bool },
+    GetDiagnostics,
+}
+
+/// What the backend tells every connected frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
     LocalInfo { device: DeviceInfo },
     LocalInput { event: InputEvent },
-    DeviceFound { device: DeviceInfo },
-    ConnectionRequest { device: DeviceInfo },
+    /// `history` is the last session with the device, if there was one
+    DeviceFound { device: DeviceInfo, source: DiscoverySource, slot: Option<u8>, history: Option<DeviceHistory> },
+    /// A discovered device stopped announcing itself and was dropped from the list
+    DeviceLost {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+    },
+    /// Permissions are what the peer asks for, for the accept dialog. `pairingCode` is set
+    /// for an unpaired device: the dialog shows it to compare with the other screen.
+    ConnectionRequest {
+        device: DeviceInfo,
+        permissions: Vec<Permission>,
+        #[serde(rename = "pairingCode")]
+        pairing_code: Option<String>,
+    },
+    /// Requester side: the device we're connecting to isn't paired; show `code` to
+    /// compare with the one in its accept dialog
+    PairingCode {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        code: String,
+    },
+    /// The peer's user confirmed the code: it trusts this device now
+    PeerConfirmedPairing {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+    },
+    /// Device ID -> paired device, after every change
+    PairedDevices { devices: BTreeMap<String, PairedDevice> },
+    /// A ConfirmPairing or Unpair didn't go through, or a device proved another key than
+    /// the one it was paired with (identityChanged)
+    PairingFailed {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        code: ErrorCode,
+        reason: String,
+    },
     ConnectionRequestCancelled { 
         #[serde(rename = "deviceId")]
         device_id: String 
@@ -37,18 +246,317 @@ pub enum WsMessage {
     ConnectionFailed { 
         #[serde(rename = "deviceId")]
         device_id: String, 
-        reason: String 
+        reason: String,
+        /// Structured rejection from the peer, if it sent one
+        #[serde(rename = "rejectReason")]
+        reject_reason: Option<RejectReason>,
+        /// What went wrong, for the frontend to put into words; `reason` has the details
+        code: ErrorCode,
+    },
+    TransportOptions { options: TransportOptions },
+    RequestTimeouts { timeouts: RequestTimeouts },
+    /// The config file at `path`; only its auto-accept list is in effect
+    /// before a restart, `restartRequired` says whether anything else changed
+    Config {
+        config: AppConfig,
+        path: String,
+        #[serde(rename = "restartRequired")]
+        restart_required: bool,
+    },
+    /// Our request to this peer is showing there; the user has `timeoutSecs` to answer
+    ConnectionPending {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        #[serde(rename = "timeoutSecs")]
+        timeout_secs: u32,
     },
+    /// A part of the backend failed outside of any request, e.g. discovery couldn't start
+    BackendError { code: ErrorCode, message: String },
     Disconnected,
     RemoteInput { event: InputEvent },
+    SessionModeChanged { mode: SessionMode },
+    LocalInputPaused { paused: bool },
+    CaptureStarted,
+    CaptureStopped { reason: CaptureStopReason },
+    /// Controlled side: `device` started controlling this machine at `since` (Unix ms)
+    BeingControlled { device: DeviceInfo, since: u64 },
+    ControlEnded {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+    },
+    /// Sent on both sides when a time-limited session starts, shortly before
+    /// it expires and when it does (0)
+    SessionTimeLimit {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        #[serde(rename = "remainingSecs")]
+        remaining_secs: u64,
+    },
+    /// Capture vs connections changed; the two mismatched states are warnings
+    CaptureStateChanged { state: CaptureState },
+    /// Snapshot for frontends that (re)connect mid-flow
+    ConnectionStatus {
+        #[serde(rename = "outgoingRequests")]
+        outgoing_requests: Vec<String>,
+        #[serde(rename = "activeConnections")]
+        active_connections: usize,
+        capturing: bool,
+        /// Whether the capture hook is installed, and attached
+        #[serde(rename = "captureHook")]
+        capture_hook: HookState,
+    },
+    RemoteCursor {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        x: i32,
+        y: i32,
+        #[serde(rename = "screenWidth")]
+        screen_width: u32,
+        #[serde(rename = "screenHeight")]
+        screen_height: u32,
+    },
+    Diagnostics { stages: Vec<StageTiming> },
+    AuditLogStatus { mode: AuditMode, path: String },
+    CapabilityStatus { capabilities: Capabilities },
+    StatsExport { stats: StatsExport },
+    DiagnosticsReport { report: SelfCheckReport },
+    FirewallStatus { status: FirewallStatus },
+    FirewallRulesResult { success: bool, reason: Option<String> },
+    /// macOS only, at startup while the permission is missing and on request
+    AccessibilityStatus { status: AccessibilityStatus },
+    /// Slot -> device ID, after every change and with the device list
+    DeviceSlots { slots: BTreeMap<u8, String> },
+    /// Group name -> group, after every change and with the device list
+    DeviceGroups { groups: BTreeMap<String, DeviceGroup> },
+    /// Device ID -> per-device overrides
+    DeviceOverrides { overrides: BTreeMap<String, DeviceOverrides> },
+    ActiveTargetChanged {
+        #[serde(rename = "deviceId")]
+        device_id: Option<String>,
+    },
+    /// Controller side: our cursor on the peer pushed against one of its edges
+    RemoteEdgeHit {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        edge: ScreenEdge,
+    },
+    /// Edge -> onward device, after every change
+    RelayEdges { edges: HashMap<ScreenEdge, String> },
+    /// Edge of our screen -> device control switches to there
+    ScreenLayout { layout: BTreeMap<ScreenEdge, String> },
+    /// Input from `controllerId` is passed on to `targetId` instead of injected here (None: back to us)
+    RelayChanged {
+        #[serde(rename = "controllerId")]
+        controller_id: String,
+        #[serde(rename = "targetId")]
+        target_id: Option<String>,
+    },
+    /// Progress of clipboard text streamed to or from `deviceId`; `origin` is where it was copied
+    ClipboardTransfer {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        origin: String,
+        seq: u64,
+        done: u64,
+        total: u64,
+    },
+    ClipboardTransferEnded {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        origin: String,
+        seq: u64,
+        cancelled: bool,
+    },
+    /// Progress of a file sent to or received from `deviceId`
+    FileTransfer {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        id: u64,
+        name: String,
+        incoming: bool,
+        done: u64,
+        total: u64,
+    },
+    /// A file transfer is over: `path` is the file sent or where the received one
+    /// was saved, unless it failed or was cancelled, which `error` says
+    FileTransferEnded {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        id: u64,
+        incoming: bool,
+        path: Option<PathBuf>,
+        error: Option<String>,
+    },
+    /// A peer handed us a link, for the user to open, or text, now in the clipboard;
+    /// error is set when the text could not be copied
+    HandOffReceived {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        text: String,
+        action: HandOffAction,
+        error: Option<String>,
+    },
+    /// What the controlled side granted for this session, seen on both sides
+    PermissionsGranted {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        permissions: Vec<Permission>,
+    },
+    /// What a connected peer supports, so the UI can hide what would do nothing
+    PeerFeatures {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        features: Vec<PeerFeature>,
+    },
+    /// A peer's desktop scaled down, as a data: URL; None if it couldn't
+    /// send one (not connected, not permitted, or no way to read its screen)
+    PeerScreenshot {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        width: u32,
+        height: u32,
+        image: Option<String>,
+    },
+    /// The latest thumbnail of a peer's desktop, as a data: URL; None when the
+    /// stream ended or couldn't start
+    PeerThumbnail {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        image: Option<String>,
+    },
+    /// Delivery of input to a peer verified with echoes; the last one after disabling
+    InputEchoStats {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        stats: EchoStats,
+    },
+    /// Caps/Num/Scroll Lock on a peer we control, whenever they change
+    PeerLockState {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        state: LockState,
+    },
+    /// How a connected peer's monitors are arranged, for drawing the screen layout
+    PeerScreens {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        monitors: Vec<Monitor>,
+    },
+    /// Controller side: input sent to this peer can't reach its screen (unavailable
+    /// is set) or can again (null), e.g. its Windows session went to RDP and back
+    TargetStatus {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        unavailable: Option<TargetUnavailable>,
+    },
+    /// The user declined this device often enough that its requests are
+    /// declined without a prompt for `mutedSecs`; sent once when that starts
+    ConnectionRequestsMuted {
+        device: DeviceInfo,
+        #[serde(rename = "mutedSecs")]
+        muted_secs: u64,
+    },
+    /// Repeated failed attempts from `ip` locked it out; its requests are refused for `retryAfterSecs`
+    SecurityEvent {
+        kind: LockoutKind,
+        ip: String,
+        #[serde(rename = "deviceId")]
+        device_id: Option<String>,
+        failures: u32,
+        #[serde(rename = "retryAfterSecs")]
+        retry_after_secs: u64,
+    },
+    /// A session with this peer moved to `state`; sent for every step, idle included
+    SessionStateChanged {
+        #[serde(rename = "deviceId")]
+        device_id: String,
+        state: SessionState,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Why input capture ended, so the UI can tell a hotkey exit from its own request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CaptureStopReason {
+    UserRequest,
+    Hotkey,
+    Error,
+    /// Auto-stop: the last connection went away
+    ConnectionLost,
+    /// Capture can't work here, see CapabilityStatus
+    Unsupported,
+    /// The cursor on the peer crossed back over the return edge, or over the
+    /// edge it came in by with screen-edge switching
+    RemoteEdge,
+}
+
+/// Capture and connection state taken together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CaptureState {
+    Idle,
+    /// Capturing and forwarding to at least one peer
+    Active,
+    /// Capturing with nobody connected: local input is swallowed and dropped
+    CapturingWithoutPeer,
+    /// Connected but not capturing: expected on the controlled side,
+    /// on the controller it means input stays local
+    ConnectedWithoutCapture,
+}
+
+/// Controls which captured input is forwarded to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SessionMode {
+    /// Forward everything
+    Normal,
+    /// Slide deck remote: only arrows, Page Up/Down, B and Esc
+    Presentation,
+}
+
+/// What happens when injected movement hits the controlled screen's edge
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EdgeBehavior {
+    /// The OS clamps the cursor
+    #[default]
+    Stop,
+    /// Continue from the opposite edge
+    Wrap,
+    /// Tell the controller (EdgeHit), e.g. to hand control back
+    Notify,
+}
+
+/// What a slide deck remote has: next/previous, blank screen (B) and leaving the show
+pub const PRESENTATION_KEYS: [Key; 8] = [
+    Key::LeftArrow, Key::RightArrow, Key::UpArrow, Key::DownArrow,
+    Key::PageUp, Key::PageDown,
+    Key::KeyB,
+    Key::Escape,
+];
+
+impl SessionMode {
+    pub fn allows(&self, msg: &PeerMessage) -> bool {
+        match self {
+            SessionMode::Normal => true,
+            // By the key the code stands for, so 'b' typed in the frontend counts as B
+            SessionMode::Presentation => match msg {
+                PeerMessage::KeyPress { key, .. } => map_key_code(*key).is_some_and(|key| PRESENTATION_KEYS.contains(&key)),
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct DeviceInfo {
     pub id: String,
     pub name: String,
     pub ip: String,
+    /// Peer connection port
+    #[serde(default)]
+    pub port: u16,
     #[serde(rename = "type")]
     pub device_type: String,
 }
@@ -63,18 +571,132 @@ pub struct InputEvent {
     pub dx: Option<f64>,
     pub dy: Option<f64>,
     pub key: Option<String>,
+    /// Numeric key code, preferred over `key` when present
+    #[serde(rename = "keyCode")]
+    pub key_code: Option<u32>,
+    /// Mouse button, preferred over a "buttonN" `key` when present
+    pub button: Option<u8>,
+    pub modifiers: Option<Modifiers>,
     pub timestamp: u64,
+    /// Set by browser controllers on "wheel": dx/dy are then a WheelEvent's
+    /// deltaX/deltaY in this DOM_DELTA_* unit instead of notches
+    pub delta_mode: Option<u32>,
+}
+
+// Browsers report about 100 px or 3 lines per notch; a page only comes from
+// wheels set to scroll a screen at a time, so it is one notch
+const PIXELS_PER_NOTCH: f64 = 100.0;
+const LINES_PER_NOTCH: f64 = 3.0;
+
+impl InputEvent {
+    /// A "wheel" event's scroll in WHEEL_DELTA units, positive y scrolling up.
+    /// A missing axis doesn't scroll; fractions are kept for trackpads.
+    pub fn wheel_delta(&self) -> (i32, i32) {
+        let (dx, dy) = (self.dx.unwrap_or(0.0), self.dy.unwrap_or(0.0));
+        let (notches_x, notches_y) = match self.delta_mode {
+            None => (dx, dy),
+            // WheelEvent's deltaY grows scrolling down
+            Some(mode) => {
+                let per_notch = match mode {
+                    0 => PIXELS_PER_NOTCH,
+                    1 => LINES_PER_NOTCH,
+                    _ => 1.0,
+                };
+                (dx / per_notch, -dy / per_notch)
+            }
+        };
+        let units = |notches: f64| (notches * WHEEL_DELTA as f64).round() as i32;
+        (units(notches_x), units(notches_y))
+    }
+}
+
+/// Which input classes the frontend renders, so we only build events it will use
+pub struct VisualizationFilter {
+    mouse: AtomicBool,
+    keyboard: AtomicBool,
+}
+
+impl Default for VisualizationFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VisualizationFilter {
+    pub fn new() -> Self {
+        Self {
+            mouse: AtomicBool::new(true),
+            keyboard: AtomicBool::new(true),
+        }
+    }
+
+    pub fn set(&self, mouse: bool, keyboard: bool) {
+        self.mouse.store(mouse, Ordering::Relaxed);
+        self.keyboard.store(keyboard, Ordering::Relaxed);
+    }
+
+    pub fn mouse(&self) -> bool {
+        self.mouse.load(Ordering::Relaxed)
+    }
+
+    pub fn keyboard(&self) -> bool {
+        self.keyboard.load(Ordering::Relaxed)
+    }
+
+    /// Whether an InputEvent of this type ("keydown", "mousedown", ...) should be sent
+    pub fn wants(&self, event_type: &str) -> bool {
+        if event_type.starts_with("key") || event_type == "longpress" {
+            self.keyboard()
+        } else {
+            self.mouse()
+        }
+    }
 }
 
 pub struct WebSocketServer {
     port: u16,
-    broadcast_tx: broadcast::Sender<WsMessage>,
+    /// Pages allowed to open the API from a browser: the bundled UI only
+    allowed_origins: Vec<String>,
+    events: broadcast::Sender<Event>,
+    commands: mpsc::UnboundedSender<Command>,
+    /// Connected frontends; nobody can answer a connection request without one
+    clients: watch::Sender<usize>,
 }
 
 impl WebSocketServer {
-    pub fn new(port: u16) -> (Self, broadcast::Receiver<WsMessage>) {
-        let (broadcast_tx, broadcast_rx) = broadcast::channel(100);
-        (Self { port, broadcast_tx }, broadcast_rx)
+    /// The receiver gets every command from every frontend, for the main loop.
+    /// `web_port` is where the bundled UI is served, the only web page let in.
+    pub fn new(port: u16, web_port: Option<u16>) -> (Self, mpsc::UnboundedReceiver<Command>) {
+        let (events, _) = broadcast::channel(100);
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (clients, _) = watch::channel(0);
+        // Electron loads the packaged UI from file://, the Tauri shell from tauri://
+        let mut allowed_origins: Vec<String> =
+            ["file://", "tauri://localhost", "http://tauri.localhost", "https://tauri.localhost"].iter().map(|o| o.to_string()).collect();
+        if let Some(web_port) = web_port {
+            allowed_origins.push(format!("http://127.0.0.1:{}", web_port));
+            allowed_origins.push(format!("http://localhost:{}", web_port));
+        }
+        (Self { port, allowed_origins, events, commands, clients }, command_rx)
+    }
+
+    /// Browsers always send Origin, native clients don't; any other page could
+    /// otherwise drive this machine (or SendFile from it) just by being open
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            None => true,
+            Some(origin) => self.allowed_origins.iter().any(|allowed| allowed == origin),
+        }
+    }
+
+    /// Whether any frontend is connected to show prompts
+    pub fn has_clients(&self) -> bool {
+        *self.clients.borrow() > 0
+    }
+
+    /// Follows the number of connected frontends
+    pub fn watch_clients(&self) -> watch::Receiver<usize> {
+        self.clients.subscribe()
     }
 
     pub async fn start(self: Arc<Self>) -> Result<()> {
@@ -95,17 +717,87 @@ impl WebSocketServer {
         Ok(())
     }
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let ws_stream = accept_async(stream).await?;
+    /// Serve the same API over a Unix domain socket, for machines where policy
+    /// blocks local TCP listeners. Clients speak WebSocket over the socket.
+    #[cfg(unix)]
+    pub async fn start_ipc(self: Arc<Self>, path: PathBuf) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        
+        // A socket file left by a crashed instance would make bind fail
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        // Whoever can connect can control this machine
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        println!("WebSocket API also on {}", path.display());
+
+        while let Ok((stream, _)) = listener.accept().await {
+            println!("New IPC connection");
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_connection(stream).await {
+                    eprintln!("IPC connection error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Serve the same API over a named pipe (`\\.\pipe\<name>`), for machines where
+    /// policy blocks local TCP listeners. Clients speak WebSocket over the pipe.
+    #[cfg(windows)]
+    pub async fn start_ipc(self: Arc<Self>, path: PathBuf) -> Result<()> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+        
+        // Fails if another process already serves this pipe
+        let mut server = ServerOptions::new().first_pipe_instance(true).reject_remote_clients(true).create(&path)?;
+        println!("WebSocket API also on {}", path.display());
+
+        loop {
+            server.connect().await?;
+            println!("New IPC connection");
+            // Next instance before handing this one off, so clients never find no pipe
+            let connected = std::mem::replace(&mut server, ServerOptions::new().reject_remote_clients(true).create(&path)?);
+            let this = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = this.handle_connection(connected).await {
+                    eprintln!("IPC connection error: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection<S>(&self, stream: S) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let check_origin = |request: &Request, response: Response| -> std::result::Result<Response, ErrorResponse> {
+            let origin = request.headers().get("origin").map(|o| o.to_str().unwrap_or_default());
+            if self.origin_allowed(origin) {
+                return Ok(response);
+            }
+            eprintln!("拒绝来自外部网页的 WebSocket 连接: {:?}", origin);
+            let mut refused = ErrorResponse::new(Some("Origin not allowed".to_string()));
+            *refused.status_mut() = StatusCode::FORBIDDEN;
+            Err(refused)
+        };
+        let ws_stream = accept_hdr_async(stream, check_origin).await?;
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+        self.clients.send_modify(|count| *count += 1);
 
-        let mut broadcast_rx = self.broadcast_tx.subscribe();
-        let broadcast_tx = self.broadcast_tx.clone();
+        let mut events = self.events.subscribe();
+        let commands = self.commands.clone();
 
-        // Spawn task to forward broadcast messages to this client
+        // Spawn task to forward events to this client
         let sender_task = tokio::spawn(async move {
-            while let Ok(msg) = broadcast_rx.recv().await {
-                if let Ok(json) = serde_json::to_string(&msg) {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    // A client that fell behind misses some events rather than being dropped
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if let Ok(json) = serde_json::to_string(&event) {
                     if ws_sender.send(Message::Text(json)).await.is_err() {
                         break;
                     }
@@ -113,15 +805,15 @@ impl WebSocketServer {
             }
         });
 
-        // Handle incoming messages from client
+        // Commands from this client go to the main loop only
         while let Some(msg) = ws_receiver.next().await {
             match msg {
-                Ok(Message::Text(text)) => {
-                    if let Ok(ws_msg) = serde_json::from_str::<WsMessage>(&text) {
-                        // Echo back to main loop via broadcast
-                        let _ = broadcast_tx.send(ws_msg);
+                Ok(Message::Text(text)) => match serde_json::from_str::<Command>(&text) {
+                    Ok(command) => {
+                        let _ = commands.send(command);
                     }
-                }
+                    Err(e) => eprintln!("无法解析的前端命令: {}", e),
+                },
                 Ok(Message::Close(_)) => break,
                 Err(_) => break,
                 _ => {}
@@ -129,14 +821,22 @@ impl WebSocketServer {
         }
 
         sender_task.abort();
+        self.clients.send_modify(|count| *count -= 1);
         Ok(())
     }
 
-    pub fn broadcast(&self, msg: WsMessage) {
-        let _ = self.broadcast_tx.send(msg);
+    /// Tell every connected frontend
+    pub fn broadcast(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    /// Follow what frontends are told, e.g. to pass parts of it on elsewhere
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
     }
 
-    pub fn get_sender(&self) -> broadcast::Sender<WsMessage> {
-        self.broadcast_tx.clone()
+    /// Queue a command for the main loop as if a frontend had sent it
+    pub fn send_command(&self, command: Command) {
+        let _ = self.commands.send(command);
     }
 }
@@ -0,0 +1,48 @@
+//! Buttons the capture hook sees must go to the peer as the same button:
+//! rdev::Button -> InputEventData::from_button -> click() -> Message::MouseClick.
+
+use rdev::Button;
+use rust_service::input_capture::{InputEventData, Modifiers};
+use rust_service::protocol::{Message, BUTTON_BACK, BUTTON_FORWARD};
+use std::time::Instant;
+
+fn captured(button: Button, down: bool) -> Option<Message> {
+    InputEventData::from_button(button, down, Modifiers::default(), Instant::now())?.click()
+}
+
+fn sent_as(button: Button) -> Option<(u8, bool)> {
+    match captured(button, true)? {
+        Message::MouseClick { button, state, .. } => Some((button, state)),
+        other => panic!("expected a MouseClick, got {:?}", other),
+    }
+}
+
+#[test]
+fn main_buttons_keep_their_numbers() {
+    assert_eq!(sent_as(Button::Left), Some((0, true)));
+    assert_eq!(sent_as(Button::Right), Some((1, true)));
+    assert_eq!(sent_as(Button::Middle), Some((2, true)));
+}
+
+#[test]
+fn side_buttons_are_back_and_forward_not_left() {
+    // XBUTTON1/2 on Windows, buttons 8/9 on X11
+    assert_eq!(sent_as(Button::Unknown(1)), Some((BUTTON_BACK, true)));
+    assert_eq!(sent_as(Button::Unknown(8)), Some((BUTTON_BACK, true)));
+    assert_eq!(sent_as(Button::Unknown(2)), Some((BUTTON_FORWARD, true)));
+    assert_eq!(sent_as(Button::Unknown(9)), Some((BUTTON_FORWARD, true)));
+}
+
+#[test]
+fn releases_are_sent_as_releases() {
+    match captured(Button::Unknown(8), false) {
+        Some(Message::MouseClick { button, state, .. }) => assert_eq!((button, state), (BUTTON_BACK, false)),
+        other => panic!("expected a MouseClick, got {:?}", other),
+    }
+}
+
+#[test]
+fn buttons_without_a_number_are_dropped() {
+    assert!(sent_as(Button::Unknown(12)).is_none());
+    assert!(captured(Button::Unknown(12), false).is_none());
+}
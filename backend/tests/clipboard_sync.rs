@@ -0,0 +1,61 @@
+//! Clipboard sync between two peers must settle: nothing is echoed back,
+//! and when both copy at once the later copy ends up on both sides.
+
+use rust_service::clipboard::{ClipboardSync, DEBOUNCE};
+use rust_service::protocol::ClipboardUpdate;
+use std::time::Instant;
+
+fn text(s: &str) -> Option<String> {
+    Some(s.to_string())
+}
+
+/// Copy `s` locally and wait out the debounce
+fn copy(sync: &mut ClipboardSync, s: &str) -> Option<ClipboardUpdate> {
+    let start = Instant::now();
+    assert_eq!(sync.poll(text(s), start), None);
+    sync.poll(text(s), start + DEBOUNCE)
+}
+
+#[test]
+fn applied_update_is_not_sent_back() {
+    let (mut a, mut b) = (ClipboardSync::new("a".into()), ClipboardSync::new("b".into()));
+    let now = Instant::now();
+    a.poll(text("old"), now);
+    b.poll(text("old"), now);
+
+    let update = copy(&mut a, "hello").expect("local copy is sent");
+    assert_eq!(b.receive(update.clone()), text("hello"));
+    // B's clipboard now holds the text; seeing it is not a new copy
+    assert_eq!(b.poll(text("hello"), now + DEBOUNCE * 2), None);
+    assert_eq!(b.poll(text("hello"), now + DEBOUNCE * 4), None);
+    // Neither a duplicate nor our own update is applied
+    assert_eq!(b.receive(update.clone()), None);
+    assert_eq!(a.receive(update), None);
+}
+
+#[test]
+fn later_copy_wins_a_conflict() {
+    let (mut a, mut b) = (ClipboardSync::new("a".into()), ClipboardSync::new("b".into()));
+    let now = Instant::now();
+    a.poll(text("old"), now);
+    b.poll(text("old"), now);
+
+    // B copies second (or in the same millisecond, where the device ID decides)
+    let from_a = copy(&mut a, "from a").unwrap();
+    let from_b = copy(&mut b, "from b").unwrap();
+
+    // Both cross on the wire: A takes B's newer copy, B keeps its own
+    assert_eq!(a.receive(from_b), text("from b"));
+    assert_eq!(b.receive(from_a), None);
+}
+
+#[test]
+fn content_from_before_the_session_is_not_sent() {
+    let mut sync = ClipboardSync::new("a".into());
+    let now = Instant::now();
+    assert_eq!(sync.poll(text("already there"), now), None);
+    assert_eq!(sync.poll(text("already there"), now + DEBOUNCE * 2), None);
+    // A change that doesn't stay long enough isn't either
+    assert_eq!(sync.poll(text("brief"), now + DEBOUNCE * 3), None);
+    assert!(copy(&mut sync, "kept").is_some());
+}
@@ -0,0 +1,51 @@
+//! Cursor prediction may run the cursor ahead while it moves, but never
+//! further than the cap, and once it settles the cursor is where the real
+//! moves alone would have put it.
+
+use proptest::prelude::*;
+use rust_service::session::{CursorPredictor, MAX_PREDICTION_PX};
+use std::time::{Duration, Instant};
+
+proptest! {
+    #[test]
+    fn settling_lands_on_the_real_position(
+        moves in prop::collection::vec((-40i32..40, -40i32..40, 1u64..80), 1..200),
+        latency_ms in 0u64..200,
+    ) {
+        let mut predictor = CursorPredictor::new();
+        predictor.set_latency(Duration::from_millis(latency_ms));
+        let mut at = Instant::now();
+        let (mut real_x, mut real_y) = (0, 0);
+        let (mut injected_x, mut injected_y) = (0, 0);
+        for (dx, dy, gap_ms) in moves {
+            at += Duration::from_millis(gap_ms);
+            let (x, y) = predictor.on_move(dx, dy, at);
+            real_x += dx;
+            real_y += dy;
+            injected_x += x;
+            injected_y += y;
+            prop_assert!(((injected_x - real_x) as f64).abs() <= MAX_PREDICTION_PX);
+            prop_assert!(((injected_y - real_y) as f64).abs() <= MAX_PREDICTION_PX);
+        }
+        if let Some((x, y)) = predictor.settle() {
+            injected_x += x;
+            injected_y += y;
+        }
+        prop_assert_eq!((injected_x, injected_y), (real_x, real_y));
+    }
+}
+
+#[test]
+fn steady_motion_runs_ahead_by_the_latency() {
+    let mut predictor = CursorPredictor::new();
+    predictor.set_latency(Duration::from_millis(30));
+    let mut at = Instant::now();
+    let mut injected = 0;
+    // 10 px every 10 ms: 1000 px/s, so 30 ms ahead is 30 px
+    for _ in 0..20 {
+        at += Duration::from_millis(10);
+        injected += predictor.on_move(10, 0, at).0;
+    }
+    assert_eq!(injected - 200, 30);
+    assert_eq!(predictor.settle(), Some((-30, 0)));
+}
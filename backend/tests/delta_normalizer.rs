@@ -0,0 +1,34 @@
+//! Mouse deltas from a scaled monitor lose nothing to rounding: whatever is
+//! held back as a fraction comes out with a later move.
+
+use proptest::prelude::*;
+use rust_service::input_capture::DeltaNormalizer;
+
+proptest! {
+    #[test]
+    fn total_distance_is_kept(
+        moves in prop::collection::vec((-40i32..40, -40i32..40), 1..200),
+        scale in prop::sample::select(vec![1.0, 1.25, 1.5, 1.75, 2.0, 3.0]),
+    ) {
+        let mut normalizer = DeltaNormalizer::default();
+        let (mut sent_x, mut sent_y) = (0.0, 0.0);
+        let (mut raw_x, mut raw_y) = (0.0, 0.0);
+        for (dx, dy) in moves {
+            let (x, y) = normalizer.normalize(dx as f64, dy as f64, scale);
+            prop_assert_eq!(x.fract(), 0.0);
+            prop_assert_eq!(y.fract(), 0.0);
+            sent_x += x;
+            sent_y += y;
+            raw_x += dx as f64;
+            raw_y += dy as f64;
+        }
+        prop_assert!((raw_x / scale - sent_x).abs() < 1.0 + 1e-9);
+        prop_assert!((raw_y / scale - sent_y).abs() < 1.0 + 1e-9);
+    }
+}
+
+#[test]
+fn unscaled_moves_pass_unchanged() {
+    let mut normalizer = DeltaNormalizer::default();
+    assert_eq!(normalizer.normalize(7.0, -3.0, 1.0), (7.0, -3.0));
+}
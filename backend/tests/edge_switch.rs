@@ -0,0 +1,72 @@
+//! Screen-edge switching: the local cursor switches once per visit to an edge,
+//! and comes in on the other screen where it left this one.
+
+use rust_service::input_capture::EdgeWatch;
+use rust_service::protocol::ScreenEdge;
+use rust_service::screens::{Monitor, Topology};
+
+const SCREEN: (u32, u32) = (1920, 1080);
+
+#[test]
+fn an_edge_switches_once_per_visit() {
+    let mut watch = EdgeWatch::default();
+    let screens = &Topology::single(SCREEN);
+    let edges = [ScreenEdge::Right];
+
+    // Already there when switching was set up: nothing until it has been away
+    assert_eq!(watch.update((1919, 500), screens, edges), None);
+    assert_eq!(watch.update((1000, 500), screens, edges), None);
+    assert_eq!(watch.update((1919, 500), screens, edges), Some(ScreenEdge::Right));
+    assert_eq!(watch.update((1919, 510), screens, edges), None);
+    // Edges without a device beyond them don't count
+    assert_eq!(watch.update((0, 500), screens, edges), None);
+    assert_eq!(watch.update((1919, 500), screens, edges), Some(ScreenEdge::Right));
+
+    // Put back on the edge when control came home
+    watch.update((1000, 500), screens, edges);
+    watch.disarm();
+    assert_eq!(watch.update((1919, 500), screens, edges), None);
+}
+
+#[test]
+fn the_cursor_comes_in_where_it_left() {
+    // Left our 1920x1080 screen on the right, halfway down
+    let entry = ScreenEdge::Right.opposite().entry_point((1919, 540), SCREEN, (2560, 1440));
+    assert_eq!(entry, (0, 720));
+
+    // Back out at the bottom of a smaller screen, a quarter of the way across
+    let entry = ScreenEdge::Bottom.opposite().entry_point((320, 767), (1280, 768), SCREEN);
+    assert_eq!(entry, (480, 0));
+}
+
+#[test]
+fn only_outer_edges_of_the_desktop_count() {
+    // A 1280x1024 monitor left of the main one, top-aligned
+    let screens = Topology {
+        monitors: vec![
+            Monitor { x: 0, y: 0, width: 1920, height: 1080, primary: true },
+            Monitor { x: -1280, y: 0, width: 1280, height: 1024, primary: false },
+        ],
+    };
+    assert_eq!(screens.bounds(), ((-1280, 0), (3200, 1080)));
+
+    // Where the two meet is no edge, the far side of the second monitor is
+    assert!(!screens.touches(ScreenEdge::Left, (0, 500)));
+    assert!(screens.touches(ScreenEdge::Left, (-1280, 500)));
+    assert!(screens.touches(ScreenEdge::Right, (1919, 500)));
+    // The main monitor's left edge below the shorter one is outside too
+    assert!(screens.touches(ScreenEdge::Left, (0, 1050)));
+    assert!(screens.touches(ScreenEdge::Bottom, (-640, 1023)));
+
+    let mut watch = EdgeWatch::default();
+    watch.update((500, 500), &screens, [ScreenEdge::Left]);
+    assert_eq!(watch.update((0, 500), &screens, [ScreenEdge::Left]), None);
+    assert_eq!(watch.update((-1280, 500), &screens, [ScreenEdge::Left]), Some(ScreenEdge::Left));
+
+    // In from a 1920x1080 peer on the right, halfway down: onto the main monitor
+    assert_eq!(screens.entry_point(ScreenEdge::Right, (0, 540), SCREEN), Some((1919, 540)));
+    // Near the bottom on the left: the outer monitor there is shorter
+    assert_eq!(screens.entry_point(ScreenEdge::Left, (1919, 1060), SCREEN), Some((-1280, 1023)));
+    // Positions go to peers relative to the whole desktop
+    assert_eq!(screens.to_relative((-1280, 10)), (0, 10));
+}
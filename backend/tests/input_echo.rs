@@ -0,0 +1,36 @@
+//! Echoes of injected input are matched against what was sent, in order,
+//! and whatever never comes back counts against the success rate.
+
+use rust_service::echo::{digest, EchoTracker, ECHO_TIMEOUT};
+use rust_service::protocol::Message;
+use std::time::{Duration, Instant};
+
+#[test]
+fn echoes_settle_what_was_sent() {
+    let click = Message::MouseClick { button: 0, state: true, elapsed_ms: 0 };
+    let key = Message::KeyPress { key: 65, state: true };
+    let moved = Message::MouseMove { x: 3, y: -1 };
+    assert_eq!(digest(&click), digest(&click.clone()));
+    assert_ne!(digest(&click), digest(&key));
+
+    let start = Instant::now();
+    let mut tracker = EchoTracker::default();
+    for msg in [&click, &key, &moved, &moved] {
+        tracker.sent(digest(msg), start);
+    }
+    assert!(tracker.echoed(digest(&click), start + Duration::from_millis(10)));
+    // The key never came back
+    assert!(tracker.echoed(digest(&moved), start + Duration::from_millis(30)));
+    assert!(!tracker.echoed(digest(&key), start + Duration::from_millis(40)));
+
+    let stats = tracker.stats();
+    assert_eq!((stats.sent, stats.confirmed, stats.lost, stats.unexpected, stats.pending), (4, 2, 1, 1, 1));
+    assert_eq!(stats.success_rate, Some(2.0 / 3.0));
+    assert_eq!(stats.average_round_trip_ms, Some(20.0));
+
+    // The last move waited too long
+    tracker.expire(start + ECHO_TIMEOUT);
+    let stats = tracker.stats();
+    assert_eq!((stats.lost, stats.pending), (2, 0));
+    assert_eq!(EchoTracker::default().stats().success_rate, None);
+}
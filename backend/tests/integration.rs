@@ -0,0 +1,1268 @@
+//! Two full backends in one process, talking over localhost sockets.
+//! Discovery is off; the controller knows the controlled side as a static peer.
+
+use futures_util::{SinkExt, StreamExt};
+use rust_service::file_transfer::transfer_id;
+use rust_service::input_simulator::InputBackend;
+use rust_service::protocol::{MediaAction, Message as PeerMessage, RejectReason, TargetUnavailable, PROTOCOL_VERSION};
+use rust_service::screens::{Monitor, Topology};
+use rust_service::screenshot::Screenshot;
+use rust_service::transport::{Identity, PeerStream};
+use rust_service::websocket::DeviceInfo;
+use rust_service::{run_backend, BackendConfig};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Injected {
+    Move(i32, i32),
+    Click(u8, bool),
+    Wheel(i32, i32),
+    Key(u32, bool),
+    Media(MediaAction),
+    Highlight,
+}
+
+/// Records what the session would have injected into the OS
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<Injected>>,
+    unavailable: Mutex<Option<TargetUnavailable>>,
+    screenshots_taken: Mutex<usize>,
+}
+
+impl Recorder {
+    fn events(&self) -> Vec<Injected> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl InputBackend for Recorder {
+    fn mouse_move(&self, dx: i32, dy: i32) {
+        self.events.lock().unwrap().push(Injected::Move(dx, dy));
+    }
+
+    fn mouse_click(&self, button: u8, state: bool) {
+        self.events.lock().unwrap().push(Injected::Click(button, state));
+    }
+
+    fn mouse_wheel(&self, delta_x: i32, delta_y: i32) {
+        self.events.lock().unwrap().push(Injected::Wheel(delta_x, delta_y));
+    }
+
+    fn key_press(&self, key_code: u32, is_down: bool) {
+        self.events.lock().unwrap().push(Injected::Key(key_code, is_down));
+    }
+
+    fn media(&self, action: MediaAction) {
+        self.events.lock().unwrap().push(Injected::Media(action));
+    }
+
+    fn highlight(&self) {
+        self.events.lock().unwrap().push(Injected::Highlight);
+    }
+
+    fn target_unavailable(&self) -> Option<TargetUnavailable> {
+        *self.unavailable.lock().unwrap()
+    }
+
+    fn screens(&self) -> Option<Topology> {
+        Some(Topology { monitors: SCREENS.to_vec() })
+    }
+
+    // Big enough to take several frames, and slow like a real capture
+    fn screenshot(&self) -> Option<Screenshot> {
+        *self.screenshots_taken.lock().unwrap() += 1;
+        std::thread::sleep(Duration::from_millis(50));
+        let (width, height) = (320, 180);
+        Some(Screenshot { width, height, rgb: (0..width * height * 3).map(|i| i as u8).collect() })
+    }
+}
+
+/// What every test backend reports as its monitors: a second one left of the main one
+const SCREENS: [Monitor; 2] = [
+    Monitor { x: 0, y: 0, width: 1920, height: 1080, primary: true },
+    Monitor { x: -1280, y: 0, width: 1280, height: 1024, primary: false },
+];
+
+struct Instance {
+    id: String,
+    peer_port: u16,
+    ws_port: u16,
+    recorder: Arc<Recorder>,
+}
+
+impl Instance {
+    fn start(id: &str, static_peers: Vec<DeviceInfo>) -> Instance {
+        Instance::start_with_ipc(id, static_peers, None)
+    }
+
+    fn start_with_ipc(id: &str, static_peers: Vec<DeviceInfo>, ipc: Option<std::path::PathBuf>) -> Instance {
+        let recorder = Arc::new(Recorder::default());
+        let instance = Instance {
+            id: id.to_string(),
+            peer_port: free_port(),
+            ws_port: free_port(),
+            recorder: Arc::clone(&recorder),
+        };
+        let config = BackendConfig {
+            device_id: instance.id.clone(),
+            device_name: format!("{} (test)", id),
+            peer_port: instance.peer_port,
+            ws_port: instance.ws_port,
+            ipc,
+            web_port: None,
+            discovery: false,
+            broadcast_interval: Duration::from_secs(1),
+            hotkeys: false,
+            capture_hotkey: Default::default(),
+            static_peers,
+            audit_log: std::env::temp_dir().join(format!("shareflow-audit-{}.log", id)),
+            settings: settings_path(id),
+            paired_devices: paired_devices_path(id),
+            config_file: config_path(id),
+            downloads: downloads_path(id),
+            simulator: recorder,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = run_backend(config).await {
+                eprintln!("Backend error: {}", e);
+            }
+        });
+        instance
+    }
+
+    fn as_peer(&self) -> DeviceInfo {
+        DeviceInfo {
+            id: self.id.clone(),
+            name: self.id.clone(),
+            ip: "127.0.0.1".to_string(),
+            port: self.peer_port,
+            device_type: "DESKTOP".to_string(),
+        }
+    }
+
+    /// Frontend connection; retries while the backend is still starting
+    async fn connect_ws(&self) -> Ws {
+        for _ in 0..50 {
+            if let Ok((ws, _)) = connect_async(format!("ws://127.0.0.1:{}", self.ws_port)).await {
+                return ws;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("WebSocket server of {} never came up", self.id);
+    }
+
+    /// Wait until the recorder holds everything in `expected` (moves compared by total displacement)
+    async fn wait_for_input(&self, expected: &[Injected], displacement: (i32, i32)) -> Vec<Injected> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let events = self.recorder.events();
+            let others: Vec<Injected> = events.iter().copied().filter(|e| !matches!(e, Injected::Move(..))).collect();
+            let moved = events.iter().fold((0, 0), |(x, y), e| match e {
+                Injected::Move(dx, dy) => (x + dx, y + dy),
+                _ => (x, y),
+            });
+            if others.len() >= expected.len() && moved == displacement {
+                return others;
+            }
+            if tokio::time::Instant::now() > deadline {
+                panic!("timed out waiting for input, got {:?}", events);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+fn settings_path(id: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-settings-{}.json", id))
+}
+
+fn paired_devices_path(id: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-paired-{}.json", id))
+}
+
+fn config_path(id: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-config-{}.toml", id))
+}
+
+fn downloads_path(id: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-downloads-{}", id))
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>, msg: Value) {
+    ws.send(Message::Text(msg.to_string())).await.unwrap();
+}
+
+/// Next event of the given type; any others before it are skipped
+async fn wait_for<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>, event_type: &str) -> Value {
+    let wait = async {
+        while let Some(msg) = ws.next().await {
+            if let Ok(Message::Text(text)) = msg {
+                let value: Value = serde_json::from_str(&text).unwrap();
+                if value["type"] == event_type {
+                    return value;
+                }
+            }
+        }
+        panic!("WebSocket closed while waiting for {}", event_type);
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {}", event_type))
+}
+
+fn input(event_type: &str, fields: Value) -> Value {
+    let mut event = json!({ "type": event_type, "timestamp": 0 });
+    event.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+    json!({ "type": "sendInput", "event": event })
+}
+
+/// Set `instance`'s request timeouts; returns the frontend connection used
+async fn set_request_timeouts(instance: &Instance, timeouts: Value) -> Ws {
+    let mut ws = instance.connect_ws().await;
+    send(&mut ws, json!({ "type": "setRequestTimeouts", "timeouts": timeouts })).await;
+    assert_eq!(wait_for(&mut ws, "requestTimeouts").await["timeouts"], timeouts);
+    ws
+}
+
+/// Connect `controller` to `controlled`, answering the request on the controlled side
+async fn establish(controller: &Instance, controlled: &Instance) -> (Ws, Ws) {
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let request = wait_for(&mut ws_controlled, "connectionRequest").await;
+    assert_eq!(request["device"]["id"], controller.id.as_str());
+
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    let established = wait_for(&mut ws_controller, "connectionEstablished").await;
+    assert_eq!(established["deviceId"], controlled.id.as_str());
+
+    (ws_controller, ws_controlled)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripted_input_reaches_the_controlled_side() {
+    let controlled = Instance::start("device-b", Vec::new());
+    let controller = Instance::start("device-a", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+
+    send(&mut ws, input("mousemove", json!({ "dx": 5.0, "dy": -3.0 }))).await;
+    send(&mut ws, input("mousemove", json!({ "dx": 7.0, "dy": 1.0 }))).await;
+    send(&mut ws, input("mousedown", json!({ "button": 0 }))).await;
+    send(&mut ws, input("mouseup", json!({ "button": 0 }))).await;
+    send(&mut ws, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws, input("keyup", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws, input("wheel", json!({ "dx": 0.0, "dy": 1.0 }))).await;
+
+    let expected = [
+        Injected::Click(0, true),
+        Injected::Click(0, false),
+        Injected::Key(65, true),
+        Injected::Key(65, false),
+        Injected::Wheel(0, 120),
+    ];
+    let received = controlled.wait_for_input(&expected, (12, -2)).await;
+    assert_eq!(received, expected);
+
+    // Nothing flows back into the controller
+    assert!(controller.recorder.events().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn side_buttons_reach_the_controlled_side() {
+    let controlled = Instance::start("device-au", Vec::new());
+    let controller = Instance::start("device-at", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+    // They are only sent once the peer said it knows them
+    wait_for(&mut ws, "peerFeatures").await;
+
+    for button in [3, 4] {
+        send(&mut ws, input("mousedown", json!({ "button": button }))).await;
+        send(&mut ws, input("mouseup", json!({ "button": button }))).await;
+    }
+
+    let expected = [
+        Injected::Click(3, true),
+        Injected::Click(3, false),
+        Injected::Click(4, true),
+        Injected::Click(4, false),
+    ];
+    let received = controlled.wait_for_input(&expected, (0, 0)).await;
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rapid_input_keeps_its_order() {
+    let controlled = Instance::start("device-ab", Vec::new());
+    let controller = Instance::start("device-ac", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+
+    // Ctrl+A chords and double-clicks, sent back to back
+    let mut expected = Vec::new();
+    for _ in 0..10 {
+        send(&mut ws, input("keydown", json!({ "key": "Control", "keyCode": 17 }))).await;
+        send(&mut ws, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+        send(&mut ws, input("keyup", json!({ "key": "a", "keyCode": 65 }))).await;
+        send(&mut ws, input("keyup", json!({ "key": "Control", "keyCode": 17 }))).await;
+        send(&mut ws, input("mousedown", json!({ "button": 0 }))).await;
+        send(&mut ws, input("mouseup", json!({ "button": 0 }))).await;
+        expected.extend([
+            Injected::Key(17, true),
+            Injected::Key(65, true),
+            Injected::Key(65, false),
+            Injected::Key(17, false),
+            Injected::Click(0, true),
+            Injected::Click(0, false),
+        ]);
+    }
+
+    let received = controlled.wait_for_input(&expected, (0, 0)).await;
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sub_notch_scrolling_adds_up() {
+    let controlled = Instance::start("device-p", Vec::new());
+    let controller = Instance::start("device-o", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+
+    // A trackpad's quarter notches; the recorder only scrolls by whole notches
+    for _ in 0..4 {
+        send(&mut ws, input("wheel", json!({ "dx": 0.0, "dy": 0.25 }))).await;
+    }
+
+    let expected = [Injected::Wheel(0, 120)];
+    let received = controlled.wait_for_input(&expected, (0, 0)).await;
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn browser_wheel_scrolls_either_axis() {
+    let controlled = Instance::start("device-aw", Vec::new());
+    let controller = Instance::start("device-av", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+
+    // Sideways only, in notches
+    send(&mut ws, input("wheel", json!({ "dx": 1.0 }))).await;
+    // A browser WheelEvent: 100 px down, then 3 lines right
+    send(&mut ws, input("wheel", json!({ "dx": 0.0, "dy": 100.0, "deltaMode": 0 }))).await;
+    send(&mut ws, input("wheel", json!({ "dx": 3.0, "deltaMode": 1 }))).await;
+
+    let expected = [Injected::Wheel(120, 0), Injected::Wheel(0, -120), Injected::Wheel(120, 0)];
+    let received = controlled.wait_for_input(&expected, (0, 0)).await;
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batched_transport_still_delivers_input() {
+    let controlled = Instance::start("device-r", Vec::new());
+    let controller = Instance::start("device-q", vec![controlled.as_peer()]);
+    let mut ws = controller.connect_ws().await;
+    let options = json!({ "nodelay": false, "flush": { "mode": "periodic", "intervalMs": 20 }, "allowPlaintext": false, "bulkCapKibPerSec": 64 });
+    send(&mut ws, json!({ "type": "setTransportOptions", "options": options })).await;
+    let applied = wait_for(&mut ws, "transportOptions").await;
+    assert_eq!(applied["options"], options);
+    drop(ws);
+
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+    send(&mut ws, input("mousedown", json!({ "button": 1 }))).await;
+    send(&mut ws, input("mouseup", json!({ "button": 1 }))).await;
+
+    let expected = [Injected::Click(1, true), Injected::Click(1, false)];
+    let received = controlled.wait_for_input(&expected, (0, 0)).await;
+    assert_eq!(received, expected);
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn frontend_api_over_local_socket() {
+    let path = std::env::temp_dir().join(format!("shareflow-test-{}.sock", std::process::id()));
+    let _instance = Instance::start_with_ipc("device-s", Vec::new(), Some(path.clone()));
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(connected) = tokio::net::UnixStream::connect(&path).await {
+            stream = Some(connected);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let stream = stream.expect("local socket never came up");
+    let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/", stream).await.unwrap();
+    send(&mut ws, json!({ "type": "getLocalInfo" })).await;
+    let info = wait_for(&mut ws, "localInfo").await;
+    assert_eq!(info["device"]["id"], "device-s");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn declined_request_reports_the_reason() {
+    let controlled = Instance::start("device-d", Vec::new());
+    let controller = Instance::start("device-c", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "rejectConnection", "target_device_id": controller.id })).await;
+
+    let failed = wait_for(&mut ws_controller, "connectionFailed").await;
+    assert_eq!(failed["deviceId"], controlled.id.as_str());
+    assert_eq!(failed["rejectReason"], "userDeclined");
+    assert_eq!(failed["code"], "rejected");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_are_refused_without_a_frontend() {
+    let controlled = Instance::start("device-w", Vec::new());
+    let controller = Instance::start("device-v", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    // The prompt is showing when the frontend goes away
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    drop(ws_controlled);
+    let failed = wait_for(&mut ws_controller, "connectionFailed").await;
+    assert_eq!(failed["rejectReason"], "noOperator");
+
+    // No frontend at all: refused right away rather than after the accept timeout
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let failed = tokio::time::timeout(Duration::from_secs(1), wait_for(&mut ws_controller, "connectionFailed")).await.unwrap();
+    assert_eq!(failed["rejectReason"], "noOperator");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disconnect_is_seen_by_both_sides() {
+    let controlled = Instance::start("device-f", Vec::new());
+    let controller = Instance::start("device-e", vec![controlled.as_peer()]);
+    let (mut ws_controller, mut ws_controlled) = establish(&controller, &controlled).await;
+
+    send(&mut ws_controller, json!({ "type": "disconnect" })).await;
+    wait_for(&mut ws_controller, "disconnected").await;
+    wait_for(&mut ws_controlled, "disconnected").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requester_waits_as_long_as_the_prompt_is_up() {
+    let _ = std::fs::remove_file(settings_path("device-aj"));
+    let _ = std::fs::remove_file(settings_path("device-ak"));
+    let controlled = Instance::start("device-aj", Vec::new());
+    let controller = Instance::start("device-ak", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = set_request_timeouts(&controlled, json!({ "approvalSecs": 8, "waitSecs": 30 })).await;
+    set_request_timeouts(&controller, json!({ "approvalSecs": 30, "waitSecs": 5 })).await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let pending = wait_for(&mut ws_controller, "connectionPending").await;
+    assert_eq!(pending["deviceId"], controlled.id.as_str());
+    assert_eq!(pending["timeoutSecs"], 8);
+
+    // Past the requester's own 5 seconds, but the prompt is still up
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "connectionEstablished").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_peer_service_is_told_apart_from_an_unanswered_prompt() {
+    // Takes the connection, then never says anything
+    let hung = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hung_peer = DeviceInfo {
+        id: "device-hung".to_string(),
+        name: "hung".to_string(),
+        ip: "127.0.0.1".to_string(),
+        port: hung.local_addr().unwrap().port(),
+        device_type: "DESKTOP".to_string(),
+    };
+    tokio::spawn(async move {
+        let (_stream, _) = hung.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+    let _ = std::fs::remove_file(settings_path("device-al"));
+    let controller = Instance::start("device-al", vec![hung_peer]);
+    let mut ws_controller = set_request_timeouts(&controller, json!({ "approvalSecs": 30, "waitSecs": 5 })).await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": "device-hung" })).await;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let failed = wait_for(&mut ws_controller, "connectionFailed").await;
+    assert_eq!(failed["deviceId"], "device-hung");
+    assert_eq!(failed["code"], "notAcknowledged");
+}
+
+/// The next `count` session states reported for `device_id`
+async fn session_states<S: AsyncRead + AsyncWrite + Unpin>(ws: &mut WebSocketStream<S>, device_id: &str, count: usize) -> Vec<String> {
+    let mut states = Vec::new();
+    while states.len() < count {
+        let changed = wait_for(ws, "sessionStateChanged").await;
+        assert_eq!(changed["deviceId"], device_id);
+        states.push(changed["state"].as_str().unwrap().to_string());
+    }
+    states
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn session_states_follow_the_lifecycle() {
+    let controlled = Instance::start("device-ah", Vec::new());
+    let controller = Instance::start("device-ai", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    // The prompt comes right after the state change, so answer once that is seen
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    assert_eq!(session_states(&mut ws_controlled, &controller.id, 1).await, ["pendingApproval"]);
+    send(&mut ws_controlled, json!({ "type": "rejectConnection", "target_device_id": controller.id })).await;
+    assert_eq!(session_states(&mut ws_controller, &controlled.id, 2).await, ["requesting", "idle"]);
+    assert_eq!(session_states(&mut ws_controlled, &controller.id, 1).await, ["idle"]);
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    assert_eq!(session_states(&mut ws_controlled, &controller.id, 1).await, ["pendingApproval"]);
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    assert_eq!(session_states(&mut ws_controller, &controlled.id, 2).await, ["requesting", "established"]);
+    assert_eq!(session_states(&mut ws_controlled, &controller.id, 1).await, ["established"]);
+
+    send(&mut ws_controller, json!({ "type": "disconnect" })).await;
+    assert_eq!(session_states(&mut ws_controller, &controlled.id, 2).await, ["closing", "idle"]);
+    assert_eq!(session_states(&mut ws_controlled, &controller.id, 2).await, ["closing", "idle"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plaintext_peers_are_refused_unless_allowed() {
+    let _ = std::fs::remove_file(settings_path("device-am"));
+    let controlled = Instance::start("device-am", Vec::new());
+    let mut ws_controlled = controlled.connect_ws().await;
+    let request = PeerMessage::ConnectRequest {
+        id: "device-old".to_string(),
+        name: "old".to_string(),
+        public_key: None,
+        permissions: vec!["input".to_string()],
+    };
+    // A build that doesn't encrypt; the Hello only matters once plaintext is allowed
+    let (request, peer_port) = (&request, controlled.peer_port);
+    let connect_plaintext = |hello: bool| async move {
+        let stream = TcpStream::connect(("127.0.0.1", peer_port)).await.unwrap();
+        let mut stream = PeerStream::plaintext(stream);
+        if hello {
+            stream.hello().await.unwrap();
+        }
+        stream.send(request).await.unwrap();
+        stream
+    };
+
+    let mut stream = connect_plaintext(false).await;
+    match stream.recv().await.unwrap() {
+        PeerMessage::ConnectResponse { success, reason, .. } => {
+            assert!(!success);
+            assert_eq!(reason, Some(RejectReason::EncryptionRequired));
+        }
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    let options = json!({ "nodelay": true, "flush": { "mode": "immediate" }, "allowPlaintext": true });
+    send(&mut ws_controlled, json!({ "type": "setTransportOptions", "options": options })).await;
+    wait_for(&mut ws_controlled, "transportOptions").await;
+    let _stream = connect_plaintext(true).await;
+    let prompt = wait_for(&mut ws_controlled, "connectionRequest").await;
+    assert_eq!(prompt["device"]["id"], "device-old");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn protocol_versions_are_checked_in_the_hello() {
+    let controlled = Instance::start("device-bh", Vec::new());
+    let mut ws_controlled = controlled.connect_ws().await;
+    let request = PeerMessage::ConnectRequest {
+        id: "device-old".to_string(),
+        name: "old".to_string(),
+        public_key: None,
+        permissions: vec!["input".to_string()],
+    };
+    let connect = || async {
+        let stream = TcpStream::connect(("127.0.0.1", controlled.peer_port)).await.unwrap();
+        PeerStream::initiate(stream, &Identity::generate().unwrap()).await.unwrap()
+    };
+
+    // Older than anything this build talks to: told so, after its Hello is answered
+    let mut stream = connect().await;
+    stream.send(&PeerMessage::Hello { version: 0, capabilities: 0 }).await.unwrap();
+    match stream.recv().await.unwrap() {
+        PeerMessage::Hello { version, .. } => assert_eq!(version, PROTOCOL_VERSION),
+        other => panic!("expected a Hello, got {:?}", other),
+    }
+    stream.send(&request).await.unwrap();
+    match stream.recv().await.unwrap() {
+        PeerMessage::ConnectResponse { success, reason, .. } => {
+            assert!(!success);
+            assert_eq!(reason, Some(RejectReason::VersionMismatch));
+        }
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    // A build from before Hello starts with its request: version 1, refused the same way
+    let mut stream = connect().await;
+    stream.send(&request).await.unwrap();
+    match stream.recv().await.unwrap() {
+        PeerMessage::ConnectResponse { success, reason, .. } => {
+            assert!(!success);
+            assert_eq!(reason, Some(RejectReason::VersionMismatch));
+        }
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    // One that speaks the current version is asked about
+    let mut stream = connect().await;
+    stream.hello().await.unwrap();
+    stream.send(&request).await.unwrap();
+    let prompt = wait_for(&mut ws_controlled, "connectionRequest").await;
+    assert_eq!(prompt["device"]["id"], "device-old");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_without_a_device_id_are_refused() {
+    let controlled = Instance::start("device-bi", Vec::new());
+    let _ws_controlled = controlled.connect_ws().await;
+    let stream = TcpStream::connect(("127.0.0.1", controlled.peer_port)).await.unwrap();
+    let mut stream = PeerStream::initiate(stream, &Identity::generate().unwrap()).await.unwrap();
+    stream.hello().await.unwrap();
+    let request = PeerMessage::ConnectRequest {
+        id: String::new(),
+        name: "anonymous".to_string(),
+        public_key: None,
+        permissions: vec!["input".to_string()],
+    };
+    stream.send(&request).await.unwrap();
+    match stream.recv().await.unwrap() {
+        PeerMessage::ConnectResponse { success, reason, .. } => {
+            assert!(!success);
+            assert_eq!(reason, Some(RejectReason::MissingDeviceId));
+        }
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_peer_is_dropped_by_heartbeat() {
+    // A peer that accepts, announces heartbeats and then never sends another frame,
+    // while keeping the socket open, like a machine that lost power or network
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = DeviceInfo {
+        id: "device-u".to_string(),
+        name: "device-u".to_string(),
+        ip: "127.0.0.1".to_string(),
+        port: listener.local_addr().unwrap().port(),
+        device_type: "DESKTOP".to_string(),
+    };
+    let silent_peer = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut stream, _request) = PeerStream::accept(stream, &Identity::generate().unwrap()).await.unwrap();
+        let response = PeerMessage::ConnectResponse { success: true, reason: None, granted: vec!["input".to_string()] };
+        stream.send(&response).await.unwrap();
+        let features = PeerMessage::Features { features: vec!["heartbeat".to_string()] };
+        stream.send(&features).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        drop(stream);
+    });
+
+    let controller = Instance::start("device-t", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-u" })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+    wait_for(&mut ws, "disconnected").await;
+    silent_peer.abort();
+}
+
+/// A controlled side that accepts with `granted` and then sends `messages`, as a
+/// modified build could; resolves to what the controller sent in the second after
+async fn rogue_controlled_peer(id: &str, granted: &[&str], messages: Vec<PeerMessage>) -> (DeviceInfo, tokio::task::JoinHandle<Vec<PeerMessage>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = DeviceInfo {
+        id: id.to_string(),
+        name: id.to_string(),
+        ip: "127.0.0.1".to_string(),
+        port: listener.local_addr().unwrap().port(),
+        device_type: "DESKTOP".to_string(),
+    };
+    let granted: Vec<String> = granted.iter().map(|name| name.to_string()).collect();
+    let task = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut stream, _request) = PeerStream::accept(stream, &Identity::generate().unwrap()).await.unwrap();
+        stream.send(&PeerMessage::ConnectResponse { success: true, reason: None, granted }).await.unwrap();
+        for msg in &messages {
+            stream.send(msg).await.unwrap();
+        }
+        let mut received = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Ok(msg) = stream.recv().await {
+                received.push(msg);
+            }
+        })
+        .await;
+        received
+    });
+    (peer, task)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlled_side_cannot_send_input_back() {
+    let messages = vec![
+        PeerMessage::MouseMove { x: 5, y: 5 },
+        PeerMessage::MouseMoveBatch(vec![(1, 2), (3, 4)]),
+        PeerMessage::MouseClick { button: 0, state: true, elapsed_ms: 0 },
+        PeerMessage::MouseClick { button: 0, state: false, elapsed_ms: 0 },
+        PeerMessage::KeyPress { key: 65, state: true },
+        PeerMessage::KeyPress { key: 65, state: false },
+        PeerMessage::MouseWheel { delta_x: 0, delta_y: 120 },
+    ];
+    let (peer, rogue) = rogue_controlled_peer("device-bj", &["input"], messages).await;
+    let controller = Instance::start("device-bk", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-bj" })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+
+    rogue.await.unwrap();
+    assert!(controller.recorder.events().is_empty(), "{:?}", controller.recorder.events());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlled_side_cannot_hand_off_back() {
+    let messages = vec![PeerMessage::HandOff { text: "https://example.com/".to_string() }];
+    let (peer, rogue) = rogue_controlled_peer("device-br", &["handOff"], messages).await;
+    let controller = Instance::start("device-bs", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-br", "permissions": ["handOff"] })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+
+    let quiet = tokio::time::timeout(Duration::from_millis(1500), wait_for(&mut ws, "handOffReceived")).await;
+    assert!(quiet.is_err(), "the controller took a hand-off from the peer it controls");
+    rogue.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn handed_off_links_are_offered_not_opened() {
+    let controlled = Instance::start("device-bt", Vec::new());
+    let controller = Instance::start("device-bu", vec![controlled.as_peer()]);
+    let (mut ws_controller, mut ws_controlled) = establish(&controller, &controlled).await;
+    wait_for(&mut ws_controller, "peerFeatures").await;
+
+    send(&mut ws_controller, json!({ "type": "sendToDevice", "text": "https://example.com/page" })).await;
+    let received = wait_for(&mut ws_controlled, "handOffReceived").await;
+    assert_eq!(received["deviceId"], controller.id.as_str());
+    assert_eq!(received["text"], "https://example.com/page");
+    assert_eq!(received["action"], "link");
+    assert!(received["error"].is_null());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn last_session_is_remembered() {
+    let _ = std::fs::remove_file(settings_path("device-x"));
+    let controlled = Instance::start("device-y", Vec::new());
+    let controller = Instance::start("device-x", vec![controlled.as_peer()]);
+    let (mut ws_controller, _ws_controlled) = establish(&controller, &controlled).await;
+
+    // Long enough for heartbeats to go back and forth
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    send(&mut ws_controller, json!({ "type": "disconnect" })).await;
+    let refreshed = loop {
+        let found = wait_for(&mut ws_controller, "deviceFound").await;
+        if !found["history"].is_null() {
+            break found;
+        }
+    };
+    assert_eq!(refreshed["device"]["id"], controlled.id.as_str());
+    assert_eq!(refreshed["history"]["direction"], "outgoing");
+    assert!(refreshed["history"]["avgRttMs"].as_f64().unwrap() >= 0.0);
+
+    let saved: Value = serde_json::from_str(&std::fs::read_to_string(settings_path("device-x")).unwrap()).unwrap();
+    assert_eq!(saved["devices"][controlled.id.as_str()], refreshed["history"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn time_limited_session_expires_on_both_sides() {
+    let controlled = Instance::start("device-h", Vec::new());
+    let controller = Instance::start("device-g", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id, "time_limit_secs": 2 })).await;
+
+    let granted = wait_for(&mut ws_controller, "sessionTimeLimit").await;
+    assert_eq!(granted["deviceId"], controlled.id.as_str());
+    assert_eq!(granted["remainingSecs"], 2);
+
+    wait_for(&mut ws_controlled, "disconnected").await;
+    wait_for(&mut ws_controller, "disconnected").await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn features_are_exchanged_on_connect() {
+    let controlled = Instance::start("device-j", Vec::new());
+    let controller = Instance::start("device-i", vec![controlled.as_peer()]);
+    let (mut ws_controller, mut ws_controlled) = establish(&controller, &controlled).await;
+
+    let seen_by_controller = wait_for(&mut ws_controller, "peerFeatures").await;
+    assert_eq!(seen_by_controller["deviceId"], controlled.id.as_str());
+    assert_eq!(seen_by_controller["features"], json!(["wheel", "clipboard", "handOff", "heartbeat", "targetStatus", "sideButtons", "edgeSwitch", "screens", "fileTransfer", "lockState", "thumbnails", "highlight", "inputEcho"]));
+
+    let seen_by_controlled = wait_for(&mut ws_controlled, "peerFeatures").await;
+    assert_eq!(seen_by_controlled["deviceId"], controller.id.as_str());
+    assert_eq!(seen_by_controlled["features"], json!(["wheel", "clipboard", "handOff", "heartbeat", "targetStatus", "sideButtons", "edgeSwitch", "screens", "fileTransfer", "lockState", "thumbnails", "highlight", "inputEcho"]));
+
+    // Then each side's monitor arrangement
+    let screens = serde_json::to_value(SCREENS).unwrap();
+    let seen_by_controller = wait_for(&mut ws_controller, "peerScreens").await;
+    assert_eq!(seen_by_controller["deviceId"], controlled.id.as_str());
+    assert_eq!(seen_by_controller["monitors"], screens);
+    let seen_by_controlled = wait_for(&mut ws_controlled, "peerScreens").await;
+    assert_eq!(seen_by_controlled["deviceId"], controller.id.as_str());
+    assert_eq!(seen_by_controlled["monitors"], screens);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn traffic_is_counted_per_session() {
+    let controlled = Instance::start("device-aq", Vec::new());
+    let controller = Instance::start("device-ap", vec![controlled.as_peer()]);
+    let (mut ws_controller, mut ws_controlled) = establish(&controller, &controlled).await;
+
+    send(&mut ws_controller, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws_controller, input("keyup", json!({ "key": "a", "keyCode": 65 }))).await;
+    let expected = [Injected::Key(65, true), Injected::Key(65, false)];
+    controlled.wait_for_input(&expected, (0, 0)).await;
+
+    send(&mut ws_controller, json!({ "type": "exportStats" })).await;
+    let sent = wait_for(&mut ws_controller, "statsExport").await["stats"]["peers"][controlled.id.as_str()].clone();
+    send(&mut ws_controlled, json!({ "type": "exportStats" })).await;
+    let received = wait_for(&mut ws_controlled, "statsExport").await["stats"]["peers"][controller.id.as_str()].clone();
+
+    let session_sent = sent["history"][0]["bytesSent"].as_u64().unwrap();
+    assert!(session_sent > 0);
+    assert_eq!(sent["bytesSent"], session_sent);
+    assert!(received["history"][0]["bytesReceived"].as_u64().unwrap() > 0);
+    assert_eq!(received["bytesReceived"], received["history"][0]["bytesReceived"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreachable_target_is_reported_to_the_controller() {
+    let controlled = Instance::start("device-ad", Vec::new());
+    let controller = Instance::start("device-ae", vec![controlled.as_peer()]);
+    let (mut ws_controller, _ws_controlled) = establish(&controller, &controlled).await;
+
+    *controlled.recorder.unavailable.lock().unwrap() = Some(TargetUnavailable::RemoteSession);
+    let status = wait_for(&mut ws_controller, "targetStatus").await;
+    assert_eq!(status["deviceId"], controlled.id.as_str());
+    assert_eq!(status["unavailable"], "remoteSession");
+
+    // A reconnecting frontend hears it again
+    send(&mut ws_controller, json!({ "type": "getConnectionStatus" })).await;
+    assert_eq!(wait_for(&mut ws_controller, "targetStatus").await["unavailable"], "remoteSession");
+
+    *controlled.recorder.unavailable.lock().unwrap() = None;
+    let status = wait_for(&mut ws_controller, "targetStatus").await;
+    assert_eq!(status["unavailable"], Value::Null);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn group_defaults_apply_to_members() {
+    let _ = std::fs::remove_file(settings_path("device-aa"));
+    let controlled = Instance::start("device-aa", Vec::new());
+    let controller = Instance::start("device-z", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+
+    let mut ws_controlled = controlled.connect_ws().await;
+    let group = json!({ "members": [controller.id], "pointerSpeed": 2.0, "permissions": ["input"], "autoAccept": true });
+    send(&mut ws_controlled, json!({ "type": "setDeviceGroup", "name": "desk", "group": group })).await;
+    let groups = wait_for(&mut ws_controlled, "deviceGroups").await;
+    assert_eq!(groups["groups"]["desk"]["autoAccept"], true);
+
+    // Not paired yet, so auto-accept doesn't apply; accepting without choosing takes the group's permissions
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    let granted = wait_for(&mut ws_controller, "permissionsGranted").await;
+    assert_eq!(granted["permissions"], json!(["input"]));
+
+    send(&mut ws_controller, input("mousemove", json!({ "dx": 5.0, "dy": -3.0 }))).await;
+    controlled.wait_for_input(&[], (10, -6)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paired_devices_skip_the_prompt() {
+    for id in ["device-an", "device-ao"] {
+        let _ = std::fs::remove_file(paired_devices_path(id));
+    }
+    let controlled = Instance::start("device-ao", Vec::new());
+    let controller = Instance::start("device-an", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    // First meeting: both sides show the same code, the user confirms it on both
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let shown = wait_for(&mut ws_controller, "pairingCode").await;
+    let request = wait_for(&mut ws_controlled, "connectionRequest").await;
+    let code = request["pairingCode"].as_str().unwrap().to_string();
+    assert_eq!(code.len(), 6);
+    assert_eq!(shown["code"], code.as_str());
+
+    send(&mut ws_controlled, json!({ "type": "confirmPairing", "device_id": controller.id, "code": "000000x" })).await;
+    let failed = wait_for(&mut ws_controlled, "pairingFailed").await;
+    assert_eq!(failed["code"], "pairingCodeMismatch");
+    send(&mut ws_controlled, json!({ "type": "confirmPairing", "device_id": controller.id, "code": code })).await;
+    let paired = wait_for(&mut ws_controlled, "pairedDevices").await;
+    assert!(paired["devices"][controller.id.as_str()]["publicKey"].is_string());
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    let confirmed = wait_for(&mut ws_controller, "peerConfirmedPairing").await;
+    assert_eq!(confirmed["deviceId"], controlled.id.as_str());
+    send(&mut ws_controller, json!({ "type": "confirmPairing", "device_id": controlled.id, "code": code })).await;
+    wait_for(&mut ws_controller, "pairedDevices").await;
+    wait_for(&mut ws_controlled, "peerConfirmedPairing").await;
+
+    send(&mut ws_controller, json!({ "type": "disconnect" })).await;
+    wait_for(&mut ws_controlled, "disconnected").await;
+    // Accepted without a prompt, so without anybody at the other end
+    drop(ws_controlled);
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let established = wait_for(&mut ws_controller, "connectionEstablished").await;
+    assert_eq!(established["deviceId"], controlled.id.as_str());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn config_file_auto_accept_applies_at_once_but_not_to_unpaired_keys() {
+    let _ = std::fs::remove_file(config_path("device-as"));
+    let controlled = Instance::start("device-as", Vec::new());
+    let controller = Instance::start("device-ar", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controlled, json!({ "type": "setConfig", "config": { "auto_accept": [controller.id], "ws_port": 0 } })).await;
+    let refused = wait_for(&mut ws_controlled, "config").await;
+    assert_eq!(refused["config"]["auto_accept"], json!([]));
+
+    send(&mut ws_controlled, json!({ "type": "setConfig", "config": { "auto_accept": [controller.id] } })).await;
+    let saved = wait_for(&mut ws_controlled, "config").await;
+    assert_eq!(saved["config"]["auto_accept"], json!([controller.id]));
+    assert_eq!(saved["restartRequired"], false);
+    let file = std::fs::read_to_string(config_path("device-as")).unwrap();
+    assert!(file.contains("auto_accept = [\"device-ar\"]"), "{}", file);
+
+    send(&mut ws_controlled, json!({ "type": "setConfig", "config": { "auto_accept": [controller.id], "capture_hotkey": "Ctrl+Shift+F9" } })).await;
+    let saved = wait_for(&mut ws_controlled, "config").await;
+    assert_eq!(saved["restartRequired"], true);
+
+    // Listed, but anybody could send that ID: without a paired key the user is still asked
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let request = wait_for(&mut ws_controlled, "connectionRequest").await;
+    assert_eq!(request["device"]["id"], controller.id.as_str());
+    assert!(request["pairingCode"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_overrides_beat_group_defaults() {
+    let _ = std::fs::remove_file(settings_path("device-af"));
+    let controlled = Instance::start("device-af", Vec::new());
+    let controller = Instance::start("device-ag", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+
+    let mut ws_controlled = controlled.connect_ws().await;
+    let group = json!({ "members": [controller.id], "pointerSpeed": 2.0, "autoAccept": true });
+    send(&mut ws_controlled, json!({ "type": "setDeviceGroup", "name": "desk", "group": group })).await;
+    let overrides = json!({ "pointerSpeed": 3.0, "invertScroll": true, "permissions": ["input"] });
+    send(&mut ws_controlled, json!({ "type": "setDeviceOverrides", "device_id": controller.id, "overrides": overrides })).await;
+    let stored = wait_for(&mut ws_controlled, "deviceOverrides").await;
+    assert_eq!(stored["overrides"][controller.id.as_str()]["pointerSpeed"], 3.0);
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    let granted = wait_for(&mut ws_controller, "permissionsGranted").await;
+    assert_eq!(granted["permissions"], json!(["input"]));
+
+    send(&mut ws_controller, input("mousemove", json!({ "dx": 5.0, "dy": -3.0 }))).await;
+    send(&mut ws_controller, input("wheel", json!({ "dx": 0.0, "dy": 1.0 }))).await;
+    let received = controlled.wait_for_input(&[Injected::Wheel(0, -120)], (15, -9)).await;
+    assert_eq!(received, [Injected::Wheel(0, -120)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn view_only_grant_drops_input() {
+    let controlled = Instance::start("device-l", Vec::new());
+    let controller = Instance::start("device-k", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id })).await;
+    let request = wait_for(&mut ws_controlled, "connectionRequest").await;
+    assert_eq!(request["permissions"], json!(["input", "clipboard", "media", "handOff"]));
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id, "permissions": [] })).await;
+
+    let granted = wait_for(&mut ws_controller, "permissionsGranted").await;
+    assert_eq!(granted["deviceId"], controlled.id.as_str());
+    assert_eq!(granted["permissions"], json!([]));
+
+    send(&mut ws_controller, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws_controller, input("keyup", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws_controller, input("mousemove", json!({ "dx": 5.0, "dy": 5.0 }))).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(controlled.recorder.events().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn media_control_works_without_input() {
+    let controlled = Instance::start("device-n", Vec::new());
+    let controller = Instance::start("device-m", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id, "permissions": ["media"] })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "permissionsGranted").await;
+
+    send(&mut ws_controller, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws_controller, json!({ "type": "sendMedia", "action": "volumeUp" })).await;
+    send(&mut ws_controller, json!({ "type": "sendMedia", "action": "playPause" })).await;
+
+    let expected = [Injected::Media(MediaAction::VolumeUp), Injected::Media(MediaAction::PlayPause)];
+    assert_eq!(controlled.wait_for_input(&expected, (0, 0)).await, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlled_side_cannot_send_media_keys_back() {
+    let messages = vec![PeerMessage::Media { action: MediaAction::VolumeUp }, PeerMessage::Media { action: MediaAction::PlayPause }];
+    let (peer, rogue) = rogue_controlled_peer("device-bl", &["media"], messages).await;
+    let controller = Instance::start("device-bm", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-bl", "permissions": ["media"] })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+
+    rogue.await.unwrap();
+    assert!(controller.recorder.events().is_empty(), "{:?}", controller.recorder.events());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlled_side_cannot_take_screenshots_back() {
+    let (peer, rogue) = rogue_controlled_peer("device-bn", &["screenshot"], vec![PeerMessage::ScreenshotRequest]).await;
+    let controller = Instance::start("device-bo", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-bn", "permissions": ["screenshot"] })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+
+    let received = rogue.await.unwrap();
+    assert!(
+        !received.iter().any(|msg| matches!(msg, PeerMessage::ScreenshotData { .. } | PeerMessage::ScreenshotUnavailable)),
+        "{:?}",
+        received
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlled_side_cannot_stream_thumbnails_back() {
+    let (peer, rogue) = rogue_controlled_peer("device-bp", &["screenshot"], vec![PeerMessage::ThumbnailStart { fps: 5 }]).await;
+    let controller = Instance::start("device-bq", vec![peer]);
+    let mut ws = controller.connect_ws().await;
+    send(&mut ws, json!({ "type": "requestConnection", "target_device_id": "device-bp", "permissions": ["screenshot"] })).await;
+    wait_for(&mut ws, "connectionEstablished").await;
+
+    let received = rogue.await.unwrap();
+    assert!(!received.iter().any(|msg| matches!(msg, PeerMessage::ThumbnailFrame { .. })), "{:?}", received);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_file_transfer_resumes() {
+    let controlled = Instance::start("device-ay", Vec::new());
+    let controller = Instance::start("device-ax", vec![controlled.as_peer()]);
+    let downloads = downloads_path(&controlled.id);
+    let _ = std::fs::remove_dir_all(&downloads);
+    std::fs::create_dir_all(&downloads).unwrap();
+
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let source = std::env::temp_dir().join("shareflow-send-ax.bin");
+    std::fs::write(&source, &content).unwrap();
+    // What an earlier, dropped attempt left on the receiving side
+    let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+    let modified_ms = modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let id = transfer_id(&source, content.len() as u64, modified_ms);
+    std::fs::write(downloads.join(format!("shareflow-send-ax.bin.{:016x}.part", id)), &content[..70_000]).unwrap();
+
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+    let permissions = json!(["input", "fileTransfer"]);
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id, "permissions": permissions })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "peerFeatures").await;
+
+    send(&mut ws_controller, json!({ "type": "sendFile", "device_id": controlled.id, "path": source })).await;
+    let started = wait_for(&mut ws_controlled, "fileTransfer").await;
+    assert_eq!(started["id"], id);
+    assert_eq!(started["done"], 70_000);
+    assert_eq!(started["total"], content.len());
+
+    let received = wait_for(&mut ws_controlled, "fileTransferEnded").await;
+    assert_eq!(received["error"], Value::Null);
+    let saved = std::path::PathBuf::from(received["path"].as_str().unwrap());
+    assert_eq!(saved, downloads.join("shareflow-send-ax.bin"));
+    assert_eq!(std::fs::read(&saved).unwrap(), content);
+
+    let sent = wait_for(&mut ws_controller, "fileTransferEnded").await;
+    assert_eq!(sent["error"], Value::Null);
+    assert_eq!(sent["incoming"], false);
+}
+
+#[tokio::test]
+async fn screenshots_only_with_the_permission() {
+    let controlled = Instance::start("device-az", Vec::new());
+    let controller = Instance::start("device-ba", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    // Not connected yet
+    send(&mut ws_controller, json!({ "type": "requestScreenshot", "device_id": controlled.id })).await;
+    let refused = wait_for(&mut ws_controller, "peerScreenshot").await;
+    assert_eq!(refused["image"], Value::Null);
+
+    let permissions = json!(["input", "screenshot"]);
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id, "permissions": permissions })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "peerFeatures").await;
+
+    send(&mut ws_controller, json!({ "type": "requestScreenshot", "device_id": controlled.id })).await;
+    let screenshot = wait_for(&mut ws_controller, "peerScreenshot").await;
+    assert_eq!(screenshot["deviceId"], controlled.id);
+    assert_eq!((screenshot["width"].as_u64(), screenshot["height"].as_u64()), (Some(320), Some(180)));
+    let image = screenshot["image"].as_str().unwrap();
+    assert!(image.starts_with("data:image/bmp;base64,Qk0"));
+}
+
+#[tokio::test]
+async fn a_burst_of_screenshot_requests_takes_one() {
+    let controlled = Instance::start("device-bw", Vec::new());
+    let controller = Instance::start("device-bx", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id, "permissions": ["input", "screenshot"] })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "peerFeatures").await;
+
+    for _ in 0..5 {
+        send(&mut ws_controller, json!({ "type": "requestScreenshot", "device_id": controlled.id })).await;
+    }
+    let screenshot = wait_for(&mut ws_controller, "peerScreenshot").await;
+    assert!(screenshot["image"].is_string());
+    assert!(tokio::time::timeout(Duration::from_millis(500), wait_for(&mut ws_controller, "peerScreenshot")).await.is_err());
+    assert_eq!(*controlled.recorder.screenshots_taken.lock().unwrap(), 1);
+
+    // Once it is done the next request takes a new one
+    send(&mut ws_controller, json!({ "type": "requestScreenshot", "device_id": controlled.id })).await;
+    wait_for(&mut ws_controller, "peerScreenshot").await;
+    assert_eq!(*controlled.recorder.screenshots_taken.lock().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn thumbnails_stream_until_stopped() {
+    let controlled = Instance::start("device-bb", Vec::new());
+    let controller = Instance::start("device-bc", vec![controlled.as_peer()]);
+    let mut ws_controller = controller.connect_ws().await;
+    let mut ws_controlled = controlled.connect_ws().await;
+
+    let permissions = json!(["input", "screenshot"]);
+    send(&mut ws_controller, json!({ "type": "requestConnection", "target_device_id": controlled.id, "permissions": permissions })).await;
+    wait_for(&mut ws_controlled, "connectionRequest").await;
+    send(&mut ws_controlled, json!({ "type": "acceptConnection", "target_device_id": controller.id })).await;
+    wait_for(&mut ws_controller, "peerFeatures").await;
+
+    send(&mut ws_controller, json!({ "type": "startThumbnails", "device_id": controlled.id, "fps": 2 })).await;
+    for _ in 0..2 {
+        let thumbnail = wait_for(&mut ws_controller, "peerThumbnail").await;
+        assert_eq!(thumbnail["deviceId"], controlled.id);
+        assert!(thumbnail["image"].as_str().unwrap().starts_with("data:image/jpeg;base64,/9j/"));
+    }
+
+    send(&mut ws_controller, json!({ "type": "stopThumbnails", "device_id": controlled.id })).await;
+    // Let one already on its way arrive
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    while let Ok(Some(_)) = tokio::time::timeout(Duration::from_millis(50), ws_controller.next()).await {}
+    let quiet = tokio::time::timeout(Duration::from_millis(1200), wait_for(&mut ws_controller, "peerThumbnail")).await;
+    assert!(quiet.is_err(), "thumbnails kept coming after stopThumbnails");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn highlight_follows_the_moves_before_it() {
+    let controlled = Instance::start("device-bd", Vec::new());
+    let controller = Instance::start("device-be", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+    wait_for(&mut ws, "peerFeatures").await;
+
+    send(&mut ws, input("mousemove", json!({ "dx": 40.0, "dy": 25.0 }))).await;
+    send(&mut ws, json!({ "type": "highlightPointer", "device_id": controlled.id })).await;
+    assert_eq!(controlled.wait_for_input(&[Injected::Highlight], (40, 25)).await, [Injected::Highlight]);
+    // The ring goes where the cursor ended up
+    assert_eq!(controlled.recorder.events().last(), Some(&Injected::Highlight));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn injected_input_is_echoed_back() {
+    let controlled = Instance::start("device-bf", Vec::new());
+    let controller = Instance::start("device-bg", vec![controlled.as_peer()]);
+    let (mut ws, _ws_controlled) = establish(&controller, &controlled).await;
+    wait_for(&mut ws, "peerFeatures").await;
+
+    send(&mut ws, json!({ "type": "setInputEcho", "device_id": controlled.id, "enabled": true })).await;
+    send(&mut ws, input("mousemove", json!({ "dx": 5.0, "dy": 2.0 }))).await;
+    send(&mut ws, input("mousedown", json!({ "button": 0 }))).await;
+    send(&mut ws, input("mouseup", json!({ "button": 0 }))).await;
+    send(&mut ws, input("keydown", json!({ "key": "a", "keyCode": 65 }))).await;
+    send(&mut ws, input("keyup", json!({ "key": "a", "keyCode": 65 }))).await;
+
+    // Moves may be coalesced on the way, the rest arrive one by one
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    let stats = loop {
+        let stats = wait_for(&mut ws, "inputEchoStats").await["stats"].clone();
+        if stats["sent"].as_u64().unwrap() >= 5 && stats["pending"] == 0 {
+            break stats;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "echoes never settled: {}", stats);
+    };
+    assert_eq!(stats["confirmed"], stats["sent"]);
+    assert_eq!(stats["lost"], 0);
+    assert_eq!(stats["unexpected"], 0);
+    assert_eq!(stats["successRate"], 1.0);
+
+    send(&mut ws, json!({ "type": "setInputEcho", "device_id": controlled.id, "enabled": false })).await;
+    let last = wait_for(&mut ws, "inputEchoStats").await;
+    assert_eq!(last["stats"]["confirmed"], stats["confirmed"]);
+}
+
+#[tokio::test]
+async fn foreign_web_pages_cannot_open_the_api() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let instance = Instance::start("device-bv", Vec::new());
+    // Native clients send no Origin and get in
+    let _ws = instance.connect_ws().await;
+
+    let from = |origin: &str| {
+        let mut request = format!("ws://127.0.0.1:{}", instance.ws_port).into_client_request().unwrap();
+        request.headers_mut().insert("Origin", origin.parse().unwrap());
+        connect_async(request)
+    };
+    assert!(from("http://evil.example").await.is_err(), "a foreign page got the API");
+    assert!(from("null").await.is_err(), "a sandboxed frame got the API");
+    // The packaged Electron UI
+    assert!(from("file://").await.is_ok());
+}
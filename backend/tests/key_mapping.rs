@@ -0,0 +1,69 @@
+//! Every key rdev can capture must survive the trip to the peer:
+//! rdev_key_to_code -> Message::KeyPress on the wire -> map_key_code.
+
+use proptest::prelude::*;
+use rdev::Key;
+use rust_service::input_capture::rdev_key_to_code;
+use rust_service::input_simulator::map_key_code;
+use rust_service::protocol::{self, Message};
+
+// All of rdev's keys except Unknown(_), which has no portable meaning
+const ALL_KEYS: &[Key] = &[
+    Key::Alt, Key::AltGr, Key::Backspace, Key::CapsLock, Key::ControlLeft, Key::ControlRight,
+    Key::Delete, Key::DownArrow, Key::End, Key::Escape,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6,
+    Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::Home, Key::LeftArrow, Key::MetaLeft, Key::MetaRight, Key::PageDown, Key::PageUp,
+    Key::Return, Key::RightArrow, Key::ShiftLeft, Key::ShiftRight, Key::Space, Key::Tab,
+    Key::UpArrow, Key::PrintScreen, Key::ScrollLock, Key::Pause, Key::NumLock, Key::BackQuote,
+    Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5,
+    Key::Num6, Key::Num7, Key::Num8, Key::Num9, Key::Num0,
+    Key::Minus, Key::Equal,
+    Key::KeyQ, Key::KeyW, Key::KeyE, Key::KeyR, Key::KeyT, Key::KeyY, Key::KeyU, Key::KeyI,
+    Key::KeyO, Key::KeyP, Key::LeftBracket, Key::RightBracket,
+    Key::KeyA, Key::KeyS, Key::KeyD, Key::KeyF, Key::KeyG, Key::KeyH, Key::KeyJ, Key::KeyK,
+    Key::KeyL, Key::SemiColon, Key::Quote, Key::BackSlash, Key::IntlBackslash,
+    Key::KeyZ, Key::KeyX, Key::KeyC, Key::KeyV, Key::KeyB, Key::KeyN, Key::KeyM,
+    Key::Comma, Key::Dot, Key::Slash, Key::Insert,
+    Key::KpReturn, Key::KpMinus, Key::KpPlus, Key::KpMultiply, Key::KpDivide,
+    Key::Kp0, Key::Kp1, Key::Kp2, Key::Kp3, Key::Kp4,
+    Key::Kp5, Key::Kp6, Key::Kp7, Key::Kp8, Key::Kp9,
+    Key::KpDelete, Key::Function,
+];
+
+// Keys the OS reports but never lets us inject on its own
+const CAPTURE_ONLY: &[Key] = &[Key::Function];
+
+fn any_key() -> impl Strategy<Value = Key> {
+    prop::sample::select(ALL_KEYS)
+}
+
+fn over_the_wire(key: u32, state: bool) -> (u32, bool) {
+    let bytes = protocol::encode(&Message::KeyPress { key, state }).unwrap();
+    match protocol::decode(&bytes).unwrap() {
+        Message::KeyPress { key, state } => (key, state),
+        other => panic!("KeyPress decoded as {:?}", other),
+    }
+}
+
+proptest! {
+    #[test]
+    fn captured_keys_round_trip(key in any_key(), state in any::<bool>()) {
+        let code = rdev_key_to_code(key);
+        if CAPTURE_ONLY.contains(&key) {
+            prop_assert_eq!(code, 0);
+            return Ok(());
+        }
+        prop_assert_ne!(code, 0, "{:?} has no code", key);
+
+        let (received, received_state) = over_the_wire(code, state);
+        prop_assert_eq!(received_state, state);
+        prop_assert_eq!(map_key_code(received), Some(key));
+    }
+
+    #[test]
+    fn distinct_keys_get_distinct_codes(a in any_key(), b in any_key()) {
+        prop_assume!(a != b && !CAPTURE_ONLY.contains(&a) && !CAPTURE_ONLY.contains(&b));
+        prop_assert_ne!(rdev_key_to_code(a), rdev_key_to_code(b));
+    }
+}
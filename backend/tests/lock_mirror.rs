@@ -0,0 +1,73 @@
+//! While controlling, our keyboard LEDs show the controlled peer's lock keys,
+//! and go back to how they were once control ends.
+
+use rust_service::input_capture::LockMirror;
+use rust_service::input_simulator::InputBackend;
+use rust_service::protocol::LockState;
+use std::sync::Mutex;
+
+const CAPS_LOCK: u32 = 20;
+const NUM_LOCK: u32 = 144;
+
+/// Lock keys that toggle on each press, like a real keyboard's
+#[derive(Default)]
+struct Keyboard {
+    state: Mutex<LockState>,
+    presses: Mutex<Vec<u32>>,
+}
+
+impl InputBackend for Keyboard {
+    fn mouse_move(&self, _dx: i32, _dy: i32) {}
+    fn mouse_click(&self, _button: u8, _state: bool) {}
+    fn mouse_wheel(&self, _delta_x: i32, _delta_y: i32) {}
+
+    fn key_press(&self, key_code: u32, is_down: bool) {
+        if !is_down {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        match key_code {
+            20 => state.caps = !state.caps,
+            144 => state.num = !state.num,
+            145 => state.scroll = !state.scroll,
+            _ => {}
+        }
+        self.presses.lock().unwrap().push(key_code);
+    }
+
+    fn lock_state(&self) -> Option<LockState> {
+        Some(*self.state.lock().unwrap())
+    }
+}
+
+#[test]
+fn leds_follow_the_peer_and_come_back() {
+    let keyboard = Keyboard::default();
+    *keyboard.state.lock().unwrap() = LockState { caps: false, num: true, scroll: false };
+    let mut mirror = LockMirror::default();
+
+    // Not controlling: nothing to do
+    mirror.update(None, &keyboard);
+    assert!(keyboard.presses.lock().unwrap().is_empty());
+
+    let peer = LockState { caps: true, num: false, scroll: false };
+    mirror.update(Some(peer), &keyboard);
+    assert_eq!(keyboard.lock_state(), Some(peer));
+    assert_eq!(*keyboard.presses.lock().unwrap(), [CAPS_LOCK, NUM_LOCK]);
+
+    // Already matching: no more presses
+    mirror.update(Some(peer), &keyboard);
+    assert_eq!(keyboard.presses.lock().unwrap().len(), 2);
+
+    // Caps Lock pressed over there
+    let peer = LockState { caps: false, ..peer };
+    mirror.update(Some(peer), &keyboard);
+    assert_eq!(keyboard.lock_state(), Some(peer));
+
+    // Control ended: ours as they were before
+    mirror.update(None, &keyboard);
+    assert_eq!(keyboard.lock_state(), Some(LockState { caps: false, num: true, scroll: false }));
+    let presses = keyboard.presses.lock().unwrap().len();
+    mirror.update(None, &keyboard);
+    assert_eq!(keyboard.presses.lock().unwrap().len(), presses);
+}
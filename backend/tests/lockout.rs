@@ -0,0 +1,67 @@
+//! Failed attempts from one address slow it down exponentially and finally ban it,
+//! without affecting other addresses; a device declined often enough is muted.
+
+use rust_service::lockout::{
+    AttemptLimiter, LockoutKind, PromptMuter, BAN_AFTER, BAN_DURATION, BASE_BACKOFF, FREE_ATTEMPTS, MUTE_AFTER, MUTE_DURATION,
+};
+use std::net::IpAddr;
+use std::time::Instant;
+
+fn ip(last: u8) -> IpAddr {
+    IpAddr::from([192, 168, 1, last])
+}
+
+#[test]
+fn backoff_doubles_until_ban() {
+    let mut limiter = AttemptLimiter::new();
+    let mut now = Instant::now();
+    for _ in 0..FREE_ATTEMPTS {
+        assert_eq!(limiter.fail(ip(7), now), None);
+        assert!(limiter.check(ip(7), now).is_ok());
+    }
+
+    let mut expected = BASE_BACKOFF;
+    for _ in FREE_ATTEMPTS + 1..BAN_AFTER {
+        assert_eq!(limiter.fail(ip(7), now), Some((LockoutKind::Throttled, expected)));
+        assert_eq!(limiter.check(ip(7), now), Err((LockoutKind::Throttled, expected)));
+        // Other sources are untouched
+        assert!(limiter.check(ip(8), now).is_ok());
+        now += expected;
+        assert!(limiter.check(ip(7), now).is_ok());
+        expected *= 2;
+    }
+
+    assert_eq!(limiter.fail(ip(7), now), Some((LockoutKind::Banned, BAN_DURATION)));
+    assert!(matches!(limiter.check(ip(7), now + BAN_DURATION / 2), Err((LockoutKind::Banned, _))));
+    assert!(limiter.check(ip(7), now + BAN_DURATION).is_ok());
+}
+
+#[test]
+fn success_clears_failures() {
+    let mut limiter = AttemptLimiter::new();
+    let now = Instant::now();
+    for _ in 0..FREE_ATTEMPTS {
+        limiter.fail(ip(7), now);
+    }
+    limiter.succeed(ip(7));
+    assert_eq!(limiter.failures(ip(7)), 0);
+    assert_eq!(limiter.fail(ip(7), now), None);
+}
+
+#[test]
+fn repeated_declines_mute_once() {
+    let mut muter = PromptMuter::new();
+    let now = Instant::now();
+    for _ in 1..MUTE_AFTER {
+        assert!(!muter.declined("peer", now));
+    }
+    assert!(muter.declined("peer", now));
+    assert!(muter.is_muted("peer", now));
+    assert!(!muter.is_muted("other", now));
+    // Only the decline that starts the mute notifies
+    assert!(!muter.declined("peer", now));
+    assert!(!muter.is_muted("peer", now + MUTE_DURATION));
+
+    muter.unmute("peer");
+    assert!(!muter.declined("peer", now));
+}
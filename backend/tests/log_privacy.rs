@@ -0,0 +1,22 @@
+//! Input contents stay out of log lines unless unsafe debug logging is on.
+
+use rust_service::privacy::{self, LoggedMessage, Redacted};
+use rust_service::protocol::Message;
+
+#[test]
+fn keys_and_text_are_redacted_by_default() {
+    assert!(!privacy::unsafe_debug());
+
+    let key = format!("{:?}", LoggedMessage(&Message::KeyPress { key: 81, state: true }));
+    assert!(!key.contains("81"), "{}", key);
+    assert!(key.contains("state: true"), "{}", key);
+
+    let hand_off = format!("{:?}", LoggedMessage(&Message::HandOff { text: "hunter2".to_string() }));
+    assert!(!hand_off.contains("hunter2"), "{}", hand_off);
+
+    assert_eq!(format!("{}", Redacted(42)), "<redacted>");
+
+    // Control messages are logged as they are
+    let disconnect = format!("{:?}", LoggedMessage(&Message::Disconnect));
+    assert_eq!(disconnect, "Disconnect");
+}
@@ -0,0 +1,70 @@
+use rust_service::protocol::{self, Hello, Message, PeerFeature, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use serde::Serialize;
+
+/// Message as builds from before Hello (version 1) had it, tags in this order
+#[derive(Debug, Serialize)]
+enum V1Message {
+    Discovery { id: String, name: String, port: u16 },
+    MouseMove { x: i32, y: i32 },
+    MouseWheel { delta_x: i32, delta_y: i32 },
+    MouseClick { button: u8, state: bool },
+    KeyPress { key: u32, state: bool },
+    ConnectRequest,
+    ConnectResponse { success: bool },
+    Disconnect,
+}
+
+#[test]
+fn capability_bits_round_trip() {
+    let bits = PeerFeature::capabilities(protocol::LOCAL_FEATURES);
+    let mut features = PeerFeature::from_capabilities(bits);
+    let mut local = protocol::LOCAL_FEATURES.to_vec();
+    features.sort_by_key(|feature| feature.bit());
+    local.sort_by_key(|feature| feature.bit());
+    assert_eq!(features, local);
+
+    // Bits are positions in the enum and must never move
+    assert_eq!(PeerFeature::Wheel.bit(), 1);
+    assert_eq!(PeerFeature::FileTransfer.bit(), 1 << 2);
+    assert_eq!(PeerFeature::InputEcho.bit(), 1 << 13);
+    // A newer peer's bits are ignored
+    assert_eq!(PeerFeature::from_capabilities(PeerFeature::Clipboard.bit() | 1 << 63), [PeerFeature::Clipboard]);
+}
+
+#[test]
+fn hello_survives_the_wire() {
+    let hello = Hello::local();
+    assert_eq!(hello.version, PROTOCOL_VERSION);
+    assert!(hello.compatible());
+    match protocol::decode(&protocol::encode(&hello.message()).unwrap()).unwrap() {
+        Message::Hello { version, capabilities } => assert_eq!(Hello { version, capabilities }, hello),
+        other => panic!("expected a Hello, got {:?}", other),
+    }
+    assert!(!Hello { version: MIN_PROTOCOL_VERSION - 1, capabilities: 0 }.compatible());
+}
+
+#[test]
+fn version_1_frames_are_refused_not_misread() {
+    let v1 = |message: &V1Message| bincode::serialize(message).unwrap();
+
+    // Discovery kept its tag, so version 1 peers are still listed
+    let announcement = V1Message::Discovery { id: "device-old".to_string(), name: "old".to_string(), port: 8888 };
+    match protocol::decode(&v1(&announcement)).unwrap() {
+        Message::Discovery { id, port, .. } => assert_eq!((id.as_str(), port), ("device-old", 8888)),
+        other => panic!("expected a Discovery, got {:?}", other),
+    }
+
+    // What follows moved when messages were added in between
+    let session = [
+        V1Message::MouseWheel { delta_x: 0, delta_y: 120 },
+        V1Message::MouseClick { button: 0, state: true },
+        V1Message::KeyPress { key: 65, state: true },
+        V1Message::ConnectRequest,
+        V1Message::ConnectResponse { success: true },
+        V1Message::Disconnect,
+    ];
+    for message in &session {
+        assert!(protocol::decode(&v1(message)).is_err(), "{:?} was taken for something else", message);
+    }
+    assert!(!Hello { version: 1, capabilities: 0 }.compatible());
+}
@@ -0,0 +1,62 @@
+//! Every Noise message has its own nonce, so a frame someone on the path
+//! sends a second time doesn't open and ends the connection.
+
+use rust_service::error::TransportError;
+use rust_service::protocol::Message;
+use rust_service::transport::{Identity, PeerStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::test]
+async fn replayed_frame_is_rejected() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+
+    // Passes the client's frames on, its first one after the handshake twice
+    tokio::spawn(async move {
+        let (client, _) = proxy.accept().await.unwrap();
+        let upstream = TcpStream::connect(server_addr).await.unwrap();
+        let (mut client_read, mut client_write) = client.into_split();
+        let (mut upstream_read, mut upstream_write) = upstream.into_split();
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut upstream_read, &mut client_write).await;
+        });
+        // The initiator's two handshake messages, then the ConnectRequest
+        for frame in 0..3 {
+            let mut len = [0u8; 4];
+            client_read.read_exact(&mut len).await.unwrap();
+            let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+            client_read.read_exact(&mut data).await.unwrap();
+            let raw = [len.as_slice(), &data].concat();
+            upstream_write.write_all(&raw).await.unwrap();
+            if frame == 2 {
+                upstream_write.write_all(&raw).await.unwrap();
+            }
+        }
+        let _ = tokio::io::copy(&mut client_read, &mut upstream_write).await;
+    });
+
+    let client = tokio::spawn(async move {
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut stream = PeerStream::initiate(stream, &Identity::generate().unwrap()).await.unwrap();
+        let request = Message::ConnectRequest {
+            id: "device-a".to_string(),
+            name: "a".to_string(),
+            public_key: None,
+            permissions: vec!["input".to_string()],
+        };
+        stream.send(&request).await.unwrap();
+        stream
+    });
+
+    let (stream, _) = server.accept().await.unwrap();
+    let (mut stream, first) = PeerStream::accept(stream, &Identity::generate().unwrap()).await.unwrap();
+    assert!(matches!(first, Message::ConnectRequest { .. }), "{:?}", first);
+    match stream.recv().await {
+        Err(TransportError::Encryption(_)) => {}
+        other => panic!("replayed frame was not rejected: {:?}", other.map(|_| ())),
+    }
+    drop(client.await.unwrap());
+}
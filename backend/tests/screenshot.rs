@@ -0,0 +1,78 @@
+//! Screenshots and thumbnails cross in chunks that must arrive whole and in
+//! order, and reach the frontend as images it can show.
+
+use rust_service::protocol::Message;
+use rust_service::screenshot::{scaled_size, thumbnail_chunks, Assembler, Screenshot, ThumbnailAssembler, MAX_SIDE};
+
+fn chunk_args(msg: Message) -> (u32, u32, u32, Vec<u8>) {
+    match msg {
+        Message::ScreenshotData { width, height, offset, data } => (width, height, offset, data),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn chunks_come_back_together() {
+    let screenshot = Screenshot { width: 300, height: 200, rgb: (0..300 * 200 * 3).map(|i| (i % 251) as u8).collect() };
+    let chunks: Vec<_> = screenshot.chunks().map(chunk_args).collect();
+    assert!(chunks.len() > 1);
+
+    let mut assembler = Assembler::default();
+    let mut done = None;
+    for (width, height, offset, data) in chunks.clone() {
+        assert!(done.is_none());
+        done = assembler.add(width, height, offset, data);
+    }
+    assert_eq!(done.as_ref(), Some(&screenshot));
+
+    // A lost chunk drops the screenshot instead of showing a torn one
+    let mut assembler = Assembler::default();
+    let mut done = None;
+    for (width, height, offset, data) in chunks.into_iter().filter(|(_, _, offset, _)| *offset != 32 * 1024) {
+        done = done.or(assembler.add(width, height, offset, data));
+    }
+    assert_eq!(done, None);
+
+    // More than a peer may send
+    assert_eq!(Assembler::default().add(MAX_SIDE + 1, 1, 0, vec![0; (MAX_SIDE as usize + 1) * 3]), None);
+}
+
+#[test]
+fn shown_as_a_bmp() {
+    assert_eq!(scaled_size((3840, 2160)), (MAX_SIDE, 270));
+    assert_eq!(scaled_size((400, 300)), (400, 300));
+
+    // Two red pixels, rows padded to 8 bytes
+    let screenshot = Screenshot { width: 2, height: 1, rgb: vec![255, 0, 0, 255, 0, 0] };
+    let url = screenshot.data_url();
+    let base64 = url.strip_prefix("data:image/bmp;base64,").unwrap();
+    // 54 header bytes and one row
+    assert_eq!(base64.len(), 62_usize.div_ceil(3) * 4);
+    assert!(base64.starts_with("Qk0+AAAA"));
+    assert!(base64.ends_with("AAD/AAD/AAA="));
+}
+
+#[test]
+fn thumbnails_are_jpegs() {
+    let screenshot = Screenshot { width: 37, height: 21, rgb: (0..37 * 21 * 3).map(|i| (i * 7 % 256) as u8).collect() };
+    let jpeg = screenshot.jpeg();
+    assert!(jpeg.starts_with(&[0xFF, 0xD8, 0xFF, 0xE0]));
+    assert!(jpeg.ends_with(&[0xFF, 0xD9]));
+    // Height and width in the frame header
+    let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+    assert_eq!(jpeg[sof + 5..sof + 9], [0, 21, 0, 37]);
+
+    // Thumbnails larger than a frame, and a new one replacing one cut short
+    let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let mut assembler = ThumbnailAssembler::default();
+    let first = thumbnail_chunks(1, &big).next().unwrap();
+    let Message::ThumbnailFrame { seq, offset, total, data } = first else { unreachable!() };
+    assert_eq!(assembler.add(seq, offset, total, data), None);
+    let mut done = None;
+    for msg in thumbnail_chunks(2, &big) {
+        let Message::ThumbnailFrame { seq, offset, total, data } = msg else { unreachable!() };
+        assert!(done.is_none());
+        done = assembler.add(seq, offset, total, data);
+    }
+    assert_eq!(done, Some(big));
+}
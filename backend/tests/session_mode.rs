@@ -0,0 +1,75 @@
+//! Presentation mode forwards only what a slide deck remote would send.
+
+use rdev::Key;
+use rust_service::input_capture::rdev_key_to_code;
+use rust_service::input_simulator::map_key_code;
+use rust_service::protocol::Message;
+use rust_service::websocket::SessionMode;
+
+fn press(key: u32) -> Message {
+    Message::KeyPress { key, state: true }
+}
+
+fn presentation_allows(key: Key) -> bool {
+    SessionMode::Presentation.allows(&press(rdev_key_to_code(key)))
+}
+
+#[test]
+fn left_arrow_is_forwarded() {
+    assert!(presentation_allows(Key::LeftArrow));
+}
+
+#[test]
+fn right_arrow_is_forwarded() {
+    assert!(presentation_allows(Key::RightArrow));
+}
+
+#[test]
+fn up_arrow_is_forwarded() {
+    assert!(presentation_allows(Key::UpArrow));
+}
+
+#[test]
+fn down_arrow_is_forwarded() {
+    assert!(presentation_allows(Key::DownArrow));
+}
+
+#[test]
+fn page_up_is_forwarded() {
+    assert!(presentation_allows(Key::PageUp));
+}
+
+#[test]
+fn page_down_is_forwarded() {
+    assert!(presentation_allows(Key::PageDown));
+}
+
+#[test]
+fn b_is_forwarded_captured_or_typed() {
+    assert!(presentation_allows(Key::KeyB));
+    assert!(SessionMode::Presentation.allows(&press('B' as u32)));
+    assert!(SessionMode::Presentation.allows(&press('b' as u32)));
+}
+
+#[test]
+fn escape_is_forwarded() {
+    assert!(presentation_allows(Key::Escape));
+}
+
+#[test]
+fn everything_else_stays_here() {
+    for key in [Key::KeyA, Key::Kp2, Key::Return, Key::Space, Key::Num1, Key::Quote] {
+        assert!(!presentation_allows(key), "{:?} got through", key);
+    }
+    // Typed '!' and '"' share VK_PRIOR/VK_NEXT's numbers but aren't Page Up/Down
+    assert!(!SessionMode::Presentation.allows(&press('!' as u32)));
+    assert!(!SessionMode::Presentation.allows(&press('"' as u32)));
+    assert!(!SessionMode::Presentation.allows(&Message::MouseMove { x: 1, y: 1 }));
+    assert!(SessionMode::Normal.allows(&press(rdev_key_to_code(Key::KeyA))));
+}
+
+#[test]
+fn typed_punctuation_still_maps_to_its_key() {
+    assert_eq!(map_key_code('!' as u32), Some(Key::Num1));
+    assert_eq!(map_key_code('"' as u32), Some(Key::Quote));
+}
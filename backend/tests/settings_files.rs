@@ -0,0 +1,43 @@
+//! The settings file holds who is trusted: old files keep loading, and files this
+//! build can't use are kept rather than overwritten with the defaults.
+
+use rust_service::settings::{Settings, SETTINGS_VERSION};
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-settings-{}-{}.json", name, std::process::id()))
+}
+
+#[test]
+fn files_from_before_versioning_are_migrated() {
+    let path = temp_path("unversioned");
+    std::fs::write(&path, r#"{ "slots": { "1": "device-desk" }, "groups": { "desk": { "members": ["device-desk"], "autoAccept": true } } }"#).unwrap();
+
+    let mut settings = Settings::load(&path);
+    assert_eq!(settings.slot_of("device-desk"), Some(1));
+    assert!(settings.auto_accepted().contains("device-desk"));
+
+    settings.assign_slot(2, Some("device-tv".to_string()));
+    settings.save(&path).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["version"], SETTINGS_VERSION);
+    assert_eq!(Settings::load(&path).slot_of("device-tv"), Some(2));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn unusable_files_are_set_aside() {
+    for (name, text) in [("torn", r#"{ "slots": { "1": "devi"#), ("future", r#"{ "version": 99, "slots": {} }"#)] {
+        let path = temp_path(name);
+        let kept = path.with_file_name(format!("{}.bak", path.file_name().unwrap().to_string_lossy()));
+        std::fs::write(&path, text).unwrap();
+
+        let settings = Settings::load(&path);
+        assert!(settings.slots.is_empty());
+        settings.save(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&kept).unwrap(), text);
+
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(kept);
+    }
+}
@@ -0,0 +1,49 @@
+//! Settings exported on one machine and imported on another keep what the new
+//! machine already had, unless the export says otherwise.
+
+use rust_service::settings::{self, DeviceGroup, Settings};
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shareflow-trust-{}-{}.json", name, std::process::id()))
+}
+
+#[test]
+fn import_merges_into_the_new_machine() {
+    let (old_settings, new_settings, file) = (temp_path("old"), temp_path("new"), temp_path("file"));
+
+    let mut old = Settings::default();
+    old.assign_slot(1, Some("device-desk".to_string()));
+    let desk = DeviceGroup { members: vec!["device-desk".to_string()], auto_accept: true, ..Default::default() };
+    old.set_group("desk", Some(desk));
+    old.save(&old_settings).unwrap();
+
+    // Already set up on the new machine: a slot the export reuses and a group it doesn't know
+    let mut new = Settings::default();
+    new.assign_slot(1, Some("device-tv".to_string()));
+    new.assign_slot(2, Some("device-desk".to_string()));
+    new.set_group("living room", Some(DeviceGroup { members: vec!["device-tv".to_string()], ..Default::default() }));
+    new.save(&new_settings).unwrap();
+
+    settings::export_trust(&old_settings, "device-old-laptop", &file).unwrap();
+    let trust = settings::import_trust(&new_settings, &file).unwrap();
+    assert_eq!(trust.device_id, "device-old-laptop");
+
+    let merged = Settings::load(&new_settings);
+    assert_eq!(merged.slot_of("device-desk"), Some(1));
+    assert_eq!(merged.slot_of("device-tv"), None);
+    assert!(merged.auto_accepted().contains("device-desk"));
+    assert!(merged.groups.contains_key("living room"));
+
+    for path in [old_settings, new_settings, file] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[test]
+fn newer_trust_files_are_refused() {
+    let (settings_path, file) = (temp_path("refused"), temp_path("future"));
+    std::fs::write(&file, r#"{ "version": 99, "deviceId": "device-x", "settings": {} }"#).unwrap();
+    assert!(settings::import_trust(&settings_path, &file).is_err());
+    assert!(!settings_path.exists());
+    let _ = std::fs::remove_file(file);
+}
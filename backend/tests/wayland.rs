@@ -0,0 +1,22 @@
+//! On Wayland, keys go in and out as evdev codes; they must stay the keys
+//! rdev's X11 backend would have reported.
+#![cfg(target_os = "linux")]
+
+use rdev::Key;
+use rust_service::wayland::{evdev_code, key_from_evdev};
+
+#[test]
+fn keys_survive_the_round_trip() {
+    for key in [Key::KeyA, Key::Escape, Key::Return, Key::MetaRight, Key::Kp0, Key::F12, Key::AltGr] {
+        let code = evdev_code(key).unwrap();
+        assert_eq!(key_from_evdev(code), key);
+    }
+    // KEY_A, KEY_LEFTCTRL
+    assert_eq!(evdev_code(Key::KeyA), Some(30));
+    assert_eq!(key_from_evdev(29), Key::ControlLeft);
+
+    // Keys rdev has no name for keep their X keycode, 8 above evdev's
+    assert_eq!(key_from_evdev(115), Key::Unknown(123));
+    assert_eq!(evdev_code(Key::Unknown(123)), Some(115));
+    assert_eq!(evdev_code(Key::Unknown(3)), None);
+}
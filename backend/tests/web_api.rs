@@ -0,0 +1,69 @@
+//! The HTTP API's published contract matches what it actually sends.
+
+use rust_service::discovery::DiscoverySource;
+use rust_service::settings::{DeviceHistory, Direction};
+use rust_service::web_server::{ApiDoc, DeviceEvent, KnownDevices};
+use rust_service::websocket::{DeviceInfo, Event};
+use utoipa::OpenApi;
+
+#[test]
+fn openapi_document_describes_event_stream() {
+    let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    assert!(doc["paths"]["/api/events"]["get"]["responses"]["200"]["content"]["text/event-stream"].is_object());
+    assert!(doc["paths"]["/api/openapi.json"].is_object());
+    let schemas = &doc["components"]["schemas"];
+    for name in ["DeviceEvent", "DeviceInfo", "DiscoverySource", "DeviceHistory"] {
+        assert!(schemas[name].is_object(), "{} missing", name);
+    }
+}
+
+#[test]
+fn device_events_keep_the_frontend_wire_format() {
+    let device = DeviceInfo {
+        id: "peer".to_string(),
+        name: "Peer".to_string(),
+        ip: "192.168.1.7".to_string(),
+        port: 8081,
+        device_type: "DESKTOP".to_string(),
+    };
+    let messages = [
+        Event::DeviceFound {
+            device,
+            source: DiscoverySource::Broadcast,
+            slot: Some(1),
+            history: Some(DeviceHistory { last_connected: 1_700_000_000_000, direction: Direction::Outgoing, avg_rtt_ms: Some(1.5) }),
+        },
+        Event::DeviceLost { device_id: "peer".to_string() },
+        Event::ConnectionEstablished { device_id: "peer".to_string() },
+        Event::Disconnected,
+    ];
+    for msg in &messages {
+        let event = DeviceEvent::from_ws(msg).unwrap();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json, serde_json::to_value(msg).unwrap());
+        assert_eq!(json["type"], event.name());
+    }
+    assert!(DeviceEvent::from_ws(&Event::CaptureStarted).is_none());
+}
+
+#[test]
+fn known_devices_follow_found_and_lost() {
+    let device = |id: &str| DeviceInfo {
+        id: id.to_string(),
+        name: id.to_string(),
+        ip: "192.168.1.7".to_string(),
+        port: 8081,
+        device_type: "DESKTOP".to_string(),
+    };
+    let found = |id: &str| DeviceEvent::DeviceFound { device: device(id), source: DiscoverySource::Broadcast, slot: None, history: None };
+    let known = KnownDevices::default();
+    known.update(&found("a"));
+    known.update(&found("b"));
+    known.update(&found("a"));
+    known.update(&DeviceEvent::ConnectionEstablished { device_id: "b".to_string() });
+    known.update(&DeviceEvent::DeviceLost { device_id: "b".to_string() });
+
+    let snapshot = known.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert!(matches!(&snapshot[0], DeviceEvent::DeviceFound { device, .. } if device.id == "a"));
+}
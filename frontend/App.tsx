@@ -38,11 +38,16 @@ const App: React.FC = () => {
 
     const handleDeviceFound = (device: Device) => {
       setDevices(prev => {
-        if (prev.find(d => d.id === device.id)) return prev;
-        return [...prev, device];
+        // Sent again when its history changes; recently used devices come first
+        const next = [...prev.filter(d => d.id !== device.id), device];
+        return next.sort((a, b) => (b.lastConnected ?? 0) - (a.lastConnected ?? 0));
       });
     };
 
+    const handleDeviceLost = (deviceId: string) => {
+      setDevices(prev => prev.filter(d => d.id !== deviceId));
+    };
+
     const handleDisconnect = () => {
       console.log('[App] Received disconnected event, stopping capture if active');
       backend.stopCapture();
@@ -69,6 +74,7 @@ const App: React.FC = () => {
 
     backend.on('local-info', handleLocalInfo);
     backend.on('device-found', handleDeviceFound);
+    backend.on('device-lost', handleDeviceLost);
     backend.on('disconnected', handleDisconnect);
     backend.on('connection-request', handleIncomingRequest);
     backend.on('connection-request-cancelled', handleRequestCancelled);
@@ -76,6 +82,7 @@ const App: React.FC = () => {
     return () => {
       backend.off('local-info', handleLocalInfo);
       backend.off('device-found', handleDeviceFound);
+      backend.off('device-lost', handleDeviceLost);
       backend.off('disconnected', handleDisconnect);
       backend.off('connection-request', handleIncomingRequest);
       backend.off('connection-request-cancelled', handleRequestCancelled);
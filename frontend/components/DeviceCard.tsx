@@ -54,6 +54,12 @@ const DeviceCard: React.FC<DeviceCardProps> = ({
             {device.ip}
             {isConnected && <span className="w-1.5 h-1.5 rounded-full bg-emerald-500 animate-pulse"></span>}
           </p>
+          {device.lastConnected && (
+            <p className="text-xs text-gray-600">
+              上次连接 {new Date(device.lastConnected).toLocaleString()}
+              {device.avgRttMs !== undefined && ` · 延迟 ${device.avgRttMs.toFixed(1)} ms`}
+            </p>
+          )}
         </div>
       </div>
 
@@ -116,11 +116,19 @@ class RealBackendService {
             name: msg.device.name,
             ip: msg.device.ip,
             type: this.mapDeviceType(msg.device.type),
+            lastConnected: msg.history?.lastConnected,
+            avgRttMs: msg.history?.avgRttMs ?? undefined,
           };
           this.emit('device-found', device);
         }
         break;
 
+      case 'deviceLost':
+        if (msg.deviceId) {
+          this.emit('device-lost', msg.deviceId);
+        }
+        break;
+
       case 'connectionRequest':
         if (msg.device) {
           const device: Device = {
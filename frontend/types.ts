@@ -16,6 +16,10 @@ export interface Device {
   ip: string;
   type: DeviceType;
   isSelf?: boolean;
+  /** Start of the last session with this device (Unix ms) */
+  lastConnected?: number;
+  /** Smoothed round trip of that session */
+  avgRttMs?: number;
 }
 
 export interface InputEvent {